@@ -8,7 +8,8 @@ extern crate alloc;
 use core::{
     panic::PanicInfo,
     arch::asm,
-    ptr::addr_of,
+    mem::MaybeUninit,
+    ptr::{addr_of, addr_of_mut},
     slice::{
         from_raw_parts_mut,
         from_raw_parts
@@ -23,13 +24,15 @@ use uefi::proto::media::{
 };
 use uefi::data_types::CStr16;
 use uefi::proto::console::gop::GraphicsOutput;
+use uefi::proto::loaded_image::LoadedImage;
 use xmas_elf::{ElfFile, header, program};
 use shared_lib::addr::{PhysAddr, VirtAddr};
 use shared_lib::logger::FrameBufferInfo;
 use shared_lib::page_table::{PageTable, PageTablesAllocator, map_address, remap_address, align_down, align_down_u64};
 use shared_lib::{BootInfo, logger, VIRT_MAPPING_OFFSET};
 use shared_lib::allocator::ALLOCATOR;
-use shared_lib::frame_allocator::{MemoryRegion, FrameAllocator, MemoryMap, MAX_MEMORY_MAP_SIZE, MEMORY_MAP_PAGES};
+use shared_lib::cmdline::CMDLINE_MAX_LEN;
+use shared_lib::frame_allocator::{MemoryRegion, BumpFrameAllocator, MemoryMap, MAX_MEMORY_MAP_SIZE, MEMORY_MAP_PAGES};
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -52,9 +55,11 @@ fn convert_memory_type(t: MemoryType) -> shared_lib::frame_allocator::MemoryType
         | MemoryType::RESERVED | MemoryType::UNUSABLE => shared_lib::frame_allocator::MemoryType::Reserved,
 
         MemoryType::PERSISTENT_MEMORY | MemoryType::CONVENTIONAL
-        | MemoryType::LOADER_DATA | MemoryType::LOADER_CODE
         | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => shared_lib::frame_allocator::MemoryType::Free,
 
+        // the loader image with the boot info and the command line, the kernel image and its stack
+        MemoryType::LOADER_DATA | MemoryType::LOADER_CODE => shared_lib::frame_allocator::MemoryType::InUse,
+
         MemoryType::ACPI_NON_VOLATILE | MemoryType::RUNTIME_SERVICES_CODE
         | MemoryType::RUNTIME_SERVICES_DATA => shared_lib::frame_allocator::MemoryType::Acpi1_3,
 
@@ -96,7 +101,10 @@ fn init_framebuffer(image: uefi::Handle, system_table: &mut uefi::table::SystemT
         pixel_format: match mode_info.pixel_format() {
             uefi::proto::console::gop::PixelFormat::Rgb => shared_lib::logger::PixelFormat::Rgb,
             uefi::proto::console::gop::PixelFormat::Bgr => shared_lib::logger::PixelFormat::Bgr,
-            uefi::proto::console::gop::PixelFormat::Bitmask => shared_lib::logger::PixelFormat::Bitmask,
+            uefi::proto::console::gop::PixelFormat::Bitmask => {
+                let mask = mode_info.pixel_bitmask().unwrap();
+                shared_lib::logger::PixelFormat::Bitmask { red: mask.red, green: mask.green, blue: mask.blue }
+            },
             uefi::proto::console::gop::PixelFormat::BltOnly => shared_lib::logger::PixelFormat::BltOnly
         },
         stride: mode_info.stride()
@@ -150,7 +158,7 @@ fn load_kernel(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<
 }
 
 unsafe fn init_allocator(memory_map: uefi::table::boot::MemoryMap)
-                         -> Result<(FrameAllocator, MemoryMap), &'static str> {
+                         -> Result<(BumpFrameAllocator, MemoryMap), &'static str> {
     static mut MMAP: MemoryMap = MemoryMap {
         entries: [ MemoryRegion{ ty: shared_lib::frame_allocator::MemoryType::Reserved, addr: 0, page_count: 0 }; MAX_MEMORY_MAP_SIZE ],
         next_free_entry_idx: 0
@@ -174,7 +182,7 @@ unsafe fn init_allocator(memory_map: uefi::table::boot::MemoryMap)
     }
     MMAP.next_free_entry_idx = (memory_map.entries().len()) as u64;
 
-    Ok((FrameAllocator::new(addr_of!(MMAP), 0, 0), MMAP.clone()))
+    Ok((BumpFrameAllocator::new(addr_of!(MMAP), 0, 0), MMAP.clone()))
 }
 
 #[derive(Copy, Clone)]
@@ -183,7 +191,7 @@ struct MappedEntry {
     pub frame: u64
 }
 
-fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, allocator: &mut BumpFrameAllocator) -> Result<(), &'static str> {
     let mut mapped_frames: [MappedEntry; 100] = [ MappedEntry{ page: VirtAddr::zero(), frame: 0 }; 100 ];
     let mut mapped_frames_counter = 0;
 
@@ -209,7 +217,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
 
                         log::debug!("[kernel map] Mapping {} to {:#x}", virt, phys);
                         unsafe {
-                            map_address(page_table, virt, phys, allocator)
+                            map_address(page_table, virt, PhysAddr::new(phys), allocator)
                                 .expect("Failed to map kernel");
                         }
                         mapped_frames[mapped_frames_counter] = MappedEntry { page: virt, frame: phys };
@@ -221,7 +229,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
 
                     log::debug!("[kernel map] Mapping {} to {:#x}", virt_start_addr_aligned, phys_start_addr_aligned);
                     unsafe {
-                        map_address(page_table, virt_start_addr_aligned, phys_start_addr_aligned, allocator)
+                        map_address(page_table, virt_start_addr_aligned, PhysAddr::new(phys_start_addr_aligned), allocator)
                             .expect("Failed to map kernel");
                     }
                     mapped_frames[mapped_frames_counter] = MappedEntry { page: virt_start_addr_aligned, frame: phys_start_addr_aligned };
@@ -243,7 +251,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
                             for i in 0..mapped_frames_counter {
                                 if mapped_frames[i].frame == frame_to_copy {
                                     log::debug!("[kernel map] Remapping {} to {:#x}", mapped_frames[i].page, frame);
-                                    remap_address(page_table, mapped_frames[i].page, frame, allocator)
+                                    remap_address(page_table, mapped_frames[i].page, PhysAddr::new(frame), allocator)
                                         .expect("Failed to map kernel");
                                 }
                             }
@@ -276,7 +284,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
                             log::debug!("[kernel map] Mapping {} to {:#x}", virt_ptr, frame);
 
                             unsafe {
-                                map_address(page_table, virt_ptr, frame, allocator)
+                                map_address(page_table, virt_ptr, PhysAddr::new(frame), allocator)
                                     .expect("Failed to map kernel");
                                 core::ptr::write_bytes(
                                     frame as *mut u8,
@@ -295,7 +303,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
     Ok(())
 }
 
-fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, allocator: &mut BumpFrameAllocator) -> Result<(), &'static str> {
     let fb_start = framebuffer.addr;
     let fb_end = framebuffer.addr + framebuffer.size as u64 - 1;
     let pages_needed_for_fb = framebuffer.size / 4096;
@@ -304,7 +312,7 @@ fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, al
     for i in 0..pages_needed_for_fb {
         let ptr = fb_start + i as u64 * 4096;
         unsafe {
-            map_address(page_table, VirtAddr::new_checked(ptr + VIRT_MAPPING_OFFSET).unwrap(), ptr, allocator)
+            map_address(page_table, VirtAddr::new_checked(ptr + VIRT_MAPPING_OFFSET).unwrap(), PhysAddr::new(ptr), allocator)
                 .expect("Failed to map framebuffer");
         }
     }
@@ -312,19 +320,19 @@ fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, al
     Ok(())
 }
 
-fn create_stack(stack_addr: PhysAddr, stack_depth: usize, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<u64, &'static str> {
+fn create_stack(stack_addr: PhysAddr, stack_depth: usize, page_table: &mut PageTable, allocator: &mut BumpFrameAllocator) -> Result<u64, &'static str> {
     log::info!("Mapping stack");
     for i in 0..stack_depth {
         let ptr = stack_addr.0 + i as u64 * 4096;
         unsafe {
-            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), ptr, allocator)
+            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), PhysAddr::new(ptr), allocator)
                 .expect("Failed to map stack");
         }
     }
     Ok(stack_addr.0 + (stack_depth as u64 - 1) * 4096)
 }
 
-fn setup_mappings(last_frame_addr: PhysAddr, page_table: &mut PageTable, allocator: &mut FrameAllocator, kernel: *const u8, kernel_size: usize, framebuffer: &FrameBufferInfo) -> VirtAddr {
+fn setup_mappings(last_frame_addr: PhysAddr, page_table: &mut PageTable, allocator: &mut BumpFrameAllocator, kernel: *const u8, kernel_size: usize, framebuffer: &FrameBufferInfo) -> VirtAddr {
     let elf_file = ElfFile::new(unsafe { from_raw_parts(kernel, kernel_size) }).unwrap();
     header::sanity_check(&elf_file).expect("Failed to parse kernel file. Expected ELF");
 
@@ -335,7 +343,7 @@ fn setup_mappings(last_frame_addr: PhysAddr, page_table: &mut PageTable, allocat
         let virt = VirtAddr::new(phys + VIRT_MAPPING_OFFSET);
 
         unsafe {
-            map_address(page_table, virt, phys, allocator)
+            map_address(page_table, virt, PhysAddr::new(phys), allocator)
                 .expect("Failed to map memory");
         }
     }
@@ -348,7 +356,7 @@ fn setup_mappings(last_frame_addr: PhysAddr, page_table: &mut PageTable, allocat
 
     unsafe {
         let ctx_switch_ptr = context_switch as *const () as u64;
-        map_address(page_table, align_down(VirtAddr::new_checked(ctx_switch_ptr).unwrap()), align_down_u64(ctx_switch_ptr), allocator)
+        map_address(page_table, align_down(VirtAddr::new_checked(ctx_switch_ptr).unwrap()), PhysAddr::new(align_down_u64(ctx_switch_ptr)), allocator)
             .expect("Failed to map context switch function");
     }
 
@@ -361,23 +369,60 @@ fn init_logger(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<
 
     let logger = logger::LOGGER.get_or_init(move || logger::LockedLogger::new(framebuffer));
     log::set_logger(logger).unwrap();
-    log::set_max_level(log::LevelFilter::Info);
+    logger::set_max_level(log::LevelFilter::Info);
     framebuffer
 }
 
-fn map_bootinfo(boot_info: &BootInfo, page_table: &mut PageTable, allocator: &mut FrameAllocator) {
+/// Copies the image load options to a static buffer, so they stay available after exiting boot
+/// services. Non ASCII characters are replaced with '?'.
+fn read_cmdline(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>) -> &'static str {
+    static mut CMDLINE: [u8; CMDLINE_MAX_LEN] = [0; CMDLINE_MAX_LEN];
+
+    let loaded_image = system_table
+        .boot_services()
+        .open_protocol_exclusive::<LoadedImage>(image)
+        .expect("Failed to open LoadedImage protocol");
+
+    let options = match loaded_image.load_options_as_cstr16() {
+        Ok(options) => options,
+        Err(_) => return ""
+    };
+
+    let cmdline = unsafe { &mut *core::ptr::addr_of_mut!(CMDLINE) };
+    let mut len = 0;
+    for (dst, c) in cmdline.iter_mut().zip(options.iter()) {
+        let c = u16::from(*c);
+        *dst = if c < 0x80 { c as u8 } else { b'?' };
+        len += 1;
+    }
+
+    let cmdline = core::str::from_utf8(&cmdline[..len]).unwrap();
+    log::info!("Kernel command line: {}", cmdline);
+    cmdline
+}
+
+fn map_bootinfo(boot_info: &BootInfo, page_table: &mut PageTable, allocator: &mut BumpFrameAllocator) {
     let boot_info_ptr = boot_info as *const _ as u64;
     log::info!("Mapping boot info. addr: {:#x}", boot_info_ptr);
 
     unsafe {
-        map_address(page_table, align_down(VirtAddr::new_checked(boot_info_ptr).unwrap()), align_down_u64(boot_info_ptr), allocator)
+        map_address(page_table, align_down(VirtAddr::new_checked(boot_info_ptr).unwrap()), PhysAddr::new(align_down_u64(boot_info_ptr)), allocator)
             .expect("Failed to map boot info");
     }
 
+    let cmdline_start = align_down_u64(boot_info.cmdline.as_ptr() as u64);
+    let cmdline_end = boot_info.cmdline.as_ptr() as u64 + CMDLINE_MAX_LEN as u64;
+    for ptr in (cmdline_start..cmdline_end).step_by(4096) {
+        unsafe {
+            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), PhysAddr::new(ptr), allocator)
+                .expect("Failed to map kernel command line");
+        }
+    }
+
     for i in 0..=MEMORY_MAP_PAGES {
         let ptr = align_down_u64(boot_info.memory_map.entries.as_ptr() as u64) + i as u64 * 4096;
         unsafe {
-            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), ptr, allocator)
+            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), PhysAddr::new(ptr), allocator)
                 .expect("Failed to map boot info");
         }
     }
@@ -402,6 +447,8 @@ fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi
 
     log::info!("This is a very simple UEFI bootloader");
 
+    let cmdline = read_cmdline(image, &mut system_table);
+
     let kernel_max_size = 100 * 4096;
     let kernel = load_kernel(image, &mut system_table, kernel_max_size)
         .expect("Failed to load kernel");
@@ -428,6 +475,7 @@ fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi
         let page_table_ptr = allocator.allocate_page_table()
             .expect("Failed to allocate page table")
             as *mut PageTable;
+        (*page_table_ptr).clear();
 
         &mut *page_table_ptr
     };
@@ -436,7 +484,7 @@ fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi
 
     framebuffer.addr += VIRT_MAPPING_OFFSET;
 
-    let stack = create_stack(stack_addr, 20, page_table, &mut allocator)
+    let stack = create_stack(stack_addr, stack_depth, page_table, &mut allocator)
         .expect("Failed to create stack");
 
     let rsdp_addr = {
@@ -462,14 +510,20 @@ fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi
     log::info!("FB info: {:#x}", &framebuffer as *const _ as u64);
     log::info!("RSDP: {:#x}", rsdp_addr.unwrap_or(0));
 
-    let mut boot_info = BootInfo{ fb_info: framebuffer, rsdp_addr: rsdp_addr.unwrap_or(0), memory_map, memory_map_next_free_frame: 0 };
+    // kept in the loader image, which the memory map doesn't report as free
+    static mut BOOT_INFO: MaybeUninit<BootInfo> = MaybeUninit::uninit();
+    let boot_info = unsafe {
+        (*addr_of_mut!(BOOT_INFO)).write(BootInfo{ fb_info: framebuffer, rsdp_addr: rsdp_addr.unwrap_or(0), memory_map, memory_map_next_free_frame: 0,
+            kernel_addr: kernel as u64, kernel_size: kernel_max_size as u64,
+            kernel_stack_addr: stack_addr.0, kernel_stack_size: stack_depth as u64 * 4096, cmdline })
+    };
 
-    map_bootinfo(&boot_info, page_table, &mut allocator);
+    map_bootinfo(boot_info, page_table, &mut allocator);
 
     boot_info.memory_map_next_free_frame = allocator.next;
 
     unsafe {
-        context_switch(page_table as *const PageTable as u64, entry_point.0, stack, &boot_info);
+        context_switch(page_table as *const PageTable as u64, entry_point.0, stack, boot_info);
     }
 }
 
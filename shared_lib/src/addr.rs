@@ -1,7 +1,7 @@
 use core::fmt;
 use core::fmt::Formatter;
 use core::ops::{Add, BitAnd};
-use crate::page_table::ENTRY_COUNT;
+use crate::page_table::{align_down_to, align_up_to, ENTRY_COUNT};
 
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
@@ -11,6 +11,65 @@ pub struct PhysAddr(pub u64);
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct VirtAddr(pub u64);
 
+impl PhysAddr {
+    /// Create a new physical address, truncating bits 52 to 64.
+    #[inline]
+    pub const fn new(addr: u64) -> PhysAddr {
+        PhysAddr(addr & 0x000f_ffff_ffff_ffff)
+    }
+
+    /// Tries to create a new physical address.
+    ///
+    /// Fails if any bits in the range 52 to 64 are set.
+    #[inline]
+    pub const fn new_checked(addr: u64) -> Result<PhysAddr, &'static str> {
+        match addr & 0xfff0_0000_0000_0000 {
+            0 => Ok(PhysAddr(addr)),
+            _ => Err("Phys addr not valid"),
+        }
+    }
+
+    #[inline]
+    pub const fn zero() -> PhysAddr {
+        PhysAddr(0)
+    }
+
+    #[inline]
+    pub const fn offset(&self, offset: u64) -> Result<PhysAddr, &'static str> {
+        let (result, overflow) = self.0.overflowing_add(offset);
+        if overflow {
+            return Err("Phys addr overflow");
+        }
+        PhysAddr::new_checked(result)
+    }
+
+    /// Checks whether the address is aligned to `align`, which must be a power of two.
+    #[inline]
+    pub const fn is_aligned(&self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+}
+
+impl From<u64> for PhysAddr {
+    fn from(addr: u64) -> Self {
+        PhysAddr::new(addr)
+    }
+}
+
+impl Add<u64> for PhysAddr {
+    type Output = Self;
+
+    fn add(self, rhs: u64) -> Self::Output {
+        Self::new(self.0 + rhs)
+    }
+}
+
+impl fmt::Display for PhysAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PhysAddr({:#x})", self.0)
+    }
+}
+
 impl VirtAddr {
     /// Create a new canonical virtual address.
     #[inline]
@@ -35,6 +94,25 @@ impl VirtAddr {
         }
     }
 
+    /// Tries to create a new virtual address without sign extension.
+    ///
+    /// Fails unless bits 48 to 64 are copies of bit 47.
+    #[inline]
+    pub const fn new_canonical(addr: u64) -> Result<VirtAddr, &'static str> {
+        let virt = VirtAddr(addr);
+        if virt.is_canonical() {
+            Ok(virt)
+        } else {
+            Err("Virt addr not canonical")
+        }
+    }
+
+    /// Checks whether bits 48 to 64 are copies of bit 47, as the CPU requires.
+    #[inline]
+    pub const fn is_canonical(&self) -> bool {
+        VirtAddr::new(self.0).0 == self.0
+    }
+
     #[inline]
     pub const fn offset(&self, offset: u64) -> Result<VirtAddr, &'static str> {
         let (result, overflow) = self.0.overflowing_add(offset);
@@ -44,11 +122,34 @@ impl VirtAddr {
         Ok(VirtAddr::new(result))
     }
 
+    /// Checks whether the address is aligned to `align`, which must be a power of two.
+    #[inline]
+    pub const fn is_aligned(&self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+
+    /// Rounds the address down to a multiple of `align`, which must be a power of two.
+    #[inline]
+    pub const fn align_down_to(self, align: u64) -> VirtAddr {
+        VirtAddr::new(align_down_to(self.0, align))
+    }
+
+    /// Rounds the address up to a multiple of `align`, which must be a power of two.
+    #[inline]
+    pub const fn align_up_to(self, align: u64) -> VirtAddr {
+        VirtAddr::new(align_up_to(self.0, align))
+    }
+
     #[inline]
     pub fn from_ptr<T: ?Sized>(ptr: *const T) -> Self {
         Self::new(ptr as *const () as u64)
     }
 
+    #[inline]
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
     #[inline]
     pub const fn zero() -> VirtAddr {
         VirtAddr(0)
@@ -122,4 +223,28 @@ fn check_ctr_new_checked() {
     assert_eq!(0xffff_8000_0700_0000, virt3.0);
 
     assert!(VirtAddr::new_checked(0x1020_0000_0000_0002).is_err());
+}
+
+#[test_case]
+fn check_canonical() {
+    assert!(VirtAddr(0x0000_7fff_ffff_ffff).is_canonical());
+    assert!(VirtAddr(0xffff_8000_0000_0000).is_canonical());
+    assert!(!VirtAddr(0x0000_8000_0000_0000).is_canonical());
+    assert!(!VirtAddr(0x1000_0000_0000_1000).is_canonical());
+
+    assert_eq!(VirtAddr(0xffff_8000_0000_1000), VirtAddr::new_canonical(0xffff_8000_0000_1000).unwrap());
+    assert!(VirtAddr::new_canonical(0x0000_8000_0700_0000).is_err());
+    assert!(VirtAddr::new_canonical(0xff00_0000_0000_0000).is_err());
+}
+
+#[test_case]
+fn check_phys_addr() {
+    assert_eq!(PhysAddr(0x0000_f000_0000_1000), PhysAddr::new(0xfff0_f000_0000_1000));
+    assert!(PhysAddr::new_checked(0x0010_0000_0000_0000).is_err());
+
+    let phys = PhysAddr::new(0x2000);
+    assert!(phys.is_aligned(4096));
+    assert!(!(phys + 0x10).is_aligned(4096));
+    assert_eq!(PhysAddr(0x3000), phys.offset(0x1000).unwrap());
+    assert!(PhysAddr(0x000f_ffff_ffff_f000).offset(0x1000).is_err());
 }
\ No newline at end of file
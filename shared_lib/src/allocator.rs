@@ -1,4 +1,7 @@
 pub mod fixed_size_block;
+pub mod slab;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::allocator::fixed_size_block::FixedSizeBlockAllocator;
 
 pub struct Locked<A> {
@@ -17,6 +20,57 @@ impl<A> Locked<A> {
     }
 }
 
+static BYTES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static BYTES_FREED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATION_COUNT: AtomicUsize = AtomicUsize::new(0);
+static PEAK_USAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot of the heap counters. Sizes are the requested ones, not the blocks that serve them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub allocation_count: usize,
+    pub peak_usage: usize
+}
+
+impl HeapStats {
+    /// Returns the bytes allocated and not freed yet.
+    pub fn in_use(&self) -> usize {
+        self.bytes_allocated - self.bytes_freed
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "heap: {} allocations, {} bytes allocated, {} freed, {} in use, {} peak",
+            self.allocation_count, self.bytes_allocated, self.bytes_freed, self.in_use(), self.peak_usage)
+    }
+}
+
+/// Returns the counters of the global allocator. They are read one by one, so a snapshot taken
+/// while another CPU allocates might be slightly off.
+pub fn heap_stats() -> HeapStats {
+    // read freed first, so in_use can't underflow
+    let bytes_freed = BYTES_FREED.load(Ordering::Relaxed);
+    HeapStats {
+        bytes_allocated: BYTES_ALLOCATED.load(Ordering::Relaxed),
+        bytes_freed,
+        allocation_count: ALLOCATION_COUNT.load(Ordering::Relaxed),
+        peak_usage: PEAK_USAGE.load(Ordering::Relaxed)
+    }
+}
+
+fn record_alloc(size: usize) {
+    let allocated = BYTES_ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    ALLOCATION_COUNT.fetch_add(1, Ordering::Relaxed);
+    PEAK_USAGE.fetch_max(allocated - BYTES_FREED.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+fn record_dealloc(size: usize) {
+    BYTES_FREED.fetch_add(size, Ordering::Relaxed);
+}
+
 #[global_allocator]
 pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
@@ -1,7 +1,7 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 use core::ptr::NonNull;
-use crate::allocator::Locked;
+use crate::allocator::{record_alloc, record_dealloc, Locked};
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -15,9 +15,26 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// Number of bytes an allocation takes from the heap: the whole block for small ones.
+fn allocation_size(layout: &Layout) -> usize {
+    match list_index(layout) {
+        Some(index) => BLOCK_SIZES[index],
+        None => layout.size()
+    }
+}
+
+/// Maps at least `min_size` bytes of new memory starting at the heap `top` and returns how many
+/// bytes were mapped. Called with the allocator locked, so it must not allocate.
+pub type GrowHandler = fn(top: usize, min_size: usize) -> Result<usize, &'static str>;
+
+/// Small allocations are served from per size free lists, the rest and the blocks for the lists
+/// come from a linked list allocator that coalesces adjacent free regions.
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap
+    fallback_allocator: linked_list_allocator::Heap,
+    // bytes handed out and not freed yet
+    used: usize,
+    grow_handler: Option<GrowHandler>
 }
 
 impl FixedSizeBlockAllocator {
@@ -25,15 +42,83 @@ impl FixedSizeBlockAllocator {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty()
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+            used: 0,
+            grow_handler: None
         }
     }
 
+    /// Sets the function used to map more memory when an allocation doesn't fit.
+    pub fn set_grow_handler(&mut self, handler: GrowHandler) {
+        self.grow_handler = Some(handler);
+    }
+
+    /// Returns the address right after the end of the heap.
+    pub fn top(&self) -> usize {
+        self.fallback_allocator.top()
+    }
+
+    /// Adds `by` bytes starting at [`Self::top`] to the heap.
+    ///
+    /// # Safety
+    /// The memory must be mapped, writable and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
+    /// Returns the heap size given to `init`.
+    pub fn size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    /// Returns the number of bytes currently allocated, including the unused rest of small blocks.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the number of free bytes in the fallback heap, which large allocations and new
+    /// blocks for the lists are taken from. Freed small blocks stay in the lists and don't count.
+    pub fn fallback_free(&self) -> usize {
+        self.fallback_allocator.free()
+    }
+
+    /// Returns the number of bytes in the free blocks kept in the lists. They only serve small
+    /// allocations of the same size and are never given back to the fallback heap.
+    pub fn free_list_bytes(&self) -> usize {
+        self.list_heads.iter().zip(BLOCK_SIZES).map(|(head, &block_size)| {
+            let mut count = 0;
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                count += 1;
+                node = current.next.as_deref();
+            }
+            count * block_size
+        }).sum()
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        let grow_handler = match self.grow_handler {
+            Some(grow_handler) => grow_handler,
+            None => return ptr::null_mut()
+        };
+
+        // the new memory may be merged with a free region at the end, but the alignment may waste some
+        match grow_handler(self.top(), layout.size() + layout.align()) {
+            Ok(added) => unsafe { self.extend(added) },
+            Err(err) => {
+                log::warn!("[heap] failed to grow the heap: {}", err);
+                return ptr::null_mut();
+            }
+        }
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
@@ -44,7 +129,7 @@ impl FixedSizeBlockAllocator {
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
@@ -62,11 +147,20 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout)
+        };
+
+        if !ptr.is_null() {
+            allocator.used += allocation_size(&layout);
+            record_alloc(layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.used -= allocation_size(&layout);
+        record_dealloc(layout.size());
+
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
@@ -0,0 +1,170 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::NonNull;
+
+pub const SLAB_PAGE_SIZE: usize = 4096;
+
+/// Source of the pages slabs are carved from.
+pub trait SlabPageAllocator {
+    /// Returns a writable, page aligned page that stays mapped for the lifetime of the cache.
+    fn allocate_slab_page(&mut self) -> Option<NonNull<u8>>;
+}
+
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>
+}
+
+/// Cache of fixed size slots for values of type `T`.
+///
+/// Slots come from dedicated pages and freed slots go to an intrusive free list, so both
+/// allocation and freeing are O(1). Pages are never given back.
+pub struct SlabCache<T> {
+    free_list: Option<NonNull<FreeSlot>>,
+    allocated: usize,
+    capacity: usize,
+    _marker: PhantomData<T>
+}
+
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<FreeSlot>() { align_of::<T>() } else { align_of::<FreeSlot>() };
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<FreeSlot>() { size_of::<T>() } else { size_of::<FreeSlot>() };
+        (size + Self::SLOT_ALIGN - 1) & !(Self::SLOT_ALIGN - 1)
+    };
+    const SLOTS_PER_PAGE: usize = SLAB_PAGE_SIZE / Self::SLOT_SIZE;
+
+    pub const fn new() -> Self {
+        assert!(Self::SLOT_SIZE <= SLAB_PAGE_SIZE && Self::SLOT_ALIGN <= SLAB_PAGE_SIZE, "type doesn't fit in a slab page");
+
+        SlabCache {
+            free_list: None,
+            allocated: 0,
+            capacity: 0,
+            _marker: PhantomData
+        }
+    }
+
+    /// Returns the number of slots in use.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Returns the number of slots in all slab pages, used or not.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn grow(&mut self, page_allocator: &mut impl SlabPageAllocator) -> Result<(), &'static str> {
+        let page = page_allocator.allocate_slab_page()
+            .ok_or("Failed to allocate slab page")?;
+
+        // push in reverse, so slots are handed out in address order
+        for i in (0..Self::SLOTS_PER_PAGE).rev() {
+            let slot = unsafe { page.as_ptr().add(i * Self::SLOT_SIZE) } as *mut FreeSlot;
+            unsafe { slot.write(FreeSlot { next: self.free_list }) };
+            self.free_list = NonNull::new(slot);
+        }
+
+        self.capacity += Self::SLOTS_PER_PAGE;
+        Ok(())
+    }
+
+    /// Moves `value` to a free slot, taking a new page from `page_allocator` if there is none.
+    pub fn alloc(&mut self, value: T, page_allocator: &mut impl SlabPageAllocator) -> Result<NonNull<T>, &'static str> {
+        if self.free_list.is_none() {
+            self.grow(page_allocator)?;
+        }
+
+        let slot = self.free_list.unwrap();
+        unsafe {
+            self.free_list = slot.as_ref().next;
+            let ptr = slot.cast::<T>();
+            ptr.as_ptr().write(value);
+
+            self.allocated += 1;
+            Ok(ptr)
+        }
+    }
+
+    /// Moves the value out of its slot and returns the slot to the cache.
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` of this cache and must not be used after.
+    pub unsafe fn free(&mut self, ptr: NonNull<T>) -> T {
+        let value = ptr.as_ptr().read();
+
+        let slot = ptr.cast::<FreeSlot>();
+        slot.as_ptr().write(FreeSlot { next: self.free_list });
+        self.free_list = Some(slot);
+
+        self.allocated -= 1;
+        value
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[repr(align(4096))]
+struct TestPages([[u8; SLAB_PAGE_SIZE]; 2]);
+
+#[cfg(test)]
+static mut TEST_PAGES: TestPages = TestPages([[0; SLAB_PAGE_SIZE]; 2]);
+
+#[cfg(test)]
+struct TestPageAllocator {
+    next: usize
+}
+
+#[cfg(test)]
+impl SlabPageAllocator for TestPageAllocator {
+    fn allocate_slab_page(&mut self) -> Option<NonNull<u8>> {
+        let pages = unsafe { &mut *core::ptr::addr_of_mut!(TEST_PAGES.0) };
+        let page = pages.get_mut(self.next)?;
+        self.next += 1;
+        NonNull::new(page.as_mut_ptr())
+    }
+}
+
+#[test_case]
+fn slab_cache_test() {
+    let mut page_allocator = TestPageAllocator { next: 0 };
+    let mut cache: SlabCache<[u64; 64]> = SlabCache::new();
+    assert_eq!(0, cache.capacity());
+
+    // 512 byte slots, 8 per page
+    let first = cache.alloc([1; 64], &mut page_allocator).unwrap();
+    assert_eq!(8, cache.capacity());
+    assert_eq!(1, cache.allocated());
+
+    let mut slots = [first; 16];
+    for (i, slot) in slots.iter_mut().enumerate().skip(1) {
+        *slot = cache.alloc([i as u64; 64], &mut page_allocator).unwrap();
+    }
+    assert_eq!(16, cache.capacity());
+    assert_eq!(16, cache.allocated());
+    assert!(cache.alloc([0; 64], &mut page_allocator).is_err());
+
+    unsafe {
+        assert_eq!([5; 64], *slots[5].as_ptr());
+        assert_eq!([5; 64], cache.free(slots[5]));
+        assert_eq!(15, cache.allocated());
+
+        // the freed slot is reused without a new page
+        let reused = cache.alloc([42; 64], &mut page_allocator).unwrap();
+        assert_eq!(slots[5], reused);
+        assert_eq!([42; 64], *reused.as_ptr());
+        assert_eq!([6; 64], *slots[6].as_ptr());
+
+        for slot in slots {
+            cache.free(slot);
+        }
+    }
+
+    assert_eq!(0, cache.allocated());
+    assert_eq!(16, cache.capacity());
+}
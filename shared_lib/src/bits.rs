@@ -1,55 +1,145 @@
 use core::ops::Range;
 
-pub fn set_bit(num: &mut u64, n: u8, value: bool) {
-    let mask = 1 << n;
-    if value {
-        *num |= mask;
+/// Unsigned integers the bit helpers work on.
+pub trait BitField: Copy {
+    const BIT_LENGTH: u8;
+
+    fn into_u64(self) -> u64;
+
+    /// Truncates `value` to the type width.
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_bit_field {
+    ($($t:ty),*) => {
+        $(
+            impl BitField for $t {
+                const BIT_LENGTH: u8 = <$t>::BITS as u8;
+
+                fn into_u64(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_u64(value: u64) -> Self {
+                    value as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_bit_field!(u8, u16, u32, u64);
+
+fn check_bit<T: BitField>(n: u8) {
+    assert!(n < T::BIT_LENGTH, "bit {} is out of {} bit value", n, T::BIT_LENGTH);
+}
+
+fn range_mask<T: BitField>(range: &Range<u8>) -> u64 {
+    assert!(range.start < range.end && range.end <= T::BIT_LENGTH,
+            "bit range {:?} is out of {} bit value", range, T::BIT_LENGTH);
+
+    let len = range.end - range.start;
+    if len == 64 {
+        u64::MAX
     } else {
-        *num &= !mask;
+        (1 << len) - 1
     }
 }
 
-pub fn set_bits(num: &mut u64, mask: u64, shift: u8) {
-    let mask = mask << shift;
-
-    *num |= mask;
+pub fn get_bit<T: BitField>(num: T, n: u8) -> bool {
+    check_bit::<T>(n);
+    num.into_u64() & (1 << n) != 0
 }
 
-pub fn get_bits(num: u64, range: Range<u8>) -> u64 {
-    let cut_first_bits = num >> range.start;
-    if range.end != 64 {
-        cut_first_bits & (0xffff_ffff_ffff_ffff >> range.end)
+pub fn set_bit<T: BitField>(num: &mut T, n: u8, value: bool) {
+    check_bit::<T>(n);
+
+    let mask = 1 << n;
+    let mut bits = num.into_u64();
+    if value {
+        bits |= mask;
     } else {
-        cut_first_bits
+        bits &= !mask;
     }
+    *num = T::from_u64(bits);
+}
+
+/// Returns the bits in `range` shifted down to bit 0.
+pub fn get_bits<T: BitField>(num: T, range: Range<u8>) -> T {
+    let mask = range_mask::<T>(&range);
+    T::from_u64((num.into_u64() >> range.start) & mask)
+}
+
+/// Replaces the bits in `range` with `value`, which must fit in the range.
+pub fn set_bits<T: BitField>(num: &mut T, range: Range<u8>, value: T) {
+    let mask = range_mask::<T>(&range);
+    let value = value.into_u64();
+    assert!(value & !mask == 0, "value {:#x} doesn't fit in bit range {:?}", value, range);
+
+    let bits = (num.into_u64() & !(mask << range.start)) | (value << range.start);
+    *num = T::from_u64(bits);
 }
 
 #[test_case]
 fn set_bit_test() {
-    let mut num = 0b1000_0000;
+    let mut num = 0b1000_0000u64;
 
     set_bit(&mut num, 4, true);
     assert_eq!(0b1001_0000, num);
 
     set_bit(&mut num, 7, false);
     assert_eq!(0b0001_0000, num);
+
+    let mut byte = 0u8;
+    set_bit(&mut byte, 7, true);
+    assert_eq!(0x80, byte);
+
+    let mut num = 0u64;
+    set_bit(&mut num, 63, true);
+    assert_eq!(0x8000_0000_0000_0000, num);
+}
+
+#[test_case]
+fn get_bit_test() {
+    assert!(get_bit(0x8000u16, 15));
+    assert!(!get_bit(0x8000u16, 14));
+    assert!(get_bit(1u8, 0));
+    assert!(get_bit(0x8000_0000u32, 31));
+    assert!(get_bit(0x8000_0000_0000_0000u64, 63));
 }
 
 #[test_case]
 fn set_bits_test() {
-    let mask = 0b1001_1010;
+    let mut num = (1u64 << 47) | 0xff_0000;
 
-    let mut num = 1 << 47;
+    set_bits(&mut num, 16..24, 0b1001_1010);
+    assert_eq!(0x8000_009a_0000, num);
 
-    set_bits(&mut num, mask, 16);
+    set_bits(&mut num, 60..64, 0xf);
+    assert_eq!(0xf000_8000_009a_0000, num);
 
-    assert_eq!(0x8000_009a_0000, num);
+    set_bits(&mut num, 0..64, 0x1234);
+    assert_eq!(0x1234, num);
+
+    let mut byte = 0xffu8;
+    set_bits(&mut byte, 4..8, 0);
+    assert_eq!(0x0f, byte);
+
+    let mut word = 0u32;
+    set_bits(&mut word, 31..32, 1);
+    assert_eq!(0x8000_0000, word);
 }
 
 #[test_case]
 fn get_bits_test() {
-    assert_eq!(0b101, get_bits(0b0010_1000, 3..6));
-    assert_eq!(1, get_bits(0x8000_0000_0000_0000, 63..64));
-    assert_eq!(0x3777, get_bits(0x0000_3777_0000_0000, 32..48));
-    assert_eq!(0x22, get_bits(0x0000_0000_0000_0022, 0..6));
-}
\ No newline at end of file
+    assert_eq!(0b101, get_bits(0b0010_1000u64, 3..6));
+    assert_eq!(0b101, get_bits(0b1110_1000u64, 3..6));
+    assert_eq!(1, get_bits(0x8000_0000_0000_0000u64, 63..64));
+    assert_eq!(0x3777, get_bits(0xffff_3777_0000_0000u64, 32..48));
+    assert_eq!(0x22, get_bits(0x0000_0000_0000_0022u64, 0..6));
+    assert_eq!(u64::MAX, get_bits(u64::MAX, 0..64));
+
+    assert_eq!(0xa, get_bits(0xa5u8, 4..8));
+    assert_eq!(0x1ff, get_bits(0xffffu16, 7..16));
+    assert_eq!(0x8000_0000, get_bits(0x8000_0000u32, 0..32));
+}
@@ -0,0 +1,95 @@
+/// Maximum length of the kernel command line passed by the loader. Longer command lines are cut.
+pub const CMDLINE_MAX_LEN: usize = 256;
+
+/// Kernel command line: `key=value` pairs and bare flags separated by spaces, e.g.
+/// `log=trace noapic heap_size=0x100000`. When a key is repeated the last occurrence wins.
+#[derive(Clone, Copy, Debug)]
+pub struct CmdLine<'a> {
+    args: &'a str
+}
+
+impl<'a> CmdLine<'a> {
+    pub const fn new(args: &'a str) -> Self {
+        CmdLine { args }
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.args
+    }
+
+    /// Iterates over all arguments as `(key, value)`, bare flags have no value.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        self.args.split_ascii_whitespace().map(|arg| match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (arg, None)
+        })
+    }
+
+    fn find(&self, key: &str) -> Option<Option<&'a str>> {
+        self.iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+            .last()
+    }
+
+    /// Returns true if the key is present, as a bare flag or with a value.
+    pub fn contains(&self, key: &str) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Returns the value of `key=value`. Bare flags have an empty value.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.find(key).map(|value| value.unwrap_or(""))
+    }
+
+    /// Parses `key`, `key=1|true|on|yes` as true and `key=0|false|off|no` as false.
+    pub fn parse_bool(&self, key: &str) -> Option<bool> {
+        match self.find(key)? {
+            None => Some(true),
+            Some("1" | "true" | "on" | "yes") => Some(true),
+            Some("0" | "false" | "off" | "no") => Some(false),
+            Some(_) => None
+        }
+    }
+
+    /// Parses `key=value` as a decimal or a `0x` prefixed hex number.
+    pub fn parse_u64(&self, key: &str) -> Option<u64> {
+        let value = self.find(key)??;
+
+        match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok()
+        }
+    }
+}
+
+#[test_case]
+fn cmdline_get_test() {
+    let cmdline = CmdLine::new("  log=trace noapic  path=/a=b log=info ");
+
+    assert_eq!(Some("info"), cmdline.get("log"));
+    assert_eq!(Some(""), cmdline.get("noapic"));
+    assert_eq!(Some("/a=b"), cmdline.get("path"));
+    assert_eq!(None, cmdline.get("no"));
+    assert!(cmdline.contains("noapic"));
+    assert!(!cmdline.contains("apic"));
+    assert_eq!(4, cmdline.iter().count());
+}
+
+#[test_case]
+fn cmdline_parse_test() {
+    let cmdline = CmdLine::new("noapic smp=off serial=yes heap=0x1000 cpus=4 bad=4k");
+
+    assert_eq!(Some(true), cmdline.parse_bool("noapic"));
+    assert_eq!(Some(false), cmdline.parse_bool("smp"));
+    assert_eq!(Some(true), cmdline.parse_bool("serial"));
+    assert_eq!(None, cmdline.parse_bool("cpus"));
+    assert_eq!(None, cmdline.parse_bool("missing"));
+
+    assert_eq!(Some(0x1000), cmdline.parse_u64("heap"));
+    assert_eq!(Some(4), cmdline.parse_u64("cpus"));
+    assert_eq!(None, cmdline.parse_u64("bad"));
+    assert_eq!(None, cmdline.parse_u64("noapic"));
+
+    assert!(CmdLine::new("").iter().next().is_none());
+}
@@ -0,0 +1,97 @@
+use core::arch::asm;
+use spin::Once;
+
+const EXTENDED_LEAVES: u32 = 0x8000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32
+}
+
+/// Executes CPUID with `leaf` in EAX and `subleaf` in ECX. The caller checks that the leaf exists,
+/// unsupported leaves return the data of the highest basic leaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // rbx is reserved by LLVM, so it's saved around the instruction
+        asm!(
+        "mov {rbx_copy:r}, rbx",
+        "cpuid",
+        "xchg {rbx_copy:r}, rbx",
+        rbx_copy = out(reg) ebx,
+        inout("eax") leaf => eax,
+        inout("ecx") subleaf => ecx,
+        out("edx") edx,
+        options(nomem, nostack, preserves_flags)
+        );
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+/// CPU features the kernel checks before using them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Features {
+    /// CPUID.01h:EDX bit 0, x87 FPU
+    pub fpu: bool,
+    /// CPUID.01h:EDX bit 9, on-chip local APIC
+    pub apic: bool,
+    /// CPUID.01h:EDX bit 13, global pages
+    pub pge: bool,
+    /// CPUID.01h:EDX bit 16, page attribute table
+    pub pat: bool,
+    /// CPUID.01h:EDX bit 25
+    pub sse: bool,
+    /// CPUID.01h:ECX bit 30
+    pub rdrand: bool,
+    /// CPUID.(07h,0):EBX bit 18
+    pub rdseed: bool,
+    /// CPUID.80000001h:EDX bit 20, execute disable bit
+    pub nx: bool,
+    /// CPUID.80000001h:EDX bit 26, 1 GiB pages
+    pub pdpe1gb: bool
+}
+
+fn bit(value: u32, index: u32) -> bool {
+    value & (1 << index) != 0
+}
+
+fn detect() -> Features {
+    let max_leaf = cpuid(0, 0).eax;
+    let max_extended = cpuid(EXTENDED_LEAVES, 0).eax;
+
+    let leaf1 = if max_leaf >= 1 { cpuid(1, 0) } else { CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 } };
+    let leaf7_ebx = if max_leaf >= 7 { cpuid(7, 0).ebx } else { 0 };
+    let extended_edx = if max_extended >= 0x8000_0001 { cpuid(0x8000_0001, 0).edx } else { 0 };
+
+    Features {
+        fpu: bit(leaf1.edx, 0),
+        apic: bit(leaf1.edx, 9),
+        pge: bit(leaf1.edx, 13),
+        pat: bit(leaf1.edx, 16),
+        sse: bit(leaf1.edx, 25),
+        rdrand: bit(leaf1.ecx, 30),
+        rdseed: bit(leaf7_ebx, 18),
+        nx: bit(extended_edx, 20),
+        pdpe1gb: bit(extended_edx, 26)
+    }
+}
+
+static FEATURES: Once<Features> = Once::new();
+
+/// Returns the features of the CPU, read on the first call. All CPUs are assumed to report the
+/// same features as the first one asking.
+pub fn features() -> &'static Features {
+    FEATURES.call_once(detect)
+}
+
+#[test_case]
+fn features_test() {
+    // both are part of x86-64
+    assert!(features().fpu);
+    assert!(features().sse);
+    assert!(cpuid(0, 0).eax >= 1);
+    assert!(core::ptr::eq(features(), features()));
+}
@@ -1,7 +1,7 @@
 // CRC-32
 // CCITT32 ANSI CRC with the polynomial 0x04c11db7 / 0xEDB88320
 
-static CRC32_TABLE: [u32; 256] = {
+const CRC32_TABLE: [u32; 256] = {
     let mut table = [0u32; 256];
 
     let mut i = 0;
@@ -38,18 +38,90 @@ pub fn calculate_crc32_partial(input: &[u8], mut crc: u32) -> u32 {
 }
 
 pub fn calculate_crc32(input: &[u8]) -> u32 {
-    let mut crc: u32 = 0xFFFFFFFF;
+    crc32(input)
+}
 
-    for byte in input {
-        let idx = (*byte as u32 ^ crc) & 0xFF;
-        crc = (crc >> 8) ^ CRC32_TABLE[idx as usize];
+/// CRC-32 (IEEE) of the whole input.
+pub fn crc32(input: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(input);
+    crc.finalize()
+}
+
+/// Incremental CRC-32 (IEEE) for data that arrives in parts.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    crc: u32
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { crc: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, input: &[u8]) {
+        self.crc = calculate_crc32_partial(input, self.crc);
     }
 
-    !crc
+    pub fn finalize(self) -> u32 {
+        !self.crc
+    }
+}
+
+/// Streaming digest of the CRC the module computes. Feeding the input in any number of chunks gives
+/// the same result as the one-shot [`crc32`].
+pub type CrcDigest = Crc32;
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[test_case]
 fn simple_crc32_test() {
     assert_eq!(1267612143, calculate_crc32("abcdef".as_bytes()));
     assert_eq!(0xCBF43926, calculate_crc32("123456789".as_bytes()));
+}
+
+#[test_case]
+fn crc32_test() {
+    assert_eq!(0, crc32(&[]));
+    assert_eq!(0xCBF43926, crc32(b"123456789"));
+
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(0xCBF43926, crc.finalize());
+}
+
+#[test_case]
+fn crc_digest_chunks_test() {
+    let mut data = [0u8; 1000];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = (i * 7 + 3) as u8;
+    }
+
+    let mut one_chunk = CrcDigest::new();
+    one_chunk.update(&data);
+    let expected = one_chunk.finalize();
+    assert_eq!(crc32(&data), expected);
+
+    let mut bytewise = CrcDigest::new();
+    for byte in data.chunks(1) {
+        bytewise.update(byte);
+    }
+    assert_eq!(expected, bytewise.finalize());
+
+    let mut uneven = CrcDigest::new();
+    let mut rest = &data[..];
+    let mut size = 1;
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(size.min(rest.len()));
+        uneven.update(chunk);
+        uneven.update(&[]);
+        rest = tail;
+        size = size * 3 + 1;
+    }
+    assert_eq!(expected, uneven.finalize());
 }
\ No newline at end of file
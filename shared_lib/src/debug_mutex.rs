@@ -0,0 +1,137 @@
+//! A spin mutex that catches a CPU taking a lock it already holds.
+//!
+//! In debug builds [`DebugMutex`] remembers the CPU and the call site of the last acquisition. When
+//! a CPU with interrupts disabled finds the lock held by itself, the holder can never run again to
+//! release it, so instead of spinning forever it panics with both call sites. In release builds
+//! it's a plain `spin::Mutex`.
+
+#[cfg(not(debug_assertions))]
+pub type DebugMutex<T> = spin::Mutex<T>;
+
+#[cfg(not(debug_assertions))]
+pub type DebugMutexGuard<'a, T> = spin::MutexGuard<'a, T>;
+
+#[cfg(debug_assertions)]
+pub use checked::{DebugMutex, DebugMutexGuard};
+
+#[cfg(debug_assertions)]
+mod checked {
+    use core::ops::{Deref, DerefMut};
+    use core::panic::Location;
+    use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+    use crate::interrupts::are_enabled;
+
+    const NO_OWNER: u32 = u32::MAX;
+
+    /// Initial APIC ID from CPUID.01h:EBX[31:24], it doesn't need the local APIC to be mapped.
+    fn current_cpu() -> u32 {
+        unsafe { core::arch::x86_64::__cpuid(1).ebx >> 24 }
+    }
+
+    pub struct DebugMutex<T> {
+        inner: spin::Mutex<T>,
+        owner_cpu: AtomicU32,
+        owner_location: AtomicPtr<Location<'static>>
+    }
+
+    impl<T> DebugMutex<T> {
+        pub const fn new(value: T) -> Self {
+            DebugMutex {
+                inner: spin::Mutex::new(value),
+                owner_cpu: AtomicU32::new(NO_OWNER),
+                owner_location: AtomicPtr::new(core::ptr::null_mut())
+            }
+        }
+
+        /// Spins until the lock is taken. Panics when the calling CPU already holds it and has
+        /// interrupts disabled.
+        #[track_caller]
+        pub fn lock(&self) -> DebugMutexGuard<'_, T> {
+            let cpu = current_cpu();
+            loop {
+                if let Some(guard) = self.try_lock_on(cpu) {
+                    return guard;
+                }
+
+                // with interrupts enabled the holder may be a preempted thread of this CPU
+                if self.owner_cpu.load(Ordering::Acquire) == cpu && !are_enabled() {
+                    let location = self.owner_location.load(Ordering::Acquire);
+                    panic!("Deadlock: lock taken at {} is already held by CPU {} since {}",
+                        Location::caller(), cpu, unsafe { &*location });
+                }
+
+                core::hint::spin_loop();
+            }
+        }
+
+        #[track_caller]
+        pub fn try_lock(&self) -> Option<DebugMutexGuard<'_, T>> {
+            self.try_lock_on(current_cpu())
+        }
+
+        #[track_caller]
+        fn try_lock_on(&self, cpu: u32) -> Option<DebugMutexGuard<'_, T>> {
+            let guard = self.inner.try_lock()?;
+            self.owner_location.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+            self.owner_cpu.store(cpu, Ordering::Release);
+            Some(DebugMutexGuard { mutex: self, guard })
+        }
+
+        pub fn is_locked(&self) -> bool {
+            self.inner.is_locked()
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.inner.get_mut()
+        }
+
+        /// Releases the lock without a guard, see `spin::Mutex::force_unlock`.
+        ///
+        /// # Safety
+        /// The guard of the current holder must never be used again.
+        pub unsafe fn force_unlock(&self) {
+            self.owner_cpu.store(NO_OWNER, Ordering::Release);
+            self.inner.force_unlock();
+        }
+    }
+
+    pub struct DebugMutexGuard<'a, T> {
+        mutex: &'a DebugMutex<T>,
+        guard: spin::MutexGuard<'a, T>
+    }
+
+    impl<T> Drop for DebugMutexGuard<'_, T> {
+        fn drop(&mut self) {
+            // cleared before `guard` unlocks, so no other CPU can see itself as the owner
+            self.mutex.owner_cpu.store(NO_OWNER, Ordering::Release);
+        }
+    }
+
+    impl<T> Deref for DebugMutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.guard
+        }
+    }
+
+    impl<T> DerefMut for DebugMutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.guard
+        }
+    }
+}
+
+#[test_case]
+fn debug_mutex_test() {
+    let mutex = DebugMutex::new(1);
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.is_locked());
+        assert!(mutex.try_lock().is_none());
+    }
+
+    assert!(!mutex.is_locked());
+    assert_eq!(2, *mutex.try_lock().unwrap());
+}
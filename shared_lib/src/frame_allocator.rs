@@ -1,5 +1,8 @@
 use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
 use crate::addr::VirtAddr;
+use crate::allocator::slab::SlabPageAllocator;
+use crate::bits::set_bit;
 use crate::page_table::{PageTable, PageTablesAllocator};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -12,6 +15,26 @@ pub enum MemoryType {
     Acpi1_4,
 }
 
+/// Coarse grouping of `MemoryType`s, e.g. for summaries.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RegionKind {
+    Usable,
+    Reserved,
+    Acpi,
+    Bootloader
+}
+
+impl MemoryType {
+    pub const fn kind(self) -> RegionKind {
+        match self {
+            MemoryType::Free => RegionKind::Usable,
+            MemoryType::Reserved => RegionKind::Reserved,
+            MemoryType::InUse => RegionKind::Bootloader,
+            MemoryType::Acpi1_3 | MemoryType::AcpiReclaim | MemoryType::Acpi1_4 => RegionKind::Acpi
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct MemoryRegion {
     pub ty: MemoryType,
@@ -19,7 +42,25 @@ pub struct MemoryRegion {
     pub page_count: usize
 }
 
+impl MemoryRegion {
+    pub const fn kind(&self) -> RegionKind {
+        self.ty.kind()
+    }
+
+    pub const fn size(&self) -> u64 {
+        4096 * self.page_count as u64
+    }
+
+    /// Returns the address right after the region.
+    pub const fn end(&self) -> u64 {
+        self.addr + self.size()
+    }
+}
+
 pub const MAX_MEMORY_MAP_SIZE: usize = 256;
+
+/// Maximum number of regions `FrameAllocator::reserve_region` can hold back.
+pub const MAX_RESERVED_REGIONS: usize = 8;
 pub const MEMORY_MAP_PAGES: usize = 1 + (core::mem::size_of::<MemoryRegion>() * MAX_MEMORY_MAP_SIZE) / 4096;
 
 #[repr(C)]
@@ -33,6 +74,42 @@ impl MemoryMap {
     fn next_free_entry_index(&self) -> usize {
         self.next_free_entry_idx as usize
     }
+
+    /// Iterates over the regions of the given kind.
+    pub fn regions(&self, kind: RegionKind) -> impl Iterator<Item = &MemoryRegion> {
+        self.iter().filter(move |region| region.kind() == kind)
+    }
+
+    /// Returns the bytes of RAM: all regions except the reserved ones, which also cover MMIO.
+    pub fn total_memory(&self) -> u64 {
+        self.iter()
+            .filter(|region| region.kind() != RegionKind::Reserved)
+            .map(MemoryRegion::size)
+            .sum()
+    }
+
+    /// Returns the bytes the frame allocator can work with, including frames the loader took.
+    pub fn usable_memory(&self) -> u64 {
+        self.regions(RegionKind::Usable).map(MemoryRegion::size).sum()
+    }
+
+    /// Checks that the regions are sorted by address and don't overlap.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.next_free_entry_index() > MAX_MEMORY_MAP_SIZE {
+            return Err("Memory map has more entries than fit");
+        }
+
+        for pair in self.windows(2) {
+            if pair[1].addr < pair[0].addr {
+                return Err("Memory map is not sorted");
+            }
+            if pair[1].addr < pair[0].end() {
+                return Err("Memory map regions overlap");
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Deref for MemoryMap {
@@ -49,41 +126,406 @@ impl DerefMut for MemoryMap {
         &mut self.entries[0..next_index]
     }
 }
-
+/// Frame allocator used by the loader: frames are handed out one by one in memory map order and are
+/// never returned. The kernel continues with [`FrameAllocator`] from `next`.
 #[repr(align(4096))]
-pub struct FrameAllocator {
+pub struct BumpFrameAllocator {
     memory_map: *const MemoryMap,
     pub next: usize,
     mapping_offset: u64
 }
 
-impl FrameAllocator {
+impl BumpFrameAllocator {
     pub fn new(memory_map: *const MemoryMap, mapping_offset: u64, next_free_frame: usize) -> Self {
-        FrameAllocator {
+        BumpFrameAllocator {
             memory_map,
             next: next_free_frame,
             mapping_offset
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = u64> + '_ {
+    pub fn allocate_frame(&mut self) -> Option<u64> {
+        let frame = unsafe { usable_frames(&*self.memory_map) }.nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+impl PageTablesAllocator for BumpFrameAllocator {
+    fn allocate_page_table(&mut self) -> Result::<&mut PageTable, &'static str> {
+        let frame = self.allocate_frame().expect("Out of memory - failed to allocate frame");
+
+        log::debug!("Allocated page table. Addr: {:#x}", frame);
+        let page = VirtAddr::new_checked(frame + self.mapping_offset)
+            .expect("Failed to create virt address");
+
+        Ok(unsafe { &mut *(page.0 as *mut PageTable) })
+    }
+}
+
+/// Number of references to every frame of a [`FrameAllocator`], indexed like its bitmap. A frame
+/// that is in use starts with one reference, free frames have none.
+pub struct FrameRefCount {
+    counts: *mut u16
+}
+
+impl FrameRefCount {
+    fn get(&self, index: usize) -> u16 {
+        unsafe { *self.counts.add(index) }
+    }
+
+    fn set(&mut self, index: usize, count: u16) {
+        unsafe { *self.counts.add(index) = count; }
+    }
+}
+
+/// Bytes taken by the bitmap and the reference counts for `frames_count` frames.
+fn metadata_size(frames_count: usize) -> u64 {
+    let bitmap_size = frames_count.div_ceil(64) as u64 * 8;
+    bitmap_size + frames_count as u64 * core::mem::size_of::<u16>() as u64
+}
+
+/// Frame allocator keeping one bit per frame between the lowest and the highest usable frame, a set
+/// bit means the frame is in use. Frames outside of free memory regions are never handed out.
+///
+/// Frames shared by several mappings are reference counted, see [`FrameAllocator::incref`]. The
+/// bitmap and the reference counts are stored in the first usable frames after the ones allocated
+/// by the loader.
+pub struct FrameAllocator {
+    memory_map: *const MemoryMap,
+    bitmap: *mut u64,
+    refcounts: FrameRefCount,
+    base: u64,
+    frames_count: usize,
+    free_frames_count: usize,
+    // index of the frame where the search for a free frame starts
+    next: usize,
+    // page aligned [start, end) ranges that are never handed out or freed
+    reserved: [Option<(u64, u64)>; MAX_RESERVED_REGIONS],
+    mapping_offset: u64
+}
+
+// The memory map is handed over by the loader and never changes afterwards
+unsafe impl Send for FrameAllocator {}
+
+impl FrameAllocator {
+    /// Creates the allocator from the memory map, which has to outlive the allocator. The first
+    /// `next_free_frame` usable frames are considered allocated by the loader.
+    pub fn new(memory_map: &MemoryMap, mapping_offset: u64, next_free_frame: usize) -> Self {
+        Self::with_reserved(memory_map, mapping_offset, next_free_frame, &[])
+            .expect("Failed to create the frame allocator")
+    }
+
+    /// Same as [`FrameAllocator::new`], but the `reserved` [start, end) ranges are held back like
+    /// with [`FrameAllocator::reserve_region`] before anything is written to memory, so the bitmap
+    /// is never placed on them.
+    pub fn with_reserved(memory_map: &MemoryMap, mapping_offset: u64, next_free_frame: usize, reserved: &[(u64, u64)])
+                         -> Result<Self, &'static str> {
+        if reserved.len() > MAX_RESERVED_REGIONS {
+            return Err("Too many reserved regions");
+        }
+
+        let usable_regions = || memory_map.iter().filter(|r| r.ty == MemoryType::Free && r.page_count > 0);
+
+        let base = usable_regions().map(|r| r.addr).min().unwrap_or(0);
+        let end = usable_regions().map(|r| r.addr + 4096 * r.page_count as u64).max().unwrap_or(0);
+        let frames_count = ((end - base) / 4096) as usize;
+        let bitmap_size = frames_count.div_ceil(64) as u64 * 8;
+        let metadata_size = metadata_size(frames_count);
+
+        let mut region_start_index = 0;
+        let mut bitmap_addr = None;
+        for region in usable_regions() {
+            let mut first_free = region.addr + 4096 * next_free_frame.saturating_sub(region_start_index) as u64;
+            while first_free + metadata_size <= region.end() {
+                match reserved.iter().find(|&&(start, end)| start < first_free + metadata_size && first_free < end) {
+                    Some(&(_, end)) => first_free = end.div_ceil(4096) * 4096,
+                    None => {
+                        bitmap_addr = Some(first_free);
+                        break;
+                    }
+                }
+            }
+
+            if bitmap_addr.is_some() {
+                break;
+            }
+
+            region_start_index += region.page_count;
+        }
+        let bitmap_addr = bitmap_addr.ok_or("Out of memory - failed to place the frame bitmap")?;
+
+        let mut allocator = FrameAllocator {
+            memory_map,
+            bitmap: (bitmap_addr + mapping_offset) as *mut u64,
+            refcounts: FrameRefCount { counts: (bitmap_addr + bitmap_size + mapping_offset) as *mut u16 },
+            base,
+            frames_count,
+            free_frames_count: 0,
+            next: 0,
+            reserved: [None; MAX_RESERVED_REGIONS],
+            mapping_offset
+        };
+
+        // everything is in use until proven otherwise
         unsafe {
-            // get usable regions from memory map
-            let regions = (*self.memory_map).iter();
-            let usable_regions = regions.filter(|r| r.ty == MemoryType::Free);
+            core::ptr::write_bytes(allocator.bitmap, 0xff, frames_count.div_ceil(64));
+            core::slice::from_raw_parts_mut(allocator.refcounts.counts, frames_count).fill(1);
+        }
 
-            // map each region to its address range
-            let addr_ranges = usable_regions.map(|r| r.addr..(r.addr + 4096 * r.page_count as u64));
+        for &(start, end) in reserved {
+            allocator.reserve_region(start, end)?;
+        }
 
-            // transform to an iterator of frame start addresses
-            addr_ranges.flat_map(|r| r.step_by(4096))
+        let bitmap_end = bitmap_addr + metadata_size.div_ceil(4096) * 4096;
+        for frame in usable_frames(memory_map).skip(next_free_frame) {
+            if !(bitmap_addr..bitmap_end).contains(&frame) && !allocator.is_reserved(frame) {
+                allocator.set_used(frame, false);
+            }
         }
+
+        log::info!("Frame allocator: {} free frames, bitmap at {:#x}", allocator.free_frames_count, bitmap_addr);
+        Ok(allocator)
     }
 
     pub fn allocate_frame(&mut self) -> Option<u64> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let words = self.frames_count.div_ceil(64);
+        let first_word = self.next / 64;
+
+        for i in 0..words {
+            let word = (first_word + i) % words;
+            let bits = unsafe { *self.bitmap.add(word) };
+            if bits != u64::MAX {
+                let frame = self.base + 4096 * (word * 64 + bits.trailing_ones() as usize) as u64;
+                self.set_used(frame, true);
+                self.next = word * 64;
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    /// Allocates the lowest free frame that ends at or below `limit`, e.g. for code that has to run
+    /// in real mode.
+    pub fn allocate_frame_below(&mut self, limit: u64) -> Option<u64> {
+        let frames = (limit.saturating_sub(self.base) / 4096) as usize;
+        let index = (0..frames.min(self.frames_count)).find(|&index| self.is_free_index(index))?;
+
+        let frame = self.base + 4096 * index as u64;
+        self.set_used(frame, true);
+        Some(frame)
+    }
+
+    /// Allocates a frame and fills it with zeros, so no stale data leaks into the new owner.
+    pub fn allocate_frame_zeroed(&mut self) -> Option<u64> {
+        let frame = self.allocate_frame()?;
+        unsafe { core::ptr::write_bytes((frame + self.mapping_offset) as *mut u8, 0, 4096); }
+        Some(frame)
+    }
+
+    /// Allocates `count` physically contiguous frames, the first of them aligned to `align` bytes.
+    /// Returns the address of the first frame.
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64) -> Result<u64, &'static str> {
+        if count == 0 {
+            return Err("Cannot allocate zero frames");
+        }
+        if !align.is_power_of_two() {
+            return Err("Alignment must be a power of two");
+        }
+
+        let align = align.max(4096);
+        let step = (align / 4096) as usize;
+        let first = self.base.checked_add(align - 1).map(|addr| addr & !(align - 1))
+            .ok_or("No contiguous run of free frames is large enough")?;
+
+        let mut start = ((first - self.base) / 4096) as usize;
+        while start + count <= self.frames_count {
+            match (start..start + count).find(|&index| !self.is_free_index(index)) {
+                // the next aligned start after the used frame
+                Some(used) => start += (used - start) / step * step + step,
+                None => {
+                    let addr = self.base + 4096 * start as u64;
+                    for index in start..start + count {
+                        self.set_used(self.base + 4096 * index as u64, true);
+                    }
+
+                    log::debug!("Allocated {} contiguous frames. Addr: {:#x}", count, addr);
+                    return Ok(addr);
+                }
+            }
+        }
+
+        Err("No contiguous run of free frames is large enough")
+    }
+
+    /// Marks the frames overlapping `start..end` as used for good, e.g. the kernel image or the
+    /// boot info, which the memory map reports as free. Reserved frames are never allocated and
+    /// can't be deallocated.
+    pub fn reserve_region(&mut self, start: u64, end: u64) -> Result<(), &'static str> {
+        if end <= start {
+            return Err("Reserved region is empty");
+        }
+
+        let start = start & !0xfff;
+        let end = end.checked_add(0xfff).ok_or("Reserved region is too large")? & !0xfff;
+
+        let (bitmap_start, bitmap_end) = self.metadata_range();
+        if start < bitmap_end && bitmap_start < end {
+            return Err("Reserved region overlaps the frame bitmap");
+        }
+
+        let slot = self.reserved.iter_mut()
+            .find(|region| region.is_none())
+            .ok_or("Too many reserved regions")?;
+        *slot = Some((start, end));
+
+        let frames_end = self.base + 4096 * self.frames_count as u64;
+        for frame in (start.max(self.base)..end.min(frames_end)).step_by(4096) {
+            self.set_used(frame, true);
+        }
+
+        log::info!("Frame allocator: reserved {:#x} - {:#x}", start, end);
+        Ok(())
+    }
+
+    /// Returns the page aligned [start, end) range of the frames holding the bitmap and the
+    /// reference counts.
+    fn metadata_range(&self) -> (u64, u64) {
+        let start = self.bitmap as u64 - self.mapping_offset;
+        (start, start + metadata_size(self.frames_count).div_ceil(4096) * 4096)
+    }
+
+    pub fn is_reserved(&self, frame: u64) -> bool {
+        self.reserved.iter().flatten().any(|&(start, end)| (start..end).contains(&frame))
+    }
+
+    /// Returns a frame to the allocator so it can be handed out again.
+    pub fn deallocate_frame(&mut self, frame: u64) -> Result<(), &'static str> {
+        if frame % 4096 != 0 {
+            return Err("Frame address is not aligned");
+        }
+
+        if self.is_reserved(frame) {
+            return Err("Frame is reserved");
+        }
+
+        let (metadata_start, metadata_end) = self.metadata_range();
+        if (metadata_start..metadata_end).contains(&frame) {
+            return Err("Frame holds the frame bitmap");
+        }
+
+        if !self.is_usable(frame) {
+            return Err("Frame is not in usable memory");
+        }
+
+        if self.is_free(frame) {
+            return Err("Frame is already free");
+        }
+
+        self.set_used(frame, false);
+        Ok(())
+    }
+
+    fn is_usable(&self, frame: u64) -> bool {
+        unsafe { (*self.memory_map).iter() }
+            .filter(|r| r.ty == MemoryType::Free)
+            .any(|r| (r.addr..r.addr + 4096 * r.page_count as u64).contains(&frame))
+    }
+
+    /// Returns the bitmap index of a frame the allocator hands out and frees: an aligned frame in
+    /// usable memory that is not reserved.
+    fn managed_index(&self, frame: u64) -> Option<usize> {
+        if frame % 4096 != 0 || frame < self.base || !self.is_usable(frame) || self.is_reserved(frame) {
+            return None;
+        }
+        Some(((frame - self.base) / 4096) as usize)
+    }
+
+    /// Takes another reference on an allocated frame, e.g. when it gets mapped a second time.
+    /// Returns the new count.
+    pub fn incref(&mut self, frame: u64) -> Result<u16, &'static str> {
+        let index = self.managed_index(frame).ok_or("Frame is not managed by the allocator")?;
+        if self.is_free_index(index) {
+            return Err("Frame is free");
+        }
+
+        let count = self.refcounts.get(index).checked_add(1).ok_or("Frame reference count overflow")?;
+        self.refcounts.set(index, count);
+        Ok(count)
+    }
+
+    /// Drops a reference on an allocated frame and frees it when it was the last one. Returns
+    /// whether the frame was freed.
+    pub fn decref(&mut self, frame: u64) -> Result<bool, &'static str> {
+        let index = self.managed_index(frame).ok_or("Frame is not managed by the allocator")?;
+        if self.is_free_index(index) {
+            return Err("Frame is already free");
+        }
+
+        let count = self.refcounts.get(index).saturating_sub(1);
+        if count == 0 {
+            self.deallocate_frame(frame)?;
+            return Ok(true);
+        }
+
+        self.refcounts.set(index, count);
+        Ok(false)
+    }
+
+    /// Returns the number of references on the frame, 0 for free frames and frames the allocator
+    /// doesn't manage.
+    pub fn refcount(&self, frame: u64) -> u16 {
+        match self.managed_index(frame) {
+            Some(index) => self.refcounts.get(index),
+            None => 0
+        }
+    }
+
+    pub fn is_free(&self, frame: u64) -> bool {
+        frame >= self.base && self.is_free_index(((frame - self.base) / 4096) as usize)
+    }
+
+    /// Returns the number of frames that are still available for allocation.
+    pub fn free_frames_count(&self) -> usize {
+        self.free_frames_count
+    }
+
+    fn is_free_index(&self, index: usize) -> bool {
+        index < self.frames_count && unsafe { *self.bitmap.add(index / 64) } & (1 << (index % 64)) == 0
+    }
+
+    fn set_used(&mut self, frame: u64, used: bool) {
+        let index = ((frame - self.base) / 4096) as usize;
+        if self.is_free_index(index) == used {
+            if used {
+                self.free_frames_count -= 1;
+            } else {
+                self.free_frames_count += 1;
+            }
+        }
+
+        set_bit(unsafe { &mut *self.bitmap.add(index / 64) }, (index % 64) as u8, used);
+        self.refcounts.set(index, used as u16);
+    }
+}
+
+fn usable_frames(memory_map: &MemoryMap) -> impl Iterator<Item = u64> + '_ {
+    // get usable regions from memory map
+    let regions = memory_map.iter();
+    let usable_regions = regions.filter(|r| r.ty == MemoryType::Free);
+
+    // map each region to its address range
+    let addr_ranges = usable_regions.map(|r| r.addr..(r.addr + 4096 * r.page_count as u64));
+
+    // transform to an iterator of frame start addresses
+    addr_ranges.flat_map(|r| r.step_by(4096))
+}
+
+impl SlabPageAllocator for FrameAllocator {
+    fn allocate_slab_page(&mut self) -> Option<NonNull<u8>> {
+        let frame = self.allocate_frame()?;
+        NonNull::new((frame + self.mapping_offset) as *mut u8)
     }
 }
 
@@ -95,8 +537,217 @@ impl PageTablesAllocator for FrameAllocator {
         let page = VirtAddr::new_checked(frame + self.mapping_offset)
             .expect("Failed to create virt address");
 
-        let page_table = unsafe { core::slice::from_raw_parts_mut(page.0 as *mut PageTable, 4096) };
-        page_table[0].clear();
-        Ok(&mut page_table[0])
+        Ok(unsafe { &mut *(page.0 as *mut PageTable) })
+    }
+
+    fn free_page_table(&mut self, table: &mut PageTable) -> Result::<(), &'static str> {
+        let frame = (table as *mut PageTable as u64).checked_sub(self.mapping_offset)
+            .ok_or("Page table is outside of the physical memory mapping")?;
+
+        log::debug!("Freed page table. Addr: {:#x}", frame);
+        self.deallocate_frame(frame)
     }
-}
\ No newline at end of file
+
+    fn share_frame(&mut self, frame: u64) {
+        // frames the allocator doesn't hand out, e.g. MMIO, aren't counted
+        let _ = self.incref(frame);
+    }
+
+    fn release_frame(&mut self, frame: u64) -> Result::<bool, &'static str> {
+        if self.managed_index(frame).is_none() {
+            return Ok(false);
+        }
+        self.decref(frame)
+    }
+}
+
+#[cfg(test)]
+#[repr(align(4096))]
+struct TestMemory([u8; 16 * 4096]);
+
+#[cfg(test)]
+static mut TEST_MEMORY: TestMemory = TestMemory([0; 16 * 4096]);
+
+// Uses a static buffer as physical memory: a 3 frames region followed by a 12 frames region
+#[cfg(test)]
+fn test_memory_map() -> MemoryMap {
+    let base = unsafe { core::ptr::addr_of!(TEST_MEMORY.0) as u64 };
+    let mut memory_map = MemoryMap {
+        entries: [MemoryRegion { ty: MemoryType::Reserved, addr: 0, page_count: 0 }; MAX_MEMORY_MAP_SIZE],
+        next_free_entry_idx: 3
+    };
+
+    memory_map.entries[0] = MemoryRegion { ty: MemoryType::Free, addr: base, page_count: 3 };
+    memory_map.entries[1] = MemoryRegion { ty: MemoryType::InUse, addr: base + 3 * 4096, page_count: 1 };
+    memory_map.entries[2] = MemoryRegion { ty: MemoryType::Free, addr: base + 4 * 4096, page_count: 12 };
+    memory_map
+}
+
+#[test_case]
+fn bitmap_init_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+    let allocator = FrameAllocator::new(&memory_map, 0, 1);
+
+    // one frame is taken by the loader and one by the bitmap
+    assert_eq!(13, allocator.free_frames_count());
+    assert!(!allocator.is_free(base));
+    assert!(!allocator.is_free(base + 4096));
+    assert!(allocator.is_free(base + 2 * 4096));
+    assert!(!allocator.is_free(base + 3 * 4096));
+    assert!(allocator.is_free(base + 15 * 4096));
+    assert!(!allocator.is_free(base + 16 * 4096));
+}
+
+#[test_case]
+fn allocate_contiguous_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+    let mut allocator = FrameAllocator::new(&memory_map, 0, 0);
+
+    // the first region is too small, so the allocation skips to the second one
+    assert_eq!(Ok(base + 4 * 4096), allocator.allocate_contiguous(4, 4096));
+    assert_eq!(Err("No contiguous run of free frames is large enough"), allocator.allocate_contiguous(9, 4096));
+    assert_eq!(Ok(base + 8 * 4096), allocator.allocate_contiguous(8, 4096));
+
+    // frames skipped by the contiguous allocation are not lost
+    assert_eq!(Some(base + 4096), allocator.allocate_frame());
+    assert_eq!(Some(base + 2 * 4096), allocator.allocate_frame());
+    assert_eq!(None, allocator.allocate_frame());
+    assert_eq!(Err("Alignment must be a power of two"), allocator.allocate_contiguous(1, 3000));
+}
+
+#[test_case]
+fn deallocate_frame_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+    let mut allocator = FrameAllocator::new(&memory_map, 0, 0);
+    assert_eq!(14, allocator.free_frames_count());
+    assert_eq!(Err("Frame holds the frame bitmap"), allocator.deallocate_frame(base));
+
+    let first = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_eq!(12, allocator.free_frames_count());
+
+    assert_eq!(Ok(()), allocator.deallocate_frame(first));
+    assert_eq!(Err("Frame is already free"), allocator.deallocate_frame(first));
+    assert_eq!(Ok(()), allocator.deallocate_frame(second));
+    assert_eq!(Err("Frame is already free"), allocator.deallocate_frame(second + 2 * 4096));
+    assert_eq!(Err("Frame is not in usable memory"), allocator.deallocate_frame(first + 2 * 4096));
+    assert_eq!(14, allocator.free_frames_count());
+
+    assert_eq!(Some(first), allocator.allocate_frame());
+    assert_eq!(Some(second), allocator.allocate_frame());
+    assert_eq!(12, allocator.free_frames_count());
+}
+
+#[test_case]
+fn allocate_frame_below_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+    let mut allocator = FrameAllocator::new(&memory_map, 0, 0);
+
+    // the first frame holds the bitmap
+    assert_eq!(Some(base + 4096), allocator.allocate_frame_below(base + 3 * 4096));
+    assert_eq!(Some(base + 2 * 4096), allocator.allocate_frame_below(base + 3 * 4096));
+    assert_eq!(None, allocator.allocate_frame_below(base + 3 * 4096));
+    assert_eq!(None, allocator.allocate_frame_below(0));
+    assert_eq!(Some(base + 4 * 4096), allocator.allocate_frame_below(u64::MAX));
+}
+
+#[test_case]
+fn allocate_frame_zeroed_test() {
+    let memory_map = test_memory_map();
+    let mut allocator = FrameAllocator::new(&memory_map, 0, 0);
+
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe { core::ptr::write_bytes(frame as *mut u8, 0xab, 4096); }
+    allocator.deallocate_frame(frame).unwrap();
+
+    assert_eq!(Some(frame), allocator.allocate_frame_zeroed());
+    let contents = unsafe { core::slice::from_raw_parts(frame as *const u8, 4096) };
+    assert!(contents.iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn memory_map_summary_test() {
+    let mut memory_map = test_memory_map();
+
+    assert_eq!(16 * 4096, memory_map.total_memory());
+    assert_eq!(15 * 4096, memory_map.usable_memory());
+    assert_eq!(2, memory_map.regions(RegionKind::Usable).count());
+    assert_eq!(1, memory_map.regions(RegionKind::Bootloader).count());
+    assert_eq!(Ok(()), memory_map.validate());
+
+    memory_map.entries[1].page_count = 2;
+    assert_eq!(Err("Memory map regions overlap"), memory_map.validate());
+
+    memory_map.entries.swap(0, 2);
+    assert_eq!(Err("Memory map is not sorted"), memory_map.validate());
+}
+
+#[test_case]
+fn reserve_region_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+    let mut allocator = FrameAllocator::new(&memory_map, 0, 0);
+
+    // the region doesn't have to be page aligned
+    assert_eq!(Ok(()), allocator.reserve_region(base + 4 * 4096 + 16, base + 6 * 4096 + 1));
+    assert_eq!(11, allocator.free_frames_count());
+    assert_eq!(Err("Reserved region overlaps the frame bitmap"), allocator.reserve_region(base, base + 4096));
+    assert_eq!(Err("Reserved region is empty"), allocator.reserve_region(base + 4096, base + 4096));
+
+    while let Some(frame) = allocator.allocate_frame() {
+        assert!(!(base + 4 * 4096..base + 7 * 4096).contains(&frame));
+    }
+    assert!(allocator.allocate_contiguous(1, 4096).is_err());
+    assert_eq!(Err("Frame is reserved"), allocator.deallocate_frame(base + 5 * 4096));
+}
+
+#[test_case]
+fn with_reserved_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+
+    // the bitmap moves past the reserved frame
+    let mut allocator = FrameAllocator::with_reserved(&memory_map, 0, 0, &[(base, base + 100)]).unwrap();
+    assert_eq!(13, allocator.free_frames_count());
+    assert!(allocator.is_reserved(base));
+    assert!(!allocator.is_free(base + 4096));
+    assert_eq!(Err("Frame holds the frame bitmap"), allocator.deallocate_frame(base + 4096));
+
+    while let Some(frame) = allocator.allocate_frame() {
+        assert!(frame > base + 4096);
+    }
+
+    let too_many = [(base, base + 4096); MAX_RESERVED_REGIONS + 1];
+    assert!(FrameAllocator::with_reserved(&memory_map, 0, 0, &too_many).is_err());
+}
+
+#[test_case]
+fn frame_refcount_test() {
+    let memory_map = test_memory_map();
+    let base = memory_map.entries[0].addr;
+    let mut allocator = FrameAllocator::new(&memory_map, 0, 0);
+
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(1, allocator.refcount(frame));
+    assert_eq!(Ok(2), allocator.incref(frame));
+    assert_eq!(Ok(3), allocator.incref(frame));
+
+    assert_eq!(Ok(false), allocator.decref(frame));
+    assert_eq!(Ok(false), allocator.decref(frame));
+    assert!(!allocator.is_free(frame));
+    assert_eq!(Ok(true), allocator.decref(frame));
+    assert!(allocator.is_free(frame));
+    assert_eq!(0, allocator.refcount(frame));
+
+    assert_eq!(Err("Frame is free"), allocator.incref(frame));
+    assert_eq!(Err("Frame is already free"), allocator.decref(frame));
+    assert_eq!(Err("Frame is not managed by the allocator"), allocator.incref(base + 3 * 4096));
+
+    // a reallocated frame starts over with one reference
+    assert_eq!(Some(frame), allocator.allocate_frame());
+    assert_eq!(1, allocator.refcount(frame));
+}
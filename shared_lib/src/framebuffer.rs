@@ -0,0 +1,247 @@
+use crate::logger::{Color, FrameBufferInfo, PixelFormat};
+
+/// Size of the biggest pixel, the buffers `PixelFormat::encode` returns have this size.
+pub const MAX_BYTES_PER_PIXEL: usize = 4;
+
+/// Scales the 8-bit `value` to the width of `mask` and moves it to the mask's position.
+fn to_mask(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let max = (mask >> mask.trailing_zeros()) as u64;
+    (((value as u64 * max + 127) / 255) as u32) << mask.trailing_zeros()
+}
+
+/// Reverse of `to_mask`.
+fn from_mask(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let max = (mask >> mask.trailing_zeros()) as u64;
+    (((pixel & mask) >> mask.trailing_zeros()) as u64 * 255 / max) as u8
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Grayscale => 1,
+            _ => 4
+        }
+    }
+
+    /// Returns the bytes of `color` in memory order, only the first `bytes_per_pixel` of them are
+    /// used. `None` for formats that can't be drawn to.
+    pub fn encode(self, color: Color) -> Option<[u8; MAX_BYTES_PER_PIXEL]> {
+        match self {
+            PixelFormat::Rgb => Some([color.r, color.g, color.b, 0]),
+            PixelFormat::Bgr => Some([color.b, color.g, color.r, 0]),
+            PixelFormat::Bitmask { red, green, blue } => {
+                let pixel = to_mask(color.r, red) | to_mask(color.g, green) | to_mask(color.b, blue);
+                Some(pixel.to_le_bytes())
+            },
+            PixelFormat::Grayscale => {
+                // BT.601 luma weights
+                let luma = (77 * color.r as u32 + 150 * color.g as u32 + 29 * color.b as u32) >> 8;
+                Some([luma as u8, 0, 0, 0])
+            },
+            PixelFormat::BltOnly => None
+        }
+    }
+
+    /// Returns the color of a pixel stored as `bytes`. Grayscale pixels come back gray.
+    pub fn decode(self, bytes: &[u8]) -> Option<Color> {
+        match self {
+            PixelFormat::Rgb => Some(Color::new(bytes[0], bytes[1], bytes[2])),
+            PixelFormat::Bgr => Some(Color::new(bytes[2], bytes[1], bytes[0])),
+            PixelFormat::Bitmask { red, green, blue } => {
+                let pixel = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                Some(Color::new(from_mask(pixel, red), from_mask(pixel, green), from_mask(pixel, blue)))
+            },
+            PixelFormat::Grayscale => Some(Color::new(bytes[0], bytes[0], bytes[0])),
+            PixelFormat::BltOnly => None
+        }
+    }
+}
+
+/// Pixel access to a framebuffer described by a [`FrameBufferInfo`]. Rows are `stride` pixels
+/// apart, only the first `width` of them are visible. Everything drawn outside of the visible
+/// area is clipped.
+pub struct FrameBuffer<'a> {
+    info: FrameBufferInfo,
+    buffer: &'a mut [u8]
+}
+
+impl<'a> FrameBuffer<'a> {
+    /// Wraps `buffer`, which is laid out as `info` describes. It doesn't have to be the memory at
+    /// `info.addr`, e.g. it can be an off-screen copy.
+    pub fn new(info: FrameBufferInfo, buffer: &'a mut [u8]) -> Result<Self, &'static str> {
+        if info.pixel_format.encode(Color::new(0, 0, 0)).is_none() {
+            return Err("Unsupported pixel format");
+        }
+        if info.width > info.stride {
+            return Err("Framebuffer width is bigger than the stride");
+        }
+        if buffer.len() < info.stride * info.height * info.pixel_format.bytes_per_pixel() {
+            return Err("Framebuffer is smaller than stride * height");
+        }
+
+        Ok(FrameBuffer { info, buffer })
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.info.pixel_format.bytes_per_pixel()
+    }
+
+    fn byte_offset(&self, x: usize, y: usize) -> usize {
+        (y * self.info.stride + x) * self.bytes_per_pixel()
+    }
+
+    fn encode(&self, color: Color) -> [u8; MAX_BYTES_PER_PIXEL] {
+        // checked in `new`
+        self.info.pixel_format.encode(color).unwrap()
+    }
+
+    fn write(&mut self, offset: usize, pixel: &[u8; MAX_BYTES_PER_PIXEL]) {
+        let bytes_per_pixel = self.bytes_per_pixel();
+        self.buffer[offset..offset + bytes_per_pixel].copy_from_slice(&pixel[..bytes_per_pixel]);
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+
+        let pixel = self.encode(color);
+        self.write(self.byte_offset(x, y), &pixel);
+    }
+
+    /// Returns the color of the pixel, `None` outside of the visible area.
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Color> {
+        if x >= self.info.width || y >= self.info.height {
+            return None;
+        }
+
+        let offset = self.byte_offset(x, y);
+        self.info.pixel_format.decode(&self.buffer[offset..offset + self.bytes_per_pixel()])
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let x_end = x.saturating_add(width).min(self.info.width);
+        let y_end = y.saturating_add(height).min(self.info.height);
+        if x >= x_end {
+            return;
+        }
+
+        let pixel = self.encode(color);
+        let bytes_per_pixel = self.bytes_per_pixel();
+        for row in y..y_end {
+            let start = self.byte_offset(x, row);
+            let end = self.byte_offset(x_end, row);
+            for chunk in self.buffer[start..end].chunks_exact_mut(bytes_per_pixel) {
+                chunk.copy_from_slice(&pixel[..bytes_per_pixel]);
+            }
+        }
+    }
+
+    /// Draws a `width` x `height` image at (x, y). `pixels` are `0xRRGGBB` values row by row,
+    /// rows missing from the slice are not drawn.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[u32]) {
+        if width == 0 || x >= self.info.width {
+            return;
+        }
+
+        let visible_width = width.min(self.info.width - x);
+        for (row, line) in pixels.chunks_exact(width).take(height).enumerate() {
+            let y = y + row;
+            if y >= self.info.height {
+                break;
+            }
+
+            for (column, &rgb) in line[..visible_width].iter().enumerate() {
+                let pixel = self.encode(Color::from_rgb(rgb));
+                self.write(self.byte_offset(x + column, y), &pixel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_info(pixel_format: PixelFormat) -> FrameBufferInfo {
+    FrameBufferInfo { addr: 0, size: 5 * 3 * MAX_BYTES_PER_PIXEL, width: 4, height: 3, pixel_format, stride: 5 }
+}
+
+#[test_case]
+fn framebuffer_drawing_test() {
+    let mut buffer = [0xAAu8; 5 * 3 * MAX_BYTES_PER_PIXEL];
+    let mut fb = FrameBuffer::new(test_info(PixelFormat::Bgr), &mut buffer).unwrap();
+    let red = Color::new(255, 0, 0);
+
+    fb.put_pixel(1, 2, red);
+    fb.put_pixel(4, 0, red);
+    fb.fill_rect(2, 0, 10, 2, Color::new(0, 0, 255));
+    fb.blit(3, 1, 2, 2, &[0x00FF00, 0x123456, 0x00FF00, 0x123456]);
+
+    assert_eq!(Some(red), fb.get_pixel(1, 2));
+    assert_eq!(Some(Color::new(0, 0, 255)), fb.get_pixel(2, 1));
+    assert_eq!(Some(Color::new(0, 255, 0)), fb.get_pixel(3, 2));
+    assert_eq!(None, fb.get_pixel(4, 0));
+
+    // BGR byte order, and the padding pixel of each row is never touched
+    assert_eq!([0, 0, 255, 0], buffer[(2 * 5 + 1) * 4..(2 * 5 + 1) * 4 + 4]);
+    for row in 0..3 {
+        assert_eq!([0xAA; 4], buffer[(row * 5 + 4) * 4..(row * 5 + 4) * 4 + 4]);
+    }
+}
+
+#[test_case]
+fn framebuffer_checks_layout_test() {
+    let mut buffer = [0u8; 5 * 3 * MAX_BYTES_PER_PIXEL];
+    assert!(FrameBuffer::new(test_info(PixelFormat::BltOnly), &mut buffer).is_err());
+    assert!(FrameBuffer::new(test_info(PixelFormat::Rgb), &mut buffer[1..]).is_err());
+}
+
+#[test_case]
+fn pixel_formats_test() {
+    let formats = [
+        PixelFormat::Rgb,
+        PixelFormat::Bgr,
+        PixelFormat::Bitmask { red: 0xFF00, green: 0xFF_0000, blue: 0xFF },
+        PixelFormat::Bitmask { red: 0xF800, green: 0x07E0, blue: 0x001F },
+        PixelFormat::Grayscale
+    ];
+    let color = Color::new(0xFF, 0x80, 0x00);
+
+    for format in formats {
+        let mut buffer = [0u8; 5 * 3 * MAX_BYTES_PER_PIXEL];
+        let mut fb = FrameBuffer::new(test_info(format), &mut buffer).unwrap();
+        fb.put_pixel(1, 1, color);
+        let read_back = fb.get_pixel(1, 1).unwrap();
+
+        match format {
+            PixelFormat::Grayscale => assert_eq!(Color::new(0x97, 0x97, 0x97), read_back),
+            // 5 and 6 bit channels lose the low bits
+            PixelFormat::Bitmask { red: 0xF800, .. } => assert_eq!(Color::new(0xFF, 0x81, 0x00), read_back),
+            _ => assert_eq!(color, read_back)
+        }
+        assert_eq!(Some(Color::new(0, 0, 0)), fb.get_pixel(0, 1));
+    }
+
+    let mut buffer = [0u8; MAX_BYTES_PER_PIXEL];
+    let bgr = FrameBufferInfo { addr: 0, size: 4, width: 1, height: 1, pixel_format: PixelFormat::Bgr, stride: 1 };
+    FrameBuffer::new(bgr, &mut buffer).unwrap().put_pixel(0, 0, color);
+    assert_eq!([0x00, 0x80, 0xFF, 0], buffer);
+
+    let bitmask = FrameBufferInfo { pixel_format: PixelFormat::Bitmask { red: 0xFF00, green: 0xFF_0000, blue: 0xFF }, ..bgr };
+    FrameBuffer::new(bitmask, &mut buffer).unwrap().put_pixel(0, 0, color);
+    assert_eq!([0x00, 0xFF, 0x80, 0], buffer);
+}
@@ -1,19 +1,25 @@
 use core::arch::asm;
 use crate::bits::get_bits;
 
+/// Returns whether the interrupt flag is set on the calling CPU.
 #[inline]
-pub fn without_interrupts<F, R>(f: F) -> R
-    where
-        F: FnOnce() -> R,
-{
+pub fn are_enabled() -> bool {
     let rflags: u64;
 
     unsafe {
         asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
     }
 
+    get_bits(rflags, 9..10) == 1
+}
+
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R,
+{
     // true if the interrupt flag is set (i.e. interrupts are enabled)
-    let saved_intpt_flag = get_bits(rflags, 9..10) == 1;
+    let saved_intpt_flag = are_enabled();
 
     // if interrupts are enabled, disable them for now
     if saved_intpt_flag {
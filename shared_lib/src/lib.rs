@@ -11,8 +11,12 @@
 extern crate alloc;
 
 pub mod logger;
+pub mod framebuffer;
 pub mod bits;
+pub mod cpu;
+pub mod debug_mutex;
 pub mod interrupts;
+pub mod mmio;
 pub mod serial;
 pub mod addr;
 pub mod page_table;
@@ -20,9 +24,12 @@ pub mod frame_allocator;
 pub mod allocator;
 pub mod serial_logger;
 pub mod crc;
+pub mod cmdline;
+pub mod superblock;
 
 use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
 use crate::frame_allocator::MemoryMap;
 use crate::logger::FrameBufferInfo;
 
@@ -30,7 +37,16 @@ pub struct BootInfo {
     pub fb_info: FrameBufferInfo,
     pub rsdp_addr: u64,
     pub memory_map: MemoryMap,
-    pub memory_map_next_free_frame: usize
+    pub memory_map_next_free_frame: usize,
+    /// Physical address and size of the buffer the kernel file was loaded to. The memory map
+    /// reports it as loader memory.
+    pub kernel_addr: u64,
+    pub kernel_size: u64,
+    /// Physical address and size of the stack the kernel starts on, which is identity mapped.
+    pub kernel_stack_addr: u64,
+    pub kernel_stack_size: u64,
+    /// Kernel command line, parse it with [`cmdline::CmdLine`]
+    pub cmdline: &'static str
 }
 
 pub const VIRT_MAPPING_OFFSET: u64 = 0x180_0000_0000;
@@ -60,11 +76,56 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
+/// Default `iobase` of QEMU's isa-debug-exit device
+pub const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// Width of the write to the isa-debug-exit device, should match its `iosize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PortWidth {
+    Byte = 1,
+    Word = 2,
+    Dword = 4
+}
+
+static EXIT_PORT: AtomicU16 = AtomicU16::new(QEMU_EXIT_PORT);
+static EXIT_PORT_WIDTH: AtomicU8 = AtomicU8::new(PortWidth::Byte as u8);
+
+/// Changes the port and the write width used by [`exit_qemu`].
+pub fn set_qemu_exit_port(port: u16, width: PortWidth) {
+    EXIT_PORT.store(port, Ordering::Relaxed);
+    EXIT_PORT_WIDTH.store(width as u8, Ordering::Relaxed);
+}
+
+/// Exits QEMU through the isa-debug-exit device configured with [`set_qemu_exit_port`],
+/// port 0xf4 with a byte write by default.
 pub fn exit_qemu(exit_code: QemuExitCode) {
+    let width = match EXIT_PORT_WIDTH.load(Ordering::Relaxed) {
+        2 => PortWidth::Word,
+        4 => PortWidth::Dword,
+        _ => PortWidth::Byte
+    };
+
+    exit_qemu_with_width(EXIT_PORT.load(Ordering::Relaxed), exit_code as u32, width);
+}
+
+/// Writes `code` to the isa-debug-exit device at `port` with a byte write.
+pub fn exit_qemu_at(port: u16, code: u32) {
+    exit_qemu_with_width(port, code, PortWidth::Byte);
+}
+
+/// Writes `code` truncated to `width` to the isa-debug-exit device at `port`, after waiting for
+/// the serial output to drain so the end of the log isn't lost.
+pub fn exit_qemu_with_width(port: u16, code: u32, width: PortWidth) {
+    // a port that doesn't drain couldn't report that either
+    let _ = serial::flush();
+
     unsafe {
-        let port = 0xf4;
-        let value = exit_code as u8;
-        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+        match width {
+            PortWidth::Byte => asm!("out dx, al", in("dx") port, in("al") code as u8, options(nomem, nostack, preserves_flags)),
+            PortWidth::Word => asm!("out dx, ax", in("dx") port, in("ax") code as u16, options(nomem, nostack, preserves_flags)),
+            PortWidth::Dword => asm!("out dx, eax", in("dx") port, in("eax") code, options(nomem, nostack, preserves_flags))
+        }
     }
 }
 
@@ -103,6 +164,9 @@ pub fn test_runner(tests: &[&dyn Testable]) {
         test.run();
     }
 
+    // a growing in use count between runs points at a leak
+    serial_println!("{}", allocator::heap_stats());
+
     exit_qemu(QemuExitCode::Success);
 }
 
@@ -126,6 +190,7 @@ entry_point!(test_kernel_main);
 #[cfg(test)]
 fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
     //init();
+    page_table::enable_nx();
     test_main();
     loop {}
 }
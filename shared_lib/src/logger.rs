@@ -4,18 +4,23 @@ use alloc::vec::Vec;
 use core::fmt;
 use core::slice::from_raw_parts_mut;
 use core::ptr::read_volatile;
-use spinning_top::{RawSpinlock, Spinlock};
 use conquer_once::spin::OnceCell;
 use core::fmt::{Arguments, Write};
 use font8x8::UnicodeFonts;
-use spinning_top::lock_api::MutexGuard;
+use crate::framebuffer::{FrameBuffer, MAX_BYTES_PER_PIXEL};
+use crate::debug_mutex::{DebugMutex, DebugMutexGuard};
 use crate::interrupts;
 
-#[derive(Clone, Copy)]
+/// Layout of a pixel in the framebuffer, see `PixelFormat::encode` for the byte order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PixelFormat {
     Rgb,
     Bgr,
-    Bitmask,
+    /// 32-bit pixels with each channel at the set bits of its mask
+    Bitmask { red: u32, green: u32, blue: u32 },
+    /// 8-bit luminance
+    Grayscale,
+    /// no framebuffer access, only the firmware can draw
     BltOnly
 }
 
@@ -29,15 +34,110 @@ pub struct FrameBufferInfo {
     pub stride: usize
 }
 
+const CHAR_HEIGHT: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+
+    /// Takes the color from the low 24 bits of `0xRRGGBB`.
+    pub const fn from_rgb(rgb: u32) -> Self {
+        Color::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+    }
+}
+
+pub const DEFAULT_FOREGROUND: Color = Color::new(255, 255, 127);
+pub const DEFAULT_BACKGROUND: Color = Color::new(0, 0, 0);
+
+// the 8 basic ANSI colors, normal and bright variants
+const ANSI_COLORS: [Color; 8] = [
+    Color::new(0, 0, 0), Color::new(170, 0, 0), Color::new(0, 170, 0), Color::new(170, 85, 0),
+    Color::new(0, 0, 170), Color::new(170, 0, 170), Color::new(0, 170, 170), Color::new(170, 170, 170)
+];
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::new(85, 85, 85), Color::new(255, 85, 85), Color::new(85, 255, 85), Color::new(255, 255, 85),
+    Color::new(85, 85, 255), Color::new(255, 85, 255), Color::new(85, 255, 255), Color::new(255, 255, 255)
+];
+
+const MAX_ESCAPE_PARAMS: usize = 4;
+
+/// State of the ANSI escape sequence parser.
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    // got ESC
+    Started,
+    // got ESC [, collecting the numeric parameters
+    Csi { params: [u16; MAX_ESCAPE_PARAMS], count: usize }
+}
+
+/// Returns the SGR sequence used to color the given log level, empty for the default color.
+pub fn level_color(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "\x1b[31m",
+        log::Level::Warn => "\x1b[33m",
+        _ => ""
+    }
+}
+
+/// Sets the most verbose level printed by the loggers. Can be changed at any time, records above
+/// the level are dropped by the `log` macros before being formatted.
+pub fn set_max_level(level: log::LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Returns true if records of the given level pass the current filter.
+pub fn level_enabled(level: log::Level) -> bool {
+    level <= log::max_level()
+}
+
+pub const COLOR_RESET: &str = "\x1b[0m";
+
+/// Pixel rectangle changed since the last flush, `x_end` and `y_end` are exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct DirtyRect {
+    x: usize,
+    y: usize,
+    x_end: usize,
+    y_end: usize
+}
+
+impl DirtyRect {
+    fn union(self, other: DirtyRect) -> DirtyRect {
+        DirtyRect {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            x_end: self.x_end.max(other.x_end),
+            y_end: self.y_end.max(other.y_end)
+        }
+    }
+}
+
 pub struct Logger {
     fb_info: FrameBufferInfo,
     fb: &'static mut [u8],
+    // off-screen copy of the framebuffer all drawing goes to, if double buffered
+    back_buffer: Option<Vec<u8>>,
+    dirty: Option<DirtyRect>,
     x_pos: usize,
     y_pos: usize,
 
     char_buffer: VecDeque<Vec<char>>,
     char_buffer_width: usize,
-    char_buffer_height: usize
+    char_buffer_height: usize,
+
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    escape: Escape
 }
 
 impl Logger {
@@ -49,11 +149,79 @@ impl Logger {
         let h = (fb_info.height - 1) / 8;
 
         let mut char_buffer = VecDeque::with_capacity(h);
-        for _ in 0..w {
+        for _ in 0..h {
             char_buffer.push_back(vec!['\0'; w]);
         }
 
-        Logger{fb_info, fb: &mut *fb_slice, x_pos: 0, y_pos: 0, char_buffer, char_buffer_width: w, char_buffer_height: h }
+        Logger{fb_info, fb: &mut *fb_slice, back_buffer: None, dirty: None, x_pos: 0, y_pos: 0, char_buffer, char_buffer_width: w, char_buffer_height: h,
+            foreground: DEFAULT_FOREGROUND, background: DEFAULT_BACKGROUND, bold: false, escape: Escape::None }
+    }
+
+    /// Same as `new`, but draws into an off-screen buffer allocated from the heap. Nothing shows
+    /// up on the screen until `flush` copies the changed region to the framebuffer, so scrolling
+    /// doesn't tear. Falls back to drawing directly if the heap can't fit the buffer.
+    pub fn new_double_buffered(fb_info: FrameBufferInfo) -> Self {
+        let mut logger = Logger::new(fb_info);
+
+        let mut back_buffer = Vec::new();
+        if back_buffer.try_reserve_exact(fb_info.size).is_ok() {
+            back_buffer.resize(fb_info.size, 0);
+            logger.back_buffer = Some(back_buffer);
+        }
+        logger
+    }
+
+    /// Copies the rows changed since the last flush from the off-screen buffer to the framebuffer.
+    /// Does nothing if the logger is not double buffered.
+    pub fn flush(&mut self) {
+        let bytes_per_pixel = self.bytes_per_pixel();
+        let (Some(back_buffer), Some(dirty)) = (&self.back_buffer, self.dirty.take()) else {
+            return;
+        };
+
+        let row_bytes = self.fb_info.stride * bytes_per_pixel;
+        let x_end = dirty.x_end.min(self.fb_info.width);
+        let y_end = dirty.y_end.min(self.fb_info.height);
+        for y in dirty.y..y_end {
+            let range = y * row_bytes + dirty.x * bytes_per_pixel..y * row_bytes + x_end * bytes_per_pixel;
+            self.fb[range.clone()].copy_from_slice(&back_buffer[range]);
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.fb_info.pixel_format.bytes_per_pixel()
+    }
+
+    /// Returns the buffer drawing goes to.
+    fn buffer(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.fb
+        }
+    }
+
+    fn mark_dirty(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        if self.back_buffer.is_none() {
+            return;
+        }
+
+        let rect = DirtyRect { x, y, x_end: x + width, y_end: y + height };
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect
+        });
+    }
+
+    /// Marks the full width of the pixel rows covered by the byte range as dirty.
+    fn mark_dirty_bytes(&mut self, range: &core::ops::Range<usize>) {
+        let row_bytes = self.fb_info.stride * self.bytes_per_pixel();
+        if range.is_empty() {
+            return;
+        }
+
+        let y = range.start / row_bytes;
+        let y_end = (range.end - 1) / row_bytes + 1;
+        self.mark_dirty(0, y, self.fb_info.width, y_end - y);
     }
 
     pub fn draw_char_buffer(&mut self) {
@@ -68,20 +236,22 @@ impl Logger {
         }
     }
 
-    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+    fn pixel_bytes(&self, color: Color) -> [u8; MAX_BYTES_PER_PIXEL] {
+        match self.fb_info.pixel_format.encode(color) {
+            Some(pixel) => pixel,
+            None => loop {}
+        }
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, color: Color) {
         let pixel_offset = y * self.fb_info.stride + x;
-        let color = match &self.fb_info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-            _other => {
-                loop {}
-            }
-        };
-        let bytes_per_pixel = 4;
+        let color = self.pixel_bytes(color);
+        let bytes_per_pixel = self.bytes_per_pixel();
         let byte_offset = pixel_offset * bytes_per_pixel;
-        self.fb[byte_offset..(byte_offset + bytes_per_pixel)]
+        let buffer = self.buffer();
+        buffer[byte_offset..(byte_offset + bytes_per_pixel)]
             .copy_from_slice(&color[..bytes_per_pixel]);
-        let _ = unsafe { read_volatile(&self.fb[byte_offset]) };
+        let _ = unsafe { read_volatile(&buffer[byte_offset]) };
     }
 
     fn newline(&mut self) {
@@ -93,7 +263,30 @@ impl Logger {
             self.char_buffer.push_back(vec!['\0'; self.char_buffer_width]);
             self.y_pos = self.char_buffer_height - 1;
             self.x_pos = 0;
-            self.draw_char_buffer();
+            self.scroll_up();
+        }
+    }
+
+    /// Moves the text up by one character row and clears the last row. Pixel rows below the last
+    /// full character row are left untouched.
+    fn scroll_up(&mut self) {
+        let row_bytes = CHAR_HEIGHT * self.fb_info.stride * self.bytes_per_pixel();
+        // characters are drawn with a 1 pixel margin
+        let text_start = self.fb_info.stride * self.bytes_per_pixel();
+        let text_end = text_start + self.char_buffer_height * row_bytes;
+
+        self.buffer().copy_within(text_start + row_bytes..text_end, text_start);
+        self.mark_dirty_bytes(&(text_start..text_end));
+        self.fill_bytes(text_end - row_bytes..text_end, self.background);
+    }
+
+    /// Fills the given byte range of the framebuffer with the color. The range must be pixel aligned.
+    fn fill_bytes(&mut self, range: core::ops::Range<usize>, color: Color) {
+        let pixel = self.pixel_bytes(color);
+        let bytes_per_pixel = self.bytes_per_pixel();
+        self.mark_dirty_bytes(&range);
+        for chunk in self.buffer()[range].chunks_exact_mut(bytes_per_pixel) {
+            chunk.copy_from_slice(&pixel[..bytes_per_pixel]);
         }
     }
 
@@ -101,14 +294,61 @@ impl Logger {
         self.x_pos = 0;
     }
 
+    /// Moves the cursor one character back, wrapping to the end of the previous row, and erases
+    /// the character there.
+    fn backspace(&mut self) {
+        if self.x_pos > 0 {
+            self.x_pos -= 1;
+        } else if self.y_pos > 0 {
+            self.y_pos -= 1;
+            self.x_pos = self.char_buffer_width - 1;
+        } else {
+            return;
+        }
+
+        self.char_buffer[self.y_pos][self.x_pos] = '\0';
+        self.write_8x8([0; 8], 1 + self.x_pos * 8, 1 + self.y_pos * 8);
+    }
+
+    /// Fills the whole screen with the background color and moves the cursor to the top left corner.
     pub fn clear(&mut self) {
         self.x_pos = 0;
         self.y_pos = 0;
-        self.fb.fill(0);
 
-        for i in 0..self.char_buffer_width {
-            self.char_buffer[i].fill('\0');
+        let row_bytes = self.fb_info.stride * self.bytes_per_pixel();
+        let visible_bytes = self.fb_info.width * self.bytes_per_pixel();
+        for y in 0..self.fb_info.height {
+            let start = y * row_bytes;
+            self.fill_bytes(start..start + visible_bytes, self.background);
+        }
+
+        for row in self.char_buffer.iter_mut() {
+            row.fill('\0');
+        }
+    }
+
+    /// Moves the cursor to the given character cell.
+    pub fn set_cursor(&mut self, row: usize, col: usize) -> Result<(), &'static str> {
+        if row >= self.char_buffer_height || col >= self.char_buffer_width {
+            return Err("Cursor position is out of screen");
         }
+
+        self.y_pos = row;
+        self.x_pos = col;
+        Ok(())
+    }
+
+    /// Returns the cursor position as (row, col).
+    pub fn get_cursor(&self) -> (usize, usize) {
+        (self.y_pos, self.x_pos)
+    }
+
+    /// Gives pixel access to the screen, e.g. to draw a cursor over the text. When double
+    /// buffered, the whole screen is copied on the next flush.
+    pub fn framebuffer(&mut self) -> Result<FrameBuffer<'_>, &'static str> {
+        let info = self.fb_info;
+        self.mark_dirty(0, 0, info.width, info.height);
+        FrameBuffer::new(info, self.buffer())
     }
 
     pub fn width(&self) -> usize {
@@ -119,18 +359,79 @@ impl Logger {
     }
 
     pub fn write_8x8(&mut self, rendered: [u8; 8], x_pos: usize, y_pos: usize) {
+        let foreground = self.foreground;
+        let background = self.background;
+        self.mark_dirty(x_pos, y_pos, 8, rendered.len());
+
         for (y, byte) in rendered.iter().enumerate() {
             for (x, bit) in (0..8).enumerate() {
-                let intensity = if *byte & (1 << bit) == 0 { 0 } else { 255 };
-                self.write_pixel(x_pos + x, y_pos + y, intensity);
+                let color = if *byte & (1 << bit) == 0 { background } else { foreground };
+                self.write_pixel(x_pos + x, y_pos + y, color);
             }
         }
     }
 
+    /// Feeds the char to the escape sequence parser. Returns false if the char is not part of a
+    /// sequence and should be printed.
+    fn handle_escape(&mut self, c: char) -> bool {
+        self.escape = match (self.escape, c) {
+            (Escape::None, '\x1b') => Escape::Started,
+            (Escape::None, _) => return false,
+            (Escape::Started, '[') => Escape::Csi { params: [0; MAX_ESCAPE_PARAMS], count: 0 },
+            (Escape::Csi { mut params, count }, '0'..='9') => {
+                if let Some(param) = params.get_mut(count) {
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+                Escape::Csi { params, count }
+            },
+            (Escape::Csi { params, count }, ';') => Escape::Csi { params, count: count + 1 },
+            (Escape::Csi { params, count }, 'm') => {
+                let count = (count + 1).min(MAX_ESCAPE_PARAMS);
+                for &param in &params[..count] {
+                    self.select_graphic_rendition(param);
+                }
+                Escape::None
+            },
+            // unsupported sequences are swallowed up to their final byte
+            (Escape::Csi { .. }, '\x40'..='\x7e') => Escape::None,
+            (Escape::Csi { .. }, _) => self.escape,
+            (Escape::Started, _) => Escape::None
+        };
+
+        true
+    }
+
+    fn select_graphic_rendition(&mut self, param: u16) {
+        let palette = if self.bold { &ANSI_BRIGHT_COLORS } else { &ANSI_COLORS };
+
+        match param {
+            0 => {
+                self.foreground = DEFAULT_FOREGROUND;
+                self.background = DEFAULT_BACKGROUND;
+                self.bold = false;
+            },
+            1 => self.bold = true,
+            22 => self.bold = false,
+            30..=37 => self.foreground = palette[param as usize - 30],
+            39 => self.foreground = DEFAULT_FOREGROUND,
+            40..=47 => self.background = ANSI_COLORS[param as usize - 40],
+            49 => self.background = DEFAULT_BACKGROUND,
+            90..=97 => self.foreground = ANSI_BRIGHT_COLORS[param as usize - 90],
+            100..=107 => self.background = ANSI_BRIGHT_COLORS[param as usize - 100],
+            _ => {}
+        }
+    }
+
     pub fn write_char(&mut self, c: char) {
+        if self.handle_escape(c) {
+            return;
+        }
+
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            '\x08' => self.backspace(),
+            '\x0c' => self.clear(),
             c => {
                 if self.x_pos >= self.char_buffer_width {
                     self.newline();
@@ -167,22 +468,29 @@ impl fmt::Write for Logger {
 
 pub static LOGGER: OnceCell<LockedLogger> = OnceCell::uninit();
 
-/// A [`Logger`] instance protected by a spinlock.
-pub struct LockedLogger(Spinlock<Logger>);
+/// A [`Logger`] instance protected by a [`DebugMutex`].
+pub struct LockedLogger(DebugMutex<Logger>);
 
 impl LockedLogger {
     /// Create a new instance that logs to the given framebuffer.
     pub fn new(fb_info: FrameBufferInfo) -> Self {
-        LockedLogger(Spinlock::new(Logger::new(fb_info)))
+        LockedLogger(DebugMutex::new(Logger::new(fb_info)))
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, RawSpinlock, Logger> {
+    /// Create a new instance drawing into an off-screen buffer, see `Logger::new_double_buffered`.
+    pub fn new_double_buffered(fb_info: FrameBufferInfo) -> Self {
+        LockedLogger(DebugMutex::new(Logger::new_double_buffered(fb_info)))
+    }
+
+    pub fn lock(&self) -> DebugMutexGuard<'_, Logger> {
         self.0.lock()
     }
 
     pub fn write_fmt(&self, arguments: Arguments ) {
         interrupts::without_interrupts(|| {
-            self.0.lock().write_fmt(arguments).unwrap();
+            let mut logger = self.0.lock();
+            logger.write_fmt(arguments).unwrap();
+            logger.flush();
         });
     }
 
@@ -195,24 +503,48 @@ impl LockedLogger {
 }
 
 impl log::Log for LockedLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        level_enabled(metadata.level())
     }
 
     fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
         interrupts::without_interrupts(|| {
             let mut logger = self.0.lock();
-            writeln!(logger, "{}:    {}", record.level(), record.args()).unwrap();
+            writeln!(logger, "{}{}{}:    {}", level_color(record.level()), record.level(), COLOR_RESET, record.args()).unwrap();
+            logger.flush();
         });
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        interrupts::without_interrupts(|| self.0.lock().flush());
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    // `write_fmt` holds the lock with interrupts disabled, so a handler can't deadlock on it
+    if let Some(logger) = LOGGER.get() {
+        logger.write_fmt(args);
+    }
 }
 
+/// Prints to the framebuffer logger, does nothing before it's initialized.
 #[macro_export]
 macro_rules! out {
     ($($arg:tt)*) => {
-        LOGGER.get().unwrap().write_fmt(format_args!($($arg)*));
+        $crate::logger::_print(format_args!($($arg)*));
     };
 }
 
+#[test_case]
+fn dirty_rect_union_test() {
+    let glyph = DirtyRect { x: 9, y: 1, x_end: 17, y_end: 9 };
+    let row = DirtyRect { x: 0, y: 17, x_end: 640, y_end: 25 };
+
+    assert_eq!(DirtyRect { x: 0, y: 1, x_end: 640, y_end: 25 }, glyph.union(row));
+    assert_eq!(glyph, glyph.union(glyph));
+}
@@ -0,0 +1,91 @@
+use core::marker::PhantomData;
+use crate::addr::VirtAddr;
+
+/// A device register of type `T`. Every access is a single volatile read or write, so it's neither
+/// merged with nor reordered around other register accesses.
+#[repr(transparent)]
+pub struct Mmio<T> {
+    ptr: *mut T
+}
+
+impl<T: Copy> Mmio<T> {
+    /// # Safety
+    /// `ptr` must point at a mapped register of type `T` for as long as the `Mmio` is used.
+    pub const unsafe fn new(ptr: *mut T) -> Self {
+        Mmio { ptr }
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.ptr) }
+    }
+
+    #[inline]
+    pub fn write(&self, value: T) {
+        unsafe { core::ptr::write_volatile(self.ptr, value) }
+    }
+
+    /// Reads the register and writes back what `f` makes of the value.
+    #[inline]
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+}
+
+/// A block of device registers, e.g. what `memory::map_mmio` returns. Registers are picked by
+/// their byte offset and type with [`MmioRegion::reg`].
+#[derive(Clone, Copy, Debug)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    size: usize,
+    // registers are shared with the device, not owned
+    _registers: PhantomData<*mut u8>
+}
+
+// the region is only an address, which CPUs can share; ordering the accesses is up to the driver
+unsafe impl Send for MmioRegion {}
+unsafe impl Sync for MmioRegion {}
+
+impl MmioRegion {
+    /// # Safety
+    /// `size` bytes at `base` must stay mapped to device registers while the region is used.
+    pub const unsafe fn new(base: VirtAddr, size: usize) -> Self {
+        MmioRegion { base, size, _registers: PhantomData }
+    }
+
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the register of type `T` at byte `offset`. Panics if it isn't aligned or doesn't
+    /// fit into the region.
+    pub fn reg<T: Copy>(&self, offset: usize) -> Mmio<T> {
+        assert!(offset.checked_add(core::mem::size_of::<T>()).is_some_and(|end| end <= self.size),
+            "MMIO register at {:#x} is out of the region", offset);
+
+        let addr = self.base.0 + offset as u64;
+        assert!(addr % core::mem::align_of::<T>() as u64 == 0, "MMIO register at {:#x} is not aligned", offset);
+        unsafe { Mmio::new(addr as *mut T) }
+    }
+}
+
+#[test_case]
+fn mmio_region_test() {
+    let mut registers = [0u32; 4];
+    let region = unsafe { MmioRegion::new(VirtAddr::new(registers.as_mut_ptr() as u64), 16) };
+
+    region.reg::<u32>(4).write(0x1234_5678);
+    region.reg::<u32>(8).update(|value| value | 0x10);
+    region.reg::<u16>(12).write(0xBEEF);
+
+    assert_eq!(0x5678, region.reg::<u16>(4).read());
+    assert_eq!([0, 0x1234_5678, 0x10, 0xBEEF], registers);
+}
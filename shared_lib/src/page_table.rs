@@ -1,9 +1,13 @@
 use core::arch::asm;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::ops::IndexMut;
 use bitflags::bitflags;
-use crate::addr::VirtAddr;
+use crate::addr::{PhysAddr, VirtAddr};
 
 pub const PAGE_SIZE: u64 = 4096;
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+pub const GIANT_PAGE_SIZE: u64 = 1024 * 1024 * 1024;
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -18,8 +22,8 @@ impl PageTableEntry {
     }
 
     #[inline]
-    pub fn set_addr(&mut self, addr: u64, flags: PageTableFlags) {
-        self.entry = addr | flags.bits();
+    pub fn set_addr(&mut self, addr: PhysAddr, flags: PageTableFlags) {
+        self.entry = addr.0 | flags.bits();
     }
 
     /// Returns the flags of this entry.
@@ -38,6 +42,30 @@ impl PageTableEntry {
     pub fn addr(&self) -> u64 {
         self.entry & 0x000f_ffff_ffff_f000
     }
+
+    /// Returns true if the CPU accessed the mapping since the bit was last cleared.
+    #[inline]
+    pub const fn is_accessed(&self) -> bool {
+        self.flags().contains(PageTableFlags::ACCESSED)
+    }
+
+    /// Returns true if the CPU wrote to the mapping since the bit was last cleared.
+    #[inline]
+    pub const fn is_dirty(&self) -> bool {
+        self.flags().contains(PageTableFlags::DIRTY)
+    }
+
+    /// Clears only the `ACCESSED` bit. The TLB entry has to be flushed for the CPU to set it again.
+    #[inline]
+    pub fn clear_accessed(&mut self) {
+        self.entry &= !PageTableFlags::ACCESSED.bits();
+    }
+
+    /// Clears only the `DIRTY` bit. The TLB entry has to be flushed for the CPU to set it again.
+    #[inline]
+    pub fn clear_dirty(&mut self) {
+        self.entry &= !PageTableFlags::DIRTY.bits();
+    }
 }
 
 bitflags! {
@@ -100,7 +128,7 @@ bitflags! {
         /// Forbid code execution from the mapped frames.
         ///
         /// Can be only used when the no-execute page protection feature is enabled in the EFER
-        /// register.
+        /// register, see [`enable_nx`].
         const NO_EXECUTE =      1 << 63;
     }
 }
@@ -121,9 +149,207 @@ impl PageTable {
         }
     }
 
+    /// Returns true if no entry of the table is present.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| !entry.is_present())
+    }
+
+    /// Returns the entry at `index`, `None` if it's not below [`ENTRY_COUNT`].
+    pub fn get(&self, index: u16) -> Option<&PageTableEntry> {
+        self.entries.get(index as usize)
+    }
+
+    pub fn get_mut(&mut self, index: u16) -> Option<&mut PageTableEntry> {
+        self.entries.get_mut(index as usize)
+    }
+
     pub fn clear(&mut self) {
         for entry in self.entries.iter_mut() {
-            entry.set_addr(0, PageTableFlags::from_bits(0).unwrap());
+            entry.set_addr(PhysAddr::zero(), PageTableFlags::from_bits(0).unwrap());
+        }
+    }
+
+    /// Returns an iterator over all present leaf mappings of this L4 table.
+    ///
+    /// Lower level tables are accessed at `offset` from their physical addresses.
+    ///
+    /// # Safety
+    /// `self` must be the root of a valid page table hierarchy with tables mapped at `offset`.
+    pub unsafe fn iter_mappings(&self, offset: u64) -> MappingsIter<'_> {
+        MappingsIter {
+            tables: [self as *const PageTable, core::ptr::null(), core::ptr::null(), core::ptr::null()],
+            indexes: [0; 4],
+            level: 0,
+            offset,
+            phantom: PhantomData
+        }
+    }
+
+    /// Creates a copy of this L4 table and all tables below it, e.g. for a new address space.
+    ///
+    /// Entries starting from [`KERNEL_L4_START_INDEX`] are shared with the original hierarchy. Frames
+    /// for copied data are taken from `page_tables_allocator` too. With [`CloneMode::ShareFrames`]
+    /// every shared 4 KiB frame gets another reference through `PageTablesAllocator::share_frame`.
+    ///
+    /// # Safety
+    /// `self` must be the root of a valid page table hierarchy with tables mapped at `offset`.
+    pub unsafe fn clone_hierarchy<'a>(&self, page_tables_allocator: &'a mut impl PageTablesAllocator, mode: CloneMode, offset: u64)
+                                      -> Result::<&'a mut PageTable, &'static str> {
+        let new_l4_table = clone_table(self, 4, page_tables_allocator, mode, offset)?;
+        Ok(&mut *new_l4_table)
+    }
+}
+
+/// First L4 entry of the kernel's higher half, shared between all address spaces.
+pub const KERNEL_L4_START_INDEX: u16 = ENTRY_COUNT / 2;
+
+/// What [`PageTable::clone_hierarchy`] does with the frames mapped by the cloned table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloneMode {
+    /// Both hierarchies map the same frames.
+    ShareFrames,
+    /// Every 4 KiB frame is copied to a newly allocated one. Huge pages are still shared.
+    CopyFrames
+}
+
+/// Clones `table` of the given level. If it fails partway, everything cloned so far is freed again.
+unsafe fn clone_table(table: &PageTable, level: u8, page_tables_allocator: &mut impl PageTablesAllocator, mode: CloneMode, offset: u64)
+                      -> Result::<*mut PageTable, &'static str> {
+    let new_table = page_tables_allocator.allocate_page_table()? as *mut PageTable;
+    (*new_table).clear();
+
+    if let Err(err) = clone_entries(table, new_table, level, page_tables_allocator, mode, offset) {
+        free_cloned_table(new_table, level, page_tables_allocator, mode, offset)?;
+        return Err(err);
+    }
+
+    Ok(new_table)
+}
+
+unsafe fn clone_entries(table: &PageTable, new_table: *mut PageTable, level: u8, page_tables_allocator: &mut impl PageTablesAllocator, mode: CloneMode, offset: u64)
+                        -> Result::<(), &'static str> {
+    for i in 0..ENTRY_COUNT {
+        let entry = table[i];
+        if !entry.is_present() {
+            continue;
+        }
+
+        if level == 4 && i >= KERNEL_L4_START_INDEX {
+            (*new_table)[i] = entry;
+            continue;
+        }
+
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if level == 1 && mode == CloneMode::CopyFrames {
+                let frame = page_tables_allocator.allocate_page_table()? as *mut PageTable;
+                core::ptr::copy_nonoverlapping((entry.addr() + offset) as *const PageTable, frame, 1);
+                (*new_table)[i].set_addr(PhysAddr::new(frame as u64 - offset), entry.flags());
+            } else {
+                // huge pages aren't reference counted
+                if level == 1 {
+                    page_tables_allocator.share_frame(entry.addr());
+                }
+                (*new_table)[i] = entry;
+            }
+            continue;
+        }
+
+        let next_table = clone_table(&*((entry.addr() + offset) as *const PageTable), level - 1, page_tables_allocator, mode, offset)?;
+        (*new_table)[i].set_addr(PhysAddr::new(next_table as u64 - offset), entry.flags());
+    }
+
+    Ok(())
+}
+
+/// Frees a table made by `clone_table` with the tables below it, and drops the references taken on
+/// shared frames or frees the copied ones. The kernel half of an L4 table isn't touched.
+unsafe fn free_cloned_table(table: *mut PageTable, level: u8, page_tables_allocator: &mut impl PageTablesAllocator, mode: CloneMode, offset: u64)
+                            -> Result::<(), &'static str> {
+    let entry_count = if level == 4 { KERNEL_L4_START_INDEX } else { ENTRY_COUNT };
+
+    for i in 0..entry_count {
+        let entry = (*table)[i];
+        if !entry.is_present() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+
+        if level == 1 {
+            match mode {
+                CloneMode::ShareFrames => { page_tables_allocator.release_frame(entry.addr())?; },
+                CloneMode::CopyFrames => page_tables_allocator.free_page_table(&mut *((entry.addr() + offset) as *mut PageTable))?
+            }
+            continue;
+        }
+
+        free_cloned_table((entry.addr() + offset) as *mut PageTable, level - 1, page_tables_allocator, mode, offset)?;
+    }
+
+    page_tables_allocator.free_page_table(&mut *table)
+}
+
+/// A present leaf mapping: a 4 KiB page or a 2 MiB / 1 GiB huge page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: u64,
+    pub flags: PageTableFlags,
+    pub size: u64
+}
+
+pub struct MappingsIter<'a> {
+    tables: [*const PageTable; 4],
+    indexes: [u16; 4],
+    level: usize,
+    offset: u64,
+    phantom: PhantomData<&'a PageTable>
+}
+
+impl MappingsIter<'_> {
+    fn current_virt(&self) -> VirtAddr {
+        let mut addr = 0;
+        for level in 0..=self.level {
+            addr |= (self.indexes[level] as u64) << (39 - 9 * level);
+        }
+        VirtAddr::new(addr)
+    }
+}
+
+impl Iterator for MappingsIter<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.indexes[self.level] >= ENTRY_COUNT {
+                if self.level == 0 {
+                    return None;
+                }
+
+                self.level -= 1;
+                self.indexes[self.level] += 1;
+                continue;
+            }
+
+            let entry = unsafe { (*self.tables[self.level])[self.indexes[self.level]] };
+            if !entry.is_present() {
+                self.indexes[self.level] += 1;
+                continue;
+            }
+
+            let is_huge = self.level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+            if self.level == 3 || is_huge {
+                let mapping = Mapping {
+                    virt: self.current_virt(),
+                    phys: entry.addr(),
+                    flags: entry.flags(),
+                    size: PAGE_SIZE << (9 * (3 - self.level))
+                };
+                self.indexes[self.level] += 1;
+                return Some(mapping);
+            }
+
+            self.tables[self.level + 1] = (entry.addr() + self.offset) as *const PageTable;
+            self.level += 1;
+            self.indexes[self.level] = 0;
         }
     }
 }
@@ -133,6 +359,7 @@ impl core::ops::Index<u16> for PageTable {
 
     #[inline]
     fn index(&self, index: u16) -> &Self::Output {
+        debug_assert!(index < ENTRY_COUNT, "Page table index {} is out of range, tables have {} entries", index, ENTRY_COUNT);
         &self.entries[index as usize]
     }
 }
@@ -140,25 +367,53 @@ impl core::ops::Index<u16> for PageTable {
 impl core::ops::IndexMut<u16> for PageTable {
     #[inline]
     fn index_mut(&mut self, index: u16) -> &mut Self::Output {
+        debug_assert!(index < ENTRY_COUNT, "Page table index {} is out of range, tables have {} entries", index, ENTRY_COUNT);
         &mut self.entries[index as usize]
     }
 }
 
-unsafe fn create_next_table<'a>(page_table_entry: &'a mut PageTableEntry, page_tables_allocator: &'a mut impl PageTablesAllocator, offset: u64)
+/// `table_flags` are set on the entry pointing to the next table, whether it is created or already
+/// present.
+unsafe fn create_next_table<'a>(page_table_entry: &'a mut PageTableEntry, page_tables_allocator: &'a mut impl PageTablesAllocator, table_flags: PageTableFlags, offset: u64)
                                 -> Result::<&'a mut PageTable, &'static str> {
+    if page_table_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err("this virtual address already mapped by a huge page");
+    }
+
     if page_table_entry.flags().contains(PageTableFlags::PRESENT) {
+        if !page_table_entry.flags().contains(table_flags) {
+            page_table_entry.set_addr(PhysAddr::new(page_table_entry.addr()), page_table_entry.flags() | table_flags);
+        }
+
         let next_page_table = unsafe { &mut *((page_table_entry.addr() + offset) as *mut PageTable) };
         Ok(next_page_table)
     }
     else {
         let new_table = page_tables_allocator.allocate_page_table()?;
-        page_table_entry.set_addr(new_table as *const _ as u64 - offset, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        new_table.clear();
+        page_table_entry.set_addr(PhysAddr::new(new_table as *const _ as u64 - offset), table_flags);
         Ok(new_table)
     }
 }
 
 pub trait PageTablesAllocator {
+    /// Allocates a frame for a page table. The frame is not guaranteed to be zeroed.
     fn allocate_page_table(&mut self) -> Result::<&mut PageTable, &'static str>;
+
+    /// Returns the frame of a table that is no longer referenced. By default the frame is leaked.
+    fn free_page_table(&mut self, _table: &mut PageTable) -> Result::<(), &'static str> {
+        Ok(())
+    }
+
+    /// Takes a reference on a frame that gets mapped once more, e.g. by [`PageTable::clone_hierarchy`]
+    /// sharing frames. By default frames aren't counted.
+    fn share_frame(&mut self, _frame: u64) {}
+
+    /// Drops the reference of a removed mapping on the frame. Returns true if it was the last one
+    /// and the frame was freed. By default the frame is kept.
+    fn release_frame(&mut self, _frame: u64) -> Result::<bool, &'static str> {
+        Ok(false)
+    }
 }
 
 enum MappingMode {
@@ -166,41 +421,56 @@ enum MappingMode {
     Remapping
 }
 
-unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator, mapping_mode: MappingMode, offset: u64)
+const DEFAULT_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+/// When `flags` is `None` a remapped entry keeps the flags it had before, a new entry gets
+/// `DEFAULT_FLAGS`.
+unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, mapping_mode: MappingMode, flags: Option<PageTableFlags>, offset: u64)
                            -> core::result::Result<(), &'static str> {
-    if virt.0 % 4096 != 0 {
+    if !virt.is_aligned(PAGE_SIZE) {
         return Err("Virtual address must be aligned!");
     }
 
-    if phys % 4096 != 0 {
+    if !virt.is_canonical() {
+        return Err("Virtual address must be canonical!");
+    }
+
+    if !phys.is_aligned(PAGE_SIZE) {
         return Err("Physical address must be aligned!");
     }
 
-    log::trace!("Mapping {} -> {:#x}", virt, phys);
+    if let Some(flags) = flags {
+        check_no_execute(flags)?;
+    }
+
+    log::trace!("Mapping {} -> {}", virt, phys);
+
+    let table_flags = DEFAULT_FLAGS | (flags.unwrap_or(DEFAULT_FLAGS) & PageTableFlags::USER_ACCESSIBLE);
 
     let l3_page_table_entry = {
-        let l3_table = create_next_table(&mut l4_page_table[virt.p4_index()], page_tables_allocator, offset)?;
+        let l3_table = create_next_table(&mut l4_page_table[virt.p4_index()], page_tables_allocator, table_flags, offset)?;
         l3_table.index_mut(virt.p3_index()) as *mut PageTableEntry
     };
 
     log::trace!("[mapper] got l3_page_table");
 
     let l2_page_table_entry = {
-        let l2_table = create_next_table(&mut *l3_page_table_entry, page_tables_allocator, offset)?;
+        let l2_table = create_next_table(&mut *l3_page_table_entry, page_tables_allocator, table_flags, offset)?;
         l2_table.index_mut(virt.p2_index()) as *mut PageTableEntry
     };
 
     log::trace!("[mapper] got l2_page_table");
 
-    let l1_table = create_next_table(&mut *l2_page_table_entry, page_tables_allocator, offset)?;
+    let l1_table = create_next_table(&mut *l2_page_table_entry, page_tables_allocator, table_flags, offset)?;
 
     log::trace!("[mapper] got l1_page_table");
 
     let l1_entry = &mut l1_table[virt.p1_index()];
 
     log::trace!("[mapper] got l1_entry {:#x}", l1_entry as *const _ as u64);
-    return if l1_entry.flags().contains(PageTableFlags::PRESENT) {
-        if l1_entry.addr() == phys {
+    if l1_entry.flags().contains(PageTableFlags::PRESENT) {
+        let update_flags = matches!(mapping_mode, MappingMode::Remapping) && flags.is_some();
+        if l1_entry.addr() == phys.0 && !update_flags {
             log::info!("[mapper] addr {} already mapped to the same physical address. doing nothing.", virt);
             return Ok(());
         }
@@ -208,64 +478,841 @@ unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys:
         match mapping_mode {
             MappingMode::CheckFrameIsFree => Err("this virtual address already mapped to another frame"),
             MappingMode::Remapping => {
-                l1_entry.set_addr(phys, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
-                asm!("invlpg [{}]", in(reg) phys, options(nostack, preserves_flags));
+                let flags = flags.unwrap_or_else(|| l1_entry.flags() - PageTableFlags::ACCESSED - PageTableFlags::DIRTY);
+                l1_entry.set_addr(phys, flags | PageTableFlags::PRESENT);
+                flush_one(virt);
                 Ok(())
             }
         }
     } else {
-        l1_entry.set_addr(phys, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
-        asm!("invlpg [{}]", in(reg) phys, options(nostack, preserves_flags));
+        l1_entry.set_addr(phys, flags.unwrap_or(DEFAULT_FLAGS) | PageTableFlags::PRESENT);
+        flush_one(virt);
         Ok(())
     }
 }
 
-pub unsafe fn map_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator)
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn map_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator)
                           -> core::result::Result<(), &'static str> {
-    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, 0)
+    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, Some(DEFAULT_FLAGS), 0)
 }
 
-pub unsafe fn remap_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator)
+/// Maps the given virtual page with `flags` on the final entry. `PRESENT` is always set.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn map_address_with_flags(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, flags: PageTableFlags)
+                                     -> core::result::Result<(), &'static str> {
+    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, Some(flags), 0)
+}
+
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn remap_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator)
                             -> core::result::Result<(), &'static str> {
-    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::Remapping, 0)
+    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::Remapping, None, 0)
 }
 
-pub unsafe fn map_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn remap_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                                        -> core::result::Result<(), &'static str> {
+    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::Remapping, None, offset)
+}
+
+/// Remaps the given virtual page to `phys`, replacing the flags of the entry with `flags`.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn remap_address_with_flags(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, flags: PageTableFlags)
+                                       -> core::result::Result<(), &'static str> {
+    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::Remapping, Some(flags), 0)
+}
+
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn map_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
                           -> core::result::Result<(), &'static str> {
-    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, offset)
+    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, Some(DEFAULT_FLAGS), offset)
+}
+
+unsafe fn map_range_impl(l4_page_table: &mut PageTable, virt_start: VirtAddr, phys_start: PhysAddr, size: u64, page_tables_allocator: &mut impl PageTablesAllocator, flags: PageTableFlags, offset: u64)
+                         -> core::result::Result<usize, &'static str> {
+    let pages_count = align_up_u64(size) / PAGE_SIZE;
+
+    for i in 0..pages_count {
+        let virt = virt_start.offset(i * PAGE_SIZE)?;
+        let phys = phys_start.offset(i * PAGE_SIZE)?;
+
+        if let Err(err) = map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, Some(flags), offset) {
+            log::warn!("[mapper] failed to map {} in range starting at {}: {}. Rolling back", virt, virt_start, err);
+            for j in 0..i {
+                unmap_address_impl(l4_page_table, virt_start.offset(j * PAGE_SIZE)?, offset)?;
+            }
+            // tables created on the way to the failed page are freed too
+            for j in 0..=i {
+                free_empty_tables(l4_page_table, virt_start.offset(j * PAGE_SIZE)?, page_tables_allocator, offset)?;
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(pages_count as usize)
+}
+
+/// Maps `size` bytes (rounded up to whole pages) starting at `virt_start` to the contiguous physical
+/// region at `phys_start`. Returns the number of mapped pages.
+///
+/// If any page fails to map, the pages mapped so far are unmapped again and the page tables left
+/// empty by that are freed.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn map_range(l4_page_table: &mut PageTable, virt_start: VirtAddr, phys_start: PhysAddr, size: u64, page_tables_allocator: &mut impl PageTablesAllocator, flags: PageTableFlags)
+                        -> core::result::Result<usize, &'static str> {
+    map_range_impl(l4_page_table, virt_start, phys_start, size, page_tables_allocator, flags, 0)
+}
+
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn map_range_with_offset(l4_page_table: &mut PageTable, virt_start: VirtAddr, phys_start: PhysAddr, size: u64, page_tables_allocator: &mut impl PageTablesAllocator, flags: PageTableFlags, offset: u64)
+                                    -> core::result::Result<usize, &'static str> {
+    map_range_impl(l4_page_table, virt_start, phys_start, size, page_tables_allocator, flags, offset)
+}
+
+unsafe fn map_huge_2mib_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                             -> core::result::Result<(), &'static str> {
+    if !virt.is_aligned(HUGE_PAGE_SIZE) {
+        return Err("Virtual address must be 2 MiB aligned!");
+    }
+
+    if !virt.is_canonical() {
+        return Err("Virtual address must be canonical!");
+    }
+
+    if !phys.is_aligned(HUGE_PAGE_SIZE) {
+        return Err("Physical address must be 2 MiB aligned!");
+    }
+
+    log::trace!("Mapping huge page {} -> {}", virt, phys);
+
+    let table_flags = DEFAULT_FLAGS;
+
+    let l3_page_table_entry = {
+        let l3_table = create_next_table(&mut l4_page_table[virt.p4_index()], page_tables_allocator, table_flags, offset)?;
+        l3_table.index_mut(virt.p3_index()) as *mut PageTableEntry
+    };
+
+    let l2_table = create_next_table(&mut *l3_page_table_entry, page_tables_allocator, table_flags, offset)?;
+    let l2_entry = &mut l2_table[virt.p2_index()];
+
+    if l2_entry.flags().contains(PageTableFlags::PRESENT) {
+        if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) && l2_entry.addr() == phys.0 {
+            log::info!("[mapper] addr {} already mapped to the same physical address. doing nothing.", virt);
+            return Ok(());
+        }
+
+        return Err("this virtual address already mapped to another frame");
+    }
+
+    l2_entry.set_addr(phys, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE);
+    flush_one(virt);
+    Ok(())
+}
+
+/// Maps a 2 MiB huge page directly in the L2 table. Both addresses must be 2 MiB aligned.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn map_huge_2mib(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator)
+                            -> core::result::Result<(), &'static str> {
+    map_huge_2mib_impl(l4_page_table, virt, phys, page_tables_allocator, 0)
+}
+
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn map_huge_2mib_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, phys: PhysAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                                        -> core::result::Result<(), &'static str> {
+    map_huge_2mib_impl(l4_page_table, virt, phys, page_tables_allocator, offset)
+}
+
+unsafe fn next_table<'a>(page_table_entry: &PageTableEntry, offset: u64) -> Result<&'a mut PageTable, &'static str> {
+    if !page_table_entry.flags().contains(PageTableFlags::PRESENT) {
+        return Err("this virtual address is not mapped");
+    }
+
+    if page_table_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err("this virtual address is mapped by a huge page");
+    }
+
+    Ok(&mut *((page_table_entry.addr() + offset) as *mut PageTable))
+}
+
+unsafe fn unmap_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64)
+                             -> core::result::Result<u64, &'static str> {
+    if !virt.is_aligned(PAGE_SIZE) {
+        return Err("Virtual address must be aligned!");
+    }
+
+    log::trace!("Unmapping {}", virt);
+
+    let l3_table = next_table(&l4_page_table[virt.p4_index()], offset)?;
+    let l2_table = next_table(&l3_table[virt.p3_index()], offset)?;
+    let l1_table = next_table(&l2_table[virt.p2_index()], offset)?;
+
+    let l1_entry = &mut l1_table[virt.p1_index()];
+    if !l1_entry.flags().contains(PageTableFlags::PRESENT) {
+        return Err("this virtual address is not mapped");
+    }
+
+    let phys = l1_entry.addr();
+    l1_entry.set_addr(PhysAddr::zero(), PageTableFlags::empty());
+    flush_one(virt);
+
+    Ok(phys)
 }
 
-pub unsafe fn get_physical_address(l4_page_table: &PageTable, virt: VirtAddr) -> Option<u64> {
+unsafe fn unmap_and_release(l4_page_table: &mut PageTable, virt: VirtAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                             -> core::result::Result<u64, &'static str> {
+    let phys = unmap_address_impl(l4_page_table, virt, offset)?;
+    page_tables_allocator.release_frame(phys)?;
+    Ok(phys)
+}
+
+/// Removes the mapping of the given virtual page and returns the physical frame it was mapped to.
+/// The mapping's reference on the frame is dropped with `PageTablesAllocator::release_frame`,
+/// which frees the frame when no other mapping shares it.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn unmap_address(l4_page_table: &mut PageTable, virt: VirtAddr, page_tables_allocator: &mut impl PageTablesAllocator)
+                            -> core::result::Result<u64, &'static str> {
+    unmap_and_release(l4_page_table, virt, page_tables_allocator, 0)
+}
+
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn unmap_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                                        -> core::result::Result<u64, &'static str> {
+    unmap_and_release(l4_page_table, virt, page_tables_allocator, offset)
+}
+
+/// Frees the L1, L2 and L3 tables on the way to the given virtual address that have no present
+/// entries left, e.g. after unmapping the last page of a table. Missing tables are skipped.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn free_empty_tables(l4_page_table: &mut PageTable, virt: VirtAddr, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                                -> core::result::Result<(), &'static str> {
+    let mut path = [(core::ptr::null_mut::<PageTableEntry>(), core::ptr::null_mut::<PageTable>()); 3];
+    let mut depth = 0;
+
+    let mut entry = &mut l4_page_table[virt.p4_index()] as *mut PageTableEntry;
+    for index in [virt.p3_index(), virt.p2_index(), virt.p1_index()] {
+        let Ok(table) = next_table(&*entry, offset) else {
+            break;
+        };
+
+        path[depth] = (entry, table as *mut PageTable);
+        depth += 1;
+        entry = &mut table[index] as *mut PageTableEntry;
+    }
+
+    for &(entry, table) in path[..depth].iter().rev() {
+        if !(*table).is_empty() {
+            break;
+        }
+
+        log::trace!("[mapper] freeing empty page table {:#x}", (*entry).addr());
+        (*entry).set_addr(PhysAddr::zero(), PageTableFlags::empty());
+        page_tables_allocator.free_page_table(&mut *table)?;
+    }
+
+    flush_one(virt);
+    Ok(())
+}
+
+unsafe fn protect_impl(l4_page_table: &mut PageTable, virt: VirtAddr, new_flags: PageTableFlags, offset: u64)
+                       -> core::result::Result<(), &'static str> {
+    check_no_execute(new_flags)?;
+
+    let l3_table = next_table(&l4_page_table[virt.p4_index()], offset)?;
+    let l2_table = next_table(&l3_table[virt.p3_index()], offset)?;
+    let l1_table = next_table(&l2_table[virt.p2_index()], offset)?;
+
+    let l1_entry = &mut l1_table[virt.p1_index()];
+    if !l1_entry.flags().contains(PageTableFlags::PRESENT) {
+        return Err("this virtual address is not mapped");
+    }
+
+    l1_entry.set_addr(PhysAddr::new(l1_entry.addr()), new_flags | PageTableFlags::PRESENT);
+    flush_one(virt);
+
+    Ok(())
+}
+
+/// Replaces the flags of an existing mapping, keeping its physical frame. `PRESENT` is always set.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn protect(l4_page_table: &mut PageTable, virt: VirtAddr, new_flags: PageTableFlags)
+                      -> core::result::Result<(), &'static str> {
+    protect_impl(l4_page_table, virt, new_flags, 0)
+}
+
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn protect_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, new_flags: PageTableFlags, offset: u64)
+                                  -> core::result::Result<(), &'static str> {
+    protect_impl(l4_page_table, virt, new_flags, offset)
+}
+
+unsafe fn query_and_clear_accessed_impl(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64)
+                                        -> core::result::Result<bool, &'static str> {
+    let l3_table = next_table(&l4_page_table[virt.p4_index()], offset)?;
+    let l2_table = next_table(&l3_table[virt.p3_index()], offset)?;
+    let l1_table = next_table(&l2_table[virt.p2_index()], offset)?;
+
+    let l1_entry = &mut l1_table[virt.p1_index()];
+    if !l1_entry.is_present() {
+        return Err("this virtual address is not mapped");
+    }
+
+    let accessed = l1_entry.is_accessed();
+    if accessed {
+        l1_entry.clear_accessed();
+        flush_one(virt);
+    }
+
+    Ok(accessed)
+}
+
+/// Returns whether the page was accessed since the last call and clears its `ACCESSED` bit.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with identity mapped tables.
+pub unsafe fn query_and_clear_accessed(l4_page_table: &mut PageTable, virt: VirtAddr)
+                                       -> core::result::Result<bool, &'static str> {
+    query_and_clear_accessed_impl(l4_page_table, virt, 0)
+}
+
+/// Same as `query_and_clear_accessed`.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn query_and_clear_accessed_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64)
+                                                   -> core::result::Result<bool, &'static str> {
+    query_and_clear_accessed_impl(l4_page_table, virt, offset)
+}
+
+/// Returns the flags of the L1 entry mapping the given virtual address, if it's mapped by a 4 KiB page.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn get_page_flags(l4_page_table: &PageTable, virt: VirtAddr, offset: u64) -> Option<PageTableFlags> {
+    let l3_table = next_table(&l4_page_table[virt.p4_index()], offset).ok()?;
+    let l2_table = next_table(&l3_table[virt.p3_index()], offset).ok()?;
+    let l1_table = next_table(&l2_table[virt.p2_index()], offset).ok()?;
+
+    let l1_entry = l1_table[virt.p1_index()];
+    if !l1_entry.is_present() {
+        return None;
+    }
+
+    Some(l1_entry.flags())
+}
+
+/// Translates the given virtual address to the physical one, including the offset inside the page.
+///
+/// Page tables are accessed at `offset` from their physical addresses.
+///
+/// # Safety
+/// `l4_page_table` must be a valid page table hierarchy with tables mapped at `offset`.
+pub unsafe fn get_physical_address(l4_page_table: &PageTable, virt: VirtAddr, offset: u64) -> Option<u64> {
     let l4_entry = l4_page_table[virt.p4_index()];
     if !l4_entry.flags().contains(PageTableFlags::PRESENT) {
         return None;
     }
 
-    let l3_table = & *(l4_entry.addr() as *const PageTable);
+    let l3_table = & *((l4_entry.addr() + offset) as *const PageTable);
     let l3_entry = l3_table[virt.p3_index()];
     if !l3_entry.flags().contains(PageTableFlags::PRESENT) {
         return None;
     }
 
-    let l2_table = & *(l3_entry.addr() as *const PageTable);
+    if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        // 1 GiB page
+        return Some(align_down_to(l3_entry.addr(), GIANT_PAGE_SIZE) + (virt.0 & (GIANT_PAGE_SIZE - 1)));
+    }
+
+    let l2_table = & *((l3_entry.addr() + offset) as *const PageTable);
     let l2_entry = l2_table[virt.p2_index()];
     if !l2_entry.flags().contains(PageTableFlags::PRESENT) {
         return None;
     }
 
-    let l1_table = & *(l2_entry.addr() as *const PageTable);
+    if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        // 2 MiB page
+        return Some(align_down_to(l2_entry.addr(), HUGE_PAGE_SIZE) + (virt.0 & (HUGE_PAGE_SIZE - 1)));
+    }
+
+    let l1_table = & *((l2_entry.addr() + offset) as *const PageTable);
     let l1_entry = l1_table[virt.p1_index()];
     if !l1_entry.flags().contains(PageTableFlags::PRESENT) {
         return None;
     }
 
-    Some(l1_entry.addr())
+    Some(l1_entry.addr() + u64::from(virt.get_page_offset()))
+}
+
+/// Invalidates the TLB entry of the page containing the given virtual address.
+#[inline]
+pub fn flush_one(virt: VirtAddr) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt.0, options(nostack, preserves_flags));
+    }
+}
+
+/// Flushes the whole TLB by reloading CR3. Pages mapped with the GLOBAL flag survive the reload.
+#[inline]
+pub fn flush_all_tlb() {
+    unsafe {
+        asm!("mov {tmp}, cr3", "mov cr3, {tmp}", tmp = out(reg) _, options(nostack, preserves_flags));
+    }
+}
+
+const IA32_EFER: u32 = 0xC000_0080;
+const EFER_NXE: u64 = 1 << 11;
+
+// set once EFER.NXE is enabled, `NO_EXECUTE` entries are reserved bits until then
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets EFER.NXE on the calling CPU, which makes `NO_EXECUTE` usable. APs started afterwards copy
+/// the EFER of the BSP. Returns false if the CPU doesn't support NX.
+pub fn enable_nx() -> bool {
+    if !crate::cpu::features().nx {
+        return false;
+    }
+
+    unsafe {
+        let (low, high): (u32, u32);
+        asm!("rdmsr", in("ecx") IA32_EFER, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        let efer = ((high as u64) << 32) | low as u64 | EFER_NXE;
+        asm!("wrmsr", in("ecx") IA32_EFER, in("eax") efer as u32, in("edx") (efer >> 32) as u32, options(nostack, preserves_flags));
+    }
+
+    NX_ENABLED.store(true, Ordering::Release);
+    true
+}
+
+pub fn is_nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Acquire)
+}
+
+fn check_no_execute(flags: PageTableFlags) -> core::result::Result<(), &'static str> {
+    if flags.contains(PageTableFlags::NO_EXECUTE) && !is_nx_enabled() {
+        return Err("NO_EXECUTE requires NX to be enabled");
+    }
+    Ok(())
+}
+
+pub const fn align_down(val: VirtAddr) -> VirtAddr {
+    val.align_down_to(PAGE_SIZE)
+}
+
+pub const fn align_down_u64(val: u64) -> u64 {
+    align_down_to(val, PAGE_SIZE)
+}
+
+/// Rounds the address up to the page boundary. Addresses in the last page of the address space
+/// saturate to the start of that page.
+pub const fn align_up(val: VirtAddr) -> VirtAddr {
+    val.align_up_to(PAGE_SIZE)
+}
+
+pub const fn align_up_u64(val: u64) -> u64 {
+    align_up_to(val, PAGE_SIZE)
+}
+
+/// Rounds `val` down to a multiple of `align`, which must be a power of two.
+pub const fn align_down_to(val: u64, align: u64) -> u64 {
+    debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+    val & !(align - 1)
+}
+
+/// Rounds `val` up to a multiple of `align`, which must be a power of two. Values that would
+/// overflow saturate to the last aligned value.
+pub const fn align_up_to(val: u64, align: u64) -> u64 {
+    debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+    match val.checked_add(align - 1) {
+        Some(val) => val & !(align - 1),
+        None => u64::MAX & !(align - 1)
+    }
+}
+
+#[cfg(test)]
+const TEST_PAGE_TABLES_COUNT: usize = 32;
+
+#[cfg(test)]
+static mut TEST_PAGE_TABLES: [PageTable; TEST_PAGE_TABLES_COUNT] = [PageTable::new(); TEST_PAGE_TABLES_COUNT];
+
+#[cfg(test)]
+struct TestPageTablesAllocator {
+    next: usize,
+    freed: usize,
+    shared: usize
+}
+
+#[cfg(test)]
+impl TestPageTablesAllocator {
+    fn new() -> Self {
+        TestPageTablesAllocator { next: 0, freed: 0, shared: 0 }
+    }
+}
+
+#[cfg(test)]
+impl PageTablesAllocator for TestPageTablesAllocator {
+    fn allocate_page_table(&mut self) -> Result::<&mut PageTable, &'static str> {
+        let tables = unsafe { &mut *core::ptr::addr_of_mut!(TEST_PAGE_TABLES) };
+        let table = tables.get_mut(self.next).ok_or("Out of test page tables")?;
+        self.next += 1;
+        table.clear();
+        Ok(table)
+    }
+
+    fn free_page_table(&mut self, _table: &mut PageTable) -> Result::<(), &'static str> {
+        self.freed += 1;
+        Ok(())
+    }
+
+    fn share_frame(&mut self, _frame: u64) {
+        self.shared += 1;
+    }
+
+    fn release_frame(&mut self, _frame: u64) -> Result::<bool, &'static str> {
+        // unmapping frames that were never shared doesn't count
+        self.shared = self.shared.saturating_sub(1);
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+fn test_l4_table(allocator: &mut TestPageTablesAllocator) -> &'static mut PageTable {
+    let l4_table = allocator.allocate_page_table().unwrap() as *mut PageTable;
+    unsafe { &mut *l4_table }
+}
+
+#[test_case]
+fn unmap_address_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_1000);
+
+    unsafe {
+        map_address(l4_table, virt, PhysAddr(0x5000), &mut allocator).unwrap();
+        assert_eq!(Some(0x5000), get_physical_address(l4_table, virt, 0));
+        assert_eq!(Some(0x5123), get_physical_address(l4_table, virt.offset(0x123).unwrap(), 0));
+
+        assert_eq!(Ok(0x5000), unmap_address(l4_table, virt, &mut allocator));
+        assert_eq!(None, get_physical_address(l4_table, virt, 0));
+
+        assert!(unmap_address(l4_table, virt, &mut allocator).is_err());
+        assert!(unmap_address(l4_table, VirtAddr::new(0x5555_0000_0000), &mut allocator).is_err());
+    }
+}
+
+#[test_case]
+fn map_huge_2mib_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let huge = VirtAddr::new(0x4444_0020_0000);
+
+    unsafe {
+        assert!(map_huge_2mib(l4_table, VirtAddr::new(0x4444_0000_1000), PhysAddr(0x20_0000), &mut allocator).is_err());
+        assert!(map_huge_2mib(l4_table, huge, PhysAddr(0x20_1000), &mut allocator).is_err());
+
+        map_huge_2mib(l4_table, huge, PhysAddr(0x40_0000), &mut allocator).unwrap();
+        assert_eq!(Some(0x41_2345), get_physical_address(l4_table, huge.offset(0x1_2345).unwrap(), 0));
+        assert!(map_address(l4_table, huge.offset(0x1000).unwrap(), PhysAddr(0x5000), &mut allocator).is_err());
+
+        // 4 KiB pages in the neighbouring 2 MiB region share the same L2 table
+        map_address(l4_table, VirtAddr::new(0x4444_0000_1000), PhysAddr(0x5000), &mut allocator).unwrap();
+        assert!(map_huge_2mib(l4_table, VirtAddr::new(0x4444_0000_0000), PhysAddr(0x60_0000), &mut allocator).is_err());
+    }
+}
+
+#[test_case]
+fn remap_address_keeps_flags_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_1000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | PageTableFlags::BIT_9;
+
+    unsafe {
+        map_address(l4_table, virt, PhysAddr(0x5000), &mut allocator).unwrap();
+        remap_address_with_flags(l4_table, virt, PhysAddr(0x6000), &mut allocator, flags).unwrap();
+        assert_eq!(Some(0x6000), get_physical_address(l4_table, virt, 0));
+
+        remap_address(l4_table, virt, PhysAddr(0x7000), &mut allocator).unwrap();
+        assert_eq!(Some(0x7000), get_physical_address(l4_table, virt, 0));
+
+        let l3_table = &*(l4_table[virt.p4_index()].addr() as *const PageTable);
+        let l2_table = &*(l3_table[virt.p3_index()].addr() as *const PageTable);
+        let l1_table = &*(l2_table[virt.p2_index()].addr() as *const PageTable);
+        assert_eq!(flags, l1_table[virt.p1_index()].flags());
+    }
+}
+
+#[test_case]
+fn no_execute_requires_nx_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_1000);
+    let nx_enabled = is_nx_enabled();
+
+    NX_ENABLED.store(false, Ordering::Release);
+    unsafe {
+        assert!(map_address_with_flags(l4_table, virt, PhysAddr(0x5000), &mut allocator, PageTableFlags::NO_EXECUTE).is_err());
+        map_address_with_flags(l4_table, virt, PhysAddr(0x5000), &mut allocator, PageTableFlags::WRITABLE).unwrap();
+        assert!(protect(l4_table, virt, PageTableFlags::NO_EXECUTE).is_err());
+    }
+    NX_ENABLED.store(nx_enabled, Ordering::Release);
+}
+
+#[test_case]
+fn map_address_with_flags_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_1000);
+
+    unsafe {
+        map_address_with_flags(l4_table, virt, PhysAddr(0x5000), &mut allocator, PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE).unwrap();
+
+        let l4_entry = l4_table[virt.p4_index()];
+        assert!(l4_entry.flags().contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+
+        let l3_table = &*(l4_entry.addr() as *const PageTable);
+        let l2_table = &*(l3_table[virt.p3_index()].addr() as *const PageTable);
+        let l1_table = &*(l2_table[virt.p2_index()].addr() as *const PageTable);
+        assert_eq!(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE, l1_table[virt.p1_index()].flags());
+    }
+}
+
+#[test_case]
+fn map_non_canonical_address_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+
+    unsafe {
+        assert!(map_address(l4_table, VirtAddr(0x0000_8000_0000_1000), PhysAddr(0x5000), &mut allocator).is_err());
+        assert!(map_huge_2mib(l4_table, VirtAddr(0x1000_0000_0020_0000), PhysAddr(0x20_0000), &mut allocator).is_err());
+        assert!(l4_table.is_empty());
+    }
+}
+
+#[test_case]
+fn map_range_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_0000);
+
+    unsafe {
+        assert_eq!(Ok(3), map_range(l4_table, virt, PhysAddr(0x10_0000), 0x2001, &mut allocator, DEFAULT_FLAGS));
+        assert_eq!(Some(0x10_2000), get_physical_address(l4_table, virt.offset(0x2000).unwrap(), 0));
+        assert_eq!(None, get_physical_address(l4_table, virt.offset(0x3000).unwrap(), 0));
+
+        // the last page collides with an existing mapping, nothing should stay mapped
+        let second = VirtAddr::new(0x4444_0001_0000);
+        map_address(l4_table, second.offset(0x2000).unwrap(), PhysAddr(0x5000), &mut allocator).unwrap();
+        assert!(map_range(l4_table, second, PhysAddr(0x20_0000), 0x3000, &mut allocator, DEFAULT_FLAGS).is_err());
+        assert_eq!(None, get_physical_address(l4_table, second, 0));
+        assert_eq!(None, get_physical_address(l4_table, second.offset(0x1000).unwrap(), 0));
+        assert_eq!(Some(0x5000), get_physical_address(l4_table, second.offset(0x2000).unwrap(), 0));
+
+        // the L1 table created for the first page of the range is freed on rollback
+        let third = VirtAddr::new(0x4444_0040_0000);
+        map_address(l4_table, third, PhysAddr(0x6000), &mut allocator).unwrap();
+        let freed = allocator.freed;
+        let before = VirtAddr::new(third.0 - PAGE_SIZE);
+        assert!(map_range(l4_table, before, PhysAddr(0x30_0000), 0x2000, &mut allocator, DEFAULT_FLAGS).is_err());
+        assert_eq!(freed + 1, allocator.freed);
+        assert_eq!(None, get_page_flags(l4_table, before, 0));
+        assert_eq!(Some(0x6000), get_physical_address(l4_table, third, 0));
+    }
+}
+
+#[test_case]
+fn iter_mappings_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+
+    unsafe {
+        map_address(l4_table, VirtAddr::new(0x4444_0000_1000), PhysAddr(0x5000), &mut allocator).unwrap();
+        map_huge_2mib(l4_table, VirtAddr::new(0x4444_0020_0000), PhysAddr(0x40_0000), &mut allocator).unwrap();
+        map_address(l4_table, VirtAddr::new(0xffff_8000_0000_0000), PhysAddr(0x6000), &mut allocator).unwrap();
+
+        let mut mappings = l4_table.iter_mappings(0);
+
+        let first = mappings.next().unwrap();
+        assert_eq!((VirtAddr::new(0x4444_0000_1000), 0x5000, PAGE_SIZE), (first.virt, first.phys, first.size));
+
+        let second = mappings.next().unwrap();
+        assert_eq!((VirtAddr::new(0x4444_0020_0000), 0x40_0000, HUGE_PAGE_SIZE), (second.virt, second.phys, second.size));
+        assert!(second.flags.contains(PageTableFlags::HUGE_PAGE));
+
+        let third = mappings.next().unwrap();
+        assert_eq!((VirtAddr::new(0xffff_8000_0000_0000), 0x6000), (third.virt, third.phys));
+
+        assert!(mappings.next().is_none());
+    }
+}
+
+#[test_case]
+fn protect_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_1000);
+
+    unsafe {
+        assert!(protect(l4_table, virt, PageTableFlags::NO_EXECUTE).is_err());
+
+        map_address(l4_table, virt, PhysAddr(0x5000), &mut allocator).unwrap();
+        protect(l4_table, virt, PageTableFlags::NO_EXECUTE).unwrap();
+
+        let mapping = l4_table.iter_mappings(0).next().unwrap();
+        assert_eq!(0x5000, mapping.phys);
+        assert_eq!(PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE, mapping.flags);
+    }
+}
+
+#[test_case]
+fn accessed_dirty_test() {
+    let mut entry = PageTableEntry::new();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    entry.set_addr(PhysAddr(0x5000), flags | PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+    assert!(entry.is_accessed() && entry.is_dirty());
+
+    entry.clear_dirty();
+    assert!(entry.is_accessed() && !entry.is_dirty());
+    entry.clear_accessed();
+    assert!(!entry.is_accessed());
+    assert_eq!((0x5000, flags), (entry.addr(), entry.flags()));
+
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let virt = VirtAddr::new(0x4444_0000_1000);
+
+    unsafe {
+        assert!(query_and_clear_accessed(l4_table, virt).is_err());
+
+        map_address_with_flags(l4_table, virt, PhysAddr(0x5000), &mut allocator, flags | PageTableFlags::ACCESSED | PageTableFlags::DIRTY).unwrap();
+        assert!(query_and_clear_accessed(l4_table, virt).unwrap());
+        assert!(!query_and_clear_accessed(l4_table, virt).unwrap());
+        assert_eq!(Some(flags | PageTableFlags::DIRTY), get_page_flags(l4_table, virt, 0));
+    }
+}
+
+#[test_case]
+fn clone_hierarchy_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let user = VirtAddr::new(0x4444_0000_1000);
+    let kernel = VirtAddr::new(0xffff_8000_0000_0000);
+
+    unsafe {
+        let frame = allocator.allocate_page_table().unwrap() as *mut PageTable;
+        (*frame)[0].set_addr(PhysAddr(0x1234_5000), PageTableFlags::empty());
+
+        map_address(l4_table, user, PhysAddr(frame as u64), &mut allocator).unwrap();
+        map_address(l4_table, kernel, PhysAddr(0x6000), &mut allocator).unwrap();
+
+        let shared = l4_table.clone_hierarchy(&mut allocator, CloneMode::ShareFrames, 0).unwrap() as *mut PageTable;
+        assert_eq!(Some(frame as u64), get_physical_address(&*shared, user, 0));
+        assert_ne!(l4_table[user.p4_index()].addr(), (*shared)[user.p4_index()].addr());
+        assert_eq!(l4_table[kernel.p4_index()].addr(), (*shared)[kernel.p4_index()].addr());
+
+        let copied = l4_table.clone_hierarchy(&mut allocator, CloneMode::CopyFrames, 0).unwrap();
+        let copied_frame = get_physical_address(copied, user, 0).unwrap();
+        assert_ne!(frame as u64, copied_frame);
+        assert_eq!(0x1234_5000, (*(copied_frame as *const PageTable))[0].addr());
+        assert_eq!(Some(0x6000), get_physical_address(copied, kernel, 0));
+    }
 }
 
-pub fn align_down(val: VirtAddr) -> VirtAddr {
-    return val & VirtAddr::new(0xffff_ffff_ffff_f000);
+#[test_case]
+fn clone_hierarchy_rollback_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+
+    unsafe {
+        // the pages are in different L1 tables, cloning needs L4, L3, L2 and two L1 tables
+        map_address(l4_table, VirtAddr::new(0x4444_0000_1000), PhysAddr(0x5000), &mut allocator).unwrap();
+        map_address(l4_table, VirtAddr::new(0x4444_0020_1000), PhysAddr(0x6000), &mut allocator).unwrap();
+
+        // only four tables are left, the second L1 table fails to allocate
+        allocator.next = TEST_PAGE_TABLES_COUNT - 4;
+        assert!(l4_table.clone_hierarchy(&mut allocator, CloneMode::ShareFrames, 0).is_err());
+        assert_eq!(4, allocator.freed);
+        assert_eq!(0, allocator.shared);
+    }
 }
 
-pub fn align_down_u64(val: u64) -> u64 {
-    return val & 0xffff_ffff_ffff_f000;
-}
\ No newline at end of file
+#[test_case]
+fn free_empty_tables_test() {
+    let mut allocator = TestPageTablesAllocator::new();
+    let l4_table = test_l4_table(&mut allocator);
+    let first = VirtAddr::new(0x4444_0000_1000);
+    let second = VirtAddr::new(0x4444_0020_0000);
+
+    unsafe {
+        map_address(l4_table, first, PhysAddr(0x5000), &mut allocator).unwrap();
+        map_address(l4_table, second, PhysAddr(0x6000), &mut allocator).unwrap();
+
+        // the L2 table still references the L1 table of the second page
+        unmap_address(l4_table, first, &mut allocator).unwrap();
+        free_empty_tables(l4_table, first, &mut allocator, 0).unwrap();
+        assert_eq!(1, allocator.freed);
+        assert_eq!(Some(0x6000), get_physical_address(l4_table, second, 0));
+
+        unmap_address(l4_table, second, &mut allocator).unwrap();
+        free_empty_tables(l4_table, second, &mut allocator, 0).unwrap();
+        assert_eq!(4, allocator.freed);
+        assert!(l4_table.is_empty());
+    }
+}
+
+#[test_case]
+fn align_up_test() {
+    assert_eq!(0, align_up_u64(0));
+    assert_eq!(0x1000, align_up_u64(1));
+    assert_eq!(0x1000, align_up_u64(0x1000));
+    assert_eq!(0x2000, align_up_u64(0x1fff));
+    assert_eq!(0xffff_ffff_ffff_f000, align_up_u64(u64::MAX));
+    assert_eq!(VirtAddr::new(0x4444_0000_2000), align_up(VirtAddr::new(0x4444_0000_1001)));
+}
+
+#[test_case]
+fn align_to_test() {
+    assert_eq!(0x20_0000, align_down_to(0x3f_ffff, HUGE_PAGE_SIZE));
+    assert_eq!(0x40_0000, align_up_to(0x20_0001, HUGE_PAGE_SIZE));
+    assert_eq!(0x4000_0000, align_up_to(1, GIANT_PAGE_SIZE));
+    assert_eq!(0x48, align_up_to(0x41, 8));
+    assert_eq!(0x41, align_down_to(0x41, 1));
+    assert_eq!(!(HUGE_PAGE_SIZE - 1), align_up_to(u64::MAX - 5, HUGE_PAGE_SIZE));
+
+    let virt = VirtAddr::new(0xffff_8000_0030_1000);
+    assert_eq!(VirtAddr::new(0xffff_8000_0020_0000), virt.align_down_to(HUGE_PAGE_SIZE));
+    assert_eq!(VirtAddr::new(0xffff_8000_0040_0000), virt.align_up_to(HUGE_PAGE_SIZE));
+    assert!(virt.align_up_to(HUGE_PAGE_SIZE).is_aligned(HUGE_PAGE_SIZE));
+}
+
+#[test_case]
+fn page_table_get_test() {
+    let mut table = PageTable::new();
+    table.get_mut(ENTRY_COUNT - 1).unwrap().set_addr(PhysAddr::new(0x1000), PageTableFlags::PRESENT);
+
+    assert_eq!(0x1000, table.get(ENTRY_COUNT - 1).unwrap().addr());
+    assert!(table.get(ENTRY_COUNT).is_none());
+    assert!(table.get_mut(u16::MAX).is_none());
+}
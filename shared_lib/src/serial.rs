@@ -1,5 +1,6 @@
 use core::arch::asm;
 use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 use bitflags::bitflags;
@@ -11,7 +12,9 @@ bitflags! {
         const INPUT_FULL = 1;
         // 1 to 4 unknown
         const OUTPUT_EMPTY = 1 << 5;
-        // 6 and 7 unknown
+        // both the FIFO and the shift register are empty
+        const TRANSMITTER_EMPTY = 1 << 6;
+        // 7 unknown
     }
 }
 
@@ -24,6 +27,22 @@ pub unsafe fn inb(port: u16) -> u8 {
     ret
 }
 
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const COM3: u16 = 0x3E8;
+pub const COM4: u16 = 0x2E8;
+
+/// Baud rate with a divisor of 1
+pub const MAX_BAUD_RATE: u32 = 115200;
+const DEFAULT_BAUD_RATE: u32 = 38400;
+
+// A port read takes about a microsecond, so this gives up after roughly 100 ms. Draining the
+// 16 byte FIFO at the default baud rate takes less than 5 ms.
+const FLUSH_TIMEOUT_READS: u32 = 100_000;
+
+// base of `DEFAULT_SERIAL`, readable without taking its lock
+static DEFAULT_PORT: AtomicU16 = AtomicU16::new(COM1);
+
 macro_rules! wait_for {
     ($cond:expr) => {
         while !$cond {
@@ -46,16 +65,13 @@ impl SerialPort {
             // Disable interrupts
             outb(port + 1, 0x00);
 
-            // Enable DLAB
-            outb(port + 3, 0x80);
-
-            // Set maximum speed to 38400 bps by configuring DLL and DLM
-            outb(port, 0x03);
-            outb(port + 1, 0x00);
-
-            // Disable DLAB and set data word length to 8 bits
+            // Set data word length to 8 bits
             outb(port + 3, 0x03);
+        }
+
+        self.set_baud_rate(DEFAULT_BAUD_RATE).expect("Default baud rate is invalid");
 
+        unsafe {
             // Enable FIFO, clear TX/RX queues and
             // set interrupt watermark at 14 bytes
             outb(port + 2, 0xc7);
@@ -69,10 +85,66 @@ impl SerialPort {
         }
     }
 
+    /// Sets the divisor latch to `MAX_BAUD_RATE / rate`. Rates that don't divide it evenly are rejected.
+    pub fn set_baud_rate(&mut self, rate: u32) -> Result<(), &'static str> {
+        if rate == 0 || rate > MAX_BAUD_RATE || MAX_BAUD_RATE % rate != 0 {
+            return Err("Baud rate must evenly divide 115200");
+        }
+
+        let divisor = (MAX_BAUD_RATE / rate) as u16;
+        let port = self.0;
+        unsafe {
+            // Enable DLAB, keeping the line settings
+            let line_control = inb(port + 3);
+            outb(port + 3, line_control | 0x80);
+
+            outb(port, divisor as u8);
+            outb(port + 1, (divisor >> 8) as u8);
+
+            outb(port + 3, line_control & !0x80);
+        }
+        Ok(())
+    }
+
+    /// Reads back the divisor latch.
+    pub fn baud_divisor(&mut self) -> u16 {
+        let port = self.0;
+        unsafe {
+            let line_control = inb(port + 3);
+            outb(port + 3, line_control | 0x80);
+
+            let divisor = inb(port) as u16 | ((inb(port + 1) as u16) << 8);
+
+            outb(port + 3, line_control & !0x80);
+            divisor
+        }
+    }
+
     fn line_sts(&mut self) -> LineStsFlags {
         unsafe { LineStsFlags::from_bits_truncate(inb(self.0 + 5)) }
     }
 
+    /// Returns a received byte if there is one, without blocking.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+            Some(unsafe { inb(self.0) })
+        } else {
+            None
+        }
+    }
+
+    /// Waits until every byte written so far has left the UART. Gives up when it doesn't drain,
+    /// e.g. because nothing receives on the other end.
+    pub fn flush(&mut self) -> Result<(), &'static str> {
+        for _ in 0..FLUSH_TIMEOUT_READS {
+            if self.line_sts().contains(LineStsFlags::TRANSMITTER_EMPTY) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err("Serial port didn't drain")
+    }
+
     pub fn send(&mut self, data: u8) {
         let port = self.0;
         unsafe {
@@ -104,19 +176,37 @@ impl fmt::Write for SerialPort {
 }
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    /// Port used by `serial_print!` and `serial_println!`, COM1 unless changed with [`set_default_port`].
+    pub static ref DEFAULT_SERIAL: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Initializes the port at `base` and makes it the target of `serial_print!` and `serial_println!`.
+pub fn set_default_port(base: u16) {
+    let mut serial_port = unsafe { SerialPort::new(base) };
+    serial_port.init();
+
+    without_interrupts(|| {
+        *DEFAULT_SERIAL.lock() = serial_port;
+        DEFAULT_PORT.store(base, Ordering::Relaxed);
+    });
+}
+
+/// Flushes the port of `serial_print!`. It only reads the line status, so unlike printing it
+/// doesn't take the port lock and works from a panic that interrupted a print.
+pub fn flush() -> Result<(), &'static str> {
+    unsafe { SerialPort::new(DEFAULT_PORT.load(Ordering::Relaxed)) }.flush()
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
 
     without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+        DEFAULT_SERIAL.lock().write_fmt(args).expect("Printing to serial failed");
     });
 }
 
@@ -135,4 +225,27 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn set_baud_rate_test() {
+    without_interrupts(|| {
+        let mut port = DEFAULT_SERIAL.lock();
+
+        port.set_baud_rate(9600).unwrap();
+        assert_eq!(12, port.baud_divisor());
+
+        assert!(port.set_baud_rate(50000).is_err());
+        assert!(port.set_baud_rate(0).is_err());
+        assert_eq!(12, port.baud_divisor());
+
+        port.set_baud_rate(DEFAULT_BAUD_RATE).unwrap();
+        assert_eq!(3, port.baud_divisor());
+    });
+}
+
+#[test_case]
+fn flush_test() {
+    crate::serial_print!("flushing...");
+    assert_eq!(Ok(()), flush());
+}
@@ -1,10 +1,10 @@
 use core::fmt;
 use core::fmt::{Arguments, Write};
 use conquer_once::spin::OnceCell;
-use spinning_top::{RawSpinlock, Spinlock};
-use spinning_top::lock_api::MutexGuard;
+use crate::debug_mutex::{DebugMutex, DebugMutexGuard};
 use crate::interrupts;
-use crate::serial::SerialPort;
+use crate::logger::{level_color, level_enabled, COLOR_RESET};
+use crate::serial::{SerialPort, COM1};
 
 pub struct SerialLogger {
     port: SerialPort
@@ -12,7 +12,11 @@ pub struct SerialLogger {
 
 impl SerialLogger {
     pub fn new() -> Self {
-        let mut port = unsafe{ SerialPort::new(0x3F8) };
+        SerialLogger::with_port(COM1)
+    }
+
+    pub fn with_port(base: u16) -> Self {
+        let mut port = unsafe{ SerialPort::new(base) };
         port.init();
         SerialLogger{ port }
     }
@@ -33,16 +37,20 @@ impl fmt::Write for SerialLogger {
 
 pub static SERIAL_LOGGER: OnceCell<LockedSerialLogger> = OnceCell::uninit();
 
-/// A [`SerialLogger`] instance protected by a spinlock.
-pub struct LockedSerialLogger(Spinlock<SerialLogger>);
+/// A [`SerialLogger`] instance protected by a [`DebugMutex`].
+pub struct LockedSerialLogger(DebugMutex<SerialLogger>);
 
 impl LockedSerialLogger {
     /// Create a new instance that logs to the given framebuffer.
     pub fn new() -> Self {
-        LockedSerialLogger(Spinlock::new(SerialLogger::new()))
+        LockedSerialLogger(DebugMutex::new(SerialLogger::new()))
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, RawSpinlock, SerialLogger> {
+    pub fn with_port(base: u16) -> Self {
+        LockedSerialLogger(DebugMutex::new(SerialLogger::with_port(base)))
+    }
+
+    pub fn lock(&self) -> DebugMutexGuard<'_, SerialLogger> {
         self.0.lock()
     }
 
@@ -61,16 +69,24 @@ impl LockedSerialLogger {
 }
 
 impl log::Log for LockedSerialLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        level_enabled(metadata.level())
     }
 
     fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
         interrupts::without_interrupts(|| {
             let mut logger = self.0.lock();
-            writeln!(logger, "{}:    {}", record.level(), record.args()).unwrap();
+            writeln!(logger, "{}{}{}:    {}", level_color(record.level()), record.level(), COLOR_RESET, record.args()).unwrap();
         });
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        interrupts::without_interrupts(|| {
+            let _ = self.0.lock().port.flush();
+        });
+    }
 }
\ No newline at end of file
@@ -0,0 +1,120 @@
+use crate::crc::Crc32;
+
+/// "FERR" in little-endian, the first bytes of every superblock
+pub const SUPERBLOCK_MAGIC: u32 = 0x5252_4546;
+
+/// Layout version written by [`Superblock::serialize`].
+pub const SUPERBLOCK_VERSION: u32 = 1;
+
+/// Size of a serialized superblock: magic, version, block size, root inode and the CRC, all
+/// little-endian.
+pub const SUPERBLOCK_SIZE: usize = 24;
+
+const CRC_OFFSET: usize = SUPERBLOCK_SIZE - 4;
+
+/// First block of a filesystem, describing the rest of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Superblock {
+    pub magic: u32,
+    pub version: u32,
+    pub block_size: u32,
+    pub root_inode: u64,
+    /// CRC-32 of all the fields before it as they are serialized
+    pub crc: u32
+}
+
+impl Superblock {
+    pub fn new(block_size: u32, root_inode: u64) -> Self {
+        let mut superblock = Superblock {
+            magic: SUPERBLOCK_MAGIC,
+            version: SUPERBLOCK_VERSION,
+            block_size,
+            root_inode,
+            crc: 0
+        };
+        superblock.crc = superblock.checksum();
+        superblock
+    }
+
+    /// Computes the CRC over the serialized fields, excluding the CRC itself.
+    pub fn checksum(&self) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(&self.magic.to_le_bytes());
+        crc.update(&self.version.to_le_bytes());
+        crc.update(&self.block_size.to_le_bytes());
+        crc.update(&self.root_inode.to_le_bytes());
+        crc.finalize()
+    }
+
+    /// Serializes the superblock with a freshly computed CRC, so fields changed after `new` are
+    /// covered too.
+    pub fn serialize(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut bytes = [0u8; SUPERBLOCK_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.block_size.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.root_inode.to_le_bytes());
+        bytes[CRC_OFFSET..].copy_from_slice(&self.checksum().to_le_bytes());
+        bytes
+    }
+
+    /// Parses a superblock from the start of `bytes`, checking the magic, the CRC and the version.
+    pub fn deserialize(bytes: &[u8]) -> Result<Superblock, &'static str> {
+        let bytes = bytes.get(..SUPERBLOCK_SIZE).ok_or("Superblock is too short")?;
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let superblock = Superblock {
+            magic: u32_at(0),
+            version: u32_at(4),
+            block_size: u32_at(8),
+            root_inode: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+            crc: u32_at(CRC_OFFSET)
+        };
+
+        if superblock.magic != SUPERBLOCK_MAGIC {
+            return Err("Bad superblock magic");
+        }
+        if superblock.crc != superblock.checksum() {
+            return Err("Superblock CRC mismatch");
+        }
+        if superblock.version != SUPERBLOCK_VERSION {
+            return Err("Unsupported superblock version");
+        }
+
+        Ok(superblock)
+    }
+}
+
+#[test_case]
+fn superblock_roundtrip_test() {
+    let superblock = Superblock::new(4096, 2);
+    let bytes = superblock.serialize();
+    assert_eq!(b"FERR", &bytes[0..4]);
+    assert_eq!(crate::crc::crc32(&bytes[..CRC_OFFSET]), superblock.crc);
+
+    let mut block = [0u8; 512];
+    block[..SUPERBLOCK_SIZE].copy_from_slice(&bytes);
+    assert_eq!(Ok(superblock), Superblock::deserialize(&block));
+}
+
+#[test_case]
+fn superblock_corruption_test() {
+    let bytes = Superblock::new(4096, 2).serialize();
+    assert_eq!(Err("Superblock is too short"), Superblock::deserialize(&bytes[..SUPERBLOCK_SIZE - 1]));
+
+    let mut bad_magic = bytes;
+    bad_magic[0] ^= 1;
+    assert_eq!(Err("Bad superblock magic"), Superblock::deserialize(&bad_magic));
+
+    let mut bad_root = bytes;
+    bad_root[12] ^= 1;
+    assert_eq!(Err("Superblock CRC mismatch"), Superblock::deserialize(&bad_root));
+
+    let mut bad_crc = bytes;
+    bad_crc[CRC_OFFSET] ^= 1;
+    assert_eq!(Err("Superblock CRC mismatch"), Superblock::deserialize(&bad_crc));
+
+    let mut newer = Superblock::new(4096, 2);
+    newer.version = SUPERBLOCK_VERSION + 1;
+    assert_eq!(Err("Unsupported superblock version"), Superblock::deserialize(&newer.serialize()));
+}
@@ -0,0 +1,144 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::slice::from_raw_parts;
+use conquer_once::spin::OnceCell;
+use shared_lib::VIRT_MAPPING_OFFSET;
+
+#[repr(C, packed)]
+struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oemid: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32, // deprecated
+
+    // ACPI 2.0+ only
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    pub reserved: [u8; 3],
+}
+
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+#[repr(C)]
+pub struct AcpiSdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oemid: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32
+}
+
+pub const SDT_HEADER_SIZE: usize = size_of::<AcpiSdtHeader>();
+
+impl AcpiSdtHeader {
+    /// Returns the table data following the header.
+    pub fn data(&self) -> &[u8] {
+        let len = (self.length as usize).saturating_sub(SDT_HEADER_SIZE);
+        unsafe { from_raw_parts((self as *const Self as *const u8).add(SDT_HEADER_SIZE), len) }
+    }
+
+    fn is_valid(&self) -> bool {
+        if (self.length as usize) < SDT_HEADER_SIZE {
+            return false;
+        }
+
+        let bytes = unsafe { from_raw_parts(self as *const Self as *const u8, self.length as usize) };
+        wrapping_sum(bytes) == 0
+    }
+}
+
+/// Root table listing all other tables: XSDT on ACPI 2.0+, RSDT on ACPI 1.0.
+#[derive(Clone, Copy)]
+struct RootTable {
+    header: &'static AcpiSdtHeader,
+    entry_size: usize
+}
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+
+fn wrapping_sum(arr: &[u8]) -> u8 {
+    arr.iter().fold(0u8, |a, b| a.wrapping_add(*b))
+}
+
+fn phys_to_virt<T>(addr: u64) -> *const T {
+    (addr + VIRT_MAPPING_OFFSET) as *const T
+}
+
+/// Validates the RSDP and the root table it points to. Must be called before `find_table`.
+pub fn init(rsdp_addr: u64) -> Result<(), &'static str> {
+    log::info!("[acpi] RSDP: {:#x}", rsdp_addr);
+
+    let rsdp_ptr = phys_to_virt::<Rsdp>(rsdp_addr);
+    let rsdp = unsafe { read_unaligned(rsdp_ptr) };
+
+    if &rsdp.signature != RSDP_SIGNATURE {
+        return Err("Invalid RSDP signature");
+    }
+
+    let v1_bytes = unsafe { from_raw_parts(rsdp_ptr as *const u8, RSDP_V1_LENGTH) };
+    if wrapping_sum(v1_bytes) != 0 {
+        return Err("RSDP checksum failed");
+    }
+
+    log::info!("[acpi] revision: {}", rsdp.revision);
+
+    let root_table = if rsdp.revision >= 2 {
+        let length = rsdp.length as usize;
+        if length < size_of::<Rsdp>() {
+            return Err("Invalid RSDP length");
+        }
+
+        let v2_bytes = unsafe { from_raw_parts(rsdp_ptr as *const u8, length) };
+        if wrapping_sum(v2_bytes) != 0 {
+            return Err("RSDP extended checksum failed");
+        }
+
+        RootTable { header: unsafe { &*phys_to_virt(rsdp.xsdt_address) }, entry_size: size_of::<u64>() }
+    } else {
+        RootTable { header: unsafe { &*phys_to_virt(rsdp.rsdt_address as u64) }, entry_size: size_of::<u32>() }
+    };
+
+    if !root_table.header.is_valid() {
+        return Err("Root SDT checksum failed");
+    }
+
+    ROOT_TABLE.try_init_once(|| root_table)
+        .map_err(|_| "ACPI is already initialized")?;
+
+    for table in tables() {
+        log::info!("[acpi] found {}", core::str::from_utf8(&table.signature).unwrap_or("????"));
+    }
+
+    Ok(())
+}
+
+/// Iterates over all tables listed in the root table. Entries are not validated.
+fn tables() -> impl Iterator<Item = &'static AcpiSdtHeader> {
+    let root_table = ROOT_TABLE.get().copied();
+
+    root_table.into_iter().flat_map(|root_table| {
+        // entries are not necessarily aligned
+        root_table.header.data().chunks_exact(root_table.entry_size).map(move |entry| {
+            let phys_addr = if root_table.entry_size == size_of::<u64>() {
+                unsafe { read_unaligned(entry.as_ptr() as *const u64) }
+            } else {
+                unsafe { read_unaligned(entry.as_ptr() as *const u32) as u64 }
+            };
+
+            unsafe { &*phys_to_virt::<AcpiSdtHeader>(phys_addr) }
+        })
+    })
+}
+
+/// Returns the first table with the given signature (`b"APIC"`, `b"HPET"`, `b"FACP"`, ...) whose
+/// checksum is correct.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static AcpiSdtHeader> {
+    tables().find(|table| &table.signature == signature && table.is_valid())
+}
@@ -1,32 +1,118 @@
-use shared_lib::addr::VirtAddr;
+use shared_lib::addr::{PhysAddr, VirtAddr};
 use shared_lib::allocator::ALLOCATOR;
-use shared_lib::page_table::{map_address_with_offset, PageTable};
+use shared_lib::page_table::{align_up_u64, map_address_with_offset, map_range_with_offset, unmap_address_with_offset, PageTable, PageTableFlags};
 use shared_lib::VIRT_MAPPING_OFFSET;
 use shared_lib::frame_allocator::FrameAllocator;
+use crate::memory::{active_level_4_table, FRAME_ALLOCATOR};
 
 pub const HEAP_START: usize = 0x_7777_7777_0000;
 pub const HEAP_SIZE: usize = 300 * 1024; // 300 KiB
 
-pub fn init_heap(page_table: &mut PageTable, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
-    let mut heap = VirtAddr::new(HEAP_START as u64);
-    let heap_end = heap.offset(HEAP_SIZE as u64)
-        .expect("Failed to offset virtual address");
+/// Minimal number of pages the heap grows by when an allocation doesn't fit.
+pub const HEAP_GROW_PAGES: usize = 16;
 
-    while heap < heap_end {
-        let frame = frame_allocator.allocate_frame()
-            .expect("Failed to allocate frame");
+/// Maps `size` bytes at `start` to fresh frames and hands them to the global allocator, which
+/// grows the heap above them later. `size` is rounded up to whole pages. The heap can only be
+/// initialized once.
+pub fn init_heap(start: VirtAddr, size: usize, page_table: &mut PageTable, frame_allocator: &mut FrameAllocator)
+    -> Result<(), &'static str> {
+    if !start.is_aligned(4096) {
+        return Err("Heap start is not page aligned");
+    }
+    if size == 0 {
+        return Err("Heap is empty");
+    }
+    let size = align_up_u64(size as u64);
+    let heap_end = start.offset(size)?;
+
+    let mut allocator = ALLOCATOR.lock();
+    if allocator.size() != 0 {
+        return Err("Heap is already initialized");
+    }
+
+    let mut page = start;
+    while page < heap_end {
+        let mapped = frame_allocator.allocate_frame()
+            .ok_or("Out of memory - failed to allocate heap frame")
+            .and_then(|frame| unsafe {
+                map_address_with_offset(page_table, page, PhysAddr::new(frame), frame_allocator, VIRT_MAPPING_OFFSET)
+                    .inspect_err(|_| { let _ = frame_allocator.deallocate_frame(frame); })
+            });
 
-        unsafe {
-            map_address_with_offset(page_table, heap, frame, frame_allocator, VIRT_MAPPING_OFFSET)
-                .expect("Failed to map new frame");
+        if let Err(err) = mapped {
+            // give back what was mapped so far
+            let mut mapped_page = start;
+            while mapped_page < page {
+                unsafe { unmap_address_with_offset(page_table, mapped_page, frame_allocator, VIRT_MAPPING_OFFSET)?; }
+                mapped_page = mapped_page.offset(4096).unwrap();
+            }
+            return Err(err);
         }
 
-        heap = heap.offset(4096).unwrap();
+        page = page.offset(4096).unwrap();
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        allocator.init(start.0 as usize, size as usize);
+        allocator.set_grow_handler(grow_heap);
+    }
+    drop(allocator);
+
+    log::debug!("[heap] {} KiB at {}", size / 1024, start);
+    Ok(())
+}
+
+/// `init_heap` with the default `HEAP_START` and `HEAP_SIZE`.
+pub fn init_default_heap(page_table: &mut PageTable, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    init_heap(VirtAddr::new(HEAP_START as u64), HEAP_SIZE, page_table, frame_allocator)
+}
+
+/// Maps `pages` contiguous frames at `top`. Fails if any page there is already mapped.
+fn map_heap_pages(top: usize, pages: usize, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    let frames = frame_allocator.allocate_contiguous(pages, 4096)?;
+    let size = (pages * 4096) as u64;
+
+    let result = unsafe {
+        map_range_with_offset(active_level_4_table(), VirtAddr::new(top as u64), PhysAddr::new(frames), size, frame_allocator,
+                              PageTableFlags::PRESENT | PageTableFlags::WRITABLE, VIRT_MAPPING_OFFSET)
+    };
+
+    if let Err(err) = result {
+        for i in 0..pages as u64 {
+            frame_allocator.deallocate_frame(frames + i * 4096)?;
+        }
+        return Err(err);
     }
 
+    log::debug!("[heap] mapped {} pages at {:#x}", pages, top);
     Ok(())
+}
+
+/// Maps `additional_pages` more pages right above the heap and adds them to it.
+pub fn extend_heap(additional_pages: usize) -> Result<(), &'static str> {
+    let mut heap = ALLOCATOR.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut()
+        .ok_or("Frame allocator is not initialized")?;
+
+    map_heap_pages(heap.top(), additional_pages, frame_allocator)?;
+
+    unsafe {
+        heap.extend(additional_pages * 4096);
+    }
+    Ok(())
+}
+
+// called by the heap allocator with its lock held when an allocation doesn't fit
+fn grow_heap(top: usize, min_size: usize) -> Result<usize, &'static str> {
+    let pages = ((align_up_u64(min_size as u64) / 4096) as usize).max(HEAP_GROW_PAGES);
+
+    // the allocation might have happened while the frame allocator was locked
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock()
+        .ok_or("Frame allocator is locked")?;
+    let frame_allocator = frame_allocator.as_mut()
+        .ok_or("Frame allocator is not initialized")?;
+
+    map_heap_pages(top, pages, frame_allocator)?;
+    Ok(pages * 4096)
 }
\ No newline at end of file
@@ -1,45 +1,60 @@
 #![allow(dead_code)]
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use shared_lib::addr::VirtAddr;
+use shared_lib::cpu;
 use crate::port::Port;
-use crate::interrupts;
-use shared_lib::{get_tsc, read_u32_ptr, write_u32_ptr};
-use shared_lib::bits::{set_bit, set_bits};
+use crate::{interrupts, ioapic};
+use shared_lib::get_tsc;
+use shared_lib::mmio::MmioRegion;
 use crate::interrupts::InterruptIndex;
 use crate::xsdt::ApicAddresses;
 use crate::task::timer;
-use crate::chrono::read_rtc;
-
-pub const APIC_APICID: u32     = 0x20;
-pub const APIC_APICVER: u32    = 0x30;
-pub const APIC_TASKPRIOR: u32  = 0x80;
-pub const APIC_EOI: u32        = 0x0B0;
-pub const APIC_LDR: u32        = 0x0D0;
-pub const APIC_DFR: u32        = 0x0E0;
-pub const APIC_SPURIOUS: u32   = 0x0F0;
-pub const APIC_ESR: u32        = 0x280;
-pub const APIC_ICRL: u32       = 0x300;
-pub const APIC_ICRH: u32       = 0x310;
-pub const APIC_LVT_TMR: u32    = 0x320;
-pub const APIC_LVT_PERF: u32   = 0x340;
-pub const APIC_LVT_LINT0: u32  = 0x350;
-pub const APIC_LVT_LINT1: u32  = 0x360;
-pub const APIC_LVT_ERR: u32    = 0x370;
-pub const APIC_TMRINITCNT: u32 = 0x380;
-pub const APIC_TMRCURRCNT: u32 = 0x390;
-pub const APIC_TMRDIV: u32     = 0x3E0;
-pub const APIC_LAST: u32       = 0x38F;
+use crate::rtc::read_rtc;
+use crate::pit;
+
+pub const APIC_APICID: usize   = 0x20;
+pub const APIC_APICVER: usize  = 0x30;
+pub const APIC_TASKPRIOR: usize = 0x80;
+pub const APIC_EOI: usize      = 0x0B0;
+pub const APIC_LDR: usize      = 0x0D0;
+pub const APIC_DFR: usize      = 0x0E0;
+pub const APIC_SPURIOUS: usize = 0x0F0;
+pub const APIC_ESR: usize      = 0x280;
+pub const APIC_ICRL: usize     = 0x300;
+pub const APIC_ICRH: usize     = 0x310;
+pub const APIC_LVT_TMR: usize  = 0x320;
+pub const APIC_LVT_PERF: usize = 0x340;
+pub const APIC_LVT_LINT0: usize = 0x350;
+pub const APIC_LVT_LINT1: usize = 0x360;
+pub const APIC_LVT_ERR: usize  = 0x370;
+pub const APIC_TMRINITCNT: usize = 0x380;
+pub const APIC_TMRCURRCNT: usize = 0x390;
+pub const APIC_TMRDIV: usize   = 0x3E0;
+pub const APIC_LAST: usize     = 0x38F;
 pub const APIC_DISABLE: u32    = 0x10000;
 pub const APIC_SW_ENABLE: u32  = 0x100;
+pub const APIC_SPURIOUS_VECTOR: u32 = 0xFF;
 pub const APIC_CPUFOCUS: u32   = 0x200;
 pub const APIC_NMI: u32        = 4<<8;
 pub const TMR_PERIODIC: u32	= 0x20000;
 pub const TMR_BASEDIV: u32	= 1 << 20;
 
+pub const ICR_INIT: u32            = 0b101 << 8;
+pub const ICR_STARTUP: u32         = 0b110 << 8;
+pub const ICR_LEVEL_ASSERT: u32    = 1 << 14;
+pub const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+// the last register, the timer divide configuration, is at 0x3E0
+const REGISTERS_SIZE: usize = 0x400;
+
 pub struct Apic {
     apic_base: VirtAddr
 }
 
+// same as `Apic::apic_base`, readable without taking the `interrupts::APIC` lock
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
 impl Apic {
     pub const fn new() -> Apic {
         Apic{ apic_base: VirtAddr::new(0) }
@@ -47,6 +62,7 @@ impl Apic {
 
     pub unsafe fn initialize(&mut self, addr: VirtAddr) {
         self.apic_base = addr;
+        LOCAL_APIC_BASE.store(addr.0, Ordering::Release);
 
         self.apic_write(APIC_DFR, 0xFFFF_FFFF);
         let mut ldr = self.apic_read(APIC_LDR) & 0x00FFFFFF;
@@ -61,19 +77,34 @@ impl Apic {
         self.apic_write(APIC_TASKPRIOR, 0);
     }
 
-    unsafe fn apic_read(&self, offset: u32) -> u32 {
-        let apic_base = self.apic_base.0 as *mut u32;
-        core::ptr::read_volatile(apic_base.offset((offset / 4) as isize))
+    unsafe fn apic_read(&self, offset: usize) -> u32 {
+        registers_at(self.apic_base).reg::<u32>(offset).read()
     }
 
-    unsafe fn apic_write(&self, offset: u32, value: u32) {
-        let apic_base = self.apic_base.0 as *mut u32;
-        core::ptr::write_volatile(apic_base.byte_offset(offset as isize), value);
+    unsafe fn apic_write(&self, offset: usize, value: u32) {
+        registers_at(self.apic_base).reg::<u32>(offset).write(value);
     }
 
     pub unsafe fn notify_end_of_interrupt(&mut self) {
         self.apic_write(APIC_EOI, 0);
     }
+
+    /// Sends an inter-processor interrupt to the local APIC `apic_id` and waits until it's
+    /// delivered. `command` is the low half of the ICR: delivery mode, level and vector.
+    pub unsafe fn send_ipi(&mut self, apic_id: u8, command: u32) {
+        self.apic_write(APIC_ICRH, (apic_id as u32) << 24);
+        self.apic_write(APIC_ICRL, command);
+
+        while self.apic_read(APIC_ICRL) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Software enables the local APIC and accepts interrupts of all priorities.
+    pub unsafe fn enable(&mut self) {
+        self.apic_write(APIC_TASKPRIOR, 0);
+        self.apic_write(APIC_SPURIOUS, spurious_register(self.apic_read(APIC_SPURIOUS)));
+    }
 }
 
 #[inline]
@@ -94,72 +125,49 @@ fn is_tsc_constant() -> bool {
     return (edx & 0x100) == 0x100;
 }
 
-/*
- * Try to calibrate the TSC against the Programmable
- * Interrupt Timer and return the frequency of the TSC
- * in kHz.
- *
- * Return ULONG_MAX on failure to calibrate.
- */
-pub fn pit_calibrate_tsc(latch: u32, ms: u64, loop_min: u16) -> u64 {
+/// Returns the spurious interrupt vector register value that software enables the local APIC and
+/// delivers its spurious interrupts to `InterruptIndex::ApicSpurious`.
+fn spurious_register(value: u32) -> u32 {
+    (value & !APIC_SPURIOUS_VECTOR) | APIC_SW_ENABLE | InterruptIndex::ApicSpurious as u32
+}
+
+/// # Safety
+/// The local APIC must be mapped at `base`.
+unsafe fn registers_at(base: VirtAddr) -> MmioRegion {
+    MmioRegion::new(base, REGISTERS_SIZE)
+}
+
+/// Signals end of interrupt to the local APIC. Must be called at the end of every IRQ handler.
+pub fn eoi() {
     unsafe {
-        // Set the Gate high, disable speaker
-        let mut pit_channel2_gate = Port::new(0x61);
-        {
-            let v = (pit_channel2_gate.read() & 0xfd) | 0x1;
-            pit_channel2_gate.write(v);
-        }
+        interrupts::APIC.lock()
+            .notify_end_of_interrupt();
+    }
+}
 
-        /*
-         * Setup CTC channel 2* for mode 0, (interrupt on terminal
-         * count mode), binary count. Set the latch register to 50ms
-         * (LSB then MSB) to begin countdown.
-         */
-        let mut pit_channel2_command = Port::new(0x43);
-        pit_channel2_command.write(0xb0);
-
-        let mut pit_channel2_data = Port::new(0x42);
-        pit_channel2_data.write((latch & 0xff) as u8);
-        pit_channel2_data.write((latch >> 8) as u8);
-
-        let mut tsc = get_tsc();
-        let t1 = tsc;
-        let mut t2 = tsc;
-        let mut delta;
-        let mut tsc_max: u64 = 0;
-        let mut tsc_min: u64 = 0xFFFF_FFFF_FFFF_FFFF;
-        let mut pitcnt = 0;
-
-        while (pit_channel2_gate.read() & 0x20) == 0 {
-            t2 = get_tsc();
-            delta = t2 - tsc;
-            tsc = t2;
-            if delta < tsc_min {
-                tsc_min = delta;
-            }
-            if delta > tsc_max {
-                tsc_max = delta;
-            }
-            pitcnt += 1;
-        }
+/// Returns the local APIC ID of the CPU running this code, 0 before the local APIC is initialized.
+///
+/// Doesn't lock, so it can be called from interrupt handlers.
+pub fn local_apic_id() -> u8 {
+    let apic_base = LOCAL_APIC_BASE.load(Ordering::Acquire);
+    if apic_base == 0 {
+        return 0;
+    }
 
-        log::info!("PIT values: {} {} {}", pitcnt, tsc_min, tsc_max);
-        /*
-         * Sanity checks:
-         *
-         * If we were not able to read the PIT more than loopmin
-         * times, then we have been hit by a massive SMI
-         *
-         * If the maximum is 10 times larger than the minimum,
-         * then we got hit by an SMI as well.
-         */
-        if pitcnt < loop_min || tsc_max > 10 * tsc_min {
-            return 0xFFFF_FFFF_FFFF_FFFF;
-        }
+    let registers = unsafe { registers_at(VirtAddr::new(apic_base)) };
+    (registers.reg::<u32>(APIC_APICID).read() >> 24) as u8
+}
 
-        delta = t2 - t1;
-        log::info!("PIT: delta: {}", delta);
-        delta / ms
+/// Enables the local APIC of an application processor. The registers are at the same address on
+/// every CPU, so `interrupts::APIC` must be initialized by the BSP first. The timer stays off and
+/// IRQs keep going to the BSP.
+pub fn initialize_ap_apic() {
+    unsafe {
+        asm!(
+        "mov ecx, 1bh; rdmsr; bts eax, 11; wrmsr",
+        out("eax") _, out("ecx") _, out("edx") _, options(nomem, nostack)
+        );
+        interrupts::APIC.lock().enable();
     }
 }
 
@@ -168,12 +176,12 @@ pub fn tsc_read_apic_ref(local_apic: VirtAddr) -> (u64, u32) {
     let tsc_default_threshold = 0x20000;
     let mut t1: u64;
     let mut t2: u64;
-    let apic_base = local_apic.0 as *mut u32;
+    let registers = unsafe { registers_at(local_apic) };
 
     let mut apic_tmr: u32 = 0;
     for _ in 0..max_retries {
         t1 = get_tsc();
-        apic_tmr = unsafe { read_u32_ptr(apic_base, APIC_TMRCURRCNT) };
+        apic_tmr = registers.reg::<u32>(APIC_TMRCURRCNT).read();
         t2 = get_tsc();
 
         if t2 - t1 < tsc_default_threshold {
@@ -259,7 +267,7 @@ pub fn pit_hpet_ptimer_calibrate_cpu(local_apic: VirtAddr) -> u64 {
          */
 
         (tsc1, ref1) = tsc_read_apic_ref(local_apic);
-        let tsc_pit_khz = pit_calibrate_tsc(latch, ms, loopmin);
+        let tsc_pit_khz = pit::pit_calibrate_tsc(latch, ms, loopmin);
         (tsc2, ref2) = tsc_read_apic_ref(local_apic);
         log::info!("calibrated TSC-PIT Khz: {}", tsc_pit_khz);
 
@@ -308,27 +316,89 @@ pub fn pit_hpet_ptimer_calibrate_cpu(local_apic: VirtAddr) -> u64 {
     return tsc_pit_min;
 }
 
+const PIC_MASTER_COMMAND: u16 = 0x20;
+const PIC_SLAVE_COMMAND: u16 = 0xA0;
+const PIC_EOI: u8 = 0x20;
+const PIC_READ_ISR: u8 = 0x0B;
+
+/// Returns the in-service registers of both PICs, the slave in the high byte.
+pub fn pic_in_service() -> u16 {
+    let mut master = Port::new(PIC_MASTER_COMMAND);
+    let mut slave = Port::new(PIC_SLAVE_COMMAND);
+
+    unsafe {
+        master.write(PIC_READ_ISR);
+        slave.write(PIC_READ_ISR);
+        u16::from_le_bytes([master.read(), slave.read()])
+    }
+}
+
+/// Sends end of interrupt to the master PIC.
+pub fn pic_master_eoi() {
+    unsafe { Port::new(PIC_MASTER_COMMAND).write(PIC_EOI) };
+}
+
+/// Sends end of interrupt to the slave PIC.
+pub fn pic_slave_eoi() {
+    unsafe { Port::new(PIC_SLAVE_COMMAND).write(PIC_EOI) };
+}
+
+// cleared by disable_pic, the PIC vectors carry IO APIC interrupts afterwards
+static PIC_ACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// Returns whether the legacy PIC still delivers the IRQs, i.e. `disable_pic` wasn't called yet.
+pub fn is_pic_active() -> bool {
+    PIC_ACTIVE.load(Ordering::Acquire)
+}
+
+/// Moves the legacy PIC vectors away from the CPU exceptions and masks all its lines, so only
+/// spurious interrupts can still come from it.
 pub fn disable_pic() {
+    let mut cmd1 = Port::new(0x20);
     let mut p1 = Port::new(0x21);
+    let mut cmd2 = Port::new(0xA0);
     let mut p2 = Port::new(0xA1);
 
     unsafe {
+        // ICW1: start initialization, ICW4 follows
+        cmd1.write(0x11);
+        cmd2.write(0x11);
+        // ICW2: vector offsets
+        p1.write(interrupts::PIC_1_OFFSET);
+        p2.write(interrupts::PIC_1_OFFSET + 8);
+        // ICW3: slave PIC is attached to IRQ2
+        p1.write(0b100);
+        p2.write(2);
+        // ICW4: 8086 mode
+        p1.write(0x01);
+        p2.write(0x01);
+
         p1.write(0xff);
         p2.write(0xff);
     }
-}
 
-unsafe fn read_io_apic(io_apic: *mut u32, register: u32) -> u32 {
-    write_u32_ptr(io_apic, 0, register & 0xff);
-    read_u32_ptr(io_apic, 0x10)
+    PIC_ACTIVE.store(false, Ordering::Release);
 }
 
-unsafe fn write_io_apic(io_apic: *mut u32, register: u32, value: u32) {
-    write_u32_ptr(io_apic, 0, register & 0xff);
-    write_u32_ptr(io_apic, 0x10, value);
+// the local APIC id interrupts are delivered to, set by initialize_apic
+static LOCAL_APIC_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Routes the legacy IRQ through the IO APIC to vector `PIC_1_OFFSET + irq` on this CPU and
+/// unmasks it.
+pub fn route_irq(irq: u8) -> Result<(), &'static str> {
+    if irq >= interrupts::IRQ_COUNT {
+        return Err("IRQ is out of range");
+    }
+
+    let gsi = ioapic::route_isa_irq(irq, LOCAL_APIC_ID.load(Ordering::Relaxed) as u8)?;
+    ioapic::unmask(gsi)
 }
 
 pub fn initialize_apic(apic_addrs: ApicAddresses) {
+    if !cpu::features().apic {
+        panic!("Local APIC is not supported by the CPU");
+    }
+
     unsafe { interrupts::APIC.lock().initialize(apic_addrs.local_apic_addr); };
 
     log::info!("Starting to initialize APIC timer");
@@ -343,15 +413,13 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
 
     log::info!("APIC enabled");
 
-    let apic_base = apic_addrs.local_apic_addr.0 as *mut u32;
+    let registers = unsafe { registers_at(apic_addrs.local_apic_addr) };
 
     let mut date_time = read_rtc();
     log::info!("CMOS datetime: {:?}", date_time);
 
-    unsafe {
-        write_u32_ptr(apic_base, APIC_TMRDIV, 0x03);
-        write_u32_ptr(apic_base, APIC_SPURIOUS, read_u32_ptr(apic_base, APIC_SPURIOUS) | APIC_SW_ENABLE);
-    }
+    registers.reg::<u32>(APIC_TMRDIV).write(0x03);
+    registers.reg::<u32>(APIC_SPURIOUS).update(spurious_register);
 
     let mut full_second_passing = false;
     let mut first_measure = 0;
@@ -361,10 +429,8 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
     loop {
         let new_date_time = read_rtc();
         if date_time != new_date_time {
-            let ticks_in_1s = 0xFFFFFFFF - unsafe {
-                write_u32_ptr(apic_base, APIC_LVT_TMR, APIC_DISABLE);
-                read_u32_ptr(apic_base, APIC_TMRCURRCNT)
-            };
+            registers.reg::<u32>(APIC_LVT_TMR).write(APIC_DISABLE);
+            let ticks_in_1s = 0xFFFFFFFF - registers.reg::<u32>(APIC_TMRCURRCNT).read();
             if !full_second_passing {
                 full_second_passing = true;
             } else if first_measure == 0 {
@@ -380,11 +446,9 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
             log::info!("New datetime: {:?}. Ticks elapsed: {}", new_date_time, ticks_in_1s);
             date_time = new_date_time;
 
-            unsafe {
-                // one-shot mode
-                write_u32_ptr(apic_base, APIC_LVT_TMR, InterruptIndex::Timer as u32);
-                write_u32_ptr(apic_base, APIC_TMRINITCNT, 0xFFFFFFFF);
-            }
+            // one-shot mode
+            registers.reg::<u32>(APIC_LVT_TMR).write(InterruptIndex::Timer as u32);
+            registers.reg::<u32>(APIC_TMRINITCNT).write(0xFFFFFFFF);
         }
     }
 
@@ -396,31 +460,30 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
     let timer_frequency = timer::TIMER_FREQUENCY; // x interrupts per sec
     let timer_value = avg_ticks / timer_frequency as u64; // x interrupts per sec
 
-    log::info!("Ok. let's enable APIC with proper value. timer init value: {}, timer_frequency per sec: {}", timer_value, timer_frequency);
-
     unsafe {
-        write_u32_ptr(apic_base, APIC_TMRINITCNT, timer_value as u32);
-        write_u32_ptr(apic_base, APIC_LVT_TMR, InterruptIndex::Timer as u32 | TMR_PERIODIC);
-
-        let local_apic_id = read_u32_ptr(apic_base, APIC_APICID);
-
-        let io_apic_base = apic_addrs.io_apic_addr.0 as *mut u32;
-
-        let version = read_io_apic(io_apic_base, 0x1);
-
-        log::info!("IOAPIC[0]: version: {}, address: {:#x}", version as u8, apic_addrs.io_apic_addr.0);
-        let mut low_reg = read_io_apic(io_apic_base, 0x12) as u64;
-
-        set_bits(&mut low_reg, InterruptIndex::Keyboard as u64, 0);
-
-        set_bits(&mut low_reg, 0, 8); // Fixed delivery mode
-        set_bit(&mut low_reg, 11, false); // Physical destination
-        set_bit(&mut low_reg, 13, false); // Pin polarity - active high
-        set_bit(&mut low_reg, 15, false); // Trigger mode - edge
-        set_bit(&mut low_reg, 16, false); // unmask interrupt
+        if timer_value != 0 {
+            log::info!("Ok. let's enable APIC with proper value. timer init value: {}, timer_frequency per sec: {}", timer_value, timer_frequency);
+            timer::set_tick_frequency((avg_ticks / timer_value) as u32);
+            registers.reg::<u32>(APIC_TMRINITCNT).write(timer_value as u32);
+            registers.reg::<u32>(APIC_LVT_TMR).write(InterruptIndex::Timer as u32 | TMR_PERIODIC);
+        }
 
-        write_io_apic(io_apic_base, 0x12, low_reg as u32);
-        write_io_apic(io_apic_base, 0x13, local_apic_id);
+        // the ID is in the top byte of the register
+        let local_apic_id = registers.reg::<u32>(APIC_APICID).read() >> 24;
+        LOCAL_APIC_ID.store(local_apic_id, Ordering::Relaxed);
+
+        route_irq(InterruptIndex::Keyboard as u8 - interrupts::PIC_1_OFFSET)
+            .expect("Failed to route the keyboard IRQ");
+
+        // the PIT shares the vector of the local APIC timer, which drives the ticks, so its line
+        // stays masked unless the local APIC timer is too slow to be calibrated
+        let timer_gsi = ioapic::route_isa_irq(InterruptIndex::Timer as u8 - interrupts::PIC_1_OFFSET, local_apic_id as u8)
+            .expect("Failed to route the timer IRQ");
+        if timer_value == 0 {
+            log::warn!("[timer] local APIC timer is not usable, ticking with the PIT instead");
+            timer::set_tick_frequency(pit::init_timer(timer_frequency as u32));
+            ioapic::unmask(timer_gsi).expect("Failed to unmask the timer IRQ");
+        }
 
         // enable hardware interrupts
         asm!("sti", options(nomem, nostack));
@@ -0,0 +1,168 @@
+use shared_lib::get_tsc;
+use crate::port::Port;
+use crate::tsc;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Sectors reachable with 28-bit LBA, 128 GiB.
+pub const MAX_LBA28_SECTORS: u64 = 1 << 28;
+
+// legacy ports of the primary channel
+const PRIMARY_IO_BASE: u16 = 0x1F0;
+const PRIMARY_CONTROL: u16 = 0x3F6;
+
+// offsets from the IO base
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_HEAD: u16 = 6;
+const STATUS_COMMAND: u16 = 7;
+
+const STATUS_BUSY: u8 = 0x80;
+const STATUS_DRIVE_FAULT: u8 = 0x20;
+const STATUS_DATA_REQUEST: u8 = 0x08;
+const STATUS_ERROR: u8 = 0x01;
+// nothing drives the bus without a drive, it reads back as all ones
+const STATUS_FLOATING: u8 = 0xFF;
+
+const COMMAND_READ_PIO: u8 = 0x20;
+const COMMAND_WRITE_PIO: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+
+// LBA mode, bits 5 and 7 are always set
+const DRIVE_HEAD_LBA: u8 = 0xE0;
+// nIEN, the driver polls so the drive must not raise IRQ14
+const CONTROL_NO_INTERRUPT: u8 = 0x02;
+
+const TIMEOUT_US: u64 = 1_000_000;
+
+// a single command transfers at most 256 sectors, written as 0
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+// serializes commands, the task file registers are shared by both drives
+static PRIMARY_CHANNEL: spin::Mutex<()> = spin::Mutex::new(());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave
+}
+
+fn register(offset: u16) -> Port {
+    Port::new(PRIMARY_IO_BASE + offset)
+}
+
+/// Reading the alternate status register takes about 100 ns, the drive needs 400 ns to update
+/// the status after a drive select or a command.
+fn delay_400ns() {
+    let mut alt_status = Port::new(PRIMARY_CONTROL);
+    for _ in 0..4 {
+        unsafe { alt_status.read(); }
+    }
+}
+
+/// Waits until BSY is clear and, with `data_request`, DRQ is set.
+fn wait_ready(data_request: bool) -> Result<(), &'static str> {
+    delay_400ns();
+
+    let mut status_register = register(STATUS_COMMAND);
+    let deadline = get_tsc() + tsc::us_to_cycles(TIMEOUT_US);
+    loop {
+        let status = unsafe { status_register.read() };
+        if status == STATUS_FLOATING {
+            return Err("No drive on the primary ATA channel");
+        }
+
+        if status & STATUS_BUSY == 0 {
+            if status & STATUS_ERROR != 0 {
+                return Err("ATA command failed");
+            }
+            if status & STATUS_DRIVE_FAULT != 0 {
+                return Err("ATA drive fault");
+            }
+            if !data_request || status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+
+        if get_tsc() >= deadline {
+            return Err("ATA drive timed out");
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn check_range(lba: u64, count: usize, buffer_len: usize) -> Result<(), &'static str> {
+    if buffer_len < count * SECTOR_SIZE {
+        return Err("Buffer is smaller than the sectors");
+    }
+    if lba + count as u64 > MAX_LBA28_SECTORS {
+        return Err("Sectors are out of 28-bit LBA range");
+    }
+    Ok(())
+}
+
+/// Selects the drive and sends a 28-bit LBA command for `count` sectors, 1 to 256.
+unsafe fn send_command(drive: Drive, lba: u64, count: usize, command: u8) -> Result<(), &'static str> {
+    Port::new(PRIMARY_CONTROL).write(CONTROL_NO_INTERRUPT);
+
+    let drive_bit = match drive { Drive::Master => 0, Drive::Slave => 1 << 4 };
+    register(DRIVE_HEAD).write(DRIVE_HEAD_LBA | drive_bit | ((lba >> 24) & 0x0F) as u8);
+    wait_ready(false)?;
+
+    register(SECTOR_COUNT).write((count % MAX_SECTORS_PER_COMMAND) as u8);
+    register(LBA_LOW).write(lba as u8);
+    register(LBA_MID).write((lba >> 8) as u8);
+    register(LBA_HIGH).write((lba >> 16) as u8);
+    register(STATUS_COMMAND).write(command);
+    Ok(())
+}
+
+/// Reads `count` sectors starting at `lba` from the drive on the primary IDE channel into `buf`
+/// with 28-bit LBA PIO, polling the status register.
+///
+/// It's independent from the PCI based `ide` driver and doesn't need it to be initialized, but
+/// the two must not be used on the primary channel at the same time.
+pub fn read_sectors(drive: Drive, lba: u64, count: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+    check_range(lba, count, buf.len())?;
+    let _channel = PRIMARY_CHANNEL.lock();
+    let mut data = register(DATA);
+
+    for (index, chunk) in buf[..count * SECTOR_SIZE].chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+        let chunk_lba = lba + (index * MAX_SECTORS_PER_COMMAND) as u64;
+        unsafe { send_command(drive, chunk_lba, chunk.len() / SECTOR_SIZE, COMMAND_READ_PIO)?; }
+
+        for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+            wait_ready(true)?;
+            for word in sector.chunks_exact_mut(2) {
+                word.copy_from_slice(&unsafe { data.read_u16() }.to_le_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `count` sectors from `buf` starting at `lba` to the drive on the primary IDE channel
+/// with 28-bit LBA PIO and flushes the drive's write cache, see [`read_sectors`].
+pub fn write_sectors(drive: Drive, lba: u64, count: usize, buf: &[u8]) -> Result<(), &'static str> {
+    check_range(lba, count, buf.len())?;
+    let _channel = PRIMARY_CHANNEL.lock();
+    let mut data = register(DATA);
+
+    for (index, chunk) in buf[..count * SECTOR_SIZE].chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+        let chunk_lba = lba + (index * MAX_SECTORS_PER_COMMAND) as u64;
+        unsafe { send_command(drive, chunk_lba, chunk.len() / SECTOR_SIZE, COMMAND_WRITE_PIO)?; }
+
+        for sector in chunk.chunks_exact(SECTOR_SIZE) {
+            wait_ready(true)?;
+            for word in sector.chunks_exact(2) {
+                unsafe { data.write_u16(u16::from_le_bytes([word[0], word[1]])); }
+            }
+        }
+    }
+
+    unsafe { register(STATUS_COMMAND).write(COMMAND_CACHE_FLUSH); }
+    wait_ready(false)
+}
@@ -0,0 +1,180 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::addr::VirtAddr;
+use crate::idt::InterruptStackFrame;
+
+/// Number of hardware breakpoints, DR0 to DR3.
+pub const WATCHPOINT_COUNT: usize = 4;
+
+// DR6 bits B0 - B3 report the breakpoint that hit, BS a single step
+const DR6_HIT_MASK: u64 = 0xF;
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+// DR6 value with no conditions reported, the reserved bits read as 1
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+// resume flag, suppresses instruction breakpoints for the instruction returned to
+const RFLAGS_RF: u64 = 1 << 16;
+
+static HITS: [AtomicU64; WATCHPOINT_COUNT] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    ReadWrite,
+    Execute
+}
+
+impl WatchKind {
+    /// R/W bits of the slot in DR7
+    fn condition(self) -> u64 {
+        match self {
+            WatchKind::Execute => 0b00,
+            WatchKind::Write => 0b01,
+            WatchKind::ReadWrite => 0b11
+        }
+    }
+
+    fn from_condition(condition: u64) -> Option<Self> {
+        match condition {
+            0b00 => Some(WatchKind::Execute),
+            0b01 => Some(WatchKind::Write),
+            0b11 => Some(WatchKind::ReadWrite),
+            // I/O breakpoints need CR4.DE
+            _ => None
+        }
+    }
+}
+
+/// LEN bits of the slot in DR7
+fn len_bits(len: usize) -> Result<u64, &'static str> {
+    match len {
+        1 => Ok(0b00),
+        2 => Ok(0b01),
+        4 => Ok(0b11),
+        8 => Ok(0b10),
+        _ => Err("Watchpoint length must be 1, 2, 4 or 8")
+    }
+}
+
+fn read_dr(index: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        match index {
+            0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack, preserves_flags)),
+            1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack, preserves_flags)),
+            2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack, preserves_flags)),
+            3 => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack, preserves_flags)),
+            6 => asm!("mov {}, dr6", out(reg) value, options(nomem, nostack, preserves_flags)),
+            7 => asm!("mov {}, dr7", out(reg) value, options(nomem, nostack, preserves_flags)),
+            _ => unreachable!()
+        }
+    }
+    value
+}
+
+unsafe fn write_dr(index: usize, value: u64) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        3 => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        6 => asm!("mov dr6, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        7 => asm!("mov dr7, {}", in(reg) value, options(nomem, nostack, preserves_flags)),
+        _ => unreachable!()
+    }
+}
+
+/// L0 - L3 enable bits of DR7
+fn enable_bit(slot: usize) -> u64 {
+    1 << (2 * slot)
+}
+
+/// Shift of the R/W and LEN bits of the slot in DR7
+fn control_shift(slot: usize) -> u64 {
+    16 + 4 * slot as u64
+}
+
+/// Watches `len` bytes at `addr` on the calling CPU and returns the slot used. The debug registers
+/// are per CPU, other CPUs don't see the watchpoint.
+///
+/// `addr` must be aligned to `len`, and execute watchpoints have a length of 1.
+pub fn set_watchpoint(addr: VirtAddr, len: usize, kind: WatchKind) -> Result<usize, &'static str> {
+    let len = if kind == WatchKind::Execute { 1 } else { len };
+    let len_bits = len_bits(len)?;
+    if !addr.is_aligned(len as u64) {
+        return Err("Watchpoint address is not aligned to its length");
+    }
+
+    let dr7 = read_dr(7);
+    let slot = (0..WATCHPOINT_COUNT)
+        .find(|&slot| dr7 & enable_bit(slot) == 0)
+        .ok_or("All debug registers are in use")?;
+
+    let shift = control_shift(slot);
+    let control = (len_bits << 2 | kind.condition()) << shift;
+    HITS[slot].store(0, Ordering::Relaxed);
+
+    unsafe {
+        write_dr(slot, addr.0);
+        write_dr(7, (dr7 & !(0xF << shift)) | control | enable_bit(slot));
+    }
+
+    log::debug!("[debug_reg] watching {} bytes at {} for {:?} in DR{}", len, addr, kind, slot);
+    Ok(slot)
+}
+
+/// Disables the watchpoint in `slot` on the calling CPU, so the slot can be used again.
+pub fn clear_watchpoint(slot: usize) -> Result<(), &'static str> {
+    if slot >= WATCHPOINT_COUNT {
+        return Err("There are only 4 debug registers");
+    }
+
+    let dr7 = read_dr(7);
+    if dr7 & enable_bit(slot) == 0 {
+        return Err("Watchpoint is not set");
+    }
+
+    unsafe {
+        write_dr(7, dr7 & !enable_bit(slot) & !(0xF << control_shift(slot)));
+        write_dr(slot, 0);
+    }
+    Ok(())
+}
+
+/// Returns how many times the watchpoint in `slot` triggered since it was set.
+pub fn hit_count(slot: usize) -> u64 {
+    HITS.get(slot).map_or(0, |hits| hits.load(Ordering::Relaxed))
+}
+
+/// Handles #DB: reports the watchpoints that triggered and lets the interrupted code continue.
+pub(crate) fn handle_debug_exception(stack_frame: &mut InterruptStackFrame) {
+    let dr6 = read_dr(6);
+    let dr7 = read_dr(7);
+    let rip = stack_frame.value.instruction_pointer;
+
+    for slot in (0..WATCHPOINT_COUNT).filter(|&slot| dr6 & (1 << slot) != 0) {
+        HITS[slot].fetch_add(1, Ordering::Relaxed);
+        let kind = WatchKind::from_condition((dr7 >> control_shift(slot)) & 0b11);
+
+        // data watchpoints trap after the access, instruction ones before the instruction runs
+        log::warn!("[debug_reg] watchpoint {} ({:?} at {:#x}) triggered, rip {}", slot, kind, read_dr(slot), rip);
+
+        if kind == Some(WatchKind::Execute) {
+            // without RF the instruction would hit the breakpoint again on return
+            unsafe {
+                let cpu_flags = core::ptr::addr_of_mut!(stack_frame.value.cpu_flags);
+                core::ptr::write_volatile(cpu_flags, core::ptr::read_volatile(cpu_flags) | RFLAGS_RF);
+            }
+        }
+    }
+
+    if dr6 & DR6_SINGLE_STEP != 0 {
+        log::info!("[debug_reg] single step, rip {}", rip);
+    }
+    if dr6 & (DR6_HIT_MASK | DR6_SINGLE_STEP) == 0 {
+        log::warn!("[debug_reg] debug exception without a known cause, DR6 {:#x}, rip {}", dr6, rip);
+    }
+
+    // the status bits are sticky
+    unsafe { write_dr(6, DR6_CLEAR); }
+}
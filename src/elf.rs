@@ -0,0 +1,145 @@
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::page_table::{align_down, get_page_flags, get_physical_address, is_nx_enabled, map_range_with_offset, protect_with_offset, PageTable, PageTableFlags, PAGE_SIZE};
+use shared_lib::VIRT_MAPPING_OFFSET;
+use xmas_elf::{header, program, ElfFile};
+
+// end of the lower half, user programs have to be loaded below it
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// size of an ELF64 program header, shorter entries can't be read
+const PROGRAM_HEADER_SIZE: u16 = 56;
+
+/// Checks that `elf` is a little-endian x86-64 executable or a static PIE. Returns whether the
+/// load bias applies to it.
+fn check_header(elf: &ElfFile) -> Result<bool, &'static str> {
+    header::sanity_check(elf)?;
+
+    if elf.header.pt1.class() != header::Class::SixtyFour || elf.header.pt1.data() != header::Data::LittleEndian {
+        return Err("ELF is not a little-endian 64-bit file");
+    }
+    if elf.header.pt2.machine().as_machine() != header::Machine::X86_64 {
+        return Err("ELF is not built for x86-64");
+    }
+    if elf.header.pt2.ph_count() > 0 && elf.header.pt2.ph_entry_size() < PROGRAM_HEADER_SIZE {
+        return Err("ELF program headers are too short");
+    }
+
+    match elf.header.pt2.type_().as_type() {
+        header::Type::Executable => Ok(false),
+        header::Type::SharedObject => Ok(true),
+        _ => Err("ELF is not an executable")
+    }
+}
+
+fn segment_flags(segment: &program::ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if segment.flags().is_write() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !segment.flags().is_execute() && is_nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Maps one page of a segment, or widens the flags of a page shared with the previous segment, and
+/// returns its frame.
+unsafe fn map_segment_page(l4_table: &mut PageTable, page: VirtAddr, flags: PageTableFlags, allocator: &mut FrameAllocator)
+    -> Result<u64, &'static str> {
+    if let Some(frame) = get_physical_address(l4_table, page, VIRT_MAPPING_OFFSET) {
+        let old_flags = get_page_flags(l4_table, page, VIRT_MAPPING_OFFSET).ok_or("ELF segment overlaps a huge page")?;
+        if !old_flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err("ELF segment overlaps a kernel mapping");
+        }
+
+        // the page stays executable if either segment is
+        let mut merged = old_flags | flags;
+        if !(old_flags & flags).contains(PageTableFlags::NO_EXECUTE) {
+            merged -= PageTableFlags::NO_EXECUTE;
+        }
+        protect_with_offset(l4_table, page, merged, VIRT_MAPPING_OFFSET)?;
+        return Ok(frame);
+    }
+
+    let frame = allocator.allocate_frame().ok_or("Out of memory - failed to allocate ELF segment frame")?;
+    core::ptr::write_bytes((frame + VIRT_MAPPING_OFFSET) as *mut u8, 0, PAGE_SIZE as usize);
+    if let Err(err) = map_range_with_offset(l4_table, page, PhysAddr::new(frame), PAGE_SIZE, allocator, flags, VIRT_MAPPING_OFFSET) {
+        let _ = allocator.deallocate_frame(frame);
+        return Err(err);
+    }
+    Ok(frame)
+}
+
+/// Loads the `PT_LOAD` segments of a static x86-64 executable into the address space of
+/// `l4_table` and returns the entry point.
+///
+/// Each segment gets fresh frames mapped user accessible, writable and executable as its flags
+/// say; the part past the file size, the bss, stays zeroed. A static PIE is loaded at
+/// `load_bias`, executables linked to a fixed address ignore it. Nothing is unmapped when a later
+/// segment fails, the address space is expected to be thrown away then.
+///
+/// # Safety
+/// `l4_table` must be a valid page table hierarchy with tables mapped at `VIRT_MAPPING_OFFSET`.
+pub unsafe fn load_elf(data: &[u8], l4_table: &mut PageTable, allocator: &mut FrameAllocator, load_bias: u64)
+    -> Result<VirtAddr, &'static str> {
+    let elf = ElfFile::new(data)?;
+    let bias = if check_header(&elf)? { load_bias } else { 0 };
+
+    let mut loaded_segments = 0;
+    for segment in elf.program_iter() {
+        if segment.get_type()? != program::Type::Load || segment.mem_size() == 0 {
+            continue;
+        }
+
+        if segment.file_size() > segment.mem_size() {
+            return Err("ELF segment is bigger in the file than in memory");
+        }
+        let file_end = segment.offset().checked_add(segment.file_size()).ok_or("ELF segment is out of the file")?;
+        if file_end > data.len() as u64 {
+            return Err("ELF segment is out of the file");
+        }
+
+        let start = segment.virtual_addr().checked_add(bias).ok_or("ELF segment is out of user space")?;
+        let end = start.checked_add(segment.mem_size()).ok_or("ELF segment is out of user space")?;
+        if end > USER_SPACE_END {
+            return Err("ELF segment is out of user space");
+        }
+
+        log::debug!("[elf] segment {:#x}..{:#x}, {} bytes from the file, flags {}",
+            start, end, segment.file_size(), segment.flags());
+
+        let file_bytes = &data[segment.offset() as usize..file_end as usize];
+        let flags = segment_flags(&segment);
+
+        let mut page = align_down(VirtAddr::new(start));
+        while page.0 < end {
+            let frame = map_segment_page(l4_table, page, flags, allocator)?;
+
+            // copy the part of the file that falls into this page, the rest is already zero
+            let copy_start = page.0.max(start);
+            let copy_end = (page.0 + PAGE_SIZE).min(start + segment.file_size());
+            if copy_start < copy_end {
+                let source = &file_bytes[(copy_start - start) as usize..(copy_end - start) as usize];
+                let destination = (frame + VIRT_MAPPING_OFFSET + (copy_start - page.0)) as *mut u8;
+                core::ptr::copy_nonoverlapping(source.as_ptr(), destination, source.len());
+            }
+
+            page = VirtAddr::new(page.0 + PAGE_SIZE);
+        }
+
+        loaded_segments += 1;
+    }
+
+    if loaded_segments == 0 {
+        return Err("ELF has no loadable segments");
+    }
+
+    let entry = elf.header.pt2.entry_point().checked_add(bias).ok_or("ELF entry point is out of user space")?;
+    if entry >= USER_SPACE_END {
+        return Err("ELF entry point is out of user space");
+    }
+
+    log::info!("[elf] loaded {} segments, entry point at {:#x}", loaded_segments, entry);
+    Ok(VirtAddr::new(entry))
+}
@@ -0,0 +1,47 @@
+use core::arch::asm;
+use shared_lib::cpu;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe { asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags)); }
+    cr0
+}
+
+/// Lets the calling CPU execute x87 and SSE instructions: no emulation, `wait` honours CR0.TS,
+/// FXSAVE and unmasked SIMD exceptions are enabled, and the x87 state is reset. Every CPU calls
+/// it. Returns false if the CPU has no FPU or SSE, the registers are left untouched then.
+pub fn init_fpu() -> bool {
+    let features = cpu::features();
+    if !features.fpu || !features.sse {
+        return false;
+    }
+
+    unsafe {
+        let cr0 = (read_cr0() & !(CR0_EM | CR0_TS)) | CR0_MP;
+        asm!("mov cr0, {}", in(reg) cr0, options(nomem, nostack, preserves_flags));
+
+        let cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        asm!("mov cr4, {}", in(reg) cr4 | CR4_OSFXSR | CR4_OSXMMEXCPT, options(nomem, nostack, preserves_flags));
+
+        asm!("fninit", options(nomem, nostack, preserves_flags));
+    }
+    true
+}
+
+/// Handles #NM. Nothing switches the FPU state lazily, so a set CR0.TS is only cleared. Returns
+/// false if the FPU is really unavailable.
+pub(crate) fn handle_device_not_available() -> bool {
+    if read_cr0() & CR0_TS == 0 {
+        return false;
+    }
+
+    unsafe { asm!("clts", options(nomem, nostack, preserves_flags)); }
+    true
+}
@@ -1,8 +1,13 @@
+use alloc::boxed::Box;
+use alloc::vec;
 use core::arch::asm;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicPtr, Ordering};
 use bitflags::bitflags;
 use lazy_static::lazy_static;
 use shared_lib::bits::{get_bits, set_bits};
 use shared_lib::addr::VirtAddr;
+use crate::percpu::PerCpu;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed(4))]
@@ -41,21 +46,16 @@ impl TaskStateSegment {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(STACK) });
-            let stack_end = VirtAddr::new(stack_start.0 + STACK_SIZE as u64);
-            stack_end
-        };
-        tss
-    };
-}
+// The TSS of the BSP. It's written through raw pointers only, the CPU reads it behind our back.
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+static mut BSP_DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+// the TSS loaded on every CPU, null until the CPU loaded its GDT
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(core::ptr::null_mut());
+static TSS: PerCpu<AtomicPtr<TaskStateSegment>> = PerCpu::new([NO_TSS; crate::percpu::MAX_CPUS]);
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -194,6 +194,16 @@ impl GlobalDescriptorTable {
     }
 }
 
+/// Reads the GDTR of the calling CPU: the address and limit of the GDT it's using.
+#[inline]
+pub fn current_gdt() -> DescriptorTablePointer {
+    let mut gdt = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut gdt, options(nostack, preserves_flags));
+    }
+    gdt
+}
+
 bitflags! {
     /// Flags for a GDT descriptor. Not all flags are valid for all descriptor types.
     #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
@@ -279,6 +289,24 @@ impl Descriptor {
             )
     }
 
+    /// 64-bit code segment for ring 3.
+    #[inline]
+    pub const fn user_code_segment() -> Descriptor {
+        match Self::kernel_code_segment() {
+            Descriptor::UserSegment(value) => Descriptor::UserSegment(value | DescriptorFlags::DPL_RING_3.bits()),
+            descriptor => descriptor
+        }
+    }
+
+    /// Data segment for ring 3.
+    #[inline]
+    pub const fn user_data_segment() -> Descriptor {
+        match Self::kernel_data_segment() {
+            Descriptor::UserSegment(value) => Descriptor::UserSegment(value | DescriptorFlags::DPL_RING_3.bits()),
+            descriptor => descriptor
+        }
+    }
+
     #[inline]
     pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
         // SAFETY: The pointer is derived from a &'static reference, which ensures its validity.
@@ -305,22 +333,31 @@ impl Descriptor {
         let mut low = Flags::PRESENT.bits();
 
         // base
-        set_bits(&mut low, get_bits(ptr, 0..24), 16);
-        set_bits(&mut low, get_bits(ptr, 24..32), 56);
+        set_bits(&mut low, 16..40, get_bits(ptr, 0..24));
+        set_bits(&mut low, 56..64, get_bits(ptr, 24..32));
 
         // limit (the `-1` in needed since the bound is inclusive)
-        set_bits(&mut low, (size_of::<TaskStateSegment>() - 1) as u64, 0);
+        set_bits(&mut low, 0..16, (size_of::<TaskStateSegment>() - 1) as u64);
 
         // type (0b1001 = available 64-bit tss)
-        set_bits(&mut low, 0b1001, 40);
+        set_bits(&mut low, 40..44, 0b1001);
 
         let mut high = 0;
-        set_bits(&mut high, get_bits(ptr, 32..64), 0);
+        set_bits(&mut high, 0..32, get_bits(ptr, 32..64));
 
         Descriptor::SystemSegment(low, high)
     }
 }
 
+// Every CPU uses the same layout. SYSCALL loads CS and SS from STAR[47:32] and the next entry,
+// SYSRET to 64-bit mode takes SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16, so the user
+// data segment has to come right before the user code segment.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
 struct GdtAndSelectors {
     pub gdt: GlobalDescriptorTable,
     pub code_selector: SegmentSelector,
@@ -328,18 +365,80 @@ struct GdtAndSelectors {
     pub data_selector: SegmentSelector
 }
 
-lazy_static! {
-    static ref GDT: GdtAndSelectors = {
+impl GdtAndSelectors {
+    /// # Safety
+    /// `tss` must stay valid for as long as the GDT is loaded.
+    unsafe fn new(tss: *const TaskStateSegment) -> Self {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
         let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment_unchecked(tss));
+
+        debug_assert!(code_selector == KERNEL_CODE_SELECTOR && data_selector == KERNEL_DATA_SELECTOR);
+        debug_assert!(user_data_selector == USER_DATA_SELECTOR && user_code_selector == USER_CODE_SELECTOR);
+        debug_assert!(tss_selector == TSS_SELECTOR);
         GdtAndSelectors { gdt: gdt, code_selector: code_selector, tss_selector: tss_selector, data_selector: data_selector }
-    };
+    }
+}
+
+lazy_static! {
+    static ref GDT: GdtAndSelectors = unsafe { GdtAndSelectors::new(addr_of!(BSP_TSS)) };
 }
 
 pub fn init() {
-    GDT.gdt.load();
+    unsafe {
+        let stack_end = VirtAddr::from_ptr(addr_of!(BSP_DOUBLE_FAULT_STACK)).offset(DOUBLE_FAULT_STACK_SIZE as u64).unwrap();
+        (*addr_of_mut!(BSP_TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+        load(&GDT, addr_of_mut!(BSP_TSS));
+    }
+}
+
+/// Loads a GDT for an application processor. A TSS can only be loaded on one CPU, so every AP
+/// gets its own GDT, TSS and double fault stack, allocated on the heap and never freed.
+pub fn init_ap() {
+    let double_fault_stack = vec![0u8; DOUBLE_FAULT_STACK_SIZE].leak();
+    let stack_end = VirtAddr::from_ptr(double_fault_stack.as_ptr_range().end);
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+
+    let tss: *mut TaskStateSegment = Box::leak(Box::new(tss));
+    load(Box::leak(Box::new(unsafe { GdtAndSelectors::new(tss) })), tss);
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception arrives in ring 3 (RSP0 in
+/// the TSS of the calling CPU). Every thread running user code needs its own kernel stack, so
+/// this is updated on every switch to such a thread.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    let tss = TSS.get().load(Ordering::Acquire);
+    assert!(!tss.is_null(), "GDT of this CPU is not loaded");
+
+    unsafe {
+        addr_of_mut!((*tss).privilege_stack_table).cast::<VirtAddr>().write_unaligned(stack_top);
+    }
+}
+
+/// Returns the TSS loaded on the calling CPU, null before its GDT is loaded.
+pub(crate) fn current_tss() -> *mut TaskStateSegment {
+    TSS.get().load(Ordering::Acquire)
+}
+
+/// Returns the RSP0 of the calling CPU, see [`set_kernel_stack`].
+pub fn kernel_stack() -> VirtAddr {
+    let tss = TSS.get().load(Ordering::Acquire);
+    if tss.is_null() {
+        return VirtAddr::zero();
+    }
+
+    unsafe { addr_of!((*tss).privilege_stack_table).cast::<VirtAddr>().read_unaligned() }
+}
+
+fn load(gdt: &'static GdtAndSelectors, tss: *mut TaskStateSegment) {
+    TSS.get().store(tss, Ordering::Release);
+
+    gdt.gdt.load();
 
     unsafe {
         asm!(
@@ -348,16 +447,16 @@ pub fn init() {
             "push {tmp}",
             "retfq",
             "1:",
-            sel = in(reg) GDT.code_selector.0 as u64,
+            sel = in(reg) gdt.code_selector.0 as u64,
             tmp = lateout(reg) _,
             options(preserves_flags),
         );
 
-        asm!("mov ds, {0:x}", in(reg) GDT.data_selector.0, options(nostack, preserves_flags));
-        asm!("mov es, {0:x}", in(reg) GDT.data_selector.0, options(nostack, preserves_flags));
-        asm!("mov ss, {0:x}", in(reg) GDT.data_selector.0, options(nostack, preserves_flags));
+        asm!("mov ds, {0:x}", in(reg) gdt.data_selector.0, options(nostack, preserves_flags));
+        asm!("mov es, {0:x}", in(reg) gdt.data_selector.0, options(nostack, preserves_flags));
+        asm!("mov ss, {0:x}", in(reg) gdt.data_selector.0, options(nostack, preserves_flags));
 
-        asm!("ltr {0:x}", in(reg) GDT.tss_selector.0, options(nostack, preserves_flags));
+        asm!("ltr {0:x}", in(reg) gdt.tss_selector.0, options(nostack, preserves_flags));
     }
 
 }
\ No newline at end of file
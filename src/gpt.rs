@@ -143,20 +143,20 @@ pub fn parse_gpt(device: Box<dyn BlockDevice>) -> Result<(), GptError> {
                                   ((partition_table_header.entries_num * partition_table_header.entry_size) / 512) as u8)
         .expect("Failed to read LBAs of partition entry array");
 
-    let mut entries_checksum = 0xFFFFFFFF;
+    let mut entries_checksum = shared_lib::crc::CrcDigest::new();
     let mut bytes_remain = partition_table_header.entries_num * partition_table_header.entry_size;
 
     for entry_lba in &entries_lba {
         let entry_slice = unsafe {
             core::slice::from_raw_parts(entry_lba.as_ptr().cast::<u8>(), min(bytes_remain as usize, 512))
         };
-        entries_checksum = shared_lib::crc::calculate_crc32_partial(entry_slice, entries_checksum);
+        entries_checksum.update(entry_slice);
         if bytes_remain > 512 {
             bytes_remain -= 512;
         }
     }
 
-    if partition_table_header.array_checksum != !entries_checksum {
+    if partition_table_header.array_checksum != entries_checksum.finalize() {
         return Err(GptError::InvalidEntriesArrayChecksum);
     }
 
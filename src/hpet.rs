@@ -0,0 +1,177 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::get_tsc;
+use shared_lib::mmio::MmioRegion;
+use crate::acpi::find_table;
+use crate::memory::{active_level_4_table, map_mmio, unmap_mmio};
+use crate::tsc;
+
+// register offsets
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+const TIMER_CONFIGURATION: usize = 0x100;
+const TIMER_COMPARATOR: usize = 0x108;
+const TIMER_STRIDE: usize = 0x20;
+
+const REGISTERS_SIZE: usize = 0x400;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_64_BIT: u64 = 1 << 5;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+// the counter period is given in femtoseconds and must not exceed 100 ns
+const FEMTOSECONDS_PER_NS: u128 = 1_000_000;
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+// virtual address of the registers and the counter period, 0 while the HPET is not initialized
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+// `now_ns` when the main counter was started from 0, keeps the time base of the TSC
+static EPOCH_NS: AtomicU64 = AtomicU64::new(0);
+
+fn registers() -> MmioRegion {
+    unsafe { MmioRegion::new(VirtAddr::new(BASE.load(Ordering::Acquire)), REGISTERS_SIZE) }
+}
+
+fn read(offset: usize) -> u64 {
+    registers().reg::<u64>(offset).read()
+}
+
+fn write(offset: usize, value: u64) {
+    registers().reg::<u64>(offset).write(value)
+}
+
+/// Finds the HPET in the ACPI tables, maps its registers and starts the main counter. Without an
+/// HPET `now_ns` keeps using the TSC calibrated against the PIT.
+pub fn init(allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    let table = find_table(b"HPET").ok_or("No HPET table")?;
+
+    // event timer block ID, then the base address as a generic address structure whose address
+    // field starts at byte 4
+    let data = table.data();
+    let address = data.get(8..16).ok_or("HPET table is too short")?;
+    let phys = u64::from_le_bytes(address.try_into().unwrap());
+    if data[4] != 0 {
+        return Err("HPET is not memory mapped");
+    }
+
+    let base = unsafe { map_mmio(active_level_4_table(), PhysAddr::new(phys), REGISTERS_SIZE as u64, allocator)? };
+
+    let period = unsafe { MmioRegion::new(base, REGISTERS_SIZE) }.reg::<u64>(CAPABILITIES).read() >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        unsafe { unmap_mmio(active_level_4_table(), base, REGISTERS_SIZE as u64, allocator)? };
+        return Err("Invalid HPET counter period");
+    }
+    BASE.store(base.0, Ordering::Release);
+
+    // the main counter can only be written while it's halted
+    write(CONFIGURATION, read(CONFIGURATION) & !CONFIGURATION_ENABLE);
+    write(MAIN_COUNTER, 0);
+    EPOCH_NS.store(tsc_now_ns(), Ordering::Relaxed);
+    write(CONFIGURATION, read(CONFIGURATION) | CONFIGURATION_ENABLE);
+    PERIOD_FS.store(period, Ordering::Release);
+
+    log::info!("[hpet] at {:#x}, {} Hz, {} timers", phys, frequency(), timers_count());
+    Ok(())
+}
+
+pub fn is_available() -> bool {
+    PERIOD_FS.load(Ordering::Acquire) != 0
+}
+
+/// Returns the main counter frequency in Hz, 0 without an HPET.
+pub fn frequency() -> u64 {
+    match PERIOD_FS.load(Ordering::Acquire) {
+        0 => 0,
+        period => 1_000_000_000_000_000 / period
+    }
+}
+
+/// Returns the number of comparators, 0 without an HPET.
+pub fn timers_count() -> u8 {
+    if !is_available() {
+        return 0;
+    }
+    ((read(CAPABILITIES) >> 8) & 0x1f) as u8 + 1
+}
+
+fn ns_to_counter(ns: u64) -> u64 {
+    let elapsed = ns.saturating_sub(EPOCH_NS.load(Ordering::Relaxed));
+    (elapsed as u128 * FEMTOSECONDS_PER_NS / PERIOD_FS.load(Ordering::Acquire) as u128) as u64
+}
+
+fn tsc_now_ns() -> u64 {
+    (get_tsc() as u128 * 1_000_000_000 / tsc::frequency() as u128) as u64
+}
+
+/// Returns the nanoseconds since the TSC was reset, measured with the TSC calibrated against the
+/// PIT until the HPET is enabled. From then on the HPET counter continues from that value, so the
+/// time never jumps back, with the resolution of one counter period (about 100 ns at most).
+pub fn now_ns() -> u64 {
+    match PERIOD_FS.load(Ordering::Acquire) {
+        0 => tsc_now_ns(),
+        period => EPOCH_NS.load(Ordering::Relaxed) + (read(MAIN_COUNTER) as u128 * period as u128 / FEMTOSECONDS_PER_NS) as u64
+    }
+}
+
+/// One-shot comparator of an HPET timer.
+pub struct Comparator {
+    index: u8
+}
+
+/// Returns the comparator with the given index, see `timers_count`.
+pub fn comparator(index: u8) -> Result<Comparator, &'static str> {
+    if index >= timers_count() {
+        return Err("HPET has no timer with this index");
+    }
+    Ok(Comparator { index })
+}
+
+impl Comparator {
+    fn register(&self, offset: usize) -> usize {
+        offset + self.index as usize * TIMER_STRIDE
+    }
+
+    /// Returns the bit mask of IO APIC inputs the timer can raise.
+    pub fn route_capabilities(&self) -> u32 {
+        (read(self.register(TIMER_CONFIGURATION)) >> 32) as u32
+    }
+
+    /// Arms the comparator to match once `now_ns` reaches `deadline_ns`. With `irq` it raises that
+    /// IO APIC input, which has to be in `route_capabilities`, otherwise use `has_fired`.
+    pub fn arm(&self, deadline_ns: u64, irq: Option<u8>) -> Result<(), &'static str> {
+        let mut config = read(self.register(TIMER_CONFIGURATION));
+        let deadline = ns_to_counter(deadline_ns);
+        if config & TIMER_64_BIT == 0 && deadline > u32::MAX as u64 {
+            return Err("Deadline is out of range of the 32 bit comparator");
+        }
+
+        config &= !(TIMER_PERIODIC | TIMER_INTERRUPT_ENABLE | TIMER_ROUTE_MASK);
+        if let Some(irq) = irq {
+            if irq >= 32 || self.route_capabilities() & (1 << irq) == 0 {
+                return Err("Timer can't be routed to this IRQ");
+            }
+            config |= TIMER_INTERRUPT_ENABLE | ((irq as u64) << TIMER_ROUTE_SHIFT);
+        }
+
+        write(self.register(TIMER_COMPARATOR), deadline);
+        write(self.register(TIMER_CONFIGURATION), config);
+        Ok(())
+    }
+
+    pub fn disarm(&self) {
+        let config = read(self.register(TIMER_CONFIGURATION));
+        write(self.register(TIMER_CONFIGURATION), config & !TIMER_INTERRUPT_ENABLE);
+    }
+
+    /// Returns true once the main counter passed the armed deadline.
+    pub fn has_fired(&self) -> bool {
+        read(MAIN_COUNTER) >= read(self.register(TIMER_COMPARATOR))
+    }
+}
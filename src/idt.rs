@@ -327,7 +327,7 @@ impl IndexMut<usize> for InterruptDescriptorTable {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C, packed(2))]
 pub struct DescriptorTablePointer {
     /// Size of the DT.
@@ -343,6 +343,22 @@ pub unsafe fn lidt(idt: &DescriptorTablePointer) {
     }
 }
 
+/// Reads the IDTR of the calling CPU: the address and limit of the IDT it's using.
+#[inline]
+pub fn current_idt() -> DescriptorTablePointer {
+    let mut idt = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        asm!("sidt [{}]", in(reg) &mut idt, options(nostack, preserves_flags));
+    }
+    idt
+}
+
+impl Default for InterruptDescriptorTable {
+    fn default() -> Self {
+        InterruptDescriptorTable::new()
+    }
+}
+
 impl InterruptDescriptorTable {
     /// Creates a new IDT filled with non-present entries.
     #[inline]
@@ -379,11 +395,17 @@ impl InterruptDescriptorTable {
         }
     }
 
+    /// Loads the table into the IDTR of the calling CPU. Every CPU has its own IDTR, so CPUs can use
+    /// different tables, and a table can be swapped for another one at any time.
     #[inline]
     pub fn load(&'static self) {
         unsafe { self.load_unsafe() }
     }
 
+    /// Like `load`, for tables that aren't static.
+    ///
+    /// # Safety
+    /// The table must not be moved, modified or dropped while any CPU uses it.
     #[inline]
     pub unsafe fn load_unsafe(&self) {
         unsafe {
@@ -391,6 +413,11 @@ impl InterruptDescriptorTable {
         }
     }
 
+    /// Returns true if the calling CPU uses this table.
+    pub fn is_loaded(&self) -> bool {
+        current_idt() == self.pointer()
+    }
+
     fn pointer(&self) -> DescriptorTablePointer {
         use core::mem::size_of;
         DescriptorTablePointer {
@@ -1,12 +1,15 @@
 use core::arch::asm;
+use core::fmt;
 use core::ptr;
-use crate::idt::{InterruptStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::idt::{HandlerFunc, InterruptStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
 use lazy_static::lazy_static;
 use crate::gdt;
 use spin;
-use shared_lib::serial_logger::SERIAL_LOGGER;
 use crate::port::Port;
-use crate::apic::Apic;
+use crate::apic::{self, Apic};
+use crate::memory;
+use shared_lib::addr::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32;
 
@@ -15,7 +18,11 @@ pub const PIC_1_OFFSET: u8 = 32;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
-    Spurious = 39
+    // IRQ7 and IRQ15, where the legacy PIC also delivers spurious interrupts
+    Spurious = PIC_1_OFFSET + 7,
+    SlaveSpurious = PIC_1_OFFSET + 15,
+    // programmed into the spurious interrupt vector register of every local APIC
+    ApicSpurious = 0xFF
 }
 
 impl InterruptIndex {
@@ -28,94 +35,376 @@ impl InterruptIndex {
     }
 }
 
-pub static APIC: spin::Mutex<Apic> =
-    spin::Mutex::new(Apic::new());
+pub const IRQ_COUNT: u8 = 16;
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+// IRQs with dedicated handlers below
+const RESERVED_IRQS: [u8; 2] = [0, 1];
+
+// spurious interrupts of the PIC and the local APIC, not acknowledged
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of spurious interrupts delivered by the PIC on IRQ7/IRQ15 or by the local
+/// APIC on its spurious vector.
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+// fn() pointers of the registered IRQ handlers, 0 if there is no handler
+static IRQ_HANDLERS: [AtomicUsize; IRQ_COUNT as usize] = [const { AtomicUsize::new(0) }; IRQ_COUNT as usize];
+
+const VECTORS_COUNT: usize = 256;
+
+// number of times each vector fired since boot
+static INTERRUPT_COUNTS: [AtomicU64; VECTORS_COUNT] = [const { AtomicU64::new(0) }; VECTORS_COUNT];
+
+#[inline(always)]
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many times the vector fired since boot.
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug",
+        3 => "breakpoint",
+        6 => "invalid opcode",
+        7 => "device not available",
+        8 => "double fault",
+        11 => "segment not present",
+        12 => "stack segment fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating point",
+        19 => "SIMD floating point",
+        v if v == InterruptIndex::Timer.as_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        v if v == InterruptIndex::Spurious.as_u8() => "IRQ7",
+        v if v == InterruptIndex::SlaveSpurious.as_u8() => "IRQ15",
+        v if v == InterruptIndex::ApicSpurious.as_u8() => "APIC spurious",
+        v if (PIC_1_OFFSET..PIC_1_OFFSET + IRQ_COUNT).contains(&v) => "IRQ",
+        _ => ""
+    }
+}
+
+/// Logs the counters of all vectors that fired at least once.
+pub fn dump_interrupt_stats() {
+    log::info!("[interrupts] vector  count       name");
+    for (vector, count) in INTERRUPT_COUNTS.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count != 0 {
+            log::info!("[interrupts] {:>6}  {:<10}  {}", vector, count, vector_name(vector as u8));
         }
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+    }
+}
+
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                count_interrupt(PIC_1_OFFSET + $irq);
+                dispatch_irq($irq);
+            }
+        )*
 
-        idt
+        const IRQ_STUBS: [HandlerFunc; IRQ_COUNT as usize] = [$($name),*];
     };
 }
 
+irq_stubs!(0 => irq0_handler, 1 => irq1_handler, 2 => irq2_handler, 3 => irq3_handler,
+           4 => irq4_handler, 5 => irq5_handler, 6 => irq6_handler, 7 => irq7_handler,
+           8 => irq8_handler, 9 => irq9_handler, 10 => irq10_handler, 11 => irq11_handler,
+           12 => irq12_handler, 13 => irq13_handler, 14 => irq14_handler, 15 => irq15_handler);
+
+/// Installs `handler` for the given IRQ, delivered on vector `PIC_1_OFFSET + irq`. The handler runs
+/// in interrupt context, so it must not block or allocate. End of interrupt is sent after it returns.
+pub fn register_irq_handler(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    if irq >= IRQ_COUNT {
+        return Err("IRQ is out of range");
+    }
+    if RESERVED_IRQS.contains(&irq) {
+        return Err("IRQ is handled by the kernel");
+    }
+
+    IRQ_HANDLERS[irq as usize]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| "IRQ already has a handler")
+}
+
+pub fn unregister_irq_handler(irq: u8) {
+    if let Some(handler) = IRQ_HANDLERS.get(irq as usize) {
+        handler.store(0, Ordering::Release);
+    }
+}
+
+fn dispatch_irq(irq: u8) {
+    match IRQ_HANDLERS[irq as usize].load(Ordering::Acquire) {
+        0 => log::warn!("[interrupts] unhandled IRQ {}", irq),
+        handler => {
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+
+    apic::eoi();
+}
+
+pub static APIC: spin::Mutex<Apic> =
+    spin::Mutex::new(Apic::new());
+
+/// Builds an IDT with the kernel's exception and IRQ handlers. `init_idt` loads a shared one, a
+/// CPU that needs its own table, e.g. with different handlers, can start from a copy.
+pub fn kernel_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + irq].set_handler_fn(*stub);
+    }
+
+    idt.debug.set_handler_fn(debug_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    unsafe {
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+    idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_handler);
+    idt[InterruptIndex::SlaveSpurious.as_usize()].set_handler_fn(slave_spurious_handler);
+    idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+
+    idt
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = kernel_idt();
+}
+
+/// Loads the IDT shared by all CPUs on the calling CPU.
 pub fn init_idt() {
     IDT.load();
 }
 
+extern "x86-interrupt" fn debug_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    count_interrupt(1);
+    crate::debug_reg::handle_debug_exception(&mut stack_frame);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
+    count_interrupt(3);
     log::info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
+
+/// Snapshot of the general purpose registers.
+///
+/// Taken inside a handler it is best effort: the handler prologue and the register holding the
+/// snapshot address may have overwritten some of the values by then.
+#[derive(Default)]
+#[repr(C)]
+struct GeneralRegisters {
+    rax: u64, rbx: u64, rcx: u64, rdx: u64,
+    rsi: u64, rdi: u64, rbp: u64, rsp: u64,
+    r8: u64, r9: u64, r10: u64, r11: u64,
+    r12: u64, r13: u64, r14: u64, r15: u64
+}
+
+impl GeneralRegisters {
+    #[inline(always)]
+    fn capture() -> Self {
+        let mut registers = GeneralRegisters::default();
+        unsafe {
+            asm!(
+                "mov [{0}], rax", "mov [{0} + 8], rbx", "mov [{0} + 16], rcx", "mov [{0} + 24], rdx",
+                "mov [{0} + 32], rsi", "mov [{0} + 40], rdi", "mov [{0} + 48], rbp", "mov [{0} + 56], rsp",
+                "mov [{0} + 64], r8", "mov [{0} + 72], r9", "mov [{0} + 80], r10", "mov [{0} + 88], r11",
+                "mov [{0} + 96], r12", "mov [{0} + 104], r13", "mov [{0} + 112], r14", "mov [{0} + 120], r15",
+                in(reg) &mut registers as *mut GeneralRegisters,
+                options(nostack, preserves_flags)
+            );
+        }
+        registers
+    }
+}
+
+impl fmt::Display for GeneralRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x} RSP: {:#018x}", self.rsi, self.rdi, self.rbp, self.rsp)?;
+        writeln!(f, "R8:  {:#018x} R9:  {:#018x} R10: {:#018x} R11: {:#018x}", self.r8, self.r9, self.r10, self.r11)?;
+        write!(f, "R12: {:#018x} R13: {:#018x} R14: {:#018x} R15: {:#018x}", self.r12, self.r13, self.r14, self.r15)
+    }
+}
+
+struct ControlRegisters {
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64
+}
+
+impl ControlRegisters {
+    fn read() -> Self {
+        let (cr0, cr3, cr4): (u64, u64, u64);
+        unsafe {
+            asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        ControlRegisters { cr0, cr2: read_cr2(), cr3, cr4 }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}", self.cr0, self.cr2, self.cr3, self.cr4)
+    }
+}
+
 extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
+    stack_frame: InterruptStackFrame, error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    // capture the registers before the handler body clobbers more of them
+    let registers = GeneralRegisters::capture();
+    let control_registers = ControlRegisters::read();
+    count_interrupt(8);
+
+    // overflowing a stack faults again when the CPU pushes the page fault frame to the guard page
+    let cause = if memory::is_guard_page(VirtAddr::new(control_registers.cr2)) {
+        " caused by STACK OVERFLOW"
+    } else {
+        ""
+    };
+
+    panic!("EXCEPTION: DOUBLE FAULT{}. Error code: {}\n{}\n{}\n{:#?}", cause, error_code, control_registers, registers, stack_frame);
 }
 
 extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame, error_code: u64)
 {
+    count_interrupt(13);
     log::info!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}. Error code: {}", stack_frame, error_code);
 }
 
+// The faulting instruction would be executed again after returning, so these faults are fatal
+
+extern "x86-interrupt" fn divide_error_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(0);
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(6);
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    count_interrupt(12);
+    panic!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}. Error code: {}", stack_frame, error_code);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    count_interrupt(11);
+    panic!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}. Error code: {}", stack_frame, error_code);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(7);
+    if !crate::fpu::handle_device_not_available() {
+        panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{}\n{:#?}", ControlRegisters::read(), stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(16);
+    panic!("EXCEPTION: x87 FLOATING POINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(
+    stack_frame: InterruptStackFrame)
+{
+    count_interrupt(19);
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
+    count_interrupt(InterruptIndex::Timer.as_u8());
     crate::task::timer::raise_timer();
 
-    unsafe {
-        APIC.lock()
-            .notify_end_of_interrupt();
-    }
+    apic::eoi();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
+    count_interrupt(InterruptIndex::Keyboard.as_u8());
     let mut port = Port::new(0x60);
     let scancode = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        APIC.lock()
-            .notify_end_of_interrupt();
-    }
+    apic::eoi();
 }
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    unsafe {
-        if shared_lib::logger::LOGGER.is_initialized() {
-            shared_lib::logger::LOGGER
-                .get()
-                .map(|l| l.force_unlock())
-                .unwrap()
-        } else if SERIAL_LOGGER.is_initialized() {
-            SERIAL_LOGGER.get().map(|l| l.force_unlock()).unwrap()
-        }
+    count_interrupt(14);
+    let cr2 = read_cr2();
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && unsafe { memory::handle_copy_on_write(VirtAddr::new(cr2)) } {
+        return;
     }
 
-    log::info!("EXCEPTION: PAGE FAULT");
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && unsafe { memory::handle_lazy_allocation(VirtAddr::new(cr2)) } {
+        return;
+    }
 
-    let cr2: u64;
-    unsafe {
-        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    if memory::is_guard_page(VirtAddr::new(cr2)) {
+        log::error!("EXCEPTION: STACK OVERFLOW");
     }
 
+    log::info!("EXCEPTION: PAGE FAULT");
+
     log::info!("Accessed Address: {:#x}", cr2);
     log::info!("Error Code: {:?}", error_code);
     log::info!("{:#?}", stack_frame);
@@ -134,7 +423,50 @@ extern "x86-interrupt" fn page_fault_handler(
     }
 }
 
+// The PIC raises IRQ7 (IRQ15 on the slave) when the interrupting line drops before the CPU
+// acknowledges it. Only a genuine interrupt has its bit set in the in-service register. Once the
+// PIC is disabled, the vectors carry the IRQs routed through the IO APIC like any other IRQ.
+
 extern "x86-interrupt" fn spurious_handler(
     _stack_frame: InterruptStackFrame)
 {
+    count_interrupt(InterruptIndex::Spurious.as_u8());
+
+    if !apic::is_pic_active() {
+        dispatch_irq(7);
+    } else if apic::pic_in_service() & (1 << 7) != 0 {
+        apic::pic_master_eoi();
+    } else {
+        // no EOI, the master doesn't consider the interrupt in service
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+extern "x86-interrupt" fn slave_spurious_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    count_interrupt(InterruptIndex::SlaveSpurious.as_u8());
+
+    if !apic::is_pic_active() {
+        dispatch_irq(15);
+        return;
+    }
+
+    if apic::pic_in_service() & (1 << 15) != 0 {
+        apic::pic_slave_eoi();
+    } else {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the master got a genuine interrupt on the cascade line either way
+    apic::pic_master_eoi();
+}
+
+extern "x86-interrupt" fn apic_spurious_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    count_interrupt(InterruptIndex::ApicSpurious.as_u8());
+
+    // the local APIC doesn't set an in-service bit for it, so there is nothing to acknowledge
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
 }
@@ -0,0 +1,209 @@
+use shared_lib::bits::{set_bit, set_bits};
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::mmio::MmioRegion;
+use crate::interrupts;
+use crate::memory::{active_level_4_table, map_mmio};
+use crate::xsdt::{read_interrupt_overrides, read_io_apics};
+
+/// Number of IO APICs the kernel keeps track of, further ones are ignored.
+pub const MAX_IO_APICS: usize = 8;
+
+// the registers are accessed indirectly: select one with IOREGSEL, then read or write IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const REGISTERS_SIZE: u64 = 0x20;
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_MASKED: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level
+}
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    entries: u32
+}
+
+/// How an ISA IRQ reaches the IO APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode
+}
+
+// ISA interrupts are active high and edge triggered, and identity mapped unless the MADT says
+// otherwise
+const ISA_DEFAULT_ROUTES: [IsaRoute; interrupts::IRQ_COUNT as usize] = {
+    let mut routes = [IsaRoute { gsi: 0, polarity: Polarity::ActiveHigh, trigger: TriggerMode::Edge }; interrupts::IRQ_COUNT as usize];
+    let mut irq = 0;
+    while irq < routes.len() {
+        routes[irq].gsi = irq as u32;
+        irq += 1;
+    }
+    routes
+};
+
+static IO_APICS: spin::Mutex<[Option<IoApic>; MAX_IO_APICS]> = spin::Mutex::new([None; MAX_IO_APICS]);
+static ISA_ROUTES: spin::Mutex<[IsaRoute; interrupts::IRQ_COUNT as usize]> = spin::Mutex::new(ISA_DEFAULT_ROUTES);
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        self.registers.reg::<u32>(IOREGSEL).write(register & 0xff);
+        self.registers.reg::<u32>(IOWIN).read()
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        self.registers.reg::<u32>(IOREGSEL).write(register & 0xff);
+        self.registers.reg::<u32>(IOWIN).write(value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    fn redirection_register(&self, gsi: u32) -> u32 {
+        IOREDTBL + 2 * (gsi - self.gsi_base)
+    }
+}
+
+// decodes the MPS INTI flags of an interrupt source override, 0 means the default of the bus
+fn decode_override_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge
+    };
+    (polarity, trigger)
+}
+
+/// Maps the registers of all IO APICs listed in the MADT, masks all their lines and reads the
+/// interrupt source overrides of the ISA IRQs. Returns the number of IO APICs.
+pub fn init(allocator: &mut FrameAllocator) -> Result<usize, &'static str> {
+    let infos = read_io_apics();
+    if infos.is_empty() {
+        return Err("No IO APIC in the MADT");
+    }
+    if infos.len() > MAX_IO_APICS {
+        log::warn!("[ioapic] {} IO APICs found, only the first {} are used", infos.len(), MAX_IO_APICS);
+    }
+
+    let mut io_apics = [None; MAX_IO_APICS];
+    for (slot, info) in io_apics.iter_mut().zip(infos.iter()) {
+        let base = unsafe { map_mmio(active_level_4_table(), info.addr, REGISTERS_SIZE, allocator)? };
+        let registers = unsafe { MmioRegion::new(base, REGISTERS_SIZE as usize) };
+        let mut io_apic = IoApic { registers, gsi_base: info.gsi_base, entries: 0 };
+
+        unsafe {
+            let version = io_apic.read(IOAPICVER);
+            io_apic.entries = ((version >> 16) & 0xff) + 1;
+
+            for gsi in info.gsi_base..info.gsi_base + io_apic.entries {
+                let register = io_apic.redirection_register(gsi);
+                io_apic.write(register, io_apic.read(register) | (1 << REDIRECTION_MASKED));
+            }
+
+            log::info!("[ioapic] ID: {}, version: {}, address: {:#x}, GSIs: {}..{}",
+                info.id, version as u8, info.addr.0, info.gsi_base, info.gsi_base + io_apic.entries);
+        }
+        *slot = Some(io_apic);
+    }
+
+    let mut routes = ISA_DEFAULT_ROUTES;
+    for entry in read_interrupt_overrides() {
+        let Some(route) = routes.get_mut(entry.irq as usize) else {
+            continue;
+        };
+
+        let (polarity, trigger) = decode_override_flags(entry.flags);
+        *route = IsaRoute { gsi: entry.gsi, polarity, trigger };
+        log::info!("[ioapic] IRQ{} -> GSI {}, {:?}, {:?}", entry.irq, entry.gsi, polarity, trigger);
+    }
+
+    without_interrupts(|| {
+        *IO_APICS.lock() = io_apics;
+        *ISA_ROUTES.lock() = routes;
+    });
+    Ok(infos.len().min(MAX_IO_APICS))
+}
+
+/// Returns how the ISA IRQ is connected, with the MADT overrides applied.
+pub fn isa_route(irq: u8) -> Result<IsaRoute, &'static str> {
+    ISA_ROUTES.lock().get(irq as usize).copied().ok_or("IRQ is out of range")
+}
+
+fn with_io_apic<R>(gsi: u32, f: impl FnOnce(&IoApic) -> R) -> Result<R, &'static str> {
+    without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let io_apic = io_apics.iter().flatten()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or("No IO APIC handles this GSI")?;
+        Ok(f(io_apic))
+    })
+}
+
+/// Programs the redirection entry of `gsi` to deliver `vector` to the local APIC `destination`.
+/// The line stays masked until `unmask` is called.
+pub fn route(gsi: u32, vector: u8, polarity: Polarity, trigger: TriggerMode, destination: u8) -> Result<(), &'static str> {
+    if vector < 0x10 {
+        return Err("Vector is reserved");
+    }
+
+    with_io_apic(gsi, |io_apic| unsafe {
+        let register = io_apic.redirection_register(gsi);
+        let mut low_reg = io_apic.read(register);
+
+        set_bits(&mut low_reg, 0..8, vector as u32);
+        set_bits(&mut low_reg, 8..11, 0); // Fixed delivery mode
+        set_bit(&mut low_reg, 11, false); // Physical destination
+        set_bit(&mut low_reg, 13, polarity == Polarity::ActiveLow);
+        set_bit(&mut low_reg, 15, trigger == TriggerMode::Level);
+        set_bit(&mut low_reg, REDIRECTION_MASKED, true);
+
+        // the entry stays masked while it's half written
+        io_apic.write(register, low_reg);
+        io_apic.write(register + 1, (destination as u32) << 24);
+    })
+}
+
+/// Routes the ISA IRQ to vector `PIC_1_OFFSET + irq` on `destination`, following the interrupt
+/// source overrides. Returns the GSI, which is still masked.
+pub fn route_isa_irq(irq: u8, destination: u8) -> Result<u32, &'static str> {
+    let isa_route = isa_route(irq)?;
+    route(isa_route.gsi, interrupts::PIC_1_OFFSET + irq, isa_route.polarity, isa_route.trigger, destination)?;
+    Ok(isa_route.gsi)
+}
+
+fn set_masked(gsi: u32, masked: bool) -> Result<(), &'static str> {
+    with_io_apic(gsi, |io_apic| unsafe {
+        let register = io_apic.redirection_register(gsi);
+        let mut low_reg = io_apic.read(register);
+        set_bit(&mut low_reg, REDIRECTION_MASKED, masked);
+        io_apic.write(register, low_reg);
+    })
+}
+
+pub fn mask(gsi: u32) -> Result<(), &'static str> {
+    set_masked(gsi, true)
+}
+
+pub fn unmask(gsi: u32) -> Result<(), &'static str> {
+    set_masked(gsi, false)
+}
@@ -10,7 +10,7 @@ use shared_lib::serial_println;
 use crate::apic::{disable_pic, initialize_apic};
 use crate::gpt::parse_gpt;
 use crate::pci::PciDevice::{Drive, Generic};
-use crate::xsdt::read_xsdt;
+use crate::xsdt::read_madt;
 
 pub mod idt;
 mod interrupts;
@@ -21,15 +21,35 @@ pub mod task;
 pub mod allocator;
 pub mod shell;
 mod apic;
+pub mod ioapic;
+pub mod pit;
+pub mod tsc;
+pub mod hpet;
 mod xsdt;
+pub mod acpi;
 mod pci;
 mod ide;
-pub mod chrono;
+pub mod ata;
+pub mod rtc;
 mod gpt;
+pub mod smp;
+pub mod percpu;
+pub mod thread;
+pub mod unwind;
+pub mod rand;
+pub mod stack_guard;
+pub mod syscall;
+pub mod elf;
+pub mod debug_reg;
+pub mod fpu;
+pub mod power;
+
+pub use interrupts::{dump_interrupt_stats, init_idt, interrupt_count, kernel_idt, register_irq_handler, spurious_irq_count, unregister_irq_handler};
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", unwind::backtrace());
     shared_lib::exit_qemu(shared_lib::QemuExitCode::Failed);
     loop {
         unsafe {
@@ -39,11 +59,26 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 }
 
 pub fn preinit(allocator: &mut FrameAllocator, rsdp_addr: u64) {
+    if !fpu::init_fpu() {
+        log::warn!("FPU or SSE is not supported, floating point instructions fault");
+    }
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
-    let apic_addrs= read_xsdt(allocator, rsdp_addr);
+    acpi::init(rsdp_addr).expect("Failed to parse ACPI tables");
+    let apic_addrs = read_madt(allocator);
+    ioapic::init(allocator).expect("Failed to initialize the IO APIC");
+    tsc::calibrate_tsc();
+    if let Err(err) = hpet::init(allocator) {
+        log::warn!("[hpet] not used: {}. Falling back to the PIT calibrated TSC", err);
+    }
+    task::keyboard::set_scancode_set(task::keyboard::detect_scancode_set());
     disable_pic();
     initialize_apic(apic_addrs);
+
+    if let Err(err) = smp::init(allocator) {
+        log::warn!("[smp] Failed to start application processors: {}", err);
+    }
 }
 
 pub async fn init() {
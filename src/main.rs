@@ -6,21 +6,25 @@ extern crate shared_lib;
 
 use shared_lib::{BootInfo, serial_logger, VIRT_MAPPING_OFFSET};
 use shared_lib::entry_point;
-use ferr_os::memory::active_level_4_table;
+use ferr_os::memory::{active_level_4_table, map_framebuffer, FRAME_ALLOCATOR};
 
 use core::panic::PanicInfo;
 use shared_lib::logger;
+use shared_lib::cmdline::CmdLine;
 use core::arch::asm;
 use core::sync::atomic::{ AtomicU64, Ordering };
-use ferr_os::allocator::init_heap;
+use ferr_os::allocator::init_default_heap;
 use ferr_os::shell::Shell;
-use ferr_os::task::executor::Executor;
-use ferr_os::task::{keyboard, Task, timer::{timer_loop, sleep_for}};
+use ferr_os::task::executor::{Executor, SHUTDOWN_TIMEOUT_MS};
+use ferr_os::task::{keyboard, Task, timer::sleep_for};
 use ferr_os::port::Port;
-use ferr_os::chrono::read_rtc;
+use ferr_os::power;
+use ferr_os::rtc::read_rtc;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    power::enter_panic();
+
     unsafe {
         logger::LOGGER
             .get()
@@ -32,17 +36,15 @@ fn panic(info: &PanicInfo) -> ! {
     };
 
     log::error!("{}", info);
+    log::error!("{}", ferr_os::unwind::backtrace());
 
-    loop {
-        unsafe {
-            asm!("hlt", options(nomem, nostack, preserves_flags));
-        }
-    }
+    power::finish_panic()
 }
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
+    ferr_os::stack_guard::init_stack_guard();
     shared_lib::serial_println!("Hello from kernel!");
     let fb_info = boot_info.fb_info;
     let memory_map = &boot_info.memory_map;
@@ -52,10 +54,12 @@ fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
         active_level_4_table()
     };
 
-    let mut allocator = shared_lib::frame_allocator::FrameAllocator::new(memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+    let boot_regions = ferr_os::memory::boot_regions(boot_info);
+    let mut allocator = shared_lib::frame_allocator::FrameAllocator::with_reserved(memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame, &boot_regions)
+        .expect("Failed to create frame allocator");
 
     shared_lib::serial_println!("Creating heap");
-    init_heap(l4_table, &mut allocator)
+    init_default_heap(l4_table, &mut allocator)
         .expect("Failed to init heap");
 
     shared_lib::serial_println!("Creating logger");
@@ -66,21 +70,50 @@ fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
         let logger = serial_logger::SERIAL_LOGGER.get_or_init(move || serial_logger::LockedSerialLogger::new());
         log::set_logger(logger).unwrap();
     } else {
-        let logger = logger::LOGGER.get_or_init(move || logger::LockedLogger::new(fb_info));
+        let logger = logger::LOGGER.get_or_init(move || logger::LockedLogger::new_double_buffered(fb_info));
         log::set_logger(logger).unwrap();
     }
 
-    log::set_max_level(log::LevelFilter::Debug);
+    let cmdline = CmdLine::new(boot_info.cmdline);
+    let log_level = cmdline.get("log")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(log::LevelFilter::Info);
+    logger::set_max_level(log_level);
+
+    if let Some(name) = cmdline.get("panic") {
+        match power::PanicAction::from_name(name) {
+            Some(action) => power::set_panic_action(action),
+            None => log::warn!("Unknown panic action: {}", name)
+        }
+    }
+
+    if let Some(name) = cmdline.get("keymap") {
+        match keyboard::Layout::from_name(name) {
+            Some(layout) => keyboard::set_keyboard_layout(layout),
+            None => log::warn!("Unknown keymap: {}", name)
+        }
+    }
 
     log::info!("Hello from kernel!");
+    ferr_os::memory::log_memory_map(memory_map);
+
+    if !shared_lib::page_table::enable_nx() {
+        log::warn!("NX is not supported, NO_EXECUTE mappings are rejected");
+    }
+    if !ferr_os::memory::init_pat() {
+        log::warn!("PAT is not supported, the framebuffer is mapped write-through");
+    }
+    if let Err(err) = unsafe { map_framebuffer(&fb_info, l4_table, &mut allocator) } {
+        log::warn!("Failed to map the framebuffer write-combining: {}", err);
+    }
 
     ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
 
     log::info!("Preinit done");
 
-    let mut executor: Executor = Executor::new();
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
 
-    executor.spawn(Task::new(timer_loop()));
+    let mut executor: Executor = Executor::new();
 
     let shell = Shell::new(fb_info);
     executor.spawn(Task::new(keyboard::print_keypresses(shell)));
@@ -92,6 +125,7 @@ fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
     executor.spawn(Task::new(ferr_os::init()));
 
     executor.run();
+    executor.shutdown(SHUTDOWN_TIMEOUT_MS);
 
     // TODO: ACPI shutdown
     log::info!("exited");
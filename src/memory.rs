@@ -1,17 +1,95 @@
 use core::arch::asm;
-use shared_lib::addr::VirtAddr;
-use shared_lib::page_table::PageTable;
-use shared_lib::VIRT_MAPPING_OFFSET;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use shared_lib::frame_allocator::{FrameAllocator, MemoryMap, RegionKind};
+use shared_lib::page_table::{align_down, align_up_u64, get_page_flags, get_physical_address, map_address_with_offset, map_range_with_offset, protect_with_offset, unmap_address_with_offset, remap_address_with_offset, free_empty_tables, PageTable, PageTableFlags};
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::logger::FrameBufferInfo;
+use shared_lib::cmdline::CMDLINE_MAX_LEN;
+use shared_lib::{BootInfo, VIRT_MAPPING_OFFSET};
 
-pub unsafe fn active_level_4_table() -> &'static mut PageTable
-{
-    let value: u64;
+/// OS-available page table bit marking a read-only page that has to be copied on the first write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Page attribute table MSR, 8 memory types selected by the PAT, PCD and PWT bits of an entry.
+const IA32_PAT: u32 = 0x277;
+
+// power-on PAT with PA4 changed from write-back (06h) to write-combining (01h). PA0-PA3, selected
+// by entries without the PAT bit, keep their meaning
+const PAT_WITH_WRITE_COMBINING: u64 = 0x0007_0401_0007_0406;
+
+// bit 7 of a level 1 entry selects PA4-PA7, it's the huge page bit on the higher levels
+const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static PAT_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Virtual window `map_mmio` places device registers in, below the heap.
+pub const MMIO_WINDOW_START: u64 = 0x_7000_0000_0000;
+pub const MMIO_WINDOW_SIZE: u64 = 0x10_0000_0000; // 64 GiB
+
+// next free address in the MMIO window, only the last mapping can be given back
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_WINDOW_START);
+
+/// Frame allocator used after the kernel initialization, e.g. by the page fault handler.
+pub static FRAME_ALLOCATOR: spin::Mutex<Option<FrameAllocator>> = spin::Mutex::new(None);
+
+/// Maximum number of lazily allocated regions registered at the same time.
+const MAX_LAZY_REGIONS: usize = 16;
+
+/// Virtual range backed by zeroed frames allocated on the first access to each page.
+#[derive(Clone, Copy, Debug)]
+struct LazyRegion {
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags
+}
+
+/// Maximum number of guard pages registered at the same time.
+const MAX_GUARD_PAGES: usize = 32;
+
+// a fixed array, so the page fault handler doesn't have to touch the heap
+static GUARD_PAGES: spin::Mutex<[Option<VirtAddr>; MAX_GUARD_PAGES]> = spin::Mutex::new([None; MAX_GUARD_PAGES]);
+
+// a fixed array, so the page fault handler doesn't have to touch the heap
+static LAZY_REGIONS: spin::Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> = spin::Mutex::new([None; MAX_LAZY_REGIONS]);
+
+// CR3 holds the L4 frame and the PWT and PCD caching flags of the L4 table
+const CR3_ADDRESS_MASK: u64 = 0x_000f_ffff_ffff_f000;
+const CR3_FLAGS_MASK: u64 = 0x18;
 
+fn read_cr3() -> u64 {
+    let value: u64;
     unsafe {
         asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
     }
+    value
+}
 
-    let level_4_table_frame = value & 0x_000f_ffff_ffff_f000;
+/// Returns the physical address of the active L4 table.
+pub fn current_l4() -> u64 {
+    read_cr3() & CR3_ADDRESS_MASK
+}
+
+/// Loads the L4 table at `l4_phys` into CR3, keeping the caching flags. Switching flushes all
+/// non-global TLB entries.
+///
+/// # Safety
+/// The new hierarchy has to map the kernel the same way as the active one: the code, stacks, heap
+/// and the physical memory at `VIRT_MAPPING_OFFSET`, otherwise the next instruction or page table
+/// access faults. `PageTable::clone_hierarchy` creates such a hierarchy. The table must stay
+/// allocated while it's loaded on any CPU.
+pub unsafe fn switch_address_space(l4_phys: u64) -> Result<(), &'static str> {
+    if l4_phys & !CR3_ADDRESS_MASK != 0 {
+        return Err("L4 table address is not page aligned");
+    }
+
+    let value = l4_phys | (read_cr3() & CR3_FLAGS_MASK);
+    asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+    Ok(())
+}
+
+pub unsafe fn active_level_4_table() -> &'static mut PageTable
+{
+    let level_4_table_frame = current_l4();
 
     let virt = VIRT_MAPPING_OFFSET + level_4_table_frame;
     let page_table_ptr = virt as *mut PageTable;
@@ -19,6 +97,162 @@ pub unsafe fn active_level_4_table() -> &'static mut PageTable
     &mut *page_table_ptr // unsafe
 }
 
+/// Programs the PAT of the calling CPU so `write_combining_flags` selects write-combining. All
+/// CPUs have to agree on the memory types, so every AP calls it too. Returns false if the CPU has
+/// no PAT.
+pub fn init_pat() -> bool {
+    if !shared_lib::cpu::features().pat {
+        return false;
+    }
+
+    unsafe {
+        asm!(
+        "wrmsr",
+        in("ecx") IA32_PAT,
+        in("eax") PAT_WITH_WRITE_COMBINING as u32,
+        in("edx") (PAT_WITH_WRITE_COMBINING >> 32) as u32,
+        options(nostack, preserves_flags)
+        );
+    }
+
+    PAT_INITIALIZED.store(true, Ordering::Release);
+    true
+}
+
+/// Caching flags of a write-combining 4 KiB page. Without a PAT it falls back to write-through,
+/// which at least doesn't keep the written pixels in the cache.
+pub fn write_combining_flags() -> PageTableFlags {
+    if PAT_INITIALIZED.load(Ordering::Acquire) {
+        PAT_4KIB
+    } else {
+        PageTableFlags::WRITE_THROUGH
+    }
+}
+
+/// Maps the framebuffer at its place in the physical memory mapping with `write_combining_flags`.
+/// The loader maps it write-back, which makes every pixel write go through the cache hierarchy.
+///
+/// # Safety
+/// `l4_table` must be the active level 4 table and `info` must describe the framebuffer passed by
+/// the loader. `init_pat` should be called before, otherwise the mapping is write-through.
+pub unsafe fn map_framebuffer(info: &FrameBufferInfo, l4_table: &mut PageTable, allocator: &mut FrameAllocator)
+    -> Result<(), &'static str> {
+    let start = VirtAddr::new_checked(info.addr)?;
+    if info.addr < VIRT_MAPPING_OFFSET || !start.is_aligned(4096) {
+        return Err("Framebuffer is not page aligned in the physical memory mapping");
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | write_combining_flags();
+    let phys_start = info.addr - VIRT_MAPPING_OFFSET;
+    let pages = (info.size as u64).div_ceil(4096);
+
+    for i in 0..pages {
+        let virt = start.offset(i * 4096)?;
+        // the loader usually mapped it already, then only the caching flags change
+        if get_page_flags(l4_table, virt, VIRT_MAPPING_OFFSET).is_none() {
+            map_address_with_offset(l4_table, virt, PhysAddr::new(phys_start + i * 4096), allocator, VIRT_MAPPING_OFFSET)?;
+        }
+        protect_with_offset(l4_table, virt, flags, VIRT_MAPPING_OFFSET)?;
+    }
+
+    // lines cached through the old write-back mapping must not be written back later
+    asm!("wbinvd", options(nostack, preserves_flags));
+
+    log::info!("[memory] mapped framebuffer {} ({} pages) with {:?}", start, pages, flags);
+    Ok(())
+}
+
+/// Maps `size` bytes of device registers at `phys` uncached into the MMIO window. The range is
+/// extended to whole pages, the returned address points at `phys` within the first page. Drivers
+/// wrap it into a `shared_lib::mmio::MmioRegion` of `size` bytes.
+///
+/// # Safety
+/// `l4_table` must be the active level 4 table and `phys` must be device memory, not RAM used by
+/// something else.
+pub unsafe fn map_mmio(l4_table: &mut PageTable, phys: PhysAddr, size: u64, allocator: &mut FrameAllocator)
+    -> Result<VirtAddr, &'static str> {
+    if size == 0 {
+        return Err("MMIO region is empty");
+    }
+
+    let page_offset = phys.0 % 4096;
+    let phys_start = PhysAddr::new(phys.0 - page_offset);
+    let mapped_size = align_up_u64(page_offset.checked_add(size).ok_or("MMIO region is too large")?);
+
+    let virt_start = NEXT_MMIO.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+        next.checked_add(mapped_size)
+            .filter(|&end| end <= MMIO_WINDOW_START + MMIO_WINDOW_SIZE)
+    }).map_err(|_| "MMIO window is full")?;
+
+    // PCD together with PWT selects strong uncacheable, MTRRs can't make it write-combining
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    if let Err(err) = map_range_with_offset(l4_table, VirtAddr::new(virt_start), phys_start, mapped_size, allocator, flags, VIRT_MAPPING_OFFSET) {
+        release_mmio_range(virt_start, mapped_size);
+        return Err(err);
+    }
+
+    log::debug!("[memory] mapped MMIO {} ({:#x} bytes) at {:#x}", phys, size, virt_start);
+    Ok(VirtAddr::new(virt_start + page_offset))
+}
+
+/// Unmaps device registers mapped by `map_mmio`, `virt` and `size` as returned by and passed to
+/// it. The window range is only reused if nothing was mapped after it.
+///
+/// # Safety
+/// `l4_table` must be the active level 4 table and the registers must not be accessed anymore.
+pub unsafe fn unmap_mmio(l4_table: &mut PageTable, virt: VirtAddr, size: u64, allocator: &mut FrameAllocator)
+    -> Result<(), &'static str> {
+    let page_offset = virt.0 % 4096;
+    let virt_start = virt.0 - page_offset;
+    let mapped_size = align_up_u64(page_offset + size);
+
+    for page in (virt_start..virt_start + mapped_size).step_by(4096) {
+        unmap_address_with_offset(l4_table, VirtAddr::new(page), allocator, VIRT_MAPPING_OFFSET)?;
+        free_empty_tables(l4_table, VirtAddr::new(page), allocator, VIRT_MAPPING_OFFSET)?;
+    }
+
+    release_mmio_range(virt_start, mapped_size);
+    Ok(())
+}
+
+// gives the range back unless another mapping was placed after it, then it stays unused
+fn release_mmio_range(virt_start: u64, mapped_size: u64) {
+    let _ = NEXT_MMIO.compare_exchange(virt_start + mapped_size, virt_start, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Returns what the loader left in memory and the kernel still uses: the kernel image, the stack,
+/// the boot info and the command line buffer. The loader maps the last three identity.
+///
+/// Pass them to `FrameAllocator::with_reserved`, so they're never handed out even if the memory
+/// map reports them as free.
+pub fn boot_regions(boot_info: &BootInfo) -> [(u64, u64); 4] {
+    let boot_info_start = boot_info as *const BootInfo as u64;
+    let cmdline_start = boot_info.cmdline.as_ptr() as u64;
+
+    [
+        (boot_info.kernel_addr, boot_info.kernel_addr + boot_info.kernel_size),
+        (boot_info.kernel_stack_addr, boot_info.kernel_stack_addr + boot_info.kernel_stack_size),
+        (boot_info_start, boot_info_start + core::mem::size_of::<BootInfo>() as u64),
+        (cmdline_start, cmdline_start + CMDLINE_MAX_LEN as u64)
+    ]
+}
+
+/// Checks the memory map handed over by the loader and logs how much memory there is.
+pub fn log_memory_map(memory_map: &MemoryMap) {
+    if let Err(err) = memory_map.validate() {
+        log::warn!("[memory] invalid memory map: {}", err);
+    }
+
+    for kind in [RegionKind::Usable, RegionKind::Bootloader, RegionKind::Acpi, RegionKind::Reserved] {
+        let (count, size) = memory_map.regions(kind)
+            .fold((0, 0), |(count, size), region| (count + 1, size + region.size()));
+        log::info!("[memory] {:?}: {} regions, {} KiB", kind, count, size / 1024);
+    }
+
+    log::info!("[memory] RAM: {} MiB total, {} MiB usable",
+        memory_map.total_memory() / (1024 * 1024), memory_map.usable_memory() / (1024 * 1024));
+}
+
 pub unsafe fn translate_addr(addr: VirtAddr) -> Option<u64> {
     translate_addr_inner(addr)
 }
@@ -28,11 +262,7 @@ fn translate_addr_inner(addr: VirtAddr) -> Option<u64> {
         addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
     ];
 
-    let mut value: u64;
-    unsafe {
-        asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
-    }
-    let mut frame = value & 0x_000f_ffff_ffff_f000;
+    let mut frame = current_l4();
 
     for &index in &table_indexes {
         let virt = frame + VIRT_MAPPING_OFFSET;
@@ -49,3 +279,228 @@ fn translate_addr_inner(addr: VirtAddr) -> Option<u64> {
 
     Some(frame + u64::from(addr.get_page_offset()))
 }
+
+/// Resolves a write fault on a copy-on-write page: copies the frame and maps the copy writable.
+///
+/// Returns `false` if the page isn't a copy-on-write one or no frame could be allocated.
+pub(crate) unsafe fn handle_copy_on_write(addr: VirtAddr) -> bool {
+    let page = align_down(addr);
+    let l4_table = active_level_4_table();
+
+    let flags = match get_page_flags(l4_table, page, VIRT_MAPPING_OFFSET) {
+        Some(flags) => flags,
+        None => return false
+    };
+
+    if !flags.contains(COPY_ON_WRITE) || flags.contains(PageTableFlags::WRITABLE) {
+        return false;
+    }
+
+    // the fault might have happened while the allocator was locked
+    let mut allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(allocator) => allocator,
+        None => return false
+    };
+
+    let allocator = match allocator.as_mut() {
+        Some(allocator) => allocator,
+        None => return false
+    };
+
+    let old_frame = get_physical_address(l4_table, page, VIRT_MAPPING_OFFSET).unwrap();
+    let new_frame = match allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false
+    };
+
+    core::ptr::copy_nonoverlapping(
+        (old_frame + VIRT_MAPPING_OFFSET) as *const u8,
+        (new_frame + VIRT_MAPPING_OFFSET) as *mut u8,
+        4096);
+
+    remap_address_with_offset(l4_table, page, PhysAddr::new(new_frame), allocator, VIRT_MAPPING_OFFSET)
+        .expect("Failed to remap copy-on-write page");
+    protect_with_offset(l4_table, page, (flags | PageTableFlags::WRITABLE) - COPY_ON_WRITE, VIRT_MAPPING_OFFSET)
+        .expect("Failed to make copy-on-write page writable");
+
+    // the page no longer shares the old frame, the last sharer frees it
+    if allocator.refcount(old_frame) > 0 {
+        allocator.decref(old_frame).expect("Failed to release copied frame");
+    }
+
+    log::debug!("[memory] copied on write {} to frame {:#x}", page, new_frame);
+    true
+}
+
+/// Reserves `size` bytes starting at `start` for demand paging: pages stay unmapped until accessed,
+/// then the page fault handler maps a zeroed frame with `flags`.
+///
+/// The range must be page aligned and must not overlap another lazy region. Already mapped pages
+/// in the range are left as they are.
+pub fn reserve_lazy(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+    if !start.is_aligned(4096) || size % 4096 != 0 {
+        return Err("Lazy region is not page aligned");
+    }
+    if size == 0 {
+        return Err("Lazy region is empty");
+    }
+
+    let end = start.offset(size)?;
+    let mut regions = LAZY_REGIONS.lock();
+
+    if regions.iter().flatten().any(|region| start < region.end && region.start < end) {
+        return Err("Lazy region overlaps an existing one");
+    }
+
+    let slot = regions.iter_mut()
+        .find(|region| region.is_none())
+        .ok_or("Too many lazy regions")?;
+    *slot = Some(LazyRegion { start, end, flags: flags | PageTableFlags::PRESENT });
+
+    log::debug!("[memory] reserved lazy region {}..{}", start, end);
+    Ok(())
+}
+
+/// Removes the lazy region starting at `start`. Pages that were already touched stay mapped.
+pub fn release_lazy(start: VirtAddr) -> Result<(), &'static str> {
+    let mut regions = LAZY_REGIONS.lock();
+
+    let slot = regions.iter_mut()
+        .find(|region| matches!(region, Some(region) if region.start == start))
+        .ok_or("No lazy region starts at this address")?;
+    *slot = None;
+
+    Ok(())
+}
+
+/// Resolves a fault on a not present page inside a lazy region by mapping a zeroed frame.
+///
+/// Returns `false` if the address is outside all lazy regions or no frame could be allocated.
+pub(crate) unsafe fn handle_lazy_allocation(addr: VirtAddr) -> bool {
+    let page = align_down(addr);
+
+    // the fault might have happened while the registry or the allocator were locked
+    let region = match LAZY_REGIONS.try_lock() {
+        Some(regions) => regions.iter()
+            .flatten()
+            .find(|region| region.start <= page && page < region.end)
+            .copied(),
+        None => return false
+    };
+
+    let region = match region {
+        Some(region) => region,
+        None => return false
+    };
+
+    let mut allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(allocator) => allocator,
+        None => return false
+    };
+
+    let allocator = match allocator.as_mut() {
+        Some(allocator) => allocator,
+        None => return false
+    };
+
+    let frame = match allocator.allocate_frame_zeroed() {
+        Some(frame) => frame,
+        None => return false
+    };
+
+    let l4_table = active_level_4_table();
+    if let Err(err) = map_range_with_offset(l4_table, page, PhysAddr::new(frame), 4096, allocator, region.flags, VIRT_MAPPING_OFFSET) {
+        log::warn!("[memory] failed to map lazy page {}: {}", page, err);
+        allocator.deallocate_frame(frame)
+            .expect("Failed to free lazy frame");
+        return false;
+    }
+
+    log::trace!("[memory] allocated lazy page {} to frame {:#x}", page, frame);
+    true
+}
+
+/// Registers an unmapped page so faults on it are reported as stack overflows.
+pub fn register_guard_page(page: VirtAddr) -> Result<(), &'static str> {
+    let page = align_down(page);
+    let mut guard_pages = GUARD_PAGES.lock();
+
+    if guard_pages.contains(&Some(page)) {
+        return Err("Guard page is already registered");
+    }
+
+    let slot = guard_pages.iter_mut()
+        .find(|guard_page| guard_page.is_none())
+        .ok_or("Too many guard pages")?;
+    *slot = Some(page);
+    Ok(())
+}
+
+pub fn unregister_guard_page(page: VirtAddr) -> Result<(), &'static str> {
+    let page = align_down(page);
+    let mut guard_pages = GUARD_PAGES.lock();
+
+    let slot = guard_pages.iter_mut()
+        .find(|guard_page| **guard_page == Some(page))
+        .ok_or("Guard page is not registered")?;
+    *slot = None;
+    Ok(())
+}
+
+/// Returns true if the address lies in a registered guard page. Called from the fault handlers.
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let page = align_down(addr);
+
+    // the fault might have happened while the registry was locked
+    match GUARD_PAGES.try_lock() {
+        Some(guard_pages) => guard_pages.contains(&Some(page)),
+        None => false
+    }
+}
+
+/// Maps `pages` zeroed writable frames right below `top` and registers the page below them as a
+/// guard page, which has to stay unmapped. Returns the initial stack pointer.
+///
+/// # Safety
+/// `l4_table` must be the active level 4 table and the range must not be used by anything else.
+pub unsafe fn map_stack_with_guard(l4_table: &mut PageTable, top: VirtAddr, pages: u64, allocator: &mut FrameAllocator)
+    -> Result<VirtAddr, &'static str> {
+    if !top.is_aligned(4096) {
+        return Err("Stack top is not page aligned");
+    }
+    if pages == 0 {
+        return Err("Stack is empty");
+    }
+
+    let bottom = VirtAddr::new(top.0.checked_sub(pages * 4096).ok_or("Stack doesn't fit below its top")?);
+    let guard_page = VirtAddr::new(bottom.0.checked_sub(4096).ok_or("Guard page doesn't fit below the stack")?);
+
+    if get_page_flags(l4_table, guard_page, VIRT_MAPPING_OFFSET).is_some() {
+        return Err("Guard page is already mapped");
+    }
+
+    for i in 0..pages {
+        let page = bottom.offset(i * 4096)?;
+        let result = allocator.allocate_frame_zeroed()
+            .ok_or("Failed to allocate stack frame")
+            .and_then(|frame| {
+                map_address_with_offset(l4_table, page, PhysAddr::new(frame), allocator, VIRT_MAPPING_OFFSET)
+                    .map_err(|err| {
+                        allocator.deallocate_frame(frame).expect("Failed to free stack frame");
+                        err
+                    })
+            });
+
+        if let Err(err) = result {
+            for j in 0..i {
+                let page = bottom.offset(j * 4096)?;
+                unmap_address_with_offset(l4_table, page, allocator, VIRT_MAPPING_OFFSET)?;
+            }
+            return Err(err);
+        }
+    }
+
+    register_guard_page(guard_page)?;
+    log::debug!("[memory] mapped stack {}..{} with guard page {}", bottom, top, guard_page);
+    Ok(top)
+}
@@ -1,11 +1,17 @@
 use alloc::boxed::Box;
-use alloc::vec;
 use alloc::vec::Vec;
 use crate::ide::BlockDevice;
 use crate::pci::PciDevice::Drive;
 use crate::port::Port;
 
-unsafe fn pci_config_read_word(bus: u8, device: u8, func: u8, offset: u8) -> u16 {
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+
+const NO_DEVICE: u16 = 0xFFFF;
+const MULTIFUNCTION_BIT: u8 = 0x80;
+const BARS_COUNT: usize = 6;
+
+unsafe fn pci_config_read_dword(bus: u8, device: u8, func: u8, offset: u8) -> u32 {
     let address: u32 =
         (bus as u32) << 16
         | (device as u32) << 11
@@ -13,11 +19,15 @@ unsafe fn pci_config_read_word(bus: u8, device: u8, func: u8, offset: u8) -> u16
         | (offset as u32 & 0xFC)
         | 0x80000000u32;
 
-    let mut config_address_port = Port::new(0xCF8);
+    let mut config_address_port = Port::new(CONFIG_ADDRESS_PORT);
     config_address_port.write_u32(address);
 
-    let mut config_data_port = Port::new(0xCFC);
-    ((config_data_port.read_u32() >> ((offset & 2) * 8)) & 0xFFFF) as u16
+    let mut config_data_port = Port::new(CONFIG_DATA_PORT);
+    config_data_port.read_u32()
+}
+
+unsafe fn pci_config_read_word(bus: u8, device: u8, func: u8, offset: u8) -> u16 {
+    ((pci_config_read_dword(bus, device, func, offset) >> ((offset & 2) * 8)) & 0xFFFF) as u16
 }
 
 fn get_device_type(class_code: u8, subclass: u8, prog_if: u8) -> &'static str {
@@ -64,14 +74,30 @@ fn get_device_type(class_code: u8, subclass: u8, prog_if: u8) -> &'static str {
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct GenericPciDevice {
-    bus: u8,
-    device: u8,
-    function: u8,
-
-    class_code: u8,
-    subclass: u8,
-    prog_if: u8,
-    vendor_id: u16
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+
+    /// Raw base address registers. Only general devices (header type 0) have all six, bridges have
+    /// two and the rest are zero.
+    pub bars: [u32; BARS_COUNT]
+}
+
+impl GenericPciDevice {
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & MULTIFUNCTION_BIT != 0
+    }
+
+    fn is_pci_bridge(&self) -> bool {
+        self.class_code == 0x6 && self.subclass == 0x4
+    }
 }
 
 pub enum PciDevice {
@@ -79,73 +105,112 @@ pub enum PciDevice {
     Generic(GenericPciDevice)
 }
 
-async unsafe fn check_function(bus: u8, device: u8, func: u8, vendor_id: u16) -> Vec<PciDevice> {
+unsafe fn read_function(bus: u8, device: u8, func: u8) -> Option<GenericPciDevice> {
+    let vendor_id = pci_config_read_word(bus, device, func, 0);
+    if vendor_id == NO_DEVICE {
+        return None;
+    }
+
+    let device_id = pci_config_read_word(bus, device, func, 0x2);
     let [_bist, header_type] = pci_config_read_word(bus, device, func, 0xE).to_be_bytes();
     let [class_code, subclass] = pci_config_read_word(bus, device, func, 0xA).to_be_bytes();
     let [prog_if, _revision_id] = pci_config_read_word(bus, device, func, 0x8).to_be_bytes();
 
-    let device_type_str = get_device_type(class_code, subclass, prog_if);
+    let bars_count = match header_type & !MULTIFUNCTION_BIT {
+        0x0 => BARS_COUNT,
+        0x1 => 2,
+        _ => 0
+    };
 
-    let mut prefix = "";
-    if func != 0 {
-        prefix = "|--- ";
+    let mut bars = [0u32; BARS_COUNT];
+    for (i, bar) in bars.iter_mut().enumerate().take(bars_count) {
+        *bar = pci_config_read_dword(bus, device, func, 0x10 + 4 * i as u8);
     }
 
-    if device_type_str == "" {
-        log::info!("[pci] {}device #{} - vendor: {:#x}, header_type: {:#x}, class: {:#x}, subclass: {:#x}, func: {}", prefix, device, vendor_id, header_type, class_code, subclass, func);
+    let device_type_str = get_device_type(class_code, subclass, prog_if);
+
+    let prefix = if func != 0 { "|--- " } else { "" };
+
+    if device_type_str.is_empty() {
+        log::info!("[pci] {}{:02x}:{:02x}.{} - vendor: {:#x}, device: {:#x}, header_type: {:#x}, class: {:#x}, subclass: {:#x}", prefix, bus, device, func, vendor_id, device_id, header_type, class_code, subclass);
     } else {
-        log::info!("[pci] {}device #{} - vendor: {:#x}, header_type: {:#x}, func: {}, device_type: {}", prefix, device, vendor_id, header_type, func, device_type_str);
+        log::info!("[pci] {}{:02x}:{:02x}.{} - vendor: {:#x}, device: {:#x}, header_type: {:#x}, device_type: {}", prefix, bus, device, func, vendor_id, device_id, header_type, device_type_str);
     }
 
-    if class_code == 0x1 && subclass == 0x1 {
-        let drives = crate::ide::ide_initialize(prog_if).await;
-        return drives.into_iter().map(|a|Drive(Box::new(a))).collect();
-    }
-    vec![PciDevice::Generic(GenericPciDevice{ bus, device, function: func, class_code, subclass, prog_if, vendor_id })]
+    Some(GenericPciDevice { bus, device, function: func, vendor_id, device_id, class_code, subclass, prog_if, header_type, bars })
 }
 
-async unsafe fn check_device(bus: u8, device: u8) -> Vec<PciDevice> {
-    let vendor_id = pci_config_read_word(bus, device, 0, 0);
+unsafe fn check_device(bus: u8, device: u8, devices: &mut Vec<GenericPciDevice>) {
+    let first = match read_function(bus, device, 0) {
+        Some(first) => first,
+        // device doesn't exist
+        None => return
+    };
 
-    // device doesn't exist
-    if vendor_id == 0xFFFF {
-        return Vec::new();
-    }
-    let [_bist, header_type] = pci_config_read_word(bus, device, 0, 0xE).to_be_bytes();
-
-    if header_type & 0x80 != 0 {
-        // it's a multifunction device!
-        let [class_code, subclass] = pci_config_read_word(bus, device, 0, 0xA).to_be_bytes();
-        log::info!("[pci] multifunction device #{} - vendor: {:#x}, header_type: {:#x}, device_type: {}", device, vendor_id, header_type, get_device_type(class_code, subclass, 0));
-
-        let mut vec = Vec::new();
-        for func in 1..8 {
-            let vendor_id = pci_config_read_word(bus, device, func, 0);
-            if vendor_id != 0xFFFF {
-                vec.append(&mut check_function(bus, device, func, vendor_id).await);
-            }
+    let functions = if first.is_multifunction() { 8 } else { 1 };
+    check_function(first, devices);
+
+    for func in 1..functions {
+        if let Some(function) = read_function(bus, device, func) {
+            check_function(function, devices);
         }
-        return vec;
     }
+}
+
+unsafe fn check_function(function: GenericPciDevice, devices: &mut Vec<GenericPciDevice>) {
+    let secondary_bus = if function.is_pci_bridge() {
+        let [_latency_timer, _subordinate_bus, secondary_bus, _primary_bus] =
+            pci_config_read_dword(function.bus, function.device, function.function, 0x18).to_be_bytes();
+        Some(secondary_bus)
+    } else {
+        None
+    };
+
+    devices.push(function);
+
+    if let Some(secondary_bus) = secondary_bus {
+        check_bus(secondary_bus, devices);
+    }
+}
 
-    check_function(bus, device, 0, vendor_id).await
+unsafe fn check_bus(bus: u8, devices: &mut Vec<GenericPciDevice>) {
+    for device in 0..32 {
+        check_device(bus, device, devices);
+    }
 }
 
-pub async fn init_pci() -> Vec<PciDevice> {
-    let mut vec = Vec::new();
+/// Scans all PCI buses reachable from the host controllers through the configuration space ports.
+pub fn enumerate() -> Vec<GenericPciDevice> {
+    let mut devices = Vec::new();
     unsafe {
         let [_bist, header_type] = pci_config_read_word(0, 0, 0, 0xE).to_be_bytes();
 
-        if header_type & 0x80 == 0 {
+        if header_type & MULTIFUNCTION_BIT == 0 {
             // Single PCI host controller
-            // checking bus #0
-            for device in 0..32 {
-                vec.append(&mut check_device(0, device).await);
+            check_bus(0, &mut devices);
+        } else {
+            // Multiple PCI host controllers, function N of 00:00 is responsible for bus N
+            for func in 0..8 {
+                if pci_config_read_word(0, 0, func, 0) != NO_DEVICE {
+                    check_bus(func, &mut devices);
+                }
             }
+        }
+    }
+    devices
+}
+
+pub async fn init_pci() -> Vec<PciDevice> {
+    let mut vec = Vec::new();
+
+    for device in enumerate() {
+        if device.class_code == 0x1 && device.subclass == 0x1 {
+            let drives = crate::ide::ide_initialize(device.prog_if).await;
+            vec.extend(drives.into_iter().map(|a| Drive(Box::new(a)) as PciDevice));
         } else {
-            // Multiple PCI host controllers
-            unimplemented!();
+            vec.push(PciDevice::Generic(device));
         }
     }
+
     vec
-}
\ No newline at end of file
+}
@@ -0,0 +1,47 @@
+use crate::apic;
+
+/// Number of CPUs per-CPU data has room for. Entries are indexed by the local APIC ID, so CPUs
+/// with a higher ID are not started.
+pub const MAX_CPUS: usize = 64;
+
+/// Returns the local APIC ID of the CPU running this code. Before the local APIC is initialized
+/// only the BSP runs, so it's 0.
+pub fn this_cpu() -> u8 {
+    apic::local_apic_id()
+}
+
+/// One `T` per CPU, indexed by the local APIC ID.
+///
+/// Values can be read from any CPU, so `T` usually is an atomic or a lock. Code running on its own
+/// CPU uses `get`, which never contends with other CPUs.
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS]
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    /// Returns the value of the CPU running this code.
+    pub fn get(&self) -> &T {
+        self.get_for(this_cpu())
+    }
+
+    /// Returns the value of the CPU with the given local APIC ID.
+    pub fn get_for(&self, cpu: u8) -> &T {
+        &self.values[cpu as usize]
+    }
+
+    /// Iterates over the values of all CPUs as `(local APIC ID, value)`, including CPUs that
+    /// don't exist.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &T)> {
+        self.values.iter().enumerate().map(|(cpu, value)| (cpu as u8, value))
+    }
+}
+
+impl<T: Default> Default for PerCpu<T> {
+    fn default() -> Self {
+        PerCpu { values: core::array::from_fn(|_| T::default()) }
+    }
+}
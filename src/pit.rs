@@ -0,0 +1,122 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared_lib::get_tsc;
+use crate::port::Port;
+
+/// Input clock of the 8253/8254 PIT in Hz
+pub const PIT_BASE_FREQUENCY: u32 = 1193182;
+
+static PIT_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Programs PIT channel 0 as a rate generator firing `hz` times per second and returns the
+/// frequency actually achieved. The divisor is clamped to what the 16 bit counter can hold.
+///
+/// IRQ0 is routed to the timer vector but masked, the caller has to unmask its GSI (see
+/// `ioapic::isa_route`) for the interrupts to reach the kernel and should then pass the result to
+/// `timer::set_tick_frequency`.
+pub fn init_timer(hz: u32) -> u32 {
+    // a reload value of 0 means 65536, mode 2 doesn't support a reload value of 1
+    let divisor = (PIT_BASE_FREQUENCY / hz.max(1)).clamp(2, 0x10000);
+    let achieved = PIT_BASE_FREQUENCY / divisor;
+
+    let mut command = Port::new(0x43);
+    let mut channel0 = Port::new(0x40);
+
+    unsafe {
+        // channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+        command.write(0b0011_0100);
+        channel0.write((divisor & 0xff) as u8);
+        channel0.write(((divisor >> 8) & 0xff) as u8);
+    }
+
+    PIT_FREQUENCY.store(achieved, Ordering::Relaxed);
+    log::info!("[pit] channel 0 divisor: {}, frequency: {} Hz", divisor, achieved);
+    achieved
+}
+
+/// Returns the frequency set by [`init_timer`], 0 if the PIT hasn't been programmed.
+pub fn frequency() -> u32 {
+    PIT_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Latches and returns the current count of channel 0, it counts down from the divisor.
+pub fn current_count() -> u16 {
+    let mut command = Port::new(0x43);
+    let mut channel0 = Port::new(0x40);
+
+    unsafe {
+        // latch command for channel 0
+        command.write(0u8);
+        let low: u8 = channel0.read();
+        let high: u8 = channel0.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/*
+ * Try to calibrate the TSC against the Programmable
+ * Interrupt Timer and return the frequency of the TSC
+ * in kHz.
+ *
+ * Return ULONG_MAX on failure to calibrate.
+ */
+pub fn pit_calibrate_tsc(latch: u32, ms: u64, loop_min: u16) -> u64 {
+    unsafe {
+        // Set the Gate high, disable speaker
+        let mut pit_channel2_gate = Port::new(0x61);
+        {
+            let v = (pit_channel2_gate.read() & 0xfd) | 0x1;
+            pit_channel2_gate.write(v);
+        }
+
+        /*
+         * Setup CTC channel 2* for mode 0, (interrupt on terminal
+         * count mode), binary count. Set the latch register to 50ms
+         * (LSB then MSB) to begin countdown.
+         */
+        let mut pit_channel2_command = Port::new(0x43);
+        pit_channel2_command.write(0xb0);
+
+        let mut pit_channel2_data = Port::new(0x42);
+        pit_channel2_data.write((latch & 0xff) as u8);
+        pit_channel2_data.write((latch >> 8) as u8);
+
+        let mut tsc = get_tsc();
+        let t1 = tsc;
+        let mut t2 = tsc;
+        let mut delta;
+        let mut tsc_max: u64 = 0;
+        let mut tsc_min: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+        let mut pitcnt = 0;
+
+        while (pit_channel2_gate.read() & 0x20) == 0 {
+            t2 = get_tsc();
+            delta = t2 - tsc;
+            tsc = t2;
+            if delta < tsc_min {
+                tsc_min = delta;
+            }
+            if delta > tsc_max {
+                tsc_max = delta;
+            }
+            pitcnt += 1;
+        }
+
+        log::info!("PIT values: {} {} {}", pitcnt, tsc_min, tsc_max);
+        /*
+         * Sanity checks:
+         *
+         * If we were not able to read the PIT more than loopmin
+         * times, then we have been hit by a massive SMI
+         *
+         * If the maximum is 10 times larger than the minimum,
+         * then we got hit by an SMI as well.
+         */
+        if pitcnt < loop_min || tsc_max > 10 * tsc_min {
+            return 0xFFFF_FFFF_FFFF_FFFF;
+        }
+
+        delta = t2 - t1;
+        log::info!("PIT: delta: {}", delta);
+        delta / ms
+    }
+}
@@ -0,0 +1,101 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use shared_lib::addr::VirtAddr;
+use crate::idt::{lidt, DescriptorTablePointer};
+use crate::port::Port;
+use crate::tsc;
+
+const PS2_STATUS_COMMAND: u16 = 0x64;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+// pulses the CPU reset line of the keyboard controller
+const PS2_RESET_PULSE: u8 = 0xFE;
+const PS2_TIMEOUT: usize = 100_000;
+
+/// Time the panic message stays on the screen before `PanicAction::Reboot` reboots.
+pub const PANIC_REBOOT_DELAY_MS: u64 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicAction {
+    Halt,
+    Reboot
+}
+
+impl PanicAction {
+    /// Parses the value of the `panic=` command line option.
+    pub fn from_name(name: &str) -> Option<PanicAction> {
+        match name {
+            "halt" => Some(PanicAction::Halt),
+            "reboot" => Some(PanicAction::Reboot),
+            _ => None
+        }
+    }
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+    log::info!("[power] on panic: {:?}", action);
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        action if action == PanicAction::Reboot as u8 => PanicAction::Reboot,
+        _ => PanicAction::Halt
+    }
+}
+
+/// Called first by the panic handler. Reboots right away if a panic is already being handled, the
+/// handler itself panicked or another CPU panicked at the same time.
+pub fn enter_panic() {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        reboot();
+    }
+}
+
+/// Called last by the panic handler, once the message is printed: halts or reboots after
+/// `PANIC_REBOOT_DELAY_MS`, as `set_panic_action` chose.
+pub fn finish_panic() -> ! {
+    if panic_action() == PanicAction::Reboot {
+        tsc::busy_sleep_us(PANIC_REBOOT_DELAY_MS * 1000);
+        reboot();
+    }
+
+    loop {
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+/// Resets the machine through the keyboard controller. If that doesn't work, an empty IDT turns
+/// the next exception into a triple fault, which resets the CPU.
+pub fn reboot() -> ! {
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+
+        let mut status = Port::new(PS2_STATUS_COMMAND);
+        for _ in 0..PS2_TIMEOUT {
+            if status.read() & PS2_STATUS_INPUT_FULL == 0 {
+                status.write(PS2_RESET_PULSE);
+                break;
+            }
+        }
+
+        // the reset takes a moment, don't triple fault before it
+        for _ in 0..PS2_TIMEOUT {
+            core::hint::spin_loop();
+        }
+
+        lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) });
+        asm!("int3", options(nomem, nostack));
+    }
+
+    loop {
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::{cpu, get_tsc};
+
+// Intel recommends giving up after 10 failed RDRAND attempts, a failure that persistent means
+// the hardware is broken. RDSEED waits for fresh entropy and fails much more often.
+const RDRAND_RETRIES: usize = 10;
+const RDSEED_RETRIES: usize = 100;
+
+// state of the fallback xorshift generator, 0 until it's seeded
+static XORSHIFT_STATE: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_rdrand_supported() -> bool {
+    cpu::features().rdrand
+}
+
+pub fn is_rdseed_supported() -> bool {
+    cpu::features().rdseed
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..RDSEED_RETRIES {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+fn xorshift_next(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+// xorshift64 seeded from the TSC. It's predictable, but better than nothing on CPUs without
+// RDRAND.
+fn xorshift() -> u64 {
+    let mut state = XORSHIFT_STATE.load(Ordering::Relaxed);
+    loop {
+        // xorshift never leaves 0, so the seed must not be 0
+        let current = if state == 0 { get_tsc() | 1 } else { state };
+        let next = xorshift_next(current);
+
+        match XORSHIFT_STATE.compare_exchange_weak(state, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(actual) => state = actual
+        }
+    }
+}
+
+/// Returns a random number from RDRAND, or from a TSC seeded xorshift on CPUs without it.
+/// Returns `None` when RDRAND keeps failing, which means the hardware generator is broken.
+pub fn random_u64() -> Option<u64> {
+    if is_rdrand_supported() {
+        rdrand()
+    } else {
+        Some(xorshift())
+    }
+}
+
+/// Returns a number from RDSEED, which is meant for seeding other generators, falling back to
+/// [`random_u64`] on CPUs without it. Returns `None` when no entropy is available.
+pub fn random_seed() -> Option<u64> {
+    if is_rdseed_supported() {
+        rdseed()
+    } else {
+        random_u64()
+    }
+}
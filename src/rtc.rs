@@ -0,0 +1,102 @@
+use chrono::{DateTime, TimeZone};
+use crate::port::Port;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const STATUS_REGISTER_A: u8 = 0x0A;
+const STATUS_REGISTER_B: u8 = 0x0B;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const BINARY_MODE: u8 = 0x04;
+const HOUR_FORMAT_24: u8 = 0x02;
+const HOUR_PM: u8 = 0x80;
+
+// used when the firmware doesn't provide the century register
+const DEFAULT_CENTURY: u8 = 20;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RtcRegisters {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut cmos_address_port = Port::new(CMOS_ADDRESS_PORT);
+    let mut cmos_data_port = Port::new(CMOS_DATA_PORT);
+
+    unsafe {
+        cmos_address_port.write(reg);
+        cmos_data_port.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(STATUS_REGISTER_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn read_registers() -> RtcRegisters {
+    while update_in_progress() {}
+
+    RtcRegisters {
+        second: read_register(0x00),
+        minute: read_register(0x02),
+        hour: read_register(0x04),
+        day: read_register(0x07),
+        month: read_register(0x08),
+        year: read_register(0x09),
+        century: read_register(0x32)
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + ((value >> 4) * 10)
+}
+
+/// Reads the current date and time from the CMOS real time clock.
+pub fn read_rtc() -> DateTime<chrono::Utc> {
+    // an update may start between the flag check and the reads, so read until two reads agree
+    let mut registers = read_registers();
+    loop {
+        let last = registers;
+        registers = read_registers();
+        if registers == last {
+            break;
+        }
+    }
+
+    let register_b = read_register(STATUS_REGISTER_B);
+    let is_pm = registers.hour & HOUR_PM != 0;
+    registers.hour &= !HOUR_PM;
+
+    // Convert BCD to binary values if necessary
+    if register_b & BINARY_MODE == 0 {
+        registers.second = bcd_to_binary(registers.second);
+        registers.minute = bcd_to_binary(registers.minute);
+        registers.hour = bcd_to_binary(registers.hour);
+        registers.day = bcd_to_binary(registers.day);
+        registers.month = bcd_to_binary(registers.month);
+        registers.year = bcd_to_binary(registers.year);
+        registers.century = bcd_to_binary(registers.century);
+    }
+
+    // Convert 12-hour clock to 24-hour clock if necessary, 12 AM is midnight
+    if register_b & HOUR_FORMAT_24 == 0 {
+        registers.hour %= 12;
+        if is_pm {
+            registers.hour += 12;
+        }
+    }
+
+    let century = if registers.century == 0 { DEFAULT_CENTURY } else { registers.century };
+
+    chrono::Utc.with_ymd_and_hms(century as i32 * 100 + registers.year as i32, registers.month as u32, registers.day as u32,
+                                 registers.hour as u32, registers.minute as u32, registers.second as u32)
+        .single()
+        .expect("RTC returned an invalid date")
+}
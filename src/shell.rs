@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use core::fmt::Write;
 use core::sync::atomic::Ordering::Relaxed;
+use shared_lib::allocator::heap_stats;
 use shared_lib::logger::{FrameBufferInfo, Logger};
 use crate::task::executor::STOP;
 
@@ -11,12 +12,18 @@ pub struct Shell {
 
 impl Shell {
     pub fn new(fb_info: FrameBufferInfo) -> Self {
-        let mut logger = Logger::new(fb_info);
+        let mut logger = Logger::new_double_buffered(fb_info);
         logger.write_str("# ").unwrap();
+        logger.flush();
         Shell{ logger, input_buffer: Vec::new() }
     }
 
     pub fn char_input(&mut self, c: char) {
+        self.handle_char(c);
+        self.logger.flush();
+    }
+
+    fn handle_char(&mut self, c: char) {
         self.logger.write_char(c);
         if c != '\n' {
             self.input_buffer.push(c);
@@ -30,7 +37,10 @@ impl Shell {
         } else if self.input_buffer == [ 'h', 'e', 'l', 'p' ] {
             self.logger.write_str("This is Rust OS! Commands list:\n").unwrap();
             self.logger.write_str("- help\n").unwrap();
+            self.logger.write_str("- heap\n").unwrap();
             self.logger.write_str("- shutdown\n").unwrap();
+        } else if self.input_buffer == ['h', 'e', 'a', 'p'] {
+            writeln!(self.logger, "{}", heap_stats()).unwrap();
         }
 
         self.input_buffer.clear();
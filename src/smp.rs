@@ -0,0 +1,275 @@
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::page_table::{get_physical_address, map_address_with_offset, unmap_address_with_offset};
+use shared_lib::VIRT_MAPPING_OFFSET;
+use crate::{apic, fpu, gdt, interrupts, memory, syscall, tsc};
+use crate::percpu::{this_cpu, MAX_CPUS};
+use crate::memory::active_level_4_table;
+use crate::task::executor::Executor;
+use crate::xsdt::read_local_apic_ids;
+
+/// Stack size of every application processor.
+pub const AP_STACK_PAGES: usize = 16;
+
+// The SIPI vector is the number of a page below 1 MiB
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+const IA32_EFER: u32 = 0xC000_0080;
+
+// The AP starts in real mode at the start of the trampoline page with CS = page >> 4. It enables
+// PAE, loads the kernel page tables and EFER, then sets PE and PG at once, going straight to long
+// mode, and calls `TrampolineData::entry` on the given stack. The trampoline page has to be
+// identity mapped, as the AP keeps executing it right after paging is enabled.
+//
+// The assembler can't use label differences in memory operands, so the layout is fixed with .org:
+// data at 0x08, GDT at 0x30, GDT pointer at 0x48, 16-bit code at 0x50 and 64-bit code at 0x100.
+global_asm!(
+    ".pushsection .text.smp_trampoline, \"ax\"",
+    ".global smp_trampoline_start",
+    ".global smp_trampoline_data",
+    ".global smp_trampoline_end",
+    ".code16",
+    "smp_trampoline_start:",
+    "    jmp 2f",
+    ".org 0x08",
+    "smp_trampoline_data:",
+    "    .fill 5, 8, 0",
+    ".org 0x30",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    ".org 0x48",
+    "    .word 23",
+    "    .long 0",
+    ".org 0x50",
+    "2:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    mov ss, ax",
+    "    mov sp, 0x1000",
+    // linear address of the trampoline
+    "    xor ebx, ebx",
+    "    mov bx, ax",
+    "    shl ebx, 4",
+    "    lea eax, [ebx + 0x30]",
+    "    mov dword ptr [0x4a], eax",
+    "    lgdt [0x48]",
+    "    mov eax, cr4",
+    "    or eax, 1 << 5",
+    "    mov cr4, eax",
+    "    mov eax, dword ptr [0x08]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080",
+    "    mov eax, dword ptr [0x10]",
+    "    mov edx, dword ptr [0x14]",
+    "    wrmsr",
+    "    mov eax, cr0",
+    "    or eax, 0x80000001",
+    "    mov cr0, eax",
+    // far return to the 64-bit code segment
+    "    mov eax, 8",
+    "    push eax",
+    "    lea eax, [ebx + 0x100]",
+    "    push eax",
+    "    .byte 0x66, 0xcb",
+    ".org 0x100",
+    ".code64",
+    "    mov ax, 16",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    xor eax, eax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov rsp, qword ptr [rip + smp_trampoline_data + 16]",
+    "    mov rax, qword ptr [rip + smp_trampoline_data + 24]",
+    "    mov rdi, qword ptr [rip + smp_trampoline_data + 32]",
+    "    xor ebp, ebp",
+    "    call rax",
+    "3:",
+    "    hlt",
+    "    jmp 3b",
+    "smp_trampoline_end:",
+    ".popsection"
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_data: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// Layout of `smp_trampoline_data`, filled for each AP before it's started.
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_index: u64
+}
+
+// number of application processors that reached `ap_entry`
+static AP_STARTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of running CPUs, including the BSP.
+pub fn cpu_count() -> usize {
+    1 + AP_STARTED.load(Ordering::Acquire)
+}
+
+extern "C" fn ap_entry(cpu_index: u64) -> ! {
+    fpu::init_fpu();
+    gdt::init_ap();
+    syscall::init();
+    memory::init_pat();
+    interrupts::init_idt();
+    apic::initialize_ap_apic();
+
+    AP_STARTED.fetch_add(1, Ordering::Release);
+    log::info!("[smp] CPU {} is up, APIC ID: {}", cpu_index, this_cpu());
+
+    // no interrupts are routed here yet, so the executor sleeps until it gets tasks
+    let mut executor = Executor::new();
+    executor.run();
+
+    loop {
+        unsafe {
+            asm!("cli; hlt", options(nomem, nostack));
+        }
+    }
+}
+
+fn read_efer() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") IA32_EFER, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3
+}
+
+fn wait_for_ap(started: usize, timeout_us: u64) -> bool {
+    let deadline = shared_lib::get_tsc() + tsc::us_to_cycles(timeout_us);
+    while shared_lib::get_tsc() < deadline {
+        if AP_STARTED.load(Ordering::Acquire) > started {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+
+    AP_STARTED.load(Ordering::Acquire) > started
+}
+
+/// Sends INIT and up to two SIPIs, returns true once the AP reached `ap_entry`.
+fn start_ap(apic_id: u8, vector: u8) -> bool {
+    let started = AP_STARTED.load(Ordering::Acquire);
+
+    unsafe { interrupts::APIC.lock().send_ipi(apic_id, apic::ICR_INIT | apic::ICR_LEVEL_ASSERT); }
+    tsc::busy_sleep_us(10_000);
+
+    for _ in 0..2 {
+        unsafe { interrupts::APIC.lock().send_ipi(apic_id, apic::ICR_STARTUP | vector as u32); }
+        if wait_for_ap(started, 100_000) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Starts all application processors listed in the MADT. Each AP gets its own stack, GDT and
+/// `Executor` and loads the shared IDT. Must be called by the BSP after the local APIC and the
+/// TSC are initialized. Returns the number of started APs.
+pub fn init(allocator: &mut FrameAllocator) -> Result<usize, &'static str> {
+    let bsp_id = this_cpu();
+    let ap_ids: Vec<u8> = read_local_apic_ids().into_iter()
+        .filter(|&id| id != bsp_id)
+        .filter(|&id| {
+            // per-CPU data is indexed by the APIC ID
+            let supported = (id as usize) < MAX_CPUS;
+            if !supported {
+                log::warn!("[smp] APIC ID {} is out of per-CPU data range", id);
+            }
+            supported
+        })
+        .collect();
+    if ap_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let cr3 = read_cr3();
+    if cr3 > u32::MAX as u64 {
+        return Err("Kernel page tables are above 4 GiB");
+    }
+
+    let trampoline = allocator.allocate_frame_below(TRAMPOLINE_LIMIT)
+        .ok_or("No free frame below 1 MiB for the AP trampoline")?;
+
+    let l4_table = unsafe { active_level_4_table() };
+    let trampoline_virt = VirtAddr::new(trampoline);
+    let identity_mapped = unsafe { get_physical_address(l4_table, trampoline_virt, VIRT_MAPPING_OFFSET) } == Some(trampoline);
+    if !identity_mapped {
+        unsafe { map_address_with_offset(l4_table, trampoline_virt, PhysAddr::new(trampoline), allocator, VIRT_MAPPING_OFFSET)?; }
+    }
+
+    let (code, data_offset) = unsafe {
+        let start = addr_of!(smp_trampoline_start);
+        let len = addr_of!(smp_trampoline_end) as usize - start as usize;
+        (core::slice::from_raw_parts(start, len), addr_of!(smp_trampoline_data) as usize - start as usize)
+    };
+
+    let trampoline_ptr = (trampoline + VIRT_MAPPING_OFFSET) as *mut u8;
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), trampoline_ptr, code.len()); }
+    let data = unsafe { trampoline_ptr.add(data_offset) } as *mut TrampolineData;
+
+    let mut started = 0;
+    let mut failed = false;
+    for (index, &apic_id) in ap_ids.iter().enumerate() {
+        let stack = allocator.allocate_contiguous(AP_STACK_PAGES, 4096)?;
+        let stack_top = stack + VIRT_MAPPING_OFFSET + (AP_STACK_PAGES * 4096) as u64;
+
+        unsafe {
+            data.write_volatile(TrampolineData {
+                cr3,
+                efer: read_efer(),
+                stack_top,
+                entry: ap_entry as usize as u64,
+                cpu_index: index as u64 + 1
+            });
+        }
+
+        if start_ap(apic_id, (trampoline >> 12) as u8) {
+            started += 1;
+        } else {
+            // the stack stays allocated, the AP might still wake up and use it
+            log::warn!("[smp] CPU with APIC ID {} didn't start", apic_id);
+            failed = true;
+        }
+    }
+
+    // a late AP would run whatever ends up in the trampoline page
+    if !failed {
+        if identity_mapped {
+            allocator.deallocate_frame(trampoline)?;
+        } else {
+            // unmapping drops the only reference and frees the frame
+            unsafe { unmap_address_with_offset(l4_table, trampoline_virt, allocator, VIRT_MAPPING_OFFSET)?; }
+        }
+    }
+
+    log::info!("[smp] {} CPUs running", cpu_count());
+    Ok(started)
+}
@@ -0,0 +1,27 @@
+use shared_lib::get_tsc;
+use crate::rand;
+
+/// Canary the code compiled with `-Z stack-protector` places between the locals and the return
+/// address. On a bare metal target LLVM reads it from this global instead of thread local storage.
+/// Until `init_stack_guard` runs it holds a fixed value, which still catches accidental overflows.
+#[no_mangle]
+pub static mut __stack_chk_guard: u64 = 0x595e_9fbd_94fd_a700;
+
+/// Called by a protected function whose canary was overwritten before it returned.
+#[no_mangle]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    panic!("Stack smashing detected");
+}
+
+/// Replaces the canary with a random one.
+///
+/// Protected functions that are running compare their saved canary with the new one when they
+/// return, so this must be inlined into a function that never returns, before anything else runs.
+#[inline(always)]
+pub fn init_stack_guard() {
+    // the low byte stays 0, so a string overflow can't write the canary back
+    let guard = rand::random_u64().unwrap_or_else(get_tsc) & !0xff;
+    unsafe {
+        core::ptr::write_volatile(core::ptr::addr_of_mut!(__stack_chk_guard), guard);
+    }
+}
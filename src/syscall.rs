@@ -0,0 +1,214 @@
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use shared_lib::addr::VirtAddr;
+use shared_lib::page_table::{get_page_flags, PageTableFlags, PAGE_SIZE};
+use shared_lib::VIRT_MAPPING_OFFSET;
+use crate::gdt::{self, TaskStateSegment, KERNEL_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::active_level_4_table;
+use crate::percpu::{PerCpu, MAX_CPUS};
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const EFER_SCE: u64 = 1 << 0;
+
+// TF, IF, DF and AC are cleared on entry. With IF clear nothing can interrupt the entry before it
+// switched to the kernel stack, and the handlers run with interrupts disabled.
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+// end of the lower half, user pointers have to be below it
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+
+pub const EBADF: i64 = 9;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENOSYS: i64 = 38;
+
+/// Registers of the calling thread, saved by `syscall_entry` on the kernel stack. The number is in
+/// RAX and the arguments follow the System V order, with R10 in place of RCX, which holds the
+/// return address. The return value goes to RAX, everything else except RCX and R11 is preserved.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// user RFLAGS
+    pub r11: u64,
+    /// user RIP
+    pub rcx: u64,
+    pub user_rsp: u64
+}
+
+/// Returns the result of the syscall: a value or a negated error number.
+pub type SyscallHandler = fn(&mut SyscallFrame) -> i64;
+
+const SYSCALL_TABLE: [SyscallHandler; 2] = [sys_write, |frame| sys_exit(frame)];
+
+/// Per-CPU data `syscall_entry` reaches through GS after SWAPGS. The layout is used by the
+/// assembly below.
+#[repr(C)]
+struct CpuData {
+    // TSS of the CPU, RSP0 is the kernel stack the entry switches to
+    tss: AtomicPtr<TaskStateSegment>,
+    // user RSP while the syscall runs
+    user_rsp: AtomicU64
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CPU_DATA: CpuData = CpuData { tss: AtomicPtr::new(core::ptr::null_mut()), user_rsp: AtomicU64::new(0) };
+static CPU_DATA: PerCpu<CpuData> = PerCpu::new([NO_CPU_DATA; MAX_CPUS]);
+
+// SYSCALL leaves the user RSP alone, so the entry first swaps GS to the per-CPU data, stashes the
+// user RSP there and loads RSP0 from the TSS. The saved registers form a `SyscallFrame`; with a
+// 16 byte aligned RSP0 the stack is aligned again for the call after the ten pushes. On the way
+// back the user RSP is restored last, GS is swapped back, and SYSRET returns to RCX with the
+// RFLAGS from R11. `CpuData::tss` is at gs:[0], `CpuData::user_rsp` at gs:[8] and RSP0 at offset 4
+// of the TSS.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "    swapgs",
+    "    mov gs:[8], rsp",
+    "    mov rsp, gs:[0]",
+    "    mov rsp, [rsp + 4]",
+    "    push qword ptr gs:[8]",
+    "    push rcx",
+    "    push r11",
+    "    push r9",
+    "    push r8",
+    "    push r10",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rax",
+    "    mov rdi, rsp",
+    "    call {dispatch}",
+    "    add rsp, 8",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop r10",
+    "    pop r8",
+    "    pop r9",
+    "    pop r11",
+    "    pop rcx",
+    "    pop rsp",
+    "    swapgs",
+    "    sysretq",
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+unsafe fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+}
+
+/// Enables SYSCALL on the calling CPU, entering `syscall_entry` on the stack set with
+/// `gdt::set_kernel_stack`. Has to be called on every CPU after its GDT is loaded.
+pub fn init() {
+    let tss = gdt::current_tss();
+    assert!(!tss.is_null(), "GDT of this CPU is not loaded");
+
+    let cpu_data = CPU_DATA.get();
+    cpu_data.tss.store(tss, Ordering::Release);
+
+    // SYSRET adds 8 for SS and 16 for CS to the selector in STAR[63:48]
+    let star = ((USER_DATA_SELECTOR.0 as u64 - 8) << 48) | ((KERNEL_CODE_SELECTOR.0 as u64) << 32);
+
+    unsafe {
+        write_msr(IA32_STAR, star);
+        write_msr(IA32_LSTAR, syscall_entry as usize as u64);
+        write_msr(IA32_FMASK, SYSCALL_RFLAGS_MASK);
+        write_msr(IA32_KERNEL_GS_BASE, cpu_data as *const CpuData as u64);
+        write_msr(IA32_EFER, read_msr(IA32_EFER) | EFER_SCE);
+    }
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) -> u64 {
+    // SYSRET to a non-canonical RCX faults in ring 0 on Intel CPUs. A SYSCALL at the very end of
+    // the lower half would return there, so such a thread isn't allowed to continue.
+    if frame.rcx >= USER_SPACE_END {
+        log::error!("[syscall] return address {:#x} is not canonical", frame.rcx);
+        sys_exit(frame);
+    }
+
+    dispatch(frame) as u64
+}
+
+/// Runs the handler for the syscall number in `frame.rax` and returns its result.
+pub fn dispatch(frame: &mut SyscallFrame) -> i64 {
+    match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => -ENOSYS
+    }
+}
+
+/// Returns the user memory at `addr` if all of it is mapped user accessible.
+fn user_slice(addr: u64, len: u64) -> Option<&'static [u8]> {
+    let end = addr.checked_add(len)?;
+    if end > USER_SPACE_END {
+        return None;
+    }
+
+    let l4_table = unsafe { active_level_4_table() };
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let flags = unsafe { get_page_flags(l4_table, VirtAddr::new(page), VIRT_MAPPING_OFFSET)? };
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return None;
+        }
+        page += PAGE_SIZE;
+    }
+
+    Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+/// write(fd, buf, len): writes UTF-8 text to the log, for fd 1 and 2. Returns the number of bytes
+/// written.
+fn sys_write(frame: &mut SyscallFrame) -> i64 {
+    let (fd, addr, len) = (frame.rdi, frame.rsi, frame.rdx);
+    if fd != 1 && fd != 2 {
+        return -EBADF;
+    }
+
+    let Some(bytes) = user_slice(addr, len) else {
+        return -EFAULT;
+    };
+    let Ok(text) = core::str::from_utf8(bytes) else {
+        return -EINVAL;
+    };
+
+    log::info!("[syscall] {}", text);
+    len as i64
+}
+
+/// exit(code): there are no processes to return to yet, so the calling CPU is parked.
+fn sys_exit(frame: &mut SyscallFrame) -> ! {
+    log::info!("[syscall] exit with code {}", frame.rdi as i64);
+
+    loop {
+        unsafe {
+            asm!("sti; hlt", options(nomem, nostack));
+        }
+    }
+}
@@ -1,55 +1,227 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{JoinHandle, Priority, Task, TaskId, PRIORITY_LEVELS};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::future::Future;
 use core::task::Waker;
 use crossbeam_queue::ArrayQueue;
 use core::task::{Context, Poll};
 use alloc::task::Wake;
 use core::arch::asm;
-use core::sync::atomic::AtomicBool;
-use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use core::sync::atomic::Ordering::{AcqRel, Relaxed, Release};
+use conquer_once::spin::OnceCell;
+use core::ptr::NonNull;
+use shared_lib::allocator::slab::SlabCache;
+use crate::memory::FRAME_ALLOCATOR;
+use crate::percpu::{PerCpu, MAX_CPUS};
+use crate::task::timer;
 
 pub static STOP: AtomicBool = AtomicBool::new(false);
 
+/// Time `Executor::shutdown` gives the remaining tasks by default.
+pub const SHUTDOWN_TIMEOUT_MS: u64 = 1000;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Returns true while `Executor::shutdown` polls the remaining tasks a last time. Tasks should
+/// finish their work then, e.g. flush buffers, instead of waiting for more.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Relaxed)
+}
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 100;
+
+/// Number of task IDs woken with `wake_task_id` that can wait for their executor at once.
+pub const WAKE_QUEUE_CAPACITY: usize = 256;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WOKEN_TASKS: OnceCell<ArrayQueue<TaskId>> = OnceCell::uninit();
+
+// created by the first executor on each CPU. A task ID is pushed to all of them, the executor
+// owning the task picks it up and the others drop it
+static WOKEN_TASKS: PerCpu<OnceCell<ArrayQueue<TaskId>>> = PerCpu::new([NO_WOKEN_TASKS; MAX_CPUS]);
+
+// bumped whenever a woken task ID didn't fit, every executor then requeues all its tasks
+static LOST_WAKEUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Wakes the task with the given ID, so interrupt handlers can wake tasks without holding a
+/// `Waker`. The executor owning the task polls it again the next time it looks for ready tasks.
+/// Waking a finished task does nothing.
+///
+/// Must not block or allocate, so it can be called from interrupt handlers.
+pub fn wake_task_id(task_id: TaskId) {
+    for (_, woken) in WOKEN_TASKS.iter() {
+        // CPUs without an executor have nothing to wake
+        if let Ok(woken) = woken.try_get() {
+            if woken.push(task_id).is_err() {
+                LOST_WAKEUPS.fetch_add(1, Relaxed);
+            }
+        }
+    }
+}
+
+/// Every N-th task is taken starting from the lowest priority queue, so busy high priority tasks
+/// can't starve the rest.
+const STARVATION_LIMIT: usize = 8;
+
+/// Polls tasks on the CPU it was created on. Tasks woken with `wake_task_id` are only picked up
+/// if no other executor runs on the same CPU at the same time.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    // tasks live in slab slots, so spawning many small tasks doesn't fragment the heap
+    tasks: BTreeMap<TaskId, NonNull<Task>>,
+    task_slab: SlabCache<Task>,
+    // one queue per priority level, indexed by `Priority`
+    task_queues: [Arc<ArrayQueue<TaskId>>; PRIORITY_LEVELS],
+    polls: usize,
+    // set when a task couldn't be queued because the queue was full
+    queue_overflowed: Arc<AtomicBool>,
+    // cleared by `shutdown`, later spawns are dropped
+    accepting: bool,
+    // value of `LOST_WAKEUPS` when this executor last requeued its tasks
+    lost_wakeups: u64,
     waker_cache: BTreeMap<TaskId, Waker>,
 }
 
 impl Executor {
     pub fn new() -> Self {
+        Executor::with_capacity(DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Creates an executor whose queues hold up to `capacity` ready tasks of each priority at once.
+    pub fn with_capacity(capacity: usize) -> Self {
+        WOKEN_TASKS.get().try_init_once(|| ArrayQueue::new(WAKE_QUEUE_CAPACITY)).ok();
+
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_slab: SlabCache::new(),
+            task_queues: core::array::from_fn(|_| Arc::new(ArrayQueue::new(capacity))),
+            polls: 0,
+            queue_overflowed: Arc::new(AtomicBool::new(false)),
+            accepting: true,
+            lost_wakeups: LOST_WAKEUPS.load(Relaxed),
             waker_cache: BTreeMap::new(),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    /// Spawns the task and returns its ID. Its slot is taken from the executor's task slab, which
+    /// allocates pages from `FRAME_ALLOCATOR`. After `shutdown` the task is dropped without being
+    /// polled.
+    pub fn spawn(&mut self, task: Task) -> TaskId {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        let priority = task.priority;
+        if !self.accepting {
+            log::warn!("[executor] dropping task {:?} spawned after shutdown", task_id);
+            return task_id;
+        }
+        if self.tasks.contains_key(&task_id) {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+
+        let task = {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let frame_allocator = frame_allocator.as_mut()
+                .expect("Frame allocator is not initialized");
+            self.task_slab.alloc(task, frame_allocator)
+                .expect("Failed to allocate task slot")
+        };
+        self.tasks.insert(task_id, task);
+        let queued = unsafe { &task.as_ref().queued };
+        push_task(&self.task_queues[priority as usize], &self.queue_overflowed, queued, task_id);
+        task_id
     }
 
-    fn run_ready_tasks(&mut self) {
-        while let Some(task_id) = self.task_queue.pop() {
-            let task = match self.tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue
-            };
-            let waker = self.waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, self.task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    self.tasks.remove(&task_id);
-                    self.waker_cache.remove(&task_id);
+    pub fn spawn_with_priority(&mut self, task: Task, priority: Priority) -> TaskId {
+        self.spawn(task.with_priority(priority))
+    }
+
+    fn next_task(&mut self) -> Option<TaskId> {
+        self.polls = self.polls.wrapping_add(1);
+
+        if self.polls % STARVATION_LIMIT == 0 {
+            self.task_queues.iter().rev().find_map(|queue| queue.pop())
+        } else {
+            self.task_queues.iter().find_map(|queue| queue.pop())
+        }
+    }
+
+    /// Spawns the future as a new task and returns a handle resolving to its output, see
+    /// [`JoinHandle`].
+    pub fn spawn_with_handle<T: 'static>(&mut self, future: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+        let (task, handle) = Task::with_handle(future);
+        self.spawn(task);
+        handle
+    }
+
+    /// Returns the number of spawned tasks that haven't finished yet.
+    pub fn tasks_count(&self) -> usize {
+        self.task_slab.allocated()
+    }
+
+    /// Returns the number of tasks the already allocated slab pages can hold.
+    pub fn tasks_capacity(&self) -> usize {
+        self.task_slab.capacity()
+    }
+
+    /// Moves the tasks woken with `wake_task_id` to the ready queues.
+    fn take_woken_tasks(&mut self) {
+        if let Ok(woken) = WOKEN_TASKS.get().try_get() {
+            while let Some(task_id) = woken.pop() {
+                // IDs of finished tasks and tasks of other CPUs are dropped
+                if let Some(task) = self.tasks.get(&task_id) {
+                    let task = unsafe { task.as_ref() };
+                    push_task(&self.task_queues[task.priority as usize], &self.queue_overflowed, &task.queued, task_id);
                 }
-                Poll::Pending => {}
+            }
+        }
+
+        let lost_wakeups = LOST_WAKEUPS.load(Relaxed);
+        if lost_wakeups != self.lost_wakeups {
+            self.lost_wakeups = lost_wakeups;
+            self.queue_overflowed.store(true, Relaxed);
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        self.take_woken_tasks();
+
+        while let Some(task_id) = self.next_task() {
+            self.poll_task(task_id);
+        }
+
+        self.requeue_if_overflowed();
+    }
+
+    fn poll_task(&mut self, task_id: TaskId) {
+        let task = match self.tasks.get_mut(&task_id) {
+            // the executor owns the slot, nothing else references it
+            Some(task) => unsafe { task.as_mut() },
+            None => return
+        };
+        // cleared before polling, so a wakeup during the poll queues the task again
+        task.queued.store(false, Release);
+
+        let task_queue = &self.task_queues[task.priority as usize];
+        let waker = self.waker_cache
+            .entry(task_id)
+            .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), self.queue_overflowed.clone(), task.queued.clone()));
+        let mut context = Context::from_waker(waker);
+
+        match task.poll(&mut context) {
+            Poll::Ready(()) => {
+                let task = self.tasks.remove(&task_id).unwrap();
+                drop(unsafe { self.task_slab.free(task) });
+                self.waker_cache.remove(&task_id);
+            }
+            Poll::Pending => {}
+        }
+    }
+
+    fn requeue_if_overflowed(&mut self) {
+        // Some wakeups were lost, so poll every task again. Spurious polls are harmless.
+        if self.queue_overflowed.swap(false, Relaxed) {
+            log::warn!("[executor] task queue overflowed, requeueing all tasks");
+            for (&task_id, task) in self.tasks.iter() {
+                let task = unsafe { task.as_ref() };
+                push_task(&self.task_queues[task.priority as usize], &self.queue_overflowed, &task.queued, task_id);
             }
         }
     }
@@ -61,13 +233,54 @@ impl Executor {
         }
     }
 
-    fn sleep_if_idle(&self) {
+    /// Stops accepting new tasks and runs the ready ones until none is left or `timeout_ms` have
+    /// passed. Then polls every remaining task once more with `is_shutting_down` returning true
+    /// and drops them. Returns the number of dropped unfinished tasks.
+    ///
+    /// The timeout is measured in timer ticks, so it's only checked with interrupts enabled.
+    pub fn shutdown(&mut self, timeout_ms: u64) -> usize {
+        self.accepting = false;
+        let deadline = timer::ticks() + timeout_ms.saturating_mul(timer::tick_frequency() as u64) / 1000;
+
+        self.take_woken_tasks();
+        self.requeue_if_overflowed();
+        while timer::ticks() < deadline {
+            match self.next_task() {
+                Some(task_id) => self.poll_task(task_id),
+                None => break
+            }
+        }
+
+        SHUTTING_DOWN.store(true, Relaxed);
+        let remaining: Vec<TaskId> = self.tasks.keys().copied().collect();
+        for task_id in remaining {
+            self.poll_task(task_id);
+        }
+        SHUTTING_DOWN.store(false, Relaxed);
+
+        let abandoned = self.tasks.len();
+        for (_, task) in core::mem::take(&mut self.tasks) {
+            drop(unsafe { self.task_slab.free(task) });
+        }
+        self.waker_cache.clear();
+        for queue in self.task_queues.iter() {
+            while queue.pop().is_some() {}
+        }
+
+        log::info!("[executor] shut down, {} unfinished tasks dropped", abandoned);
+        abandoned
+    }
+
+    fn sleep_if_idle(&mut self) {
         // disable interrupts
         unsafe {
             asm!("cli", options(preserves_flags, nostack));
         }
 
-        if self.task_queue.is_empty() {
+        // an interrupt could have woken a task by ID after the last check
+        self.take_woken_tasks();
+
+        if self.task_queues.iter().all(|queue| queue.is_empty()) && !self.queue_overflowed.load(Relaxed) {
             // enable and hlt
             unsafe {
                 asm!("sti; hlt", options(nomem, nostack));
@@ -81,20 +294,47 @@ impl Executor {
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        for (_, task) in core::mem::take(&mut self.tasks) {
+            drop(unsafe { self.task_slab.free(task) });
+        }
+    }
+}
+
+/// Queues the task unless it's already queued or, if the queue is full, remembers to requeue all
+/// tasks later.
+///
+/// Called from wakers, so must not block or allocate.
+fn push_task(task_queue: &ArrayQueue<TaskId>, queue_overflowed: &AtomicBool, queued: &AtomicBool, task_id: TaskId) {
+    if queued.swap(true, AcqRel) {
+        return;
+    }
+
+    if task_queue.push(task_id).is_err() {
+        queued.store(false, Release);
+        queue_overflowed.store(true, Relaxed);
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    queue_overflowed: Arc<AtomicBool>,
+    queued: Arc<AtomicBool>,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        push_task(&self.task_queue, &self.queue_overflowed, &self.queued, self.task_id);
     }
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, queue_overflowed: Arc<AtomicBool>, queued: Arc<AtomicBool>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            queue_overflowed,
+            queued,
         }))
     }
 }
@@ -107,4 +347,4 @@ impl Wake for TaskWaker {
     fn wake_by_ref(self: &Arc<Self>) {
         self.wake_task();
     }
-}
\ No newline at end of file
+}
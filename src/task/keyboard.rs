@@ -3,36 +3,213 @@ use core::{pin::Pin, task::{Poll, Context}};
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyEvent, Keyboard, ScancodeSet, ScancodeSet1, ScancodeSet2};
+use pc_keyboard::layouts::AnyLayout;
+use shared_lib::debug_mutex::DebugMutex;
+use shared_lib::interrupts::without_interrupts;
+use crate::port::Port;
 use shared_lib::out;
-use shared_lib::logger::LOGGER;
 use crate::shell::Shell;
 
+/// Scancodes arriving while the queue is full are dropped.
+pub const SCANCODE_QUEUE_SIZE: usize = 100;
+
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+/// Keyboard layouts selectable with `set_keyboard_layout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Dvorak104
+}
+
+impl Layout {
+    /// Parses a layout name as used on the command line: `us`, `uk`, `de` or `dvorak`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Layout::Us104),
+            "uk" => Some(Layout::Uk105),
+            "de" => Some(Layout::De105),
+            "dvorak" => Some(Layout::Dvorak104),
+            _ => None
+        }
+    }
+
+    const fn to_any_layout(self) -> AnyLayout {
+        match self {
+            Layout::Us104 => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk105 => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::De105 => AnyLayout::De105Key(layouts::De105Key),
+            Layout::Dvorak104 => AnyLayout::Dvorak104Key(layouts::Dvorak104Key)
+        }
+    }
+}
+
+/// Scancode sets the keyboard can send. Set 1 is what the PS/2 controller delivers when it
+/// translates, set 2 is the native set of most keyboards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSetKind {
+    Set1,
+    Set2
+}
+
+enum AnyScancodeSet {
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2)
+}
+
+impl AnyScancodeSet {
+    const fn new(kind: ScancodeSetKind) -> Self {
+        match kind {
+            ScancodeSetKind::Set1 => AnyScancodeSet::Set1(ScancodeSet1::new()),
+            ScancodeSetKind::Set2 => AnyScancodeSet::Set2(ScancodeSet2::new())
+        }
+    }
+}
+
+impl ScancodeSet for AnyScancodeSet {
+    fn advance_state(&mut self, code: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        match self {
+            AnyScancodeSet::Set1(set) => set.advance_state(code),
+            AnyScancodeSet::Set2(set) => set.advance_state(code)
+        }
+    }
+}
+
+struct KeyboardDecoder {
+    layout: Layout,
+    scancode_set: ScancodeSetKind,
+    keyboard: Keyboard<AnyLayout, AnyScancodeSet>
+}
+
+impl KeyboardDecoder {
+    const fn new(layout: Layout, scancode_set: ScancodeSetKind) -> Self {
+        KeyboardDecoder {
+            layout,
+            scancode_set,
+            keyboard: Keyboard::new(AnyScancodeSet::new(scancode_set), layout.to_any_layout(), HandleControl::Ignore)
+        }
+    }
+}
+
+static KEYBOARD: DebugMutex<KeyboardDecoder> = DebugMutex::new(KeyboardDecoder::new(Layout::Us104, ScancodeSetKind::Set1));
+
+/// Switches the layout used to decode key presses. Takes effect from the next scancode, keys
+/// held down at the moment (e.g. shift) are released.
+pub fn set_keyboard_layout(layout: Layout) {
+    let mut decoder = KEYBOARD.lock();
+    *decoder = KeyboardDecoder::new(layout, decoder.scancode_set);
+    log::info!("[keyboard] layout: {:?}", layout);
+}
+
+/// Switches the scancode set used to decode keyboard input, see `detect_scancode_set`.
+pub fn set_scancode_set(scancode_set: ScancodeSetKind) {
+    let mut decoder = KEYBOARD.lock();
+    *decoder = KeyboardDecoder::new(decoder.layout, scancode_set);
+    log::info!("[keyboard] scancode set: {:?}", scancode_set);
+}
+
+const PS2_DATA: u16 = 0x60;
+const PS2_STATUS_COMMAND: u16 = 0x64;
+
+const PS2_STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+const PS2_READ_CONFIG: u8 = 0x20;
+const PS2_CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const KEYBOARD_SCANCODE_SET: u8 = 0xF0;
+const KEYBOARD_ACK: u8 = 0xFA;
+
+const PS2_TIMEOUT: usize = 100_000;
+
+fn ps2_write(port: u16, value: u8) -> Result<(), &'static str> {
+    let mut status = Port::new(PS2_STATUS_COMMAND);
+    for _ in 0..PS2_TIMEOUT {
+        if unsafe { status.read() } & PS2_STATUS_INPUT_FULL == 0 {
+            unsafe { Port::new(port).write(value) };
+            return Ok(());
+        }
+    }
+
+    Err("PS/2 controller write timeout")
+}
+
+fn ps2_read() -> Result<u8, &'static str> {
+    let mut status = Port::new(PS2_STATUS_COMMAND);
+    for _ in 0..PS2_TIMEOUT {
+        if unsafe { status.read() } & PS2_STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { Port::new(PS2_DATA).read() });
+        }
+    }
+
+    Err("PS/2 controller read timeout")
+}
+
+fn keyboard_command(command: u8) -> Result<(), &'static str> {
+    ps2_write(PS2_DATA, command)?;
+    match ps2_read()? {
+        KEYBOARD_ACK => Ok(()),
+        _ => Err("Keyboard didn't acknowledge command")
+    }
+}
+
+fn query_scancode_set() -> Result<ScancodeSetKind, &'static str> {
+    ps2_write(PS2_STATUS_COMMAND, PS2_READ_CONFIG)?;
+    if ps2_read()? & PS2_CONFIG_TRANSLATION != 0 {
+        // the controller translates whatever the keyboard sends to set 1
+        return Ok(ScancodeSetKind::Set1);
+    }
+
+    // subcommand 0 reports the current set
+    keyboard_command(KEYBOARD_SCANCODE_SET)?;
+    keyboard_command(0)?;
+    match ps2_read()? {
+        1 => Ok(ScancodeSetKind::Set1),
+        2 => Ok(ScancodeSetKind::Set2),
+        _ => Err("Unsupported scancode set")
+    }
+}
+
+/// Asks the PS/2 controller and the keyboard which scancode set arrives at the data port.
+/// Falls back to set 1 if the controller doesn't answer.
+///
+/// Talks to the controller directly, so must run before keyboard interrupts are enabled.
+pub fn detect_scancode_set() -> ScancodeSetKind {
+    without_interrupts(|| {
+        query_scancode_set().unwrap_or_else(|err| {
+            log::warn!("[keyboard] {}, assuming scancode set 1", err);
+            ScancodeSetKind::Set1
+        })
+    })
+}
+
 /// Called by the keyboard interrupt handler
 ///
 /// Must not block or allocate.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            log::warn!("scancode queue full; dropping keyboard input");
+            log::warn!("[keyboard] scancode queue full; dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        log::warn!("scancode queue uninitialized");
+        log::warn!("[keyboard] scancode queue uninitialized");
     }
 }
 
+/// Raw scancodes pushed by the keyboard interrupt handler. Consumers decode them themselves, e.g.
+/// with `pc_keyboard`. Only one stream can exist.
 pub struct ScancodeStream {
     _private: ()
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
+        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
             .expect("ScancodeStream::new should only be called once");
         ScancodeStream{ _private: () }
     }
@@ -63,17 +240,22 @@ impl Stream for ScancodeStream {
 
 pub async fn print_keypresses(mut shell: Shell) {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore);
 
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => {
-                        shell.char_input(character);
-                    },
-                    DecodedKey::RawKey(key) => out!("{:?}", key)
-                }
+        let key = {
+            let keyboard = &mut KEYBOARD.lock().keyboard;
+            match keyboard.add_byte(scancode) {
+                Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+                _ => None
+            }
+        };
+
+        if let Some(key) = key {
+            match key {
+                DecodedKey::Unicode(character) => {
+                    shell.char_input(character);
+                },
+                DecodedKey::RawKey(key) => out!("{:?}", key)
             }
         }
     }
@@ -1,14 +1,32 @@
 pub mod keyboard;
 pub mod executor;
 pub mod timer;
+pub mod serial;
 
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::task::Waker;
+use futures_util::future::{abortable, AbortHandle};
+pub use futures_util::future::Aborted;
 use core::task::{Context, Poll};
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Ready tasks of a higher priority are polled first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low
+}
+
+pub const PRIORITY_LEVELS: usize = 3;
 
 pub struct Task {
     id: TaskId,
+    priority: Priority,
+    // set while the task ID is in a ready queue, so it's queued at most once
+    queued: Arc<AtomicBool>,
     future: Pin<Box<dyn Future<Output = ()>>>
 }
 
@@ -16,21 +34,115 @@ impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            priority: Priority::Normal,
+            queued: Arc::new(AtomicBool::new(false)),
             future: Box::pin(future)
         }
     }
 
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    /// Creates a task together with a handle resolving to the output of the future, or to
+    /// `Err(Aborted)` if the task gets aborted.
+    pub fn with_handle<T: 'static>(future: impl Future<Output = T> + 'static) -> (Task, JoinHandle<T>) {
+        let state = Arc::new(spin::Mutex::new(JoinState { output: None, waker: None }));
+        let (future, abort_handle) = abortable(future);
+
+        let task_state = state.clone();
+        let task = Task::new(async move {
+            let output = future.await;
+
+            let waker = {
+                let mut state = task_state.lock();
+                state.output = Some(output);
+                state.waker.take()
+            };
+
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+
+        (task, JoinHandle { state, abort_handle })
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+/// Identifies a task, e.g. for `executor::wake_task_id`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
-}
\ No newline at end of file
+}
+
+struct JoinState<T> {
+    output: Option<Result<T, Aborted>>,
+    waker: Option<Waker>
+}
+
+/// Resolves to the output of a task once it has finished, or to `Err(Aborted)` once it's aborted.
+pub struct JoinHandle<T> {
+    state: Arc<spin::Mutex<JoinState<T>>>,
+    abort_handle: AbortHandle
+}
+
+impl<T> JoinHandle<T> {
+    /// Stops the task: its future is dropped the next time the executor gets to it, then the
+    /// handle resolves to `Err(Aborted)`. Does nothing if the task has already finished.
+    pub fn abort(&self) {
+        self.abort_handle.abort();
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Aborted>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct YieldNow {
+    yielded: bool
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Lets the other ready tasks run before the current one continues.
+pub fn yield_now() -> impl Future<Output = ()> {
+    YieldNow { yielded: false }
+}
@@ -0,0 +1,71 @@
+use conquer_once::spin::OnceCell;
+use core::{pin::Pin, task::{Poll, Context}};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use shared_lib::serial::{SerialPort, COM1};
+use crate::{apic, interrupts};
+
+/// Bytes arriving while the queue is full are dropped.
+pub const SERIAL_QUEUE_SIZE: usize = 256;
+
+const COM1_IRQ: u8 = 4;
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called by the COM1 interrupt handler
+///
+/// Must not block or allocate.
+fn serial_interrupt() {
+    let mut port = unsafe { SerialPort::new(COM1) };
+
+    // reading the receive buffer also acknowledges the interrupt
+    while let Some(byte) = port.read_byte() {
+        if let Ok(queue) = BYTE_QUEUE.try_get() {
+            if queue.push(byte).is_err() {
+                log::warn!("[serial] input queue full; dropping serial input");
+            }
+        }
+    }
+
+    WAKER.wake();
+}
+
+/// Bytes received on COM1. Only one stream can exist.
+pub struct SerialStream {
+    _private: ()
+}
+
+impl SerialStream {
+    pub fn new() -> Result<Self, &'static str> {
+        BYTE_QUEUE.try_init_once(|| ArrayQueue::new(SERIAL_QUEUE_SIZE))
+            .map_err(|_| "SerialStream::new should only be called once")?;
+
+        interrupts::register_irq_handler(COM1_IRQ, serial_interrupt)?;
+        apic::route_irq(COM1_IRQ)?;
+        Ok(SerialStream { _private: () })
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            },
+            None => Poll::Pending
+        }
+    }
+}
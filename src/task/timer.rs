@@ -1,155 +1,190 @@
-use alloc::collections::BTreeMap;
 use core::future::Future;
-use core::ops::{DerefMut};
 use core::pin::Pin;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use core::task::{Context, Poll, Waker};
-use conquer_once::spin::OnceCell;
-use futures_util::stream::{Stream, StreamExt};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll};
 use futures_util::task::AtomicWaker;
 
-static TIMER_FLAG: OnceCell<AtomicBool> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
-
 pub const TIMER_FREQUENCY: u16 = 250;
 
+// frequency the timer interrupt actually fires with, may differ from the requested one
+static TICK_FREQUENCY: AtomicU32 = AtomicU32::new(TIMER_FREQUENCY as u32);
+
+/// Maximum number of sleeps waiting at the same time. Sleeps that don't find a free slot fall back
+/// to polling on every executor iteration.
+const TIMER_SLOTS: usize = 64;
+
+const FREE_SLOT: u64 = 0;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+struct TimerSlot {
+    deadline: AtomicU64,
+    waker: AtomicWaker
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: TimerSlot = TimerSlot { deadline: AtomicU64::new(FREE_SLOT), waker: AtomicWaker::new() };
+
+static TIMER_WHEEL: [TimerSlot; TIMER_SLOTS] = [EMPTY_SLOT; TIMER_SLOTS];
+
 /// Called by the timer interrupt handler
 ///
 /// Must not block or allocate.
 pub fn raise_timer() {
-    if let Ok(bool_flag) = TIMER_FLAG.try_get() {
-        bool_flag.store(true, Ordering::SeqCst);
-        if Ok(true) == bool_flag.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
-            log::error!("[timer] raised timer flag hasn't been consumed last time!");
+    let now = TICKS.fetch_add(1, Ordering::AcqRel) + 1;
+
+    for slot in TIMER_WHEEL.iter() {
+        let deadline = slot.deadline.load(Ordering::Acquire);
+        if deadline != FREE_SLOT && deadline <= now {
+            slot.waker.wake();
         }
-        WAKER.wake();
     }
 }
 
-struct TimerStream {
-    _private: ()
+/// Called by the code programming the timer with the frequency it achieved.
+pub fn set_tick_frequency(hz: u32) {
+    TICK_FREQUENCY.store(hz.max(1), Ordering::Relaxed);
 }
 
-impl TimerStream {
-    pub fn new() -> Self {
-        TIMER_FLAG.try_init_once(|| AtomicBool::from(false))
-            .expect("TimerStream::new should only be called once");
-        TimerStream { _private: () }
-    }
+pub fn tick_frequency() -> u32 {
+    TICK_FREQUENCY.load(Ordering::Relaxed)
 }
 
-impl Stream for TimerStream {
-    type Item = ();
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<()>> {
-        let timer_flag = crate::task::timer::TIMER_FLAG.try_get().expect("not initialized");
+/// Returns the number of timer interrupts since boot. The counter is 64 bits wide, at 250 Hz it
+/// wraps after billions of years.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
 
-        if Ok(true) == timer_flag.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed) {
-            return Poll::Ready(Some(()))
-        }
+/// Returns the milliseconds since the timer was started, derived from `ticks` and the achieved
+/// `tick_frequency`. The resolution is one tick, 4 ms at the default 250 Hz. Lock-free, so it
+/// can be used with interrupts disabled, the value just doesn't advance then.
+pub fn uptime_ms() -> u64 {
+    let ticks = ticks();
+    let frequency = tick_frequency() as u64;
 
-        WAKER.register(&cx.waker());
+    // split, so `ticks * 1000` can't overflow
+    ticks / frequency * 1000 + ticks % frequency * 1000 / frequency
+}
 
-        match timer_flag.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed) {
-            Ok(true) => {
-                WAKER.take();
-                Poll::Ready(Some(()))
-            },
-            Ok(false) => Poll::Pending,
-            Err(_) => Poll::Pending
-        }
-    }
+/// Returns the number of sleeps waiting for their deadline in the timer wheel.
+pub fn active_timers() -> usize {
+    TIMER_WHEEL.iter()
+        .filter(|slot| slot.deadline.load(Ordering::Acquire) != FREE_SLOT)
+        .count()
 }
 
-struct TimerTasksManager {
-    tasks: BTreeMap<u64, (u64, AtomicWaker)>, // task id -> (ticks counter, waker)
+/// Resolves once `ticks` timer interrupts have passed.
+pub struct Sleep {
+    deadline: u64,
+    slot: Option<usize>,
+    // the missing slot is only reported once, the sleep is polled until the deadline after that
+    warned: bool
 }
 
-static TIMER_TASKS_MANAGER: spin::Mutex<TimerTasksManager> = spin::Mutex::new(TimerTasksManager{ tasks: BTreeMap::new() });
+impl Sleep {
+    pub fn new(ticks: u64) -> Sleep {
+        Sleep { deadline: self::ticks().saturating_add(ticks), slot: None, warned: false }
+    }
 
-impl TimerTasksManager {
-    pub fn register_task(&mut self, id: u64, ticks: u64) -> Result<(), &'static str> {
-        return if self.tasks.contains_key(&id) {
-            Err("Task already registered")
-        } else {
-            self.tasks.insert(id, (ticks, AtomicWaker::new()));
-            Ok(())
+    fn claim_slot(&mut self) -> Option<usize> {
+        if self.slot.is_none() {
+            self.slot = TIMER_WHEEL.iter().position(|slot| {
+                slot.deadline
+                    .compare_exchange(FREE_SLOT, self.deadline, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            });
         }
+
+        self.slot
     }
 
-    pub fn decrement_all(&mut self) {
-        for mut item in self.tasks.iter_mut() {
-            let val = item.1.deref_mut();
-            val.0 = val.0.checked_sub(1).unwrap_or(0);
+    fn release_slot(&mut self) {
+        if let Some(index) = self.slot.take() {
+            TIMER_WHEEL[index].waker.take();
+            TIMER_WHEEL[index].deadline.store(FREE_SLOT, Ordering::Release);
+        }
+    }
+}
 
-            if val.0 == 0 {
-                val.1.wake();
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if ticks() >= self.deadline {
+            self.release_slot();
+            return Poll::Ready(());
+        }
+
+        match self.claim_slot() {
+            Some(index) => TIMER_WHEEL[index].waker.register(cx.waker()),
+            None => {
+                if !self.warned {
+                    log::warn!("[timer] no free timer slots, polling sleep until deadline");
+                    self.warned = true;
+                }
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
             }
         }
-    }
 
-    pub fn check_task(&mut self, id: u64) -> Result<bool, &'static str> {
-        if self.tasks.get_mut(&id).expect("There is no such task").0.eq(&0) {
-            self.tasks.remove(&id).expect("Failed to remove task from map");
-            Ok(true)
-        } else {
-            Ok(false)
+        // the deadline may have passed before the waker was registered
+        if ticks() >= self.deadline {
+            self.release_slot();
+            return Poll::Ready(());
         }
-    }
 
-    pub fn register_waker(&mut self, id: u64, waker: &Waker) -> Result<(), &'static str> {
-        self.tasks.get_mut(&id).unwrap().1.register(waker);
-        Ok(())
+        Poll::Pending
     }
 }
 
-pub async fn timer_loop() {
-    let mut timer_stream = TimerStream::new();
-
-    while let Some(()) = timer_stream.next().await {
-        TIMER_TASKS_MANAGER.lock().decrement_all();
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.release_slot();
     }
 }
 
-struct Sleep {
-    task_id: u64
+/// Sleeps for `ticks` timer interrupts.
+pub fn sleep(ticks: u64) -> Sleep {
+    Sleep::new(ticks)
 }
 
-impl Sleep {
-    pub fn new(sleep_for_ms: u64) -> Sleep {
-        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-
-        let msec_freq = (1000 / TIMER_FREQUENCY) as u64; // every tick N msec passed
-
-        let timer_value = if sleep_for_ms < msec_freq {
-            1
-        } else {
-            sleep_for_ms / msec_freq
-        };
+pub async fn sleep_for(sleep_for_ms: u64) {
+    let ticks = sleep_for_ms.saturating_mul(tick_frequency() as u64) / 1000;
+    sleep(ticks.max(1)).await;
+}
 
-        TIMER_TASKS_MANAGER
-            .lock()
-            .register_task(id, timer_value)
-            .expect("Failed to register task");
+/// Error of [`with_timeout`] when the deadline passed before the future finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
 
-        Sleep{ task_id: id }
-    }
+/// Future returned by [`with_timeout`].
+pub struct WithTimeout<F> {
+    future: F,
+    sleep: Sleep
 }
 
-pub async fn sleep_for(sleep_for_ms: u64) {
-    Sleep::new(sleep_for_ms).await;
-}
-
-impl Future for Sleep {
-    type Output = ();
+impl<F: Future> Future for WithTimeout<F> {
+    type Output = Result<F::Output, Timeout>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if TIMER_TASKS_MANAGER.lock().check_task(self.task_id).expect("Failed to check task") {
-            Poll::Ready(())
-        } else {
-            TIMER_TASKS_MANAGER.lock().register_waker(self.task_id, &cx.waker()).expect("Failed to register waker");
-            Poll::Pending
+        // `future` is pinned along with `self` and never moved out, `sleep` isn't pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            // don't let the timer wake the task later
+            this.sleep.release_slot();
+            return Poll::Ready(Ok(output));
         }
+
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Timeout))
     }
 }
+
+/// Runs `future` for at most `ticks` timer interrupts. Resolves to its output, or to `Timeout`
+/// once the deadline passes. The timer slot is released as soon as either finishes or the
+/// returned future is dropped.
+pub fn with_timeout<F: Future>(future: F, ticks: u64) -> WithTimeout<F> {
+    WithTimeout { future, sleep: Sleep::new(ticks) }
+}
@@ -0,0 +1,129 @@
+use core::arch::{asm, global_asm};
+use shared_lib::addr::VirtAddr;
+
+// IF set, interrupts are enabled in new threads
+const INITIAL_RFLAGS: u64 = 0x202;
+
+/// Registers preserved across `switch_context`: the callee-saved ones, the stack pointer, the
+/// instruction pointer to resume at and the flags.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct Context {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rflags: u64
+}
+
+impl Context {
+    pub const fn new() -> Self {
+        Context { rsp: 0, rbx: 0, rbp: 0, r12: 0, r13: 0, r14: 0, r15: 0, rip: 0, rflags: 0 }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Saves the current registers to `old` and continues with the ones in `new`. Returns when
+/// something switches back to `old`.
+///
+/// # Safety
+/// `new` must be saved by `switch_context` or created by `Thread::spawn`, and the stack it refers
+/// to must still be valid.
+#[inline(never)]
+pub unsafe fn switch_context(old: &mut Context, new: &Context) {
+    asm!(
+        "mov [rdi + 0x00], rsp",
+        "mov [rdi + 0x08], rbx",
+        "mov [rdi + 0x10], rbp",
+        "mov [rdi + 0x18], r12",
+        "mov [rdi + 0x20], r13",
+        "mov [rdi + 0x28], r14",
+        "mov [rdi + 0x30], r15",
+        "lea rax, [rip + 2f]",
+        "mov [rdi + 0x38], rax",
+        "pushfq",
+        "pop qword ptr [rdi + 0x40]",
+
+        "mov rsp, [rsi + 0x00]",
+        "mov rbx, [rsi + 0x08]",
+        "mov rbp, [rsi + 0x10]",
+        "mov r12, [rsi + 0x18]",
+        "mov r13, [rsi + 0x20]",
+        "mov r14, [rsi + 0x28]",
+        "mov r15, [rsi + 0x30]",
+        "push qword ptr [rsi + 0x40]",
+        "popfq",
+        "jmp qword ptr [rsi + 0x38]",
+        "2:",
+        in("rdi") old as *mut Context,
+        in("rsi") new as *const Context,
+        clobber_abi("C"),
+    );
+}
+
+// First code a new thread runs: r12 holds the entry point and rdi still points to the context of
+// whoever switched to the thread.
+global_asm!(
+    ".global thread_start",
+    "thread_start:",
+    "    mov rsi, rdi",
+    "    mov rdi, r12",
+    "    call {thread_main}",
+    "    ud2",
+    thread_main = sym thread_main,
+);
+
+extern "C" {
+    fn thread_start();
+}
+
+extern "C" fn thread_main(entry: *const (), return_to: *const Context) -> ! {
+    let entry = unsafe { core::mem::transmute::<*const (), fn()>(entry) };
+    entry();
+
+    // the finished thread is never resumed
+    let mut finished = Context::new();
+    unsafe { switch_context(&mut finished, &*return_to) };
+    unreachable!("finished thread was resumed");
+}
+
+/// Kernel thread with its own stack, switched to and from with `switch_context`. Independent from
+/// the async `Executor`, which keeps running on whatever thread calls `Executor::run`.
+pub struct Thread {
+    context: Context
+}
+
+impl Thread {
+    /// Prepares a thread running `entry` on the stack ending at `stack_top`. It starts when
+    /// something switches to its context. When `entry` returns, the thread switches back to the
+    /// context that first switched to it.
+    pub fn spawn(entry: fn(), stack_top: VirtAddr) -> Thread {
+        Thread {
+            context: Context {
+                // aligned as before a call, `thread_start` calls `thread_main`
+                rsp: stack_top.align_down_to(16).0,
+                r12: entry as usize as u64,
+                rip: thread_start as usize as u64,
+                rflags: INITIAL_RFLAGS,
+                ..Context::new()
+            }
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+}
@@ -0,0 +1,65 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::get_tsc;
+use shared_lib::interrupts::without_interrupts;
+use crate::pit::{pit_calibrate_tsc, PIT_BASE_FREQUENCY};
+
+// TSC cycles per second, 0 until calibrated
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+const CALIBRATION_ATTEMPTS: usize = 5;
+const CALIBRATION_MS: u64 = 10;
+const CALIBRATION_MIN_LOOPS: u16 = 1000;
+
+const CALIBRATION_FAILED: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+
+/// Measures the TSC frequency by busy waiting on PIT channel 2 and returns it in cycles per second.
+/// The lowest of several measurements is used, disturbed ones are discarded.
+///
+/// Interrupts are disabled while measuring, so an interrupt handler can't stretch the interval.
+pub fn calibrate_tsc() -> u64 {
+    let latch = (PIT_BASE_FREQUENCY as u64 * CALIBRATION_MS / 1000) as u32;
+
+    let mut khz = CALIBRATION_FAILED;
+    for _ in 0..CALIBRATION_ATTEMPTS {
+        let measured = without_interrupts(|| pit_calibrate_tsc(latch, CALIBRATION_MS, CALIBRATION_MIN_LOOPS));
+        khz = khz.min(measured);
+    }
+
+    if khz == CALIBRATION_FAILED {
+        panic!("Failed to calibrate TSC against PIT");
+    }
+
+    let hz = khz * 1000;
+    TSC_FREQUENCY.store(hz, Ordering::Relaxed);
+    log::info!("[tsc] frequency: {} kHz", khz);
+    hz
+}
+
+/// Returns the TSC frequency in cycles per second, calibrating it on first use.
+pub fn frequency() -> u64 {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => calibrate_tsc(),
+        hz => hz
+    }
+}
+
+/// Converts a number of TSC cycles to microseconds.
+pub fn cycles_to_us(cycles: u64) -> u64 {
+    (cycles as u128 * 1_000_000 / frequency() as u128) as u64
+}
+
+/// Converts microseconds to a number of TSC cycles.
+pub fn us_to_cycles(us: u64) -> u64 {
+    (us as u128 * frequency() as u128 / 1_000_000) as u64
+}
+
+/// Spins for at least `us` microseconds. Doesn't depend on interrupts, so it can be used before
+/// they are enabled or inside handlers.
+pub fn busy_sleep_us(us: u64) {
+    let cycles = us_to_cycles(us);
+    let start = get_tsc();
+
+    while get_tsc().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,71 @@
+use core::arch::asm;
+use core::fmt;
+use shared_lib::addr::VirtAddr;
+use shared_lib::page_table::get_physical_address;
+use shared_lib::VIRT_MAPPING_OFFSET;
+use crate::memory::active_level_4_table;
+
+/// Frames deeper than this are not printed, a corrupted chain might loop.
+pub const MAX_FRAMES: usize = 32;
+
+/// Call stack captured by walking the saved RBP frame pointers.
+///
+/// Without frame pointers RBP is a general purpose register and the walk stops early or prints
+/// garbage, so the kernel is built with `-C force-frame-pointers=yes` (see `.cargo/config.toml`).
+/// The addresses can be resolved with `addr2line -e <kernel binary>` or against the map file.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    rbp: u64
+}
+
+/// Captures the call stack of the caller.
+#[inline(always)]
+pub fn backtrace() -> Backtrace {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    Backtrace { rbp }
+}
+
+fn is_mapped(addr: u64) -> bool {
+    let Ok(virt) = VirtAddr::new_canonical(addr) else {
+        return false;
+    };
+
+    unsafe { get_physical_address(active_level_4_table(), virt, VIRT_MAPPING_OFFSET).is_some() }
+}
+
+impl Backtrace {
+    /// Iterates over the return addresses, innermost first.
+    pub fn frames(&self) -> impl Iterator<Item = u64> {
+        let mut rbp = self.rbp;
+
+        core::iter::from_fn(move || {
+            // the frame holds the caller's RBP followed by the return address
+            if rbp == 0 || rbp % 8 != 0 || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+                return None;
+            }
+
+            let (next_rbp, return_address) = unsafe {
+                (*(rbp as *const u64), *((rbp + 8) as *const u64))
+            };
+            if return_address == 0 {
+                return None;
+            }
+
+            rbp = next_rbp;
+            Some(return_address)
+        }).take(MAX_FRAMES)
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, return_address) in self.frames().enumerate() {
+            writeln!(f, "  #{}: {:#018x}", i, return_address)?;
+        }
+        Ok(())
+    }
+}
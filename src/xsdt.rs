@@ -1,98 +1,11 @@
-use core::slice::from_raw_parts;
+use alloc::vec::Vec;
 use shared_lib::addr::{PhysAddr, VirtAddr};
 use shared_lib::frame_allocator::FrameAllocator;
-use shared_lib::page_table::{align_down, align_down_u64, map_address_with_offset};
+use shared_lib::page_table::map_address_with_offset;
 use shared_lib::VIRT_MAPPING_OFFSET;
+use crate::acpi::{find_table, AcpiSdtHeader, SDT_HEADER_SIZE};
 use crate::memory::active_level_4_table;
 
-#[repr(C)]
-struct RsdpV2 {
-    pub signature: [u8; 8],
-    pub checksum: u8,
-    pub oemid: [u8; 6],
-    pub revision: u8,
-    pub rsdt_address: u32, // deprecated
-
-    pub length: u32,
-    pub xsdt_address: u64,
-    pub extended_checksum: u8,
-    pub reserved: [u8; 3],
-}
-
-fn wrapping_sum(arr: &[u8]) -> u8 {
-    arr.iter().fold(0u8, |a, b| a.wrapping_add(*b))
-}
-
-fn get_xsdt_address(rsdp_addr: PhysAddr) -> VirtAddr {
-    let rsdp_virt_addr = {
-        log::info!("RSDP: {:#x}", rsdp_addr.0);
-        let rsdp_virt_addr = VirtAddr::new_checked(rsdp_addr.0 + VIRT_MAPPING_OFFSET).unwrap();
-
-        log::info!("RSDP virt - {:#x}", rsdp_virt_addr.0);
-        rsdp_virt_addr
-    };
-
-    let acpi_revision_ptr = rsdp_virt_addr.offset(8 + 1 + 6).unwrap().0 as *const u8;
-    let acpi_revision = unsafe { *acpi_revision_ptr };
-
-    log::info!("ACPI revision: {}", acpi_revision);
-    if acpi_revision != 2 {
-        panic!("ACPI1 is not supported!");
-    }
-
-    let rsdp_ptr = rsdp_virt_addr.0 as *mut RsdpV2;
-    let rsdp = unsafe { rsdp_ptr.as_mut().unwrap() };
-
-    let v1_bytes_sum = wrapping_sum(&rsdp.signature)
-        + rsdp.checksum
-        + wrapping_sum(&rsdp.oemid)
-        + rsdp.revision
-        + wrapping_sum(&rsdp.rsdt_address.to_ne_bytes());
-    let v2_bytes_sum = wrapping_sum(&rsdp.length.to_ne_bytes())
-        + wrapping_sum(&rsdp.xsdt_address.to_ne_bytes())
-        + rsdp.extended_checksum
-        + wrapping_sum(&rsdp.reserved);
-
-    log::info!("v1_bytes_sum: {:#x}", v1_bytes_sum);
-    log::info!("v2_bytes_sum: {:#x}", v2_bytes_sum);
-
-    if v1_bytes_sum != 0 {
-        panic!("ACPI1 checksum failed");
-    }
-
-    if v2_bytes_sum != 0 {
-        panic!("ACPI2 checksum failed");
-    }
-    VirtAddr::new_checked(rsdp.xsdt_address + VIRT_MAPPING_OFFSET).unwrap()
-}
-
-#[repr(C)]
-struct AcpiSdtHeader {
-    pub signature: [u8; 4],
-    pub length: u32,
-    pub revision: u8,
-    pub checksum: u8,
-    pub oemid: [u8; 6],
-    pub oem_table_id: [u8; 8],
-    pub oem_revision: u32,
-    pub creator_id: u32,
-    pub creator_revision: u32
-}
-
-impl AcpiSdtHeader {
-    pub fn get_bytes_sum(&self) -> u8 {
-        wrapping_sum(&self.signature)
-            .wrapping_add(wrapping_sum(&self.creator_id.to_ne_bytes()))
-            .wrapping_add(wrapping_sum(&self.length.to_ne_bytes()))
-            .wrapping_add(wrapping_sum(&self.oemid))
-            .wrapping_add(self.revision)
-            .wrapping_add(wrapping_sum(&self.creator_revision.to_ne_bytes()))
-            .wrapping_add(wrapping_sum(&self.oem_revision.to_ne_bytes()))
-            .wrapping_add(self.checksum)
-            .wrapping_add(wrapping_sum(&self.oem_table_id))
-    }
-}
-
 #[repr(C)]
 struct MadtHeader {
     pub local_apic_addr: u32,
@@ -105,7 +18,17 @@ struct MadtEntryHeader {
     pub record_length: u8
 }
 
-#[repr(C)]
+#[repr(C, packed)]
+struct MadtEntryLocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub flags: u32
+}
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[repr(C, packed)]
 struct MadtEntryIOApic {
     pub io_apic_id: u8,
     pub reserved: u8,
@@ -113,7 +36,7 @@ struct MadtEntryIOApic {
     pub global_system_interrupt_base: u32
 }
 
-#[repr(C)]
+#[repr(C, packed)]
 struct MadtEntryIOApicInterruptSource {
     pub bus_source: u8,
     pub irq_source: u8,
@@ -121,109 +44,132 @@ struct MadtEntryIOApicInterruptSource {
     pub flags: u16
 }
 
-struct ApicPhysAddrs {
-    pub local_apic_addr: PhysAddr,
-    pub io_apic_addr: PhysAddr
+/// An IO APIC listed in the MADT, handling the GSIs starting from `gsi_base`.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub addr: PhysAddr,
+    pub gsi_base: u32
 }
 
-fn handle_madt(header: &AcpiSdtHeader, data_addr: VirtAddr) -> Result<ApicPhysAddrs, &'static str> {
-    log::info!("MADT handling. Len: {}", header.length);
-
-    let mut check_sum = header.get_bytes_sum();
-    for i in 0..(header.length - 36) {
-        unsafe {
-            check_sum = check_sum.wrapping_add(*((data_addr.0 + i as u64) as *const u8));
-        }
-    }
-    if check_sum != 0 {
-        return Err("MADT checksum failed");
-    }
-
-    let madt_header = unsafe {
-        (data_addr.0 as *const MadtHeader).as_ref().unwrap()
-    };
+/// An ISA IRQ that is connected to a different GSI or with a different polarity or trigger mode
+/// than the ISA default (identity mapped, active high, edge triggered).
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+    pub flags: u16
+}
 
-    log::info!("local apic phys: {:#x} flags: {}", madt_header.local_apic_addr, madt_header.apic_flags);
+/// Iterates over the MADT entries as `(header, address of the entry body)`.
+fn madt_entries(header: &AcpiSdtHeader) -> impl Iterator<Item = (&MadtEntryHeader, u64)> {
+    let data_addr = header.data().as_ptr() as u64;
+    let data_len = (header.length as usize - SDT_HEADER_SIZE) as u64;
 
-    let mut result: Result<ApicPhysAddrs, &'static str> = Err("Invalid MADT");
     let mut offset: u64 = 8;
-    while offset < (header.length - 36) as u64 {
+    core::iter::from_fn(move || {
+        if offset >= data_len {
+            return None;
+        }
+
         let entry_header = unsafe {
-            ((data_addr.0 + offset) as *const MadtEntryHeader).as_ref().unwrap()
+            ((data_addr + offset) as *const MadtEntryHeader).as_ref().unwrap()
         };
+        if entry_header.record_length == 0 {
+            return None;
+        }
 
-        log::info!("MADT entry: type: {}, len: {}", entry_header.entry_type, entry_header.record_length);
-
-        if entry_header.entry_type == 1 {
-            let io_apic_entry = unsafe {
-                ((data_addr.0 + offset + 2) as *const MadtEntryIOApic).as_ref().unwrap()
-            };
+        let body = data_addr + offset + 2;
+        offset += entry_header.record_length as u64;
+        Some((entry_header, body))
+    })
+}
 
-            log::info!("io apic: addr: {:#x}, global system int base: {:#x}. id: {}", io_apic_entry.io_apic_addr, io_apic_entry.global_system_interrupt_base, io_apic_entry.io_apic_id);
+fn handle_madt(header: &AcpiSdtHeader) -> Result<PhysAddr, &'static str> {
+    log::info!("MADT handling. Len: {}", header.length);
 
-            result = Ok(ApicPhysAddrs {
-                local_apic_addr: PhysAddr(madt_header.local_apic_addr as u64),
-                io_apic_addr: PhysAddr(io_apic_entry.io_apic_addr as u64)
-            });
-        } else if entry_header.entry_type == 2 {
-            let io_apic_source_interrupt_entry = unsafe {
-                ((data_addr.0 + offset + 2) as *const MadtEntryIOApicInterruptSource).as_ref().unwrap()
-            };
+    let data_addr = VirtAddr::new(header.data().as_ptr() as u64);
+    let madt_header = unsafe {
+        (data_addr.0 as *const MadtHeader).as_ref().unwrap()
+    };
 
-            log::info!("Entry Type 2: I/O APIC Interrupt Source Override. {:#x} {:#x} {:#x} {:#x}", io_apic_source_interrupt_entry.bus_source, io_apic_source_interrupt_entry.irq_source, io_apic_source_interrupt_entry.global_system_interrupt, io_apic_source_interrupt_entry.flags);
-        }
+    log::info!("local apic phys: {:#x} flags: {}", madt_header.local_apic_addr, madt_header.apic_flags);
 
-        offset += entry_header.record_length as u64;
+    for (entry_header, _) in madt_entries(header) {
+        log::info!("MADT entry: type: {}, len: {}", entry_header.entry_type, entry_header.record_length);
     }
-    result
-}
 
-pub struct ApicAddresses {
-    pub local_apic_addr: VirtAddr,
-    pub io_apic_addr: VirtAddr
+    if madt_header.local_apic_addr == 0 {
+        return Err("Invalid MADT");
+    }
+    Ok(PhysAddr(madt_header.local_apic_addr as u64))
 }
 
-pub fn read_xsdt(allocator: &mut FrameAllocator, rsdp_addr: u64) -> ApicAddresses {
-    let xsdt_addr = get_xsdt_address(PhysAddr(rsdp_addr));
-    log::info!("XSDT addr: {:#x}", xsdt_addr.0);
+/// Returns the IO APICs listed in the MADT.
+pub fn read_io_apics() -> Vec<IoApicInfo> {
+    let Some(madt) = find_table(b"APIC") else {
+        return Vec::new();
+    };
 
-    let xsdt_header_ptr = xsdt_addr.0 as *mut AcpiSdtHeader;
-    let xsdt_header = unsafe { xsdt_header_ptr.as_mut().unwrap() };
-    log::info!("XSDT header: s:{:?}, len:{:#x}, rev:{}, ch: {}, oemid: {:?}, cr_rev: {:#x}", xsdt_header.signature, xsdt_header.length, xsdt_header.revision, xsdt_header.checksum,
-    xsdt_header.oemid, xsdt_header.creator_revision);
+    madt_entries(madt)
+        .filter(|(entry_header, _)| entry_header.entry_type == 1)
+        .map(|(_, body)| unsafe { core::ptr::read_unaligned(body as *const MadtEntryIOApic) })
+        .map(|entry| IoApicInfo {
+            id: entry.io_apic_id,
+            addr: PhysAddr(entry.io_apic_addr as u64),
+            gsi_base: entry.global_system_interrupt_base
+        })
+        .collect()
+}
 
-    let mut check_sum = xsdt_header.get_bytes_sum();
+/// Returns the interrupt source overrides of the ISA bus listed in the MADT.
+pub fn read_interrupt_overrides() -> Vec<InterruptOverride> {
+    let Some(madt) = find_table(b"APIC") else {
+        return Vec::new();
+    };
 
-    let pointers_to_other_sdts = unsafe { from_raw_parts((xsdt_addr.0 + 36) as *const u64, ((xsdt_header.length - 36) / 8) as usize) };
+    madt_entries(madt)
+        .filter(|(entry_header, _)| entry_header.entry_type == 2)
+        .map(|(_, body)| unsafe { core::ptr::read_unaligned(body as *const MadtEntryIOApicInterruptSource) })
+        .filter(|entry| entry.bus_source == 0)
+        .map(|entry| InterruptOverride {
+            irq: entry.irq_source,
+            gsi: entry.global_system_interrupt,
+            flags: entry.flags
+        })
+        .collect()
+}
 
-    for ptr in pointers_to_other_sdts {
-        check_sum += wrapping_sum(&ptr.to_ne_bytes());
-    }
+/// Returns the local APIC IDs of all processors listed in the MADT that are enabled or can be
+/// brought online, including the bootstrap processor.
+pub fn read_local_apic_ids() -> Vec<u8> {
+    let Some(madt) = find_table(b"APIC") else {
+        return Vec::new();
+    };
 
-    if check_sum != 0 {
-        panic!("XSDT checksum failed");
-    }
+    madt_entries(madt)
+        .filter(|(entry_header, _)| entry_header.entry_type == 0)
+        .map(|(_, body)| unsafe { core::ptr::read_unaligned(body as *const MadtEntryLocalApic) })
+        .filter(|entry| entry.flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0)
+        .map(|entry| entry.apic_id)
+        .collect()
+}
 
-    let mut apic_addrs = ApicPhysAddrs { local_apic_addr:PhysAddr(0), io_apic_addr:PhysAddr(0)};
-    for sdt_ptr in pointers_to_other_sdts {
-        let header_ptr = (sdt_ptr + VIRT_MAPPING_OFFSET) as *const AcpiSdtHeader;
-        let header = unsafe { header_ptr.as_ref().unwrap() };
-        let s = match core::str::from_utf8(&header.signature) {
-            Ok(v) => v,
-            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-        };
-        log::info!("Found SDT {}", s);
-        if s == "APIC" {
-            apic_addrs = handle_madt(header, VirtAddr::new_checked(sdt_ptr + VIRT_MAPPING_OFFSET + 36).unwrap()).unwrap();
-        }
-    }
+pub struct ApicAddresses {
+    pub local_apic_addr: VirtAddr
+}
 
-    if apic_addrs.local_apic_addr.0 == 0 {
-        panic!("Failed to find local APIC");
-    }
+/// Reads the local APIC address from the MADT and maps its registers. The IO APICs are mapped by
+/// `ioapic::init`.
+pub fn read_madt(allocator: &mut FrameAllocator) -> ApicAddresses {
+    let madt = find_table(b"APIC")
+        .expect("Failed to find MADT");
+    let local_apic_addr = handle_madt(madt)
+        .expect("Failed to find local APIC");
 
-    let mut apic_phys = apic_addrs.local_apic_addr.0;
-    let mut apic_virt = VirtAddr::new(apic_addrs.local_apic_addr.0 + VIRT_MAPPING_OFFSET);
+    let mut apic_phys = local_apic_addr.0;
+    let mut apic_virt = VirtAddr::new(local_apic_addr.0 + VIRT_MAPPING_OFFSET);
     let apic_virt_end = apic_virt.offset(0x10_0000)
         .expect("Failed to offset virtual address");
 
@@ -233,7 +179,7 @@ pub fn read_xsdt(allocator: &mut FrameAllocator, rsdp_addr: u64) -> ApicAddresse
 
     while apic_virt < apic_virt_end {
         unsafe {
-            map_address_with_offset(l4_table, apic_virt, apic_phys, allocator, VIRT_MAPPING_OFFSET)
+            map_address_with_offset(l4_table, apic_virt, PhysAddr::new(apic_phys), allocator, VIRT_MAPPING_OFFSET)
                 .expect("Failed to map new frame");
         }
 
@@ -241,16 +187,7 @@ pub fn read_xsdt(allocator: &mut FrameAllocator, rsdp_addr: u64) -> ApicAddresse
         apic_phys += 4096;
     }
 
-    let io_apic_phys = apic_addrs.io_apic_addr.0 << 16; // hack. For some reason on qemu we need it
-    let io_apic_virt = VirtAddr::new(io_apic_phys + VIRT_MAPPING_OFFSET);
-
-    unsafe {
-        map_address_with_offset(l4_table, align_down(io_apic_virt), align_down_u64(io_apic_phys), allocator, VIRT_MAPPING_OFFSET)
-            .expect("Failed to map new frame");
-    }
-
     ApicAddresses {
-        local_apic_addr: VirtAddr::new_checked(apic_addrs.local_apic_addr.0 + VIRT_MAPPING_OFFSET).unwrap(),
-        io_apic_addr: io_apic_virt
+        local_apic_addr: VirtAddr::new_checked(local_apic_addr.0 + VIRT_MAPPING_OFFSET).unwrap()
     }
 }
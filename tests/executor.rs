@@ -0,0 +1,335 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use shared_lib::{entry_point, BootInfo, VIRT_MAPPING_OFFSET};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::Poll;
+use futures_util::future::poll_fn;
+use futures_util::FutureExt;
+use shared_lib::frame_allocator::FrameAllocator;
+use ferr_os::allocator::init_default_heap;
+use ferr_os::memory::{active_level_4_table, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use ferr_os::task::{yield_now, Aborted, Priority, Task};
+use ferr_os::task::timer::{sleep, sleep_for, tick_frequency, ticks, uptime_ms};
+use ferr_os::task::executor::{is_shutting_down, wake_task_id, Executor, STOP};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = FrameAllocator::new(&boot_info.memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+
+    init_default_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
+
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_handle_returns_output() {
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    let handle = executor.spawn_with_handle(async { 40 + 2 });
+    let result = executor.spawn_with_handle(async move {
+        let output = handle.await;
+        STOP.store(true, Ordering::Relaxed);
+        output
+    });
+
+    executor.run();
+    assert_eq!(Some(Ok(Ok(42))), result.now_or_never());
+}
+
+#[test_case]
+fn full_queue_does_not_lose_tasks() {
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::with_capacity(1);
+
+    for _ in 0..4 {
+        executor.spawn(Task::new(async {
+            if COMPLETED.fetch_add(1, Ordering::Relaxed) == 3 {
+                STOP.store(true, Ordering::Relaxed);
+            }
+        }));
+    }
+
+    executor.run();
+    assert_eq!(4, COMPLETED.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn finished_tasks_free_their_slots() {
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    for _ in 0..10 {
+        executor.spawn(Task::new(async {}));
+    }
+    executor.spawn(Task::new(async {
+        yield_now().await;
+        STOP.store(true, Ordering::Relaxed);
+    }));
+
+    assert_eq!(11, executor.tasks_count());
+    let capacity = executor.tasks_capacity();
+    assert!(capacity >= 11);
+
+    executor.run();
+    assert_eq!(0, executor.tasks_count());
+
+    // the freed slots are reused
+    executor.spawn(Task::new(async {}));
+    assert_eq!(1, executor.tasks_count());
+    assert_eq!(capacity, executor.tasks_capacity());
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    let start = ticks();
+    let elapsed = executor.spawn_with_handle(async move {
+        sleep(5).await;
+        STOP.store(true, Ordering::Relaxed);
+        ticks() - start
+    });
+
+    executor.run();
+    assert!(elapsed.now_or_never().unwrap().unwrap() >= 5);
+}
+
+#[test_case]
+fn yielding_tasks_interleave() {
+    static ORDER: spin::Mutex<Vec<(u8, u8)>> = spin::Mutex::new(Vec::new());
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    for task in 0..2 {
+        executor.spawn(Task::new(async move {
+            for step in 0..3 {
+                ORDER.lock().push((task, step));
+                yield_now().await;
+            }
+
+            if task == 1 {
+                STOP.store(true, Ordering::Relaxed);
+            }
+        }));
+    }
+
+    executor.run();
+    assert_eq!(&[(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)], ORDER.lock().as_slice());
+}
+
+#[test_case]
+fn aborted_task_is_dropped() {
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    let endless = executor.spawn_with_handle(async {
+        loop {
+            STEPS.fetch_add(1, Ordering::Relaxed);
+            yield_now().await;
+        }
+    });
+
+    executor.spawn(Task::new(async move {
+        yield_now().await;
+        endless.abort();
+        // the handle resolves once the aborted task is dropped
+        assert_eq!(Err(Aborted), endless.await);
+        STOP.store(true, Ordering::Relaxed);
+    }));
+
+    executor.run();
+    let steps = STEPS.load(Ordering::Relaxed);
+    assert!(steps > 0);
+
+    // nothing polls the aborted task anymore
+    STOP.store(false, Ordering::Relaxed);
+    executor.spawn(Task::new(async {
+        yield_now().await;
+        STOP.store(true, Ordering::Relaxed);
+    }));
+    executor.run();
+    assert_eq!(steps, STEPS.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn high_priority_runs_first_without_starving_low() {
+    static ORDER: spin::Mutex<Vec<Priority>> = spin::Mutex::new(Vec::new());
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    executor.spawn_with_priority(Task::new(async {
+        ORDER.lock().push(Priority::Low);
+    }), Priority::Low);
+
+    executor.spawn_with_priority(Task::new(async {
+        // keeps re-waking itself until the low priority task got its turn
+        while !ORDER.lock().contains(&Priority::Low) {
+            ORDER.lock().push(Priority::High);
+            yield_now().await;
+        }
+        STOP.store(true, Ordering::Relaxed);
+    }), Priority::High);
+
+    executor.run();
+
+    let order = ORDER.lock();
+    assert_eq!(Priority::High, order[0]);
+    assert_eq!(Some(&Priority::Low), order.last());
+}
+
+#[test_case]
+fn wake_task_id_polls_task_again() {
+    static WOKEN: AtomicBool = AtomicBool::new(false);
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    // never registers its waker, like a future waiting for an interrupt
+    let waiting = Task::new(poll_fn(|_| {
+        if WOKEN.load(Ordering::Relaxed) {
+            STOP.store(true, Ordering::Relaxed);
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }));
+    let task_id = waiting.id();
+    executor.spawn(waiting);
+
+    executor.spawn(Task::new(async move {
+        yield_now().await;
+        WOKEN.store(true, Ordering::Relaxed);
+        wake_task_id(task_id);
+    }));
+
+    executor.run();
+    assert_eq!(0, executor.tasks_count());
+}
+
+#[test_case]
+fn shutdown_drains_ready_tasks() {
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+    static FLUSHED: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::new();
+
+    for _ in 0..3 {
+        executor.spawn(Task::new(async {
+            yield_now().await;
+            FINISHED.fetch_add(1, Ordering::Relaxed);
+        }));
+    }
+
+    // waits forever, but gets a last poll to flush its work
+    executor.spawn(Task::new(poll_fn(|_| {
+        if is_shutting_down() {
+            FLUSHED.store(true, Ordering::Relaxed);
+        }
+        Poll::Pending
+    })));
+
+    assert_eq!(1, executor.shutdown(1000));
+    assert_eq!(3, FINISHED.load(Ordering::Relaxed));
+    assert!(FLUSHED.load(Ordering::Relaxed));
+    assert_eq!(0, executor.tasks_count());
+
+    // spawns after shutdown are dropped
+    executor.spawn(Task::new(async {}));
+    assert_eq!(0, executor.tasks_count());
+}
+
+#[test_case]
+fn uptime_advances_with_sleep() {
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+
+    let start = uptime_ms();
+    let elapsed = executor.spawn_with_handle(async move {
+        sleep_for(20).await;
+        STOP.store(true, Ordering::Relaxed);
+        uptime_ms() - start
+    });
+
+    executor.run();
+    // the first tick might have been almost over when the sleep started
+    let tick_ms = 1000 / tick_frequency() as u64;
+    assert!(elapsed.now_or_never().unwrap().unwrap() + tick_ms >= 20);
+}
+
+#[test_case]
+fn repeated_wakeups_queue_task_once() {
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::with_capacity(2);
+
+    executor.spawn(Task::new(poll_fn(|cx| {
+        if POLLS.fetch_add(1, Ordering::Relaxed) == 0 {
+            for _ in 0..5 {
+                cx.waker().wake_by_ref();
+            }
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    })));
+    executor.spawn(Task::new(async {
+        yield_now().await;
+        yield_now().await;
+        STOP.store(true, Ordering::Relaxed);
+    }));
+
+    executor.run();
+    assert_eq!(2, POLLS.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn with_timeout_resolves_to_first_finished() {
+    use ferr_os::task::timer::{active_timers, with_timeout, Timeout};
+
+    STOP.store(false, Ordering::Relaxed);
+    let mut executor = Executor::new();
+    let timers = active_timers();
+
+    let finished = executor.spawn_with_handle(with_timeout(async { 7 }, 100));
+    let timed_out = executor.spawn_with_handle(async {
+        let result = with_timeout(futures_util::future::pending::<()>(), 2).await;
+        STOP.store(true, Ordering::Relaxed);
+        result
+    });
+
+    executor.run();
+    assert_eq!(Some(Ok(Ok(7))), finished.now_or_never());
+    assert_eq!(Some(Ok(Err(Timeout))), timed_out.now_or_never());
+
+    // a dropped timeout doesn't leave its timer behind
+    let mut pending = alloc::boxed::Box::pin(with_timeout(futures_util::future::pending::<()>(), 1000));
+    assert!(pending.as_mut().now_or_never().is_none());
+    assert_eq!(timers + 1, active_timers());
+    drop(pending);
+    assert_eq!(timers, active_timers());
+}
@@ -7,11 +7,15 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
+use shared_lib::allocator::{heap_stats, ALLOCATOR};
 use shared_lib::{entry_point, BootInfo, VIRT_MAPPING_OFFSET};
 use core::panic::PanicInfo;
-use ferr_os::allocator::{HEAP_SIZE, init_heap};
-use ferr_os::memory::active_level_4_table;
+use ferr_os::allocator::{extend_heap, init_default_heap, init_heap, HEAP_SIZE, HEAP_START};
+use ferr_os::memory::{active_level_4_table, FRAME_ALLOCATOR};
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::page_table::{map_address_with_offset, unmap_address_with_offset};
 
 entry_point!(main);
 
@@ -24,11 +28,13 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     let mut allocator = FrameAllocator::new(&boot_info.memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
 
-    init_heap(l4_table, &mut allocator)
+    init_default_heap(l4_table, &mut allocator)
         .expect("Failed to init heap");
 
     ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
 
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+
     test_main();
     loop {}
 }
@@ -72,4 +78,117 @@ fn many_boxes_long_lived() {
         assert_eq!(*x, i);
     }
     assert_eq!(*long_lived, 1);
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn freed_memory_is_reusable() {
+    let big_size = ALLOCATOR.lock().fallback_free() / 2;
+    let size = ALLOCATOR.lock().size();
+    // fits into the heap as it is
+    drop(vec![0u8; big_size]);
+    assert_eq!(size, ALLOCATOR.lock().size());
+
+    let mut blocks = Vec::with_capacity(256);
+    let fallback_free = ALLOCATOR.lock().fallback_free();
+    let free_list_bytes = ALLOCATOR.lock().free_list_bytes();
+
+    // fill the heap with blocks too large for the free lists
+    while ALLOCATOR.lock().fallback_free() > 4 * 4096 && blocks.len() < blocks.capacity() {
+        blocks.push(vec![1u8; 4000]);
+    }
+    let grown = ALLOCATOR.lock().size() - size;
+
+    // every other block goes first, so no hole is larger than a block until the rest is freed
+    let mut index = 0;
+    blocks.retain(|_| {
+        index += 1;
+        index % 2 == 0
+    });
+    blocks.clear();
+
+    assert_eq!(fallback_free + grown, ALLOCATOR.lock().fallback_free());
+    assert_eq!(free_list_bytes, ALLOCATOR.lock().free_list_bytes());
+
+    // only fits without growing if the freed blocks were merged again
+    let size = ALLOCATOR.lock().size();
+    let big = vec![0u8; big_size];
+    assert_eq!(big_size, big.len());
+    assert_eq!(size, ALLOCATOR.lock().size());
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    let initial_size = ALLOCATOR.lock().size();
+
+    let big = vec![7u8; initial_size];
+    assert!(ALLOCATOR.lock().size() > initial_size);
+    assert!(big.iter().all(|&b| b == 7));
+
+    let size = ALLOCATOR.lock().size();
+    extend_heap(2).unwrap();
+    assert_eq!(size + 2 * 4096, ALLOCATOR.lock().size());
+}
+
+#[test_case]
+fn heap_does_not_grow_into_mapped_memory() {
+    let top = VirtAddr::new(ALLOCATOR.lock().top() as u64);
+    assert!(top.0 > HEAP_START as u64);
+
+    let l4_table = unsafe { active_level_4_table() };
+    let occupied = top.offset(4096).unwrap();
+    let (frame, free_frames) = {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let frame = frame_allocator.allocate_frame().unwrap();
+        unsafe {
+            map_address_with_offset(l4_table, occupied, PhysAddr::new(frame), frame_allocator, VIRT_MAPPING_OFFSET).unwrap();
+        }
+        (frame, frame_allocator.free_frames_count())
+    };
+
+    let size = ALLOCATOR.lock().size();
+    assert!(extend_heap(4).is_err());
+    assert_eq!(size, ALLOCATOR.lock().size());
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    assert_eq!(free_frames, frame_allocator.free_frames_count());
+
+    unsafe {
+        assert_eq!(frame, unmap_address_with_offset(l4_table, occupied, frame_allocator, VIRT_MAPPING_OFFSET).unwrap());
+    }
+    assert!(frame_allocator.is_free(frame));
+}
+
+#[test_case]
+fn heap_stats_track_allocations() {
+    let before = heap_stats();
+
+    let value = Box::new([1u64; 8]);
+    let during = heap_stats();
+    assert_eq!(before.allocation_count + 1, during.allocation_count);
+    assert_eq!(before.in_use() + 64, during.in_use());
+    assert!(during.peak_usage >= during.in_use());
+
+    drop(value);
+    let after = heap_stats();
+    assert_eq!(before.in_use(), after.in_use());
+    assert_eq!(during.bytes_freed + 64, after.bytes_freed);
+}
+
+#[test_case]
+fn init_heap_checks_the_region() {
+    let l4_table = unsafe { active_level_4_table() };
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    let free_frames = frame_allocator.free_frames_count();
+
+    assert!(init_heap(VirtAddr::new(0x_5555_0000_0010), 4096, l4_table, frame_allocator).is_err());
+    assert!(init_heap(VirtAddr::new(0x_5555_0000_0000), 0, l4_table, frame_allocator).is_err());
+
+    // the heap is already set up by `main`
+    let size = ALLOCATOR.lock().size();
+    assert!(init_heap(VirtAddr::new(0x_5555_0000_0000), 4096, l4_table, frame_allocator).is_err());
+    assert_eq!(size, ALLOCATOR.lock().size());
+    assert_eq!(free_frames, frame_allocator.free_frames_count());
+}
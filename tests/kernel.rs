@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use shared_lib::{entry_point, BootInfo, VIRT_MAPPING_OFFSET};
+use core::panic::PanicInfo;
+use shared_lib::frame_allocator::FrameAllocator;
+use ferr_os::allocator::init_default_heap;
+use ferr_os::memory::{active_level_4_table, FRAME_ALLOCATOR};
+use ferr_os::{ioapic, pit};
+use ferr_os::ata::{read_sectors, Drive, MAX_LBA28_SECTORS, SECTOR_SIZE};
+use shared_lib::addr::VirtAddr;
+use ferr_os::gdt::{kernel_stack, set_kernel_stack, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use ferr_os::syscall::{dispatch, SyscallFrame, EBADF, EFAULT, ENOSYS, SYS_WRITE};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ferr_os::debug_reg::{clear_watchpoint, hit_count, set_watchpoint, WatchKind};
+use ferr_os::power::{panic_action, set_panic_action, PanicAction};
+use ferr_os::idt::current_idt;
+use ferr_os::{init_idt, kernel_idt};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = FrameAllocator::new(&boot_info.memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+
+    init_default_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
+
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn hpet_comparator_fires() {
+    let start = ferr_os::hpet::now_ns();
+    let Ok(comparator) = ferr_os::hpet::comparator(0) else {
+        return;
+    };
+
+    comparator.arm(start + 100_000, None).unwrap();
+    while !comparator.has_fired() {
+        core::hint::spin_loop();
+    }
+    assert!(ferr_os::hpet::now_ns() >= start + 100_000);
+}
+
+#[test_case]
+fn ioapic_routes_isa_irqs() {
+    // the keyboard line is unmasked by preinit, QEMU has no override for it
+    assert_eq!(1, ioapic::isa_route(1).unwrap().gsi);
+    assert!(ioapic::isa_route(16).is_err());
+
+    let timer = ioapic::isa_route(0).unwrap();
+    assert_eq!(timer.gsi, ioapic::route_isa_irq(0, 0).unwrap());
+    ioapic::mask(timer.gsi).unwrap();
+
+    assert!(ioapic::route(0xffff, 0x40, ioapic::Polarity::ActiveHigh, ioapic::TriggerMode::Edge, 0).is_err());
+    assert!(ioapic::unmask(0xffff).is_err());
+}
+
+#[test_case]
+fn ata_reads_boot_disk() {
+    // the test runner boots from a GPT disk on the primary master
+    let mut buf = [0u8; 2 * SECTOR_SIZE];
+    read_sectors(Drive::Master, 0, 2, &mut buf).unwrap();
+    assert_eq!([0x55, 0xAA], buf[510..512]);
+    assert_eq!(b"EFI PART", &buf[SECTOR_SIZE..SECTOR_SIZE + 8]);
+
+    assert!(read_sectors(Drive::Master, 0, 3, &mut buf).is_err());
+    assert!(read_sectors(Drive::Master, MAX_LBA28_SECTORS - 1, 2, &mut buf).is_err());
+}
+
+#[test_case]
+fn random_numbers_differ() {
+    let first = ferr_os::rand::random_u64().unwrap();
+    let second = ferr_os::rand::random_u64().unwrap();
+    assert_ne!(first, second);
+    assert!(ferr_os::rand::random_seed().is_some());
+}
+
+#[test_case]
+fn kernel_stack_is_stored_in_tss() {
+    assert_eq!(3, USER_CODE_SELECTOR.0 & 3);
+    assert_eq!(USER_DATA_SELECTOR.0 + 8, USER_CODE_SELECTOR.0);
+
+    let previous = kernel_stack();
+    set_kernel_stack(VirtAddr::new(0x_4444_0000_1000));
+    assert_eq!(VirtAddr::new(0x_4444_0000_1000), kernel_stack());
+    set_kernel_stack(previous);
+}
+
+#[test_case]
+fn syscall_dispatch_checks_arguments() {
+    let text = b"kernel memory";
+    let mut frame = SyscallFrame {
+        rax: SYS_WRITE, rdi: 1, rsi: text.as_ptr() as u64, rdx: text.len() as u64,
+        r10: 0, r8: 0, r9: 0, r11: 0, rcx: 0, user_rsp: 0
+    };
+    // the buffer is not user accessible
+    assert_eq!(-EFAULT, dispatch(&mut frame));
+
+    frame.rdi = 5;
+    assert_eq!(-EBADF, dispatch(&mut frame));
+
+    frame.rax = 1000;
+    assert_eq!(-ENOSYS, dispatch(&mut frame));
+}
+
+#[test_case]
+fn write_watchpoint_triggers() {
+    static WATCHED: AtomicUsize = AtomicUsize::new(0);
+
+    let addr = VirtAddr::new(&WATCHED as *const _ as u64);
+    assert!(set_watchpoint(VirtAddr::new(addr.0 + 1), 8, WatchKind::Write).is_err());
+
+    let slot = set_watchpoint(addr, 8, WatchKind::Write).unwrap();
+    let _ = WATCHED.load(Ordering::SeqCst);
+    assert_eq!(0, hit_count(slot));
+
+    WATCHED.store(1, Ordering::SeqCst);
+    assert_eq!(1, hit_count(slot));
+
+    clear_watchpoint(slot).unwrap();
+    WATCHED.store(2, Ordering::SeqCst);
+    assert_eq!(1, hit_count(slot));
+    assert!(clear_watchpoint(slot).is_err());
+}
+
+#[test_case]
+fn fpu_is_usable() {
+    assert!(ferr_os::fpu::init_fpu());
+
+    // would raise #NM or #UD without CR0 and CR4 set up
+    let mut x87_control: u16 = 0;
+    unsafe {
+        core::arch::asm!("fnstcw [{}]", in(reg) &mut x87_control, options(nostack));
+    }
+    assert_eq!(0x037F, x87_control);
+}
+
+#[test_case]
+fn panic_action_is_configurable() {
+    assert_eq!(Some(PanicAction::Reboot), PanicAction::from_name("reboot"));
+    assert_eq!(None, PanicAction::from_name("restart"));
+
+    assert_eq!(PanicAction::Halt, panic_action());
+    set_panic_action(PanicAction::Reboot);
+    assert_eq!(PanicAction::Reboot, panic_action());
+    set_panic_action(PanicAction::Halt);
+}
+
+#[test_case]
+fn idt_can_be_swapped() {
+    let shared = current_idt();
+    let idt: &'static _ = alloc::boxed::Box::leak(alloc::boxed::Box::new(kernel_idt()));
+    assert!(!idt.is_loaded());
+
+    shared_lib::interrupts::without_interrupts(|| {
+        idt.load();
+        assert!(idt.is_loaded());
+        assert_eq!(idt as *const _ as u64, { current_idt().base }.0);
+        assert_eq!(4095, { current_idt().limit });
+
+        init_idt();
+    });
+
+    assert!(!idt.is_loaded());
+    assert_eq!({ shared.base }, { current_idt().base });
+    assert!({ ferr_os::gdt::current_gdt().limit } > 0);
+}
+
+#[test_case]
+fn pit_timer_frequency_is_programmed() {
+    // the PIT line stays masked while the local APIC timer drives the ticks
+    assert_eq!(1000, pit::init_timer(1000));
+    assert_eq!(1000, pit::frequency());
+    // counts down from the divisor, 1193182 / 1000
+    assert!(pit::current_count() <= 1193);
+
+    // clamped to the 16 bit divisor range
+    assert_eq!(18, pit::init_timer(1));
+    assert_eq!(pit::PIT_BASE_FREQUENCY / 2, pit::init_timer(u32::MAX));
+    pit::init_timer(1000);
+}
@@ -0,0 +1,307 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use shared_lib::{entry_point, BootInfo, VIRT_MAPPING_OFFSET};
+use core::panic::PanicInfo;
+use core::ptr::{read_volatile, write_volatile};
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::page_table::{get_page_flags, is_nx_enabled, map_address_with_offset, protect_with_offset, remap_address_with_offset, unmap_address_with_offset, CloneMode, PageTable, PageTableFlags};
+use ferr_os::allocator::init_default_heap;
+use ferr_os::elf::load_elf;
+use ferr_os::memory::{active_level_4_table, current_l4, is_guard_page, map_mmio, map_stack_with_guard, release_lazy, reserve_lazy, switch_address_space, unregister_guard_page, COPY_ON_WRITE, FRAME_ALLOCATOR};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = FrameAllocator::new(&boot_info.memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+
+    init_default_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
+
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+unsafe fn fill_frame(frame: u64, value: u64) {
+    write_volatile((frame + VIRT_MAPPING_OFFSET) as *mut u64, value);
+}
+
+#[test_case]
+fn remap_takes_effect_immediately() {
+    let l4_table = unsafe { active_level_4_table() };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+
+    let first_frame = allocator.allocate_frame().unwrap();
+    let second_frame = allocator.allocate_frame().unwrap();
+    let page = VirtAddr::new(0x_5555_0000_0000);
+
+    unsafe {
+        fill_frame(first_frame, 0x1111);
+        fill_frame(second_frame, 0x2222);
+
+        map_address_with_offset(l4_table, page, PhysAddr::new(first_frame), allocator, VIRT_MAPPING_OFFSET).unwrap();
+        assert_eq!(0x1111, read_volatile(page.0 as *const u64));
+
+        remap_address_with_offset(l4_table, page, PhysAddr::new(second_frame), allocator, VIRT_MAPPING_OFFSET).unwrap();
+        assert_eq!(0x2222, read_volatile(page.0 as *const u64));
+    }
+}
+
+#[test_case]
+fn copy_on_write() {
+    let l4_table = unsafe { active_level_4_table() };
+    let page = VirtAddr::new(0x_5555_0010_0000);
+    let frame = {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().unwrap();
+        let frame = allocator.allocate_frame().unwrap();
+
+        unsafe {
+            fill_frame(frame, 0x3333);
+            write_volatile((frame + VIRT_MAPPING_OFFSET + 8) as *mut u64, 0);
+            map_address_with_offset(l4_table, page, PhysAddr::new(frame), allocator, VIRT_MAPPING_OFFSET).unwrap();
+            protect_with_offset(l4_table, page, COPY_ON_WRITE, VIRT_MAPPING_OFFSET).unwrap();
+        }
+        frame
+    };
+
+    unsafe {
+        write_volatile((page.0 + 8) as *mut u64, 0x4444);
+
+        assert_eq!(0x3333, read_volatile(page.0 as *const u64));
+        assert_eq!(0x4444, read_volatile((page.0 + 8) as *const u64));
+        assert_eq!(0, read_volatile((frame + VIRT_MAPPING_OFFSET + 8) as *const u64));
+
+        let flags = get_page_flags(l4_table, page, VIRT_MAPPING_OFFSET).unwrap();
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        assert!(!flags.contains(COPY_ON_WRITE));
+    }
+}
+
+#[test_case]
+fn lazy_region_is_allocated_on_access() {
+    let l4_table = unsafe { active_level_4_table() };
+    let start = VirtAddr::new(0x_5555_0020_0000);
+    let size = 4 * 4096;
+
+    reserve_lazy(start, size, PageTableFlags::WRITABLE).unwrap();
+    assert!(reserve_lazy(start.offset(4096).unwrap(), 4096, PageTableFlags::WRITABLE).is_err());
+
+    unsafe {
+        assert!(get_page_flags(l4_table, start, VIRT_MAPPING_OFFSET).is_none());
+
+        let third_page = start.offset(2 * 4096).unwrap();
+        assert_eq!(0, read_volatile((third_page.0 + 16) as *const u64));
+        write_volatile(third_page.0 as *mut u64, 0x5555);
+        assert_eq!(0x5555, read_volatile(third_page.0 as *const u64));
+
+        let flags = get_page_flags(l4_table, third_page, VIRT_MAPPING_OFFSET).unwrap();
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        assert!(get_page_flags(l4_table, start, VIRT_MAPPING_OFFSET).is_none());
+    }
+
+    release_lazy(start).unwrap();
+    assert!(release_lazy(start).is_err());
+}
+
+#[test_case]
+fn stack_has_unmapped_guard_page() {
+    let l4_table = unsafe { active_level_4_table() };
+    let top = VirtAddr::new(0x_5555_0040_0000);
+    let pages = 3;
+
+    let stack_pointer = {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().unwrap();
+        unsafe { map_stack_with_guard(l4_table, top, pages, allocator).unwrap() }
+    };
+    assert_eq!(top, stack_pointer);
+
+    let bottom = VirtAddr::new(top.0 - pages * 4096);
+    let guard_page = VirtAddr::new(bottom.0 - 4096);
+
+    unsafe {
+        write_volatile((top.0 - 8) as *mut u64, 0x6666);
+        assert_eq!(0x6666, read_volatile((top.0 - 8) as *const u64));
+        assert_eq!(0, read_volatile(bottom.0 as *const u64));
+
+        assert!(get_page_flags(l4_table, guard_page, VIRT_MAPPING_OFFSET).is_none());
+    }
+
+    assert!(is_guard_page(VirtAddr::new(bottom.0 - 8)));
+    assert!(!is_guard_page(bottom));
+
+    unregister_guard_page(guard_page).unwrap();
+    assert!(!is_guard_page(guard_page));
+}
+
+#[test_case]
+fn mmio_mapping_keeps_page_offset() {
+    let l4_table = unsafe { active_level_4_table() };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+
+    // a RAM frame stands in for device registers
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe {
+        write_volatile((frame + VIRT_MAPPING_OFFSET + 0x10) as *mut u32, 0x5555);
+
+        let registers = map_mmio(l4_table, PhysAddr::new(frame + 0x10), 8, allocator).unwrap();
+        assert_eq!(0x10, registers.0 % 4096);
+        assert_eq!(0x5555, shared_lib::read_u32_ptr(registers.as_mut_ptr(), 0));
+
+        let flags = get_page_flags(l4_table, registers, VIRT_MAPPING_OFFSET).unwrap();
+        assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE));
+
+        // a range crossing a page boundary takes two pages
+        let crossing = map_mmio(l4_table, PhysAddr::new(frame + 0xffc), 8, allocator).unwrap();
+        assert!(get_page_flags(l4_table, crossing.offset(4).unwrap(), VIRT_MAPPING_OFFSET).is_some());
+    }
+}
+
+#[test_case]
+fn switch_to_cloned_address_space() {
+    let l4_table = unsafe { active_level_4_table() };
+    let original_l4 = current_l4();
+    let page = VirtAddr::new(0x_5555_0020_0000);
+
+    // nothing may touch the heap while the clone is active, new heap pages wouldn't be visible
+    // after switching back
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let frame = allocator.allocate_frame().unwrap();
+
+    unsafe {
+        fill_frame(frame, 0x6666);
+
+        let clone = l4_table.clone_hierarchy(allocator, CloneMode::ShareFrames, VIRT_MAPPING_OFFSET).unwrap() as *mut PageTable;
+        map_address_with_offset(&mut *clone, page, PhysAddr::new(frame), allocator, VIRT_MAPPING_OFFSET).unwrap();
+        let clone_l4 = clone as u64 - VIRT_MAPPING_OFFSET;
+
+        assert!(switch_address_space(clone_l4 + 8).is_err());
+
+        switch_address_space(clone_l4).unwrap();
+        assert_eq!(clone_l4, current_l4());
+        assert_eq!(0x6666, read_volatile(page.0 as *const u64));
+
+        switch_address_space(original_l4).unwrap();
+        assert_eq!(original_l4, current_l4());
+        assert!(get_page_flags(active_level_4_table(), page, VIRT_MAPPING_OFFSET).is_none());
+    }
+}
+
+#[test_case]
+fn shared_frames_are_reference_counted() {
+    let l4_table = unsafe { active_level_4_table() };
+    let page = VirtAddr::new(0x_5555_0030_0000);
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let frame = allocator.allocate_frame().unwrap();
+
+    unsafe {
+        map_address_with_offset(l4_table, page, PhysAddr::new(frame), allocator, VIRT_MAPPING_OFFSET).unwrap();
+        let clone = l4_table.clone_hierarchy(allocator, CloneMode::ShareFrames, VIRT_MAPPING_OFFSET).unwrap() as *mut PageTable;
+        assert_eq!(2, allocator.refcount(frame));
+
+        unmap_address_with_offset(l4_table, page, allocator, VIRT_MAPPING_OFFSET).unwrap();
+        assert_eq!(1, allocator.refcount(frame));
+        assert!(!allocator.is_free(frame));
+
+        unmap_address_with_offset(&mut *clone, page, allocator, VIRT_MAPPING_OFFSET).unwrap();
+        assert!(allocator.is_free(frame));
+    }
+}
+
+const ELF_BASE: u64 = 0x_5555_0040_0000;
+
+fn put(elf: &mut [u8], offset: usize, bytes: &[u8]) {
+    elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn put_segment(elf: &mut [u8], index: usize, flags: u32, offset: u64, vaddr: u64, file_size: u64, mem_size: u64) {
+    let header = 64 + index * 56;
+    put(elf, header, &1u32.to_le_bytes());
+    put(elf, header + 4, &flags.to_le_bytes());
+    put(elf, header + 8, &offset.to_le_bytes());
+    put(elf, header + 16, &vaddr.to_le_bytes());
+    put(elf, header + 32, &file_size.to_le_bytes());
+    put(elf, header + 40, &mem_size.to_le_bytes());
+    put(elf, header + 48, &4096u64.to_le_bytes());
+}
+
+// a static executable with a read-execute segment holding the headers and 4 bytes of code, and a
+// read-write segment with 8 bytes of data followed by bss crossing a page boundary
+fn minimal_elf() -> [u8; 192] {
+    let mut elf = [0u8; 192];
+    put(&mut elf, 0, &[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    put(&mut elf, 16, &2u16.to_le_bytes());
+    put(&mut elf, 18, &0x3Eu16.to_le_bytes());
+    put(&mut elf, 20, &1u32.to_le_bytes());
+    put(&mut elf, 24, &(ELF_BASE + 176).to_le_bytes());
+    put(&mut elf, 32, &64u64.to_le_bytes());
+    put(&mut elf, 52, &64u16.to_le_bytes());
+    put(&mut elf, 54, &56u16.to_le_bytes());
+    put(&mut elf, 56, &2u16.to_le_bytes());
+
+    put_segment(&mut elf, 0, 0b101, 0, ELF_BASE, 180, 180);
+    put_segment(&mut elf, 1, 0b110, 184, ELF_BASE + 0x20b8, 8, 0x1000);
+    put(&mut elf, 176, &[0x0f, 0x05, 0xeb, 0xfe]);
+    put(&mut elf, 184, &0x7777u64.to_le_bytes());
+    elf
+}
+
+#[test_case]
+fn elf_segments_are_loaded() {
+    let l4_table = unsafe { active_level_4_table() };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+
+    let mut elf = minimal_elf();
+    elf[18] = 0x03;
+    assert_eq!(Err("ELF is not built for x86-64"), unsafe { load_elf(&elf, l4_table, allocator, 0) });
+    let mut elf = minimal_elf();
+    elf[16] = 1;
+    assert_eq!(Err("ELF is not an executable"), unsafe { load_elf(&elf, l4_table, allocator, 0) });
+
+    let elf = minimal_elf();
+    unsafe {
+        let entry = load_elf(&elf, l4_table, allocator, 0).unwrap();
+        assert_eq!(ELF_BASE + 176, entry.0);
+        assert_eq!(0xfeeb050f, read_volatile(entry.0 as *const u32));
+
+        let code_flags = get_page_flags(l4_table, VirtAddr::new(ELF_BASE), VIRT_MAPPING_OFFSET).unwrap();
+        assert!(code_flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(!code_flags.contains(PageTableFlags::WRITABLE));
+        assert!(!code_flags.contains(PageTableFlags::NO_EXECUTE));
+
+        let data = ELF_BASE + 0x20b8;
+        assert_eq!(0x7777, read_volatile(data as *const u64));
+        assert_eq!(0, read_volatile((data + 8) as *const u64));
+        assert_eq!(0, read_volatile((data + 0xff8) as *const u64));
+
+        let bss_flags = get_page_flags(l4_table, VirtAddr::new(data + 0xff8), VIRT_MAPPING_OFFSET).unwrap();
+        assert!(bss_flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+        assert_eq!(is_nx_enabled(), bss_flags.contains(PageTableFlags::NO_EXECUTE));
+    }
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use shared_lib::{entry_point, BootInfo, VIRT_MAPPING_OFFSET};
+use core::panic::PanicInfo;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec;
+use shared_lib::addr::VirtAddr;
+use shared_lib::frame_allocator::FrameAllocator;
+use ferr_os::allocator::init_default_heap;
+use ferr_os::memory::active_level_4_table;
+use ferr_os::thread::{switch_context, Context, Thread};
+
+const STACK_SIZE: usize = 16 * 4096;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = FrameAllocator::new(&boot_info.memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+
+    init_default_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn run_once() {
+    RUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn finished_thread_returns_to_starter() {
+    let stack = vec![0u8; STACK_SIZE];
+    let thread = Thread::spawn(run_once, VirtAddr::from_ptr(stack.as_ptr_range().end));
+
+    let mut main_context = Context::new();
+    unsafe { switch_context(&mut main_context, thread.context()) };
+
+    assert_eq!(1, RUNS.load(Ordering::Relaxed));
+}
+
+static mut MAIN_CONTEXT: Context = Context::new();
+static mut PING_CONTEXT: Context = Context::new();
+static PINGS: AtomicUsize = AtomicUsize::new(0);
+
+fn ping() {
+    for _ in 0..3 {
+        PINGS.fetch_add(1, Ordering::Relaxed);
+        unsafe { switch_context(&mut *addr_of_mut!(PING_CONTEXT), &*addr_of!(MAIN_CONTEXT)) };
+    }
+}
+
+#[test_case]
+fn threads_switch_back_and_forth() {
+    let stack = vec![0u8; STACK_SIZE];
+    let thread = Thread::spawn(ping, VirtAddr::from_ptr(stack.as_ptr_range().end));
+
+    unsafe {
+        *addr_of_mut!(PING_CONTEXT) = thread.context().clone();
+
+        for i in 1..=3 {
+            switch_context(&mut *addr_of_mut!(MAIN_CONTEXT), &*addr_of!(PING_CONTEXT));
+            assert_eq!(i, PINGS.load(Ordering::Relaxed));
+        }
+
+        // the loop is over, so the thread returns to the context that started it
+        switch_context(&mut *addr_of_mut!(MAIN_CONTEXT), &*addr_of!(PING_CONTEXT));
+    }
+
+    assert_eq!(3, PINGS.load(Ordering::Relaxed));
+}